@@ -0,0 +1,734 @@
+//! 青空文庫形式のテキスト (`.txt`) を XHTML に変換する
+//!
+//! - ルビ: `漢字《かんじ》`, `｜青空《あおぞら》`
+//! - 注記 `［＃…］`: 傍点・傍線, 太字・斜体, 縦中横, 見出し, 改ページ, 字下げ・地付き, 外字
+//! - 文字コードは, UTF-8 として読めなければ Shift_JIS とみなす
+//!
+//! 縦書きでも横書きでも崩れないよう, 字下げは`margin-inline-start`などの論理プロパティで指定する
+
+use crate::xhtml;
+
+pub const AOZORA_CSS: &str = "aozora.css";
+pub const AOZORA_STYLE: &str = include_str!("literals/aozora.css");
+/// 改ページの目印. コンテンツはこの要素の位置で別のファイルに分割される
+pub const PAGE_BREAK_CLASS: &str = "aozora-page-break";
+
+/// 傍点・傍線などの名前, 要素, class
+const DECORATIONS: [(&str, &str, &str); 18] = [
+    ("傍点", "em", "sesame_dot"),
+    ("白ゴマ傍点", "em", "white_sesame_dot"),
+    ("丸傍点", "em", "black_circle"),
+    ("白丸傍点", "em", "white_circle"),
+    ("黒三角傍点", "em", "black_up-pointing_triangle"),
+    ("白三角傍点", "em", "white_up-pointing_triangle"),
+    ("二重丸傍点", "em", "bullseye"),
+    ("蛇の目傍点", "em", "fisheye"),
+    ("ばつ傍点", "em", "saltire"),
+    ("傍線", "em", "underline_solid"),
+    ("二重傍線", "em", "underline_double"),
+    ("鎖線", "em", "underline_dotted"),
+    ("破線", "em", "underline_dashed"),
+    ("波線", "em", "underline_wave"),
+    ("太字", "span", "futoji"),
+    ("斜体", "span", "shatai"),
+    ("縦中横", "span", "tcy"),
+    ("下線", "em", "underline_solid"),
+];
+
+/// 見出しの名前, 要素のレベル, class
+/// `h1`は作品名に使うので, 大見出しは`h2`とする
+const HEADINGS: [(&str, u8, &str); 3] = [
+    ("大見出し", 2, "o-midashi"),
+    ("中見出し", 3, "naka-midashi"),
+    ("小見出し", 4, "ko-midashi"),
+];
+
+/// `※［＃始め二重山括弧、1-1-52］` のように, 記法と重なる記号を表す外字注記
+const SYMBOLS: [(&str, char); 8] = [
+    ("始め二重山括弧", '《'),
+    ("終わり二重山括弧", '》'),
+    ("縦線", '｜'),
+    ("始め角括弧", '［'),
+    ("終わり角括弧", '］'),
+    ("井げた", '＃'),
+    ("米印", '※'),
+    ("二の字点", '〻'),
+];
+
+/// 底本の書誌情報の始まり
+const COLOPHON: &str = "底本：";
+
+/// バイト列を文字列にする. UTF-8 として読めなければ Shift_JIS とみなす
+pub fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => encoding_rs::SHIFT_JIS.decode_without_bom_handling(bytes).0.into_owned(),
+    }
+}
+
+/// 青空文庫形式の記法 (注記, ルビ, 記号の説明) を含むか否か
+/// `README.txt`のような普通のテキストを, コンテンツとして収録しないために使う
+pub fn is_aozora(text: &str) -> bool {
+    text.contains("［＃")
+        || text.contains("【テキスト中に現れる記号について】")
+        || text.lines().any(|line| Line::parse(line).atoms.iter().any(|a| matches!(a, Atom::Ruby(..))))
+}
+
+/// 変換した文書
+#[derive(Debug, Default, PartialEq)]
+pub struct AozoraDocument {
+    /// 作品名 (1 行目)
+    pub title: Option<String>,
+    /// 著者名 (表題の最後の行)
+    pub creator: Option<String>,
+    /// `<body>`の子要素
+    pub body: String,
+}
+
+/// 1 行の中の要素
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Char(char),
+    /// 親文字と読み
+    Ruby(Vec<Atom>, String),
+    /// 外字など, 1 文字として扱う html. 注記の「」の中で参照されるときの文字列を持つ
+    Glyph(String, String),
+    /// 開始・終了タグなど, 文字を持たない html
+    Tag(String),
+    /// `｜` の位置 (ルビの親文字の始まり)
+    RubyStart,
+}
+
+impl Atom {
+    /// 注記の「」の中の文字列と照合するための文字列
+    fn text(&self) -> String {
+        match self {
+            Atom::Char(c) => c.to_string(),
+            Atom::Ruby(base, _) => base.iter().map(Atom::text).collect(),
+            Atom::Glyph(text, _) => text.clone(),
+            Atom::Tag(_) | Atom::RubyStart => String::new(),
+        }
+    }
+
+    fn write_html(&self, out: &mut String) {
+        match self {
+            Atom::Char(c) => out.push_str(&xhtml::escape_text(&c.to_string())),
+            Atom::Ruby(base, rt) => {
+                out.push_str("<ruby>");
+                for atom in base {
+                    atom.write_html(out);
+                }
+                out.push_str(&format!("<rt>{}</rt></ruby>", xhtml::escape_text(rt)));
+            }
+            Atom::Glyph(_, html) | Atom::Tag(html) => out.push_str(html),
+            Atom::RubyStart => out.push('｜'),
+        }
+    }
+}
+
+fn to_html(atoms: &[Atom]) -> String {
+    let mut html = String::new();
+    for atom in atoms {
+        atom.write_html(&mut html);
+    }
+    html
+}
+
+/// ルビの親文字を自動で決めるための文字の種類
+#[derive(Debug, PartialEq)]
+enum CharClass {
+    Kanji,
+    Hiragana,
+    Katakana,
+    FullWidthAlphanumeric,
+    Alphanumeric,
+    Other,
+}
+
+impl CharClass {
+    fn of(atom: &Atom) -> Self {
+        let c = match atom {
+            Atom::Char(c) => *c,
+            // 外字はほとんどが漢字
+            Atom::Glyph(..) => return CharClass::Kanji,
+            _ => return CharClass::Other,
+        };
+        match c {
+            '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' | '\u{F900}'..='\u{FAFF}' | '\u{20000}'..='\u{2FFFF}'
+            | '々' | '〆' | '〇' | 'ヶ' | '〻' => CharClass::Kanji,
+            'ぁ'..='ゟ' => CharClass::Hiragana,
+            'ァ'..='ヿ' => CharClass::Katakana,
+            '０'..='９' | 'Ａ'..='Ｚ' | 'ａ'..='ｚ' => CharClass::FullWidthAlphanumeric,
+            c if c.is_ascii_alphanumeric() => CharClass::Alphanumeric,
+            _ => CharClass::Other,
+        }
+    }
+}
+
+/// 字下げ, 地付きなどの配置
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Layout {
+    /// 字下げ (em)
+    indent: Option<u32>,
+    /// 1 行目の字下げの, 2 行目以降との差 (em)
+    text_indent: Option<i32>,
+    /// 地付き
+    end: bool,
+    /// 地からの字上げ (em)
+    end_indent: Option<u32>,
+}
+
+impl Layout {
+    /// `３字下げ`, `３字下げ、折り返して５字下げ`, `改行天付き、折り返して１字下げ`, `地付き`, `地から２字上げ`
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.strip_prefix("天から").unwrap_or(s);
+        if s == "地付き" {
+            return Some(Self { end: true, ..Self::default() });
+        }
+        if let Some(n) = s.strip_prefix("地から").and_then(|s| s.strip_suffix("字上げ")) {
+            return Some(Self { end: true, end_indent: Some(parse_number(n)?), ..Self::default() });
+        }
+
+        let mut parts = s.splitn(2, '、');
+        let first = parts.next()?;
+        let first = if first == "改行天付き" {
+            0
+        } else {
+            parse_number(first.strip_suffix("字下げ")?)?
+        };
+        match parts.next() {
+            None => Some(Self { indent: Some(first), ..Self::default() }),
+            Some(rest) => {
+                let rest = parse_number(rest.strip_prefix("折り返して")?.strip_suffix("字下げ")?)?;
+                Some(Self { indent: Some(rest), text_indent: Some(first as i32 - rest as i32), ..Self::default() })
+            }
+        }
+    }
+
+    fn style(&self) -> Option<String> {
+        let mut declarations = Vec::new();
+        if let Some(n) = self.indent.filter(|n| *n > 0) {
+            declarations.push(format!("margin-inline-start: {}em;", n));
+        }
+        if let Some(n) = self.text_indent.filter(|n| *n != 0) {
+            declarations.push(format!("text-indent: {}em;", n));
+        }
+        if self.end {
+            declarations.push("text-align: end;".to_string());
+        }
+        if let Some(n) = self.end_indent.filter(|n| *n > 0) {
+            declarations.push(format!("margin-inline-end: {}em;", n));
+        }
+        if declarations.is_empty() {
+            None
+        } else {
+            Some(declarations.join(" "))
+        }
+    }
+
+    fn attribute(&self) -> String {
+        match self.style() {
+            Some(style) => format!(" style=\"{}\"", style),
+            None => String::new(),
+        }
+    }
+}
+
+/// `３`, `12`, `三`, `十二` を数値にする
+fn parse_number(s: &str) -> Option<u32> {
+    const KANJI: [char; 10] = ['〇', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+
+    if s.is_empty() {
+        return None;
+    }
+    if let Some(n) = s.chars().map(|c| match c {
+        '0'..='9' => c.to_digit(10),
+        '０'..='９' => Some(c as u32 - '０' as u32),
+        _ => None,
+    }).collect::<Option<Vec<u32>>>() {
+        return Some(n.iter().fold(0, |acc, d| acc * 10 + d));
+    }
+
+    // 漢数字 (九十九まで)
+    let digit = |c: char| KANJI.iter().position(|k| *k == c).map(|n| n as u32);
+    match s.split_once('十') {
+        Some((tens, ones)) => {
+            let tens = if tens.is_empty() { 1 } else { digit(tens.chars().next()?)? };
+            let ones = if ones.is_empty() { 0 } else { digit(ones.chars().next()?)? };
+            Some(tens * 10 + ones)
+        }
+        None if s.chars().count() == 1 => digit(s.chars().next()?),
+        None => None,
+    }
+}
+
+/// 行をまたいで効く注記
+#[derive(Debug, PartialEq)]
+enum Command {
+    PageBreak,
+    /// `ここから３字下げ`
+    BeginLayout(Layout),
+    /// `ここで字下げ終わり`
+    EndLayout,
+    /// `ここから大見出し`
+    BeginHeading(u8, &'static str),
+    /// `ここで大見出し終わり`
+    EndHeading,
+}
+
+/// 1 行を変換した結果
+#[derive(Debug, Default)]
+struct Line {
+    atoms: Vec<Atom>,
+    layout: Layout,
+    heading: Option<(u8, &'static str)>,
+    commands: Vec<Command>,
+}
+
+impl Line {
+    fn parse(line: &str) -> Self {
+        let chars = line.chars().collect::<Vec<char>>();
+        let mut parsed = Self::default();
+        let mut i = 0;
+
+        while i < chars.len() {
+            match chars[i] {
+                '｜' => parsed.atoms.push(Atom::RubyStart),
+                '《' => {
+                    let close = chars[i + 1..].iter().position(|c| *c == '》').map(|n| i + 1 + n);
+                    let ruby = close.and_then(|close| {
+                        let rt = chars[i + 1..close].iter().collect::<String>();
+                        parsed.ruby(rt).map(|_| close)
+                    });
+                    match ruby {
+                        Some(close) => i = close,
+                        // 親文字が見つからなければそのまま出力する
+                        None => parsed.atoms.push(Atom::Char('《')),
+                    }
+                }
+                '［' if chars.get(i + 1) == Some(&'＃') => {
+                    // 注記は入れ子になりうる (`［＃「※［＃…］」に傍点］`)
+                    let mut depth = 0;
+                    let close = chars[i..].iter().position(|c| {
+                        match c {
+                            '［' => depth += 1,
+                            '］' => depth -= 1,
+                            _ => {}
+                        }
+                        depth == 0
+                    }).map(|n| i + n);
+                    match close {
+                        Some(close) => {
+                            let annotation = chars[i + 2..close].iter().collect::<String>();
+                            parsed.annotate(&annotation);
+                            i = close;
+                        }
+                        None => parsed.atoms.push(Atom::Char('［')),
+                    }
+                }
+                c => parsed.atoms.push(Atom::Char(c)),
+            }
+            i += 1;
+        }
+
+        parsed
+    }
+
+    /// 直前の文字にルビを振る
+    fn ruby(&mut self, rt: String) -> Option<()> {
+        let start = match self.atoms.iter().rposition(|a| *a == Atom::RubyStart) {
+            Some(start) => {
+                self.atoms.remove(start);
+                start
+            }
+            None => {
+                // 直前の文字と同じ種類の文字が続く範囲
+                let class = CharClass::of(self.atoms.last()?);
+                if class == CharClass::Other {
+                    return None;
+                }
+                self.atoms.iter().rposition(|a| CharClass::of(a) != class).map(|n| n + 1).unwrap_or(0)
+            }
+        };
+        if start == self.atoms.len() {
+            return None;
+        }
+
+        let base = self.atoms.split_off(start);
+        self.atoms.push(Atom::Ruby(base, rt));
+        Some(())
+    }
+
+    /// atoms の末尾に最も近い, target と一致する範囲
+    fn find(&self, target: &str) -> Option<std::ops::Range<usize>> {
+        for end in (0..self.atoms.len()).rev() {
+            let mut text = String::new();
+            for start in (0..=end).rev() {
+                text.insert_str(0, &self.atoms[start].text());
+                if text == target {
+                    return Some(start..end + 1);
+                }
+                if text.len() >= target.len() && !matches!(self.atoms[start], Atom::Tag(_)) {
+                    break;
+                }
+            }
+        }
+        None
+    }
+
+    /// range を要素で囲む
+    fn wrap(&mut self, range: std::ops::Range<usize>, tag: &str, class: &str) {
+        self.atoms.insert(range.end, Atom::Tag(format!("</{}>", tag)));
+        self.atoms.insert(range.start, Atom::Tag(format!("<{} class=\"{}\">", tag, class)));
+    }
+
+    fn note(&mut self, annotation: &str) {
+        self.atoms.push(Atom::Tag(format!("<span class=\"notes\">［＃{}］</span>", xhtml::escape_text(annotation))));
+    }
+
+    fn annotate(&mut self, annotation: &str) {
+        // 外字
+        if self.atoms.last() == Some(&Atom::Char('※')) {
+            self.atoms.pop();
+            self.atoms.push(gaiji(annotation));
+            return;
+        }
+
+        match annotation {
+            "改ページ" | "改丁" | "改段" | "改見開き" => return self.commands.push(Command::PageBreak),
+            _ => {}
+        }
+
+        if let Some(rest) = annotation.strip_prefix("ここから") {
+            if let Some(layout) = Layout::parse(rest) {
+                return self.commands.push(Command::BeginLayout(layout));
+            }
+            if let Some((_, level, class)) = HEADINGS.iter().find(|(name, ..)| rest == *name) {
+                return self.commands.push(Command::BeginHeading(*level, class));
+            }
+            // 太字などの範囲の指定は, 段落をまたぐと要素が入れ子にならないので無視する
+            return;
+        }
+        if let Some(rest) = annotation.strip_prefix("ここで").and_then(|s| s.strip_suffix("終わり")) {
+            if HEADINGS.iter().any(|(name, ..)| rest == *name) {
+                return self.commands.push(Command::EndHeading);
+            }
+            if rest.ends_with("字下げ") || rest.ends_with("字上げ") || rest == "地付き" {
+                return self.commands.push(Command::EndLayout);
+            }
+            return;
+        }
+
+        if let Some(layout) = Layout::parse(annotation) {
+            self.layout = layout;
+            return;
+        }
+
+        // `［＃大見出し］…［＃大見出し終わり］`
+        if let Some((_, level, class)) = HEADINGS.iter().find(|(name, ..)| annotation == *name) {
+            self.heading = Some((*level, class));
+            return;
+        }
+        if HEADINGS.iter().any(|(name, ..)| annotation.strip_suffix("終わり") == Some(*name)) {
+            return;
+        }
+
+        // `［＃傍点］…［＃傍点終わり］`
+        if let Some((_, tag, class)) = DECORATIONS.iter().find(|(name, ..)| annotation == *name) {
+            return self.atoms.push(Atom::Tag(format!("<{} class=\"{}\">", tag, class)));
+        }
+        if let Some((_, tag, _)) = DECORATIONS.iter().find(|(name, ..)| annotation.strip_suffix("終わり") == Some(*name)) {
+            return self.atoms.push(Atom::Tag(format!("</{}>", tag)));
+        }
+
+        // `［＃「対象」に傍点］` のように, 直前の文字列を参照する
+        // 対象が`」`を含むこともあるので, 処理できる区切りを順に探す
+        if let Some(rest) = annotation.strip_prefix('「') {
+            for (close, _) in rest.match_indices('」') {
+                let (target, op) = (&rest[..close], &rest[close + '」'.len_utf8()..]);
+                if self.reference(target, op) {
+                    return;
+                }
+            }
+        }
+
+        // 底本との異同 (`「…」はママ`) など, 解釈しない注記はそのまま残す
+        self.note(annotation);
+    }
+
+    /// 参照の注記を処理できれば true
+    fn reference(&mut self, target: &str, op: &str) -> bool {
+        let range = match self.find(target) {
+            Some(range) => range,
+            None => return false,
+        };
+
+        // 傍点, 傍線 (`の左に傍点`も同じ扱いとする)
+        let decoration = op.strip_prefix('に').or_else(|| op.strip_prefix("の左に")).or_else(|| op.strip_prefix('は'));
+        if let Some((_, tag, class)) = decoration.and_then(|d| DECORATIONS.iter().find(|(name, ..)| d == *name)) {
+            self.wrap(range, tag, class);
+            return true;
+        }
+
+        // 見出し. 同行見出し, 窓見出しは目次に載せず, 本文中の要素とする
+        if let Some(name) = op.strip_prefix('は') {
+            let (inline, name) = match name.strip_prefix("同行").or_else(|| name.strip_prefix('窓')) {
+                Some(name) => (true, name),
+                None => (false, name),
+            };
+            if let Some((_, level, class)) = HEADINGS.iter().find(|(n, ..)| name == *n) {
+                if inline {
+                    self.wrap(range, "span", class);
+                } else {
+                    self.heading = Some((*level, class));
+                }
+                return true;
+            }
+        }
+
+        // `［＃「青空」に「あおぞら」のルビ］`
+        if let Some(rt) = op.strip_prefix("に「").and_then(|s| s.strip_suffix("」のルビ")) {
+            let base = self.atoms.drain(range.clone()).collect::<Vec<Atom>>();
+            self.atoms.insert(range.start, Atom::Ruby(base, rt.to_string()));
+            return true;
+        }
+
+        false
+    }
+}
+
+/// `※［＃「てへん＋劣」、第3水準1-84-77］` などの外字注記
+/// Unicode のコードポイント (`U+5F45`) か, 記号の名前があればその文字にする.
+/// JIS X 0213 の面区点番号は対応表を持たないため, 注記を残す
+fn gaiji(annotation: &str) -> Atom {
+    let code_point = annotation.find("U+")
+        .map(|i| annotation[i + 2..].chars().take_while(|c| c.is_ascii_hexdigit()).collect::<String>())
+        .and_then(|hex| u32::from_str_radix(&hex, 16).ok())
+        .and_then(char::from_u32);
+    let symbol = || {
+        let name = annotation.split('、').next().unwrap_or_default();
+        SYMBOLS.iter().find(|(n, _)| *n == name).map(|(_, c)| *c)
+    };
+
+    match code_point.or_else(symbol) {
+        Some(c) => Atom::Glyph(format!("※［＃{}］", annotation), xhtml::escape_text(&c.to_string())),
+        None => Atom::Glyph(
+            format!("※［＃{}］", annotation),
+            format!("<span class=\"gaiji\">※<span class=\"notes\">［＃{}］</span></span>", xhtml::escape_text(annotation)),
+        ),
+    }
+}
+
+/// 変換の途中の状態
+#[derive(Default)]
+struct Writer {
+    body: String,
+    /// `ここから` で始まった配置
+    layouts: Vec<Layout>,
+    /// `ここから大見出し` で始まった見出しの, レベル, class, 各行
+    heading: Option<(u8, &'static str, Vec<String>)>,
+}
+
+impl Writer {
+    fn open_main_text(&mut self) {
+        self.body.push_str("<div class=\"main_text\">\n");
+        for layout in &self.layouts {
+            self.body.push_str(&format!("<div{}>\n", layout.attribute()));
+        }
+    }
+
+    fn close_main_text(&mut self) {
+        for _ in &self.layouts {
+            self.body.push_str("</div>\n");
+        }
+        self.body.push_str("</div>\n");
+    }
+
+    fn write_line(&mut self, mut line: Line) {
+        let html = to_html(&line.atoms);
+        let is_empty = line.atoms.is_empty();
+
+        // 注記だけの行は, 行として出力しない
+        let only_commands = is_empty && !line.commands.is_empty();
+        for command in std::mem::take(&mut line.commands) {
+            match command {
+                Command::PageBreak => {
+                    self.close_main_text();
+                    self.body.push_str(&format!("<div class=\"{}\"></div>\n", PAGE_BREAK_CLASS));
+                    self.open_main_text();
+                }
+                Command::BeginLayout(layout) => {
+                    self.body.push_str(&format!("<div{}>\n", layout.attribute()));
+                    self.layouts.push(layout);
+                }
+                Command::EndLayout => {
+                    if self.layouts.pop().is_some() {
+                        self.body.push_str("</div>\n");
+                    }
+                }
+                Command::BeginHeading(level, class) => self.heading = Some((level, class, Vec::new())),
+                Command::EndHeading => {
+                    if let Some((level, class, lines)) = self.heading.take() {
+                        self.body.push_str(&format!("<h{0} class=\"{1}\">{2}</h{0}>\n", level, class, lines.join("<br />")));
+                    }
+                }
+            }
+        }
+        if only_commands {
+            return;
+        }
+
+        if let Some((_, _, lines)) = &mut self.heading {
+            lines.push(html);
+            return;
+        }
+
+        let attribute = line.layout.attribute();
+        match line.heading {
+            Some((level, class)) => self.body.push_str(&format!("<h{0} class=\"{1}\"{2}>{3}</h{0}>\n", level, class, attribute, html)),
+            None if is_empty => self.body.push_str("<p><br /></p>\n"),
+            None => self.body.push_str(&format!("<p{}>{}</p>\n", attribute, html)),
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if let Some((level, class, lines)) = self.heading.take() {
+            self.body.push_str(&format!("<h{0} class=\"{1}\">{2}</h{0}>\n", level, class, lines.join("<br />")));
+        }
+        self.close_main_text();
+        self.body
+    }
+}
+
+/// 区切り線 (`-------`). 表題の後の, 記号についての説明を囲む
+fn is_rule(line: &str) -> bool {
+    line.chars().count() >= 10 && line.chars().all(|c| c == '-')
+}
+
+/// 青空文庫形式のテキストを変換する
+pub fn convert(text: &str) -> AozoraDocument {
+    let mut lines = text.lines().map(|l| l.trim_end_matches('\r')).peekable();
+    let mut document = AozoraDocument::default();
+
+    // 表題: 空行までが作品名, (副題など), 著者名
+    let mut header = Vec::new();
+    while let Some(line) = lines.next_if(|l| !l.trim().is_empty()) {
+        header.push(line);
+    }
+    if !header.is_empty() {
+        document.title = Some(Line::parse(header[0]).atoms.iter().map(Atom::text).collect());
+        if header.len() > 1 {
+            document.creator = Some(Line::parse(header[header.len() - 1]).atoms.iter().map(Atom::text).collect());
+        }
+
+        document.body.push_str("<div class=\"metadata\">\n");
+        for (i, line) in header.iter().enumerate() {
+            let html = to_html(&Line::parse(line).atoms);
+            match i {
+                0 => document.body.push_str(&format!("<h1 class=\"title\">{}</h1>\n", html)),
+                i if i == header.len() - 1 => document.body.push_str(&format!("<p class=\"author\">{}</p>\n", html)),
+                _ => document.body.push_str(&format!("<p class=\"subtitle\">{}</p>\n", html)),
+            }
+        }
+        document.body.push_str("</div>\n");
+    }
+
+    // 記号についての説明は読み飛ばす
+    while lines.next_if(|l| l.trim().is_empty()).is_some() {}
+    if lines.next_if(|l| is_rule(l)).is_some() {
+        for line in lines.by_ref() {
+            if is_rule(line) {
+                break;
+            }
+        }
+        while lines.next_if(|l| l.trim().is_empty()).is_some() {}
+    }
+
+    let lines = lines.collect::<Vec<&str>>();
+    let (main_text, colophon) = match lines.iter().position(|l| l.starts_with(COLOPHON)) {
+        Some(i) => lines.split_at(i),
+        None => (&lines[..], &[][..]),
+    };
+    // 本文の末尾の空行は出力しない
+    let end = main_text.iter().rposition(|l| !l.trim().is_empty()).map(|i| i + 1).unwrap_or(0);
+
+    let mut writer = Writer::default();
+    writer.open_main_text();
+    for line in &main_text[..end] {
+        writer.write_line(Line::parse(line));
+    }
+    document.body.push_str(&writer.finish());
+
+    // 底本の書誌情報
+    if !colophon.is_empty() {
+        document.body.push_str("<div class=\"bibliographical_information\">\n");
+        for line in colophon.iter().filter(|l| !l.trim().is_empty()) {
+            document.body.push_str(&format!("<p>{}</p>\n", to_html(&Line::parse(line).atoms)));
+        }
+        document.body.push_str("</div>\n");
+    }
+
+    document
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(s: &str) -> String {
+        to_html(&Line::parse(s).atoms)
+    }
+
+    #[test]
+    fn ruby() {
+        assert_eq!(line("吾輩《わがはい》は猫である"), "<ruby>吾輩<rt>わがはい</rt></ruby>は猫である");
+        assert_eq!(line("この｜青い空《あおいそら》"), "この<ruby>青い空<rt>あおいそら</rt></ruby>");
+        assert_eq!(line("カタカナ《かたかな》"), "<ruby>カタカナ<rt>かたかな</rt></ruby>");
+        assert_eq!(line("。《まる》"), "。《まる》");
+    }
+
+    #[test]
+    fn annotations() {
+        assert_eq!(
+            line("猫である［＃「猫」に傍点］"),
+            "<em class=\"sesame_dot\">猫</em>である"
+        );
+        assert_eq!(
+            line("吾輩《わがはい》は［＃「吾輩」に白丸傍点］"),
+            "<em class=\"white_circle\"><ruby>吾輩<rt>わがはい</rt></ruby></em>は"
+        );
+        assert_eq!(line("第12回［＃「12」は縦中横］"), "第<span class=\"tcy\">12</span>回");
+        assert_eq!(line("青空［＃「青空」に「あおぞら」のルビ］"), "<ruby>青空<rt>あおぞら</rt></ruby>");
+        assert_eq!(line("※［＃「口＋世」、U+546D、12-3］"), "呭");
+        assert_eq!(line("※［＃始め二重山括弧、1-1-52］"), "《");
+        assert_eq!(
+            line("※［＃「てへん＋劣」、第3水準1-84-77］"),
+            "<span class=\"gaiji\">※<span class=\"notes\">［＃「てへん＋劣」、第3水準1-84-77］</span></span>"
+        );
+        assert_eq!(line("ママ［＃「ママ」はママ］"), "ママ<span class=\"notes\">［＃「ママ」はママ］</span>");
+
+        assert_eq!(Layout::parse("３字下げ、折り返して５字下げ").and_then(|l| l.style()).unwrap(), "margin-inline-start: 5em; text-indent: -2em;");
+        assert_eq!(Layout::parse("地から二字上げ").and_then(|l| l.style()).unwrap(), "text-align: end; margin-inline-end: 2em;");
+    }
+
+    #[test]
+    fn detection() {
+        assert!(is_aozora("吾輩《わがはい》は猫である"));
+        assert!(is_aozora("一［＃「一」は大見出し］"));
+        assert!(!is_aozora("# README\nrepub で変換します. 《》は使いません\n"));
+    }
+
+    #[test]
+    fn document() {
+        let document = convert(&decode(&encoding_rs::SHIFT_JIS.encode("作品名\r\n著者名\r\n\r\n-------------------------------------------------------\r\n【テキスト中に現れる記号について】\r\n-------------------------------------------------------\r\n\r\n［＃３字下げ］一［＃「一」は大見出し］\r\n本文\r\n［＃改ページ］\r\n［＃ここから２字下げ］\r\n字下げ\r\n［＃ここで字下げ終わり］\r\n\r\n底本：「作品名」\r\n").0));
+
+        assert_eq!(document.title.as_deref(), Some("作品名"));
+        assert_eq!(document.creator.as_deref(), Some("著者名"));
+        assert_eq!(document.body, "<div class=\"metadata\">\n<h1 class=\"title\">作品名</h1>\n<p class=\"author\">著者名</p>\n</div>\n\
+            <div class=\"main_text\">\n<h2 class=\"o-midashi\" style=\"margin-inline-start: 3em;\">一</h2>\n<p>本文</p>\n</div>\n\
+            <div class=\"aozora-page-break\"></div>\n\
+            <div class=\"main_text\">\n<div style=\"margin-inline-start: 2em;\">\n<p>字下げ</p>\n</div>\n</div>\n\
+            <div class=\"bibliographical_information\">\n<p>底本：「作品名」</p>\n</div>\n");
+    }
+}
@@ -1,14 +1,16 @@
 use crate::prelude::*;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 pub fn app<'a,'b>() -> App<'a,'b> {
     App::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        // `repub unpack` では <input> は不要
+        .setting(AppSettings::SubcommandsNegateReqs)
         // .mdファイルorフォルダ
-        .arg(Arg::from_usage("<input> '変換するマークダウンファイル OR 変換するマークダウン文書(複数可)の入ったディレクトリ OR 設定ファイル'")
+        .arg(Arg::from_usage("<input> '変換するファイル (.md, .txt, .docx など) OR 変換する文書(複数可)の入ったディレクトリ OR 設定ファイル'")
             .validator(validators::md_validator))
         // 一時ファイルを消さない
         .arg(Arg::with_name("save")
@@ -18,6 +20,10 @@ pub fn app<'a,'b>() -> App<'a,'b> {
         .arg(Arg::with_name("config")
             .help("設定ファイルを保存")
             .long("config"))
+        // .gitignore に従う
+        .arg(Arg::with_name("gitignore")
+            .help(".gitignoreに記載されたファイルを変換対象から除外する")
+            .long("gitignore"))
         // ログを表示
         .arg(Arg::with_name("verbose")
             .help("ログを表示")
@@ -57,15 +63,60 @@ pub fn app<'a,'b>() -> App<'a,'b> {
             .long("mode")
             .possible_values(&["htb", "vrl", "vlr"])
             .default_value("htb"))
+        // 出力形式
+        .arg(Arg::with_name("format")
+            .help("出力形式 (epub: EPUB3, html: Web サイト, single-html: 1つの HTML ファイル, kepub: Kobo の .kepub.epub, fb2: FictionBook 2)")
+            .long("format")
+            .possible_values(&["epub", "html", "single-html", "kepub", "fb2"])
+            .takes_value(true))
+        // 基本のスタイルシート
+        .arg(Arg::with_name("base_style")
+            .help("同梱の基本のスタイルシート (auto: スタイルシートがなければ書式に合わせて適用, horizontal: 横書き, vertical: 縦書き, none: 適用しない)")
+            .long("base-style")
+            .possible_values(&["auto", "horizontal", "vertical", "none"])
+            .takes_value(true))
         // tocに載せるヘッダーのレベル
         .arg(Arg::with_name("toc_depth")
             .help("目次に表示するHeaderの最低レベル(1~5)")
             .long("toc-depth")
             .takes_value(true))
+        // シンタックスハイライト
+        .arg(Arg::with_name("highlight")
+            .help("コードブロックのシンタックスハイライトのテーマ(light, dark など)")
+            .long("highlight")
+            .takes_value(true))
+        // コンテンツの分割
+        .arg(Arg::with_name("split_level")
+            .help("指定したレベル以上の見出しの直前で, コンテンツを複数の.xhtmlファイルに分割する")
+            .long("split-level")
+            .takes_value(true))
+        // ファイル名の番号を取り除く
+        .arg(Arg::with_name("strip_number_prefix")
+            .help("01_intro.md のようなファイル名の先頭の番号を, 変換後のファイル名から取り除く")
+            .long("strip-number-prefix"))
+        // Jupyter notebook のセルの入力, 出力を隠す
+        .arg(Arg::with_name("hide_input")
+            .help("Jupyter notebook のコードのセルの入力(ソースコード)を出力しない")
+            .long("hide-input"))
+        .arg(Arg::with_name("hide_output")
+            .help("Jupyter notebook のコードのセルの出力を出力しない")
+            .long("hide-output"))
+        // EPUB を展開する
+        .subcommand(SubCommand::with_name("unpack")
+            .about("EPUB を, repub で変換できるディレクトリに展開する")
+            .arg(Arg::from_usage("<epub> '展開する EPUB ファイル'"))
+            .arg(Arg::from_usage("<dir> '展開先のディレクトリ (空のディレクトリか, 存在しないパス)'"))
+            .arg(Arg::with_name("xhtml")
+                .help("コンテンツを markdown に変換せず, HTML のまま展開する")
+                .long("xhtml"))
+            .arg(Arg::with_name("verbose")
+                .help("ログを表示")
+                .long("verbose")))
 }
 
 mod validators {
     use super::*;
+    use crate::data::ConvertType;
 
     pub fn md_validator(v: String) -> Result<(), String> {
         let path = PathBuf::from_str(&v).map_err(|e| format!("{:?}", e))?;
@@ -82,14 +133,13 @@ mod validators {
             return Err(format!("[ERROR] {:?} does not exist.", &md_path));
         }
 
+        // ファイルであれば, コンテンツとして変換できる形式に限る
         if md_path.is_file() {
-            match md_path.extension() {
-                None => {}
-                Some(ext) => {
-                    if ext != "md" {
-                        return Err(format!("[ERROR] {:?} is not .md file.", &md_path));
-                    }
+            match md_path.extension().and_then(|e| e.to_str()) {
+                Some(ext) if ConvertType::from_extension(ext).is_none() => {
+                    return Err(format!("[ERROR] {:?} cannot be converted as a content.", &md_path));
                 }
+                _ => {}
             }
         }
 
@@ -0,0 +1,482 @@
+//! fenced div (`::: note` … `:::`) とブロック属性 (`{#id .class key=value}`)
+
+use crate::xhtml;
+
+use html5ever::rcdom::{NodeData, Handle};
+
+/// markdown 変換の間, fenced div の位置を保持するための目印
+/// 数式の目印 (U+E000, U+E001) とは別の私用領域の文字を使う
+const MARKER_BEGIN: char = '\u{E002}';
+const MARKER_END: char = '\u{E003}';
+/// 閉じる目印の中身
+const CLOSE: &str = "/";
+
+/// 既定のスタイルを持つ admonition の種類
+const ADMONITIONS: [&str; 7] = ["note", "tip", "info", "important", "warning", "caution", "danger"];
+
+/// `<aside>` として出力する `epub:type`
+const ASIDE_TYPES: [&str; 4] = ["sidebar", "notice", "tip", "pullquote"];
+
+/// admonition のスタイルシートのファイル名
+pub const ADMONITION_CSS: &str = "admonition.css";
+
+/// admonition のスタイルシート
+pub const ADMONITION_STYLE: &str = include_str!("literals/admonition.css");
+
+/// `{#id .class key=value}` で指定される属性
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockAttributes {
+    pub id: Option<String>,
+    pub classes: Vec<String>,
+    pub attrs: Vec<(String, String)>,
+}
+
+impl BlockAttributes {
+    /// `{` と `}` に囲まれた文字列を解釈する. 属性として解釈できなければ None
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if !s.starts_with('{') || !s.ends_with('}') {
+            return None;
+        }
+        let mut chars = s[1..s.len() - 1].chars().peekable();
+        let mut attributes = Self::default();
+
+        loop {
+            while chars.peek().is_some_and(|c| c.is_whitespace()) {
+                chars.next();
+            }
+            let token = match chars.peek() {
+                None => break,
+                Some(_) => {
+                    let mut token = String::new();
+                    while let Some(c) = chars.peek() {
+                        if c.is_whitespace() || *c == '"' || *c == '“' { break; }
+                        token.push(*c);
+                        chars.next();
+                    }
+                    token
+                }
+            };
+
+            if let Some(id) = token.strip_prefix('#') {
+                if id.is_empty() { return None; }
+                attributes.id = Some(id.to_string());
+            } else if let Some(class) = token.strip_prefix('.') {
+                if class.is_empty() { return None; }
+                attributes.classes.push(class.to_string());
+            } else if let Some(eq) = token.find('=') {
+                let key = &token[..eq];
+                if key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ':') {
+                    return None;
+                }
+                let mut value = token[eq + 1..].to_string();
+                // smart punctuation で引用符が置き換えられている場合もある
+                if value.is_empty() {
+                    let close = match chars.next() {
+                        Some('"') => '"',
+                        Some('“') => '”',
+                        _ => return None,
+                    };
+                    loop {
+                        match chars.next() {
+                            Some(c) if c == close => break,
+                            Some(c) => value.push(c),
+                            None => return None,
+                        }
+                    }
+                }
+                attributes.attrs.push((key.to_string(), value));
+            } else {
+                return None;
+            }
+        }
+
+        // `{}` のように何も指定されていなければ属性とみなさない
+        if attributes == Self::default() {
+            return None;
+        }
+
+        Some(attributes)
+    }
+
+    /// element の属性に反映する. class は追加, それ以外は上書きする
+    pub fn apply_to(&self, element: &Handle) {
+        if let Some(id) = &self.id {
+            xhtml::set_attr(element, "id", id);
+        }
+        if !self.classes.is_empty() {
+            let class = xhtml::get_attr(element, "class")
+                .into_iter()
+                .chain(self.classes.iter().cloned())
+                .collect::<Vec<String>>()
+                .join(" ");
+            xhtml::set_attr(element, "class", &class);
+        }
+        for (key, value) in &self.attrs {
+            xhtml::set_attr(element, key, value);
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+}
+
+/// `::: kind title {attributes}` で開かれる fenced div
+#[derive(Debug, Clone, PartialEq)]
+pub struct Div {
+    /// `note`, `warning` など. 属性のみの場合は None
+    pub kind: Option<String>,
+    pub attributes: BlockAttributes,
+}
+
+impl Div {
+    /// 既定のスタイルを持つ admonition か否か
+    pub fn is_admonition(&self) -> bool {
+        self.kind.as_ref().is_some_and(|k| ADMONITIONS.contains(&k.as_str()))
+    }
+
+    /// `:::` に続く文字列を解釈する. 戻り値の 2 つ目は見出し
+    fn parse(spec: &str) -> Option<(Self, String)> {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            return None;
+        }
+
+        // 末尾の属性
+        let (rest, attributes) = match spec.rfind('{') {
+            Some(begin) if spec.ends_with('}') => match BlockAttributes::parse(&spec[begin..]) {
+                Some(attributes) => (spec[..begin].trim(), attributes),
+                None => (spec, BlockAttributes::default()),
+            },
+            _ => (spec, BlockAttributes::default()),
+        };
+
+        let (kind, title) = match rest.find(char::is_whitespace) {
+            Some(end) => (&rest[..end], rest[end..].trim()),
+            None => (rest, ""),
+        };
+        let kind = if kind.is_empty() {
+            None
+        } else if kind.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            Some(kind.to_lowercase())
+        } else {
+            return None;
+        };
+
+        Some((Self { kind, attributes }, title.to_string()))
+    }
+
+    /// admonition の既定の見出し
+    fn label(&self, language: &str) -> String {
+        let kind = self.kind.clone().unwrap_or_default();
+        if language.starts_with("ja") {
+            match kind.as_str() {
+                "note" => "メモ",
+                "tip" => "ヒント",
+                "info" => "情報",
+                "important" => "重要",
+                "warning" => "警告",
+                "caution" => "注意",
+                "danger" => "危険",
+                _ => "",
+            }.to_string()
+        } else {
+            let mut chars = kind.chars();
+            chars.next()
+                .map(|c| c.to_uppercase().chain(chars).collect())
+                .unwrap_or_default()
+        }
+    }
+
+    /// 中身を格納する要素と, 見出しの class
+    fn create_element(&self) -> (Handle, &'static str) {
+        let (element, title_class) = if self.is_admonition() {
+            let kind = self.kind.as_ref().unwrap();
+            let epub_type = if kind == "tip" { "tip" } else { "notice" };
+            (
+                xhtml::create_element("aside", &[("class", &format!("admonition {}", kind)), ("epub:type", epub_type)]),
+                "admonition-title",
+            )
+        } else {
+            let local = match self.attributes.get("epub:type") {
+                Some(t) if t.split_whitespace().any(|t| ASIDE_TYPES.contains(&t)) => "aside",
+                _ => "div",
+            };
+            let attrs = match &self.kind {
+                Some(kind) => vec![("class", kind.as_str())],
+                None => vec![],
+            };
+            (xhtml::create_element(local, &attrs), "title")
+        };
+        self.attributes.apply_to(&element);
+
+        (element, title_class)
+    }
+}
+
+/// markdown から fenced div を抜き出し, 段落となる目印に置き換える
+/// 開く目印の段落には見出しを残すので, 見出しの中の inline 要素も markdown として変換される
+pub fn extract(markdown: &str) -> (String, Vec<Div>) {
+    let mut divs = Vec::new();
+    let mut output = String::with_capacity(markdown.len());
+    // 開いている div の数
+    let mut depth = 0;
+
+    // フェンスで囲まれたコードブロックはそのまま出力する
+    let mut fence: Option<(char, usize)> = None;
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let marker = trimmed.chars().next()
+            .filter(|c| indent < 4 && (*c == '`' || *c == '~'))
+            .map(|c| (c, trimmed.chars().take_while(|d| *d == c).count()))
+            .filter(|(_, n)| *n >= 3);
+
+        if let Some((c, n)) = fence {
+            output.push_str(line);
+            if let Some((d, m)) = marker {
+                if c == d && m >= n && trimmed.trim_end().chars().all(|e| e == c) {
+                    fence = None;
+                }
+            }
+            continue;
+        }
+        if marker.is_some() {
+            fence = marker;
+            output.push_str(line);
+            continue;
+        }
+
+        // `:::` は行頭にある場合のみ解釈する
+        let colons = line.chars().take_while(|c| *c == ':').count();
+        if colons >= 3 {
+            let spec = line[colons..].trim();
+            if spec.is_empty() || spec.chars().all(|c| c == ':') {
+                if depth > 0 {
+                    depth -= 1;
+                    output.push_str(&format!("\n{}{}{}\n\n", MARKER_BEGIN, CLOSE, MARKER_END));
+                    continue;
+                }
+            } else if let Some((div, title)) = Div::parse(spec.trim_end_matches(':')) {
+                output.push_str(&format!("\n{}{}{} {}\n\n", MARKER_BEGIN, divs.len(), MARKER_END, title));
+                divs.push(div);
+                depth += 1;
+                continue;
+            }
+        }
+
+        output.push_str(line);
+    }
+    // 閉じられていない div
+    for _ in 0..depth {
+        output.push_str(&format!("\n\n{}{}{}\n", MARKER_BEGIN, CLOSE, MARKER_END));
+    }
+
+    (output, divs)
+}
+
+/// markdown 以外から変換した html で fenced div を組み立てるための, 開く目印の段落
+/// index は`divs`の中の位置, title は見出しとなる html
+pub fn open_marker(index: usize, title: &str) -> String {
+    format!("<p>{}{}{} {}</p>\n", MARKER_BEGIN, index, MARKER_END, title)
+}
+
+/// 閉じる目印の段落
+pub fn close_marker() -> String {
+    format!("<p>{}{}{}</p>\n", MARKER_BEGIN, CLOSE, MARKER_END)
+}
+
+/// 段落が目印であれば, その中身
+fn marker_of(node: &Handle) -> Option<String> {
+    if !xhtml::is_element(node, "p") {
+        return None;
+    }
+    let text = xhtml::text_content(node);
+    let text = text.trim_start();
+    if !text.starts_with(MARKER_BEGIN) {
+        return None;
+    }
+    let end = text.find(MARKER_END)?;
+
+    Some(text[MARKER_BEGIN.len_utf8()..end].to_string())
+}
+
+/// 開く目印の段落から目印を取り除き, 見出しとして残る子要素を返す
+fn take_title(paragraph: &Handle) -> Vec<Handle> {
+    let mut children = paragraph.children.borrow_mut();
+    if let Some(first) = children.first() {
+        if let NodeData::Text { ref contents } = first.data {
+            let text = contents.borrow().to_string();
+            let rest = text.find(MARKER_END)
+                .map(|end| text[end + MARKER_END.len_utf8()..].trim_start().to_string())
+                .unwrap_or_default();
+            *contents.borrow_mut() = rest.into();
+        }
+    }
+    let title = std::mem::take(&mut *children);
+    let empty = title.iter().all(|n| match n.data {
+        NodeData::Text { ref contents } => contents.borrow().trim().is_empty(),
+        _ => false,
+    });
+
+    if empty { Vec::new() } else { title }
+}
+
+/// comrak が出力した DOM の, 目印の段落に挟まれた要素を fenced div の要素で包む
+pub fn apply(root: &Handle, divs: &[Div], language: &str) {
+    if !divs.is_empty() {
+        let children = std::mem::take(&mut *root.children.borrow_mut());
+        // 開いている div の要素
+        let mut stack: Vec<Handle> = Vec::new();
+
+        for child in children {
+            match marker_of(&child) {
+                Some(ref m) if m == CLOSE => {
+                    if let Some(element) = stack.pop() {
+                        xhtml::append(stack.last().unwrap_or(root), element);
+                    }
+                }
+                Some(m) => {
+                    let div = match m.parse::<usize>().ok().and_then(|i| divs.get(i)) {
+                        Some(div) => div,
+                        None => continue,
+                    };
+                    let (element, title_class) = div.create_element();
+
+                    let mut title = take_title(&child);
+                    if title.is_empty() && div.is_admonition() {
+                        title.push(xhtml::create_text(&div.label(language)));
+                    }
+                    if !title.is_empty() {
+                        let p = xhtml::create_element("p", &[("class", title_class)]);
+                        for node in title {
+                            xhtml::append(&p, node);
+                        }
+                        xhtml::append(&element, p);
+                    }
+
+                    stack.push(element);
+                }
+                None => xhtml::append(stack.last().unwrap_or(root), child),
+            }
+        }
+        while let Some(element) = stack.pop() {
+            xhtml::append(stack.last().unwrap_or(root), element);
+        }
+    }
+}
+
+/// 見出しと段落の末尾にあるブロック属性を反映する. `{…}` の記法は markdown のみのもの
+pub fn apply_block_attributes(node: &Handle) {
+    for child in node.children.borrow().iter() {
+        let target = ["h1", "h2", "h3", "h4", "h5", "h6", "p"].iter().any(|l| xhtml::is_element(child, l));
+        if target {
+            if let Some(attributes) = take_trailing_attributes(child) {
+                attributes.apply_to(child);
+            }
+        } else {
+            apply_block_attributes(child);
+        }
+    }
+}
+
+/// 要素の末尾の `{…}` を取り除き, 属性として返す
+fn take_trailing_attributes(element: &Handle) -> Option<BlockAttributes> {
+    let mut children = element.children.borrow_mut();
+    let (attributes, rest) = {
+        let last = children.last()?;
+        let text = match last.data {
+            NodeData::Text { ref contents } => contents.borrow().to_string(),
+            _ => return None,
+        };
+        let trimmed = text.trim_end();
+        if !trimmed.ends_with('}') {
+            return None;
+        }
+        let begin = trimmed.rfind('{')?;
+        // `{` の直前は空白か, テキストの先頭 (改行の直後) でなければならない
+        if !trimmed[..begin].chars().last().is_none_or(char::is_whitespace) {
+            return None;
+        }
+        let attributes = BlockAttributes::parse(&trimmed[begin..])?;
+        let rest = trimmed[..begin].trim_end().to_string();
+
+        // 属性のみの要素は対象外
+        if rest.is_empty() && children.len() == 1 {
+            return None;
+        }
+        if let NodeData::Text { ref contents } = last.data {
+            *contents.borrow_mut() = rest.clone().into();
+        }
+        (attributes, rest)
+    };
+
+    // 属性を別の行に書いた場合, 直前の改行も取り除く
+    if rest.is_empty() {
+        children.pop();
+        while children.last().is_some_and(|n| xhtml::is_element(n, "br")
+            || matches!(n.data, NodeData::Text { ref contents } if contents.borrow().trim().is_empty())) {
+            children.pop();
+        }
+    }
+
+    Some(attributes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use html5ever::{
+        parse_fragment,
+        ParseOpts,
+        QualName,
+        rcdom::RcDom,
+        tendril::TendrilSink,
+    };
+
+    fn convert(markdown: &str) -> String {
+        let (markdown, divs) = extract(markdown);
+        let options = comrak::ComrakOptions { hardbreaks: true, ..Default::default() };
+        let html = comrak::markdown_to_html(&markdown, &options);
+
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        ).one(html);
+        let root = dom.document.children.borrow()[0].clone();
+        apply(&root, &divs, "en");
+        apply_block_attributes(&root);
+        xhtml::serialize_children(&root).replace('\n', "")
+    }
+
+    #[test]
+    fn admonition() {
+        assert_eq!(
+            convert("::: note\nbody\n:::\n\n::: warning Be *careful*\nx\n:::"),
+            "<aside class=\"admonition note\" epub:type=\"notice\"><p class=\"admonition-title\">Note</p><p>body</p></aside>\
+             <aside class=\"admonition warning\" epub:type=\"notice\"><p class=\"admonition-title\">Be <em>careful</em></p><p>x</p></aside>"
+        );
+        // コードブロック内は変換しない
+        assert_eq!(convert("```\n::: note\n```"), "<pre><code>::: note</code></pre>");
+    }
+
+    #[test]
+    fn attributes() {
+        assert_eq!(
+            convert("::: {.box #b epub:type=sidebar}\n:::: tip\nnested\n::::\n:::"),
+            "<aside id=\"b\" class=\"box\" epub:type=\"sidebar\"><aside class=\"admonition tip\" epub:type=\"tip\">\
+             <p class=\"admonition-title\">Tip</p><p>nested</p></aside></aside>"
+        );
+        assert_eq!(
+            convert("# Title {#intro .chapter}\n\nparagraph\n{.lead data-x=\"a b\"}\n\nnot {attribute"),
+            "<h1 id=\"intro\" class=\"chapter\">Title</h1><p class=\"lead\" data-x=\"a b\">paragraph</p><p>not {attribute</p>"
+        );
+        // 空の `{}` は属性とみなさない
+        assert_eq!(convert("# Title {}
+
+map {}"), "<h1>Title {}</h1><p>map {}</p>");
+    }
+}
@@ -1,4 +1,5 @@
-use crate::{prelude::*, tmpfile::*, load::*, data::*, toc::*};
+use crate::{prelude::*, tmpfile::*, load::*, data::*, toc::*, math, block, include, aozora, review, notebook, docx, org, xhtml, pattern, order, site, kobo, fb2, style, font, highlight::*};
+use std::collections::HashMap;
 use media_type::*;
 pub use properties::*;
 
@@ -8,6 +9,9 @@ pub struct Composer {
     composed: Composed,
     toc: TableOfContents,
     navigation: Option<ComposedItem>,
+    highlighter: Option<Highlighter>,
+    /// 分割したコンテンツの, 分割前のパス -> (id -> その id を含むファイルのパス)
+    split_anchors: HashMap<PathBuf, HashMap<String, PathBuf>>,
 }
 
 impl TryFrom<InputData> for Composer {
@@ -16,6 +20,10 @@ impl TryFrom<InputData> for Composer {
     fn try_from(value: InputData) -> Result<Self, Self::Error> {
         let tmp_dir = TmpDir::new()?;
         let composed = Composed::new();
+        let highlighter = match &value.cfg.highlight {
+            Some(theme) => Some(Highlighter::new(theme)?),
+            None => None,
+        };
 
         Ok(Self {
             tmp_dir,
@@ -23,6 +31,8 @@ impl TryFrom<InputData> for Composer {
             composed,
             toc: TableOfContents::new(),
             navigation: None,
+            highlighter,
+            split_anchors: HashMap::new(),
         })
     }
 }
@@ -76,11 +86,8 @@ impl Drop for Composer {
 
 impl Composer {
     fn filter_ignored_source<T: AsRef<Source>>(src: Vec<T>, cfg: &Config) -> Vec<T> {
-        let ignores = &cfg.ignores;
         src.into_iter().filter(|c| {
-            if let Some(p) = PathBuf::path_diff(&cfg.target, &c.as_ref().path) {
-                !ignores.contains(&p)
-            } else { false }
+            PathBuf::path_diff(&cfg.target, &c.as_ref().path).is_some() && !cfg.is_ignored(&c.as_ref().path)
         }).collect::<Vec<T>>()
     }
 
@@ -89,6 +96,22 @@ impl Composer {
         // ignore する
         let style_files = Self::filter_ignored_source(self.data.files.style_files.clone(), &self.data.cfg);
 
+        // 基本のスタイルシートは, ユーザーのスタイルシートで上書きできるように先に読み込む
+        let cfg = &self.data.cfg;
+        match style::base_style(cfg.base_style, &cfg.writing_mode, !style_files.is_empty()) {
+            Some(style) => self.compose_generated_css(style::BASE_CSS, &style)?,
+            None => {
+                let vertical = !matches!(cfg.writing_mode, WritingMode::HorizontalTb);
+                let has_writing_mode = style_files.iter()
+                    .any(|f| std::fs::read_to_string(&f.path).is_ok_and(|css| css.contains("writing-mode")));
+                if vertical && cfg.base_style != BaseStyle::None && !has_writing_mode {
+                    RepubWarning(
+                        "スタイルシートに writing-mode の指定がないため, 本文は横書きになります. 縦書きにするには --base-style vertical を指定してください".to_string()
+                    ).print();
+                }
+            }
+        }
+
         for file in &style_files {
             let relative_path = PathBuf::path_diff(&self.data.cfg.target, &file.path).unwrap();
             let to = self.tmp_dir.oebps.path.join(&relative_path);
@@ -96,6 +119,7 @@ impl Composer {
             // epub3の対応している拡張子かどうかを確認する -> そうでなければreturn
             let composed = ComposedItem::new(file, &to, "css", self.composed.style_items.len())?;
             // 対応している拡張子ならばcopy
+            create_parent_dir(&to)?;
             std::fs::copy(&file.path, &to)?;
             // ログ出力
             RepubLog::packed(&format!("{:?}", &relative_path)).print();
@@ -103,9 +127,34 @@ impl Composer {
             self.composed.style_items.push(composed);
         }
 
+        // シンタックスハイライトのテーマ
+        if let Some(highlighter) = &self.highlighter {
+            let css = highlighter.css()?;
+            self.compose_generated_css(HIGHLIGHT_CSS, &css)?;
+        }
+
+        // 青空文庫形式のテキストがあれば, そのスタイルシート
+        let uses_aozora = self.data.files.content_files.iter()
+            .any(|c| c.convert_type == ConvertType::AozoraToXHTML);
+        if uses_aozora {
+            self.compose_generated_css(aozora::AOZORA_CSS, aozora::AOZORA_STYLE)?;
+        }
+
         Ok(self)
     }
 
+    /// repub が生成するスタイルシートを tmp directory に格納する. すべてのコンテンツに適用される
+    fn compose_generated_css(&mut self, name: &str, style: &str) -> RepubResult<()> {
+        let to = self.tmp_dir.oebps.path.join(name);
+        let composed = ComposedItem::without_src(&to, "css", self.composed.style_items.len())?;
+        std::fs::File::create(&to)?.write_all(style.as_bytes())?;
+        // ログ出力
+        RepubLog::packed(&format!("{:?}", PathBuf::from(name))).print();
+
+        self.composed.style_items.push(composed);
+        Ok(())
+    }
+
     /// static file を tmp directory に格納する
     pub fn compose_static(&mut self) -> RepubResult<&mut Self> {
         // ignore する
@@ -119,6 +168,7 @@ impl Composer {
             match ComposedItem::new(file, &to, "static", self.composed.static_items.len()) {
                 Ok(composed) => {
                     // 対応している拡張子ならばcopy
+                    create_parent_dir(&to)?;
                     std::fs::copy(&file.path, &to)?;
                     // ログ出力
                     RepubLog::packed(&format!("{:?}", &relative_path)).print();
@@ -135,27 +185,102 @@ impl Composer {
         Ok(self)
     }
 
+    /// コンテンツに埋め込まれていた画像を, 変換後の`.xhtml`ファイル (content) からの相対パスに書き出す
+    fn compose_embedded_images(&mut self, content: &Path, images: &[EmbeddedImage]) -> RepubResult<()> {
+        for image in images {
+            let path = content.parent().map(|p| p.join(&image.path)).unwrap_or_else(|| image.path.clone());
+            create_parent_dir(&path)?;
+            std::fs::File::create(&path)?.write_all(&image.data)?;
+            // ログ出力
+            RepubLog::packed(&format!("{:?}", PathBuf::path_diff(&self.tmp_dir.oebps.path, &path).unwrap())).print();
+
+            let composed = ComposedItem::without_src(&path, "static", self.composed.static_items.len())?;
+            self.composed.static_items.push(composed);
+        }
+
+        Ok(())
+    }
+
     /// content file を変換して, 内容を目次に登録し tmp directory に格納する
     /// `.md`ファイルを変換してつくる`.xhtml`ファイルに`.css`を適用するので,
     /// このメソッドの実行までに`compose_css()`を実行する必要がある
     pub fn compose_contents(&mut self) -> RepubResult<&mut Self> {
         use html5ever::{
-            serialize,
             parse_fragment,
             ParseOpts,
-            serialize::SerializeOpts,
             QualName,
-            LocalName,
             rcdom::{RcDom, NodeData, Handle},
-            tendril::{TendrilSink, StrTendril},
-            Attribute,
+            tendril::TendrilSink,
         };
 
-        fn register_to(toc: &mut TableOfContents, xhtml: &String, path_buf: &PathBuf) -> String {
-            fn create_attribute(name: &str, value: &str) -> Attribute {
-                Attribute {
-                    name: QualName::new(None, ns!(), LocalName::from(name)),
-                    value: StrTendril::from(value),
+        /// 変換してできる`.xhtml`ファイルひとつ分
+        struct Part {
+            path: PathBuf,
+            body: String,
+            /// このファイルに含まれる要素の id
+            ids: Vec<String>,
+        }
+
+        fn register_to(toc: &mut TableOfContents, xhtml: &str, path_buf: &Path, divs: &[block::Div], block_attributes: bool, cfg: &Config, split_level: Option<u8>) -> Vec<Part> {
+            /// 目次に登録する見出しの候補. fenced div の`<div>`, `<section>`の中の見出しも含める
+            fn headings(node: &Handle, found: &mut Vec<Handle>) {
+                for child in node.children.borrow().iter() {
+                    if xhtml::is_element(child, "div") || xhtml::is_element(child, "section") {
+                        headings(child, found);
+                    } else {
+                        found.push(child.clone());
+                    }
+                }
+            }
+
+            fn heading_level(node: &Handle) -> Option<u8> {
+                (1..=6).find(|l| xhtml::is_element(node, &format!("h{}", l)))
+            }
+
+            fn ids(node: &Handle, found: &mut Vec<String>) {
+                if let Some(id) = xhtml::get_attr(node, "id") {
+                    found.push(id);
+                }
+                for child in node.children.borrow().iter() {
+                    ids(child, found);
+                }
+            }
+
+            /// markdown, html ファイルへのリンクを, 変換後の`.xhtml`ファイルへのリンクに書き換える
+            fn rewrite_content_links(node: &Handle, strip_number_prefix: bool) {
+                if let Some(href) = xhtml::get_attr(node, "href") {
+                    let (path, fragment) = match href.find('#') {
+                        Some(hash) => href.split_at(hash),
+                        None => (href.as_str(), ""),
+                    };
+                    let stem = [".md", ".html", ".htm", ".org", ".xhtml"].iter()
+                        .find_map(|ext| path.strip_suffix(ext))
+                        .filter(|_| !path.contains(':'));
+                    if let Some(stem) = stem {
+                        let mut path = PathBuf::from(format!("{}.xhtml", stem));
+                        if strip_number_prefix {
+                            path = order::strip_number_prefix_path(&path);
+                        }
+                        xhtml::set_attr(node, "href", &format!("{}{}", path.to_string_lossy(), fragment));
+                    }
+                }
+                for child in node.children.borrow().iter() {
+                    rewrite_content_links(child, strip_number_prefix);
+                }
+            }
+
+            /// 分割によって別のファイルに移った id へのリンクを書き換える
+            fn rewrite_links(node: &Handle, anchors: &HashMap<String, String>, file_name: &str) {
+                if let Some(href) = xhtml::get_attr(node, "href") {
+                    if let Some(id) = href.strip_prefix('#') {
+                        match anchors.get(id) {
+                            Some(target) if target != file_name => xhtml::set_attr(node, "href", &format!("{}#{}", target, id)),
+                            _ => {}
+                        }
+                    }
+                }
+                for child in node.children.borrow().iter() {
+                    rewrite_links(child, anchors, file_name);
                 }
             }
 
@@ -165,32 +290,57 @@ impl Composer {
                 QualName::new(None, ns!(html), local_name!("body")),
                 vec![],
             );
-            let dom = parser.one(xhtml.clone());
-
-            let bind = dom.document.children.borrow();
-            let bind2 = bind[0].children.borrow();
-            let mut children = bind2.iter();
-
-            while let Some(child) = children.next() {
-                match child.data {
-                    NodeData::Element {
-                        ref name,
-                        ref attrs, ..
-                    } => {
-                        fn node_text(node: &Handle, text: &mut String) {
-                            match node.data {
-                                NodeData::Text { ref contents, .. } => {
-                                    let bind = contents.borrow();
-                                    let s = bind.as_ref();
-                                    text.push_str(s);
-                                }
-                                _ => {}
-                            }
+            let dom = parser.one(xhtml);
+            let root = dom.document.children.borrow()[0].clone();
 
-                            for child in node.children.borrow().iter() {
-                                node_text(child, text);
-                            }
-                        }
+            // fenced div, ブロック属性を反映
+            block::apply(&root, divs, &cfg.language);
+            if block_attributes {
+                block::apply_block_attributes(&root);
+            }
+            rewrite_content_links(&root, cfg.strip_number_prefix);
+
+            // 見出しと改ページの直前で分割する
+            let bodies = {
+                let children = std::mem::take(&mut *root.children.borrow_mut());
+                let mut bodies = vec![root.clone()];
+                for child in children {
+                    let page_break = xhtml::get_attr(&child, "class").is_some_and(|c| c == aozora::PAGE_BREAK_CLASS);
+                    let split = page_break || match (split_level, heading_level(&child)) {
+                        (Some(split_level), Some(level)) => level <= split_level,
+                        _ => false,
+                    };
+                    // 最初の見出しより前に内容がなければ分割しない
+                    let is_empty = bodies.last().unwrap().children.borrow().iter().all(|n| match n.data {
+                        NodeData::Text { ref contents } => contents.borrow().trim().is_empty(),
+                        _ => false,
+                    });
+                    if split && !is_empty {
+                        bodies.push(xhtml::create_element("body", &[]));
+                    }
+                    // 改ページの目印は出力しない
+                    if !page_break {
+                        xhtml::append(bodies.last().unwrap(), child);
+                    }
+                }
+                bodies
+            };
+
+            let paths = (0..bodies.len()).map(|i| {
+                if i == 0 {
+                    path_buf.to_path_buf()
+                } else {
+                    let stem = path_buf.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                    path_buf.with_file_name(format!("{}-{}.xhtml", stem, i))
+                }
+            }).collect::<Vec<PathBuf>>();
+
+            for (body, path_buf) in bodies.iter().zip(paths.iter()) {
+                let mut children = Vec::new();
+                headings(body, &mut children);
+
+                for child in &children {
+                    if let NodeData::Element { ref name, .. } = child.data {
                         let level = match name.local {
                             local_name!("h1") => 1,
                             local_name!("h2") => 2,
@@ -200,16 +350,18 @@ impl Composer {
                             _ => continue,
                         };
 
-                        let id = format!("header{}", toc.size());
-                        attrs.borrow_mut().push(create_attribute("id", &id));
+                        // ブロック属性で id が指定されていればそれを使う
+                        let id = match xhtml::get_attr(child, "id") {
+                            Some(id) => id,
+                            None => {
+                                let id = format!("header{}", toc.size());
+                                xhtml::set_attr(child, "id", &id);
+                                id
+                            }
+                        };
 
                         // タイトル抽出
-                        let title = {
-                            let mut title = String::new();
-                            node_text(child, &mut title);
-                            // サニタイズ(テキストと認識されているので, HTMLとして成立していない)
-                            title.replace("<", "&lt;").replace(">", "&gt;")
-                        };
+                        let title = xhtml::text_content(child);
 
                         // tocに登録
                         let toc_item = {
@@ -220,7 +372,8 @@ impl Composer {
                                 items: Vec::new(),
                                 path_buf,
                                 id,
-                                title: title.clone(),
+                                // テキストと認識されているので, XHTMLとして書き出すには escape が必要
+                                title: xhtml::escape_text(&title),
                                 level,
                             }
                         };
@@ -236,129 +389,424 @@ impl Composer {
                                          .unwrap_or_default()
                             )).print();
                     }
-                    _ => {}
                 }
             }
 
-            let mut bytes = vec![];
-            serialize(&mut bytes, &dom.document.children.borrow()[0], SerializeOpts::default()).unwrap();
-            let xhtml = String::from_utf8(bytes).unwrap();
-
-            // domをhtmlに変換しているので、xhtmlとは文法の合わない箇所がある
-            let peaces: Vec<&str> = xhtml.split('<').collect();
-            peaces.into_iter().map(|s| {
-                if s.starts_with("img") || s.starts_with("br") || s.starts_with("hr") {
-                    s.replacen(">", " />", 1)
-                } else { s.to_string() }
-            }).collect::<Vec<String>>().join("<")
+            let ids = bodies.iter().map(|body| {
+                let mut found = Vec::new();
+                ids(body, &mut found);
+                found
+            }).collect::<Vec<Vec<String>>>();
+
+            if bodies.len() > 1 {
+                let anchors = ids.iter().zip(paths.iter())
+                    .flat_map(|(ids, path)| {
+                        let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+                        ids.iter().map(move |id| (id.clone(), file_name.clone()))
+                    })
+                    .collect::<HashMap<String, String>>();
+                for (body, path) in bodies.iter().zip(paths.iter()) {
+                    let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+                    rewrite_links(body, &anchors, file_name);
+                }
+            }
+
+            bodies.iter().zip(paths).zip(ids)
+                .map(|((body, path), ids)| Part {
+                    path,
+                    body: xhtml::serialize_children(body),
+                    ids,
+                })
+                .collect()
         }
 
-        fn convert_content_file(file: &ContentSource, slf: &mut Composer, styles: Option<Vec<ComposedItem>>) -> RepubResult<ComposedItem> {
+        fn convert_content_file(file: &ContentSource, slf: &mut Composer, styles: Option<Vec<ComposedItem>>, markdown: &MarkdownOptions, split_level: Option<u8>, review_chapters: &HashMap<String, review::Chapter>) -> RepubResult<Vec<ComposedItem>> {
             match file.convert_type {
-                ConvertType::MarkdownToXHTML => {
-                    let relative_path = PathBuf::path_diff(&slf.data.cfg.target, &file.src.path).unwrap();
-                    let to = {
-                        let mut to_xhtml = slf.tmp_dir.oebps.path.join(&relative_path);
-                        to_xhtml.set_extension("xhtml");
-                        to_xhtml
-                    };
+                ConvertType::MarkdownToXHTML | ConvertType::HTMLToXHTML | ConvertType::AozoraToXHTML | ConvertType::ReVIEWToXHTML | ConvertType::NotebookToXHTML | ConvertType::DocxToXHTML | ConvertType::OrgToXHTML => {
+                    let relative_path = output_path(&slf.data.cfg, file);
+                    let to = slf.tmp_dir.oebps.path.join(&relative_path);
 
-                    let xhtml = {
-                        let mut options = comrak::ComrakOptions::default();
-                        options.github_pre_lang = true;
-                        options.ext_strikethrough = true;
-                        options.ext_tagfilter = true;
-                        options.ext_table = true;
-                        options.ext_autolink = true;
-                        options.ext_tasklist = true;
-                        options.hardbreaks = true;
-
-                        let source_str = {
-                            let mut string = String::new();
-                            std::fs::File::open(&file.src.path)?.read_to_string(&mut string)?;
-                            string
+                    // html, fenced div, <title>, <head>に置く<style>
+                    let (xhtml, divs, title, head) = if file.convert_type == ConvertType::HTMLToXHTML {
+                        let document = xhtml::parse_document(&std::fs::read_to_string(&file.src.path)?);
+                        let html = match &slf.highlighter {
+                            Some(highlighter) => highlighter.highlight_html(&document.body),
+                            None => document.body,
+                        };
+                        let title = document.title.unwrap_or_else(|| file.src.file_name.clone());
+
+                        (html, Vec::new(), title, document.styles.join("\n        "))
+                    } else if file.convert_type == ConvertType::AozoraToXHTML {
+                        let document = aozora::convert(&aozora::decode(&std::fs::read(&file.src.path)?));
+                        let title = document.title.unwrap_or_else(|| file.src.file_name.clone());
+
+                        (document.body, Vec::new(), title, String::new())
+                    } else if file.convert_type == ConvertType::OrgToXHTML {
+                        let document = org::convert(&std::fs::read_to_string(&file.src.path)?);
+                        let html = match &slf.highlighter {
+                            Some(highlighter) => highlighter.highlight_html(&document.body),
+                            None => document.body,
                         };
+                        let title = document.keywords.title.unwrap_or_else(|| file.src.file_name.clone());
+
+                        (html, Vec::new(), title, String::new())
+                    } else if file.convert_type == ConvertType::DocxToXHTML {
+                        let stem = relative_path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                        let document = docx::convert(&std::fs::read(&file.src.path)?, stem)
+                            .map_err(|e| format_err!("{:?} : {}", &file.src.path, e))?;
+                        slf.compose_embedded_images(&to, &document.images)?;
+                        let title = document.title.unwrap_or_else(|| file.src.file_name.clone());
+
+                        (document.body, Vec::new(), title, document.head)
+                    } else if file.convert_type == ConvertType::ReVIEWToXHTML {
+                        let source = std::fs::read_to_string(&file.src.path)?;
+                        let id = file.src.path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                        let dir = file.src.path.parent().unwrap_or_else(|| Path::new("."));
+                        let context = review::Context { dir, id, path: &relative_path, language: &slf.data.cfg.language, chapters: review_chapters };
+
+                        let (html, divs) = review::convert(&source, &context);
+                        let html = match &slf.highlighter {
+                            Some(highlighter) => highlighter.highlight_html(&html),
+                            None => html,
+                        };
+                        let title = review::title(&source).unwrap_or_else(|| file.src.file_name.clone());
 
-                        comrak::markdown_to_html(&source_str, &options)
+                        (html, divs, title, String::new())
+                    } else {
+                        let options = markdown.to_comrak();
+
+                        let (source_str, outputs) = if file.convert_type == ConvertType::NotebookToXHTML {
+                            // notebook はセルをつなげた markdown とセルの出力に分け, 出力の画像を書き出す
+                            let stem = relative_path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                            let options = notebook::Options { hide_input: slf.data.cfg.hide_input, hide_output: slf.data.cfg.hide_output };
+                            let converted = notebook::convert(&std::fs::read_to_string(&file.src.path)?, stem, options)
+                                .map_err(|e| format_err!("{:?} : {}", &file.src.path, e))?;
+                            slf.compose_embedded_images(&to, &converted.images)?;
+                            (converted.markdown, converted.outputs)
+                        } else {
+                            // {{#include …}} を展開する
+                            (include::expand(&file.src.path)?, Vec::new())
+                        };
+
+                        // 数式は markdown として解釈されないよう, 変換の前に抜き出しておく
+                        let (source_str, formulas) = math::extract(&source_str);
+                        // fenced div は目印の段落に置き換え, 変換後に組み立てる
+                        let (source_str, divs) = block::extract(&source_str);
+                        let html = comrak::markdown_to_html(&source_str, &options);
+                        let html = match &slf.highlighter {
+                            Some(highlighter) => highlighter.highlight_html(&html),
+                            None => html,
+                        };
+
+                        let html = notebook::restore(&math::restore(&html, &formulas), &outputs);
+
+                        (html, divs, file.src.file_name.clone(), String::new())
                     };
 
                     // tocに登録, 整形
-                    let xhtml = register_to(&mut slf.toc, &xhtml, &to);
+                    let parts = register_to(&mut slf.toc, &xhtml, &to, &divs, file.convert_type == ConvertType::MarkdownToXHTML, &slf.data.cfg, split_level);
+
+                    // admonition のスタイルシートは, 最初に使われたときに格納し, 使っているコンテンツにのみ適用する
+                    let uses_admonition = divs.iter().any(|d| d.is_admonition());
+                    let admonition_css = slf.tmp_dir.oebps.path.join(block::ADMONITION_CSS);
+                    if uses_admonition && !slf.composed.style_items.iter().any(|s| s.path == admonition_css) {
+                        slf.compose_generated_css(block::ADMONITION_CSS, block::ADMONITION_STYLE)?;
+                    }
+                    let mut styles = styles.unwrap_or_else(|| slf.composed.style_items.clone());
+                    styles.retain(|s| s.path != admonition_css);
+                    if uses_admonition {
+                        styles.extend(slf.composed.style_items.iter().find(|s| s.path == admonition_css).cloned());
+                    }
+
+                    // 分割した場合は, 他のコンテンツからのリンクを書き換えるために id の所在を記録する
+                    if parts.len() > 1 {
+                        let anchors = parts.iter()
+                            .flat_map(|part| part.ids.iter().map(move |id| (id.clone(), part.path.clone())))
+                            .collect::<HashMap<String, PathBuf>>();
+                        slf.split_anchors.insert(to.clone(), anchors);
+                    }
 
-                    // スタイルシートへの<link>要素を生成
-                    let style_xhtml = if let Some(styles) = styles {
-                        styles.iter()
+                    let mut composed = Vec::new();
+                    for part in parts {
+                        // スタイルシートへの<link>要素を生成
+                        let style_xhtml = styles.iter()
                             .map(|ci| {
                                 let rel_path
-                                    = PathBuf::path_diff(&to, &ci.path)
+                                    = PathBuf::path_diff(&part.path, &ci.path)
                                     .unwrap();
                                 format!("<link type=\"text/css\" rel=\"stylesheet\" href=\"{}\" />", &rel_path.to_str().unwrap())
                             })
                             .collect::<Vec<String>>()
-                            .join("\n")
-                    } else { slf.composed.styles_links(&to) };
+                            .join("\n");
+                        let style_xhtml = if head.is_empty() { style_xhtml } else { format!("{}\n        {}", style_xhtml, head) };
 
-                    // xhtmlを生成
-                    let xhtml = format!(
-                        include_str!("literals/template.xhtml"),
-                        &style_xhtml,
-                        &file.src.file_name,
-                        &xhtml
-                    );
+                        // xhtmlを生成
+                        let xhtml = format!(
+                            include_str!("literals/template.xhtml"),
+                            &style_xhtml,
+                            &xhtml::escape_text(&title),
+                            &part.body
+                        );
 
-                    // 書き込み
-                    std::fs::File::create(&to)?.write_all(xhtml.as_bytes())?;
+                        // 書き込み
+                        create_parent_dir(&part.path)?;
+                        std::fs::File::create(&part.path)?.write_all(xhtml.as_bytes())?;
 
-                    // ログ出力
-                    RepubLog::converted(&format!("{:?}", relative_path)).print();
+                        // ログ出力
+                        RepubLog::converted(&format!("{:?}", PathBuf::path_diff(&slf.tmp_dir.oebps.path, &part.path).unwrap())).print();
 
-                    ComposedItem::new(&file.src, &to, "contents", slf.composed.contents.len())
+                        composed.push(ComposedItem::new(&file.src, &part.path, "contents", slf.composed.contents.len() + composed.len())?);
+                    }
+
+                    Ok(composed)
                 }
                 ConvertType::NoConversion => {
-                    let relative_path = PathBuf::path_diff(&slf.data.cfg.target, &file.src.path).unwrap();
+                    let relative_path = output_path(&slf.data.cfg, file);
                     let to = slf.tmp_dir.oebps.path.join(&relative_path);
 
                     // 書き込み
-                    std::fs::copy(&file.src.path, &to)?;
+                    create_parent_dir(&to)?;
+                    if slf.data.cfg.strip_number_prefix && to.extension().is_some_and(|e| e == "xhtml") {
+                        // 番号を取り除いたファイル名に合わせて, 他のコンテンツへのリンクも書き換える
+                        let xhtml = std::fs::read_to_string(&file.src.path)?;
+                        std::fs::File::create(&to)?.write_all(strip_number_prefix_links(&xhtml).as_bytes())?;
+                    } else {
+                        std::fs::copy(&file.src.path, &to)?;
+                    }
 
                     // ログ出力
                     RepubLog::packed(&format!("{:?}", relative_path)).print();
 
-                    ComposedItem::new(&file.src, &to, "contents", slf.composed.contents.len())
+                    Ok(vec![ComposedItem::new(&file.src, &to, "contents", slf.composed.contents.len())?])
+                }
+            }
+        }
+
+        /// 変換しない`.xhtml`ファイルの中の, `.xhtml`ファイルへのリンクから番号を取り除く
+        fn strip_number_prefix_links(xhtml: &str) -> String {
+            let mut output = String::with_capacity(xhtml.len());
+            let mut rest = xhtml;
+            while let Some(begin) = rest.find(" href=\"") {
+                let begin = begin + " href=\"".len();
+                output.push_str(&rest[..begin]);
+                rest = &rest[begin..];
+                let end = match rest.find('"') {
+                    Some(end) => end,
+                    None => break,
+                };
+                let href = &rest[..end];
+                let (path, fragment) = match href.find('#') {
+                    Some(hash) => href.split_at(hash),
+                    None => (href, ""),
+                };
+
+                if path.ends_with(".xhtml") && !path.contains(':') {
+                    let path = order::strip_number_prefix_path(Path::new(path));
+                    output.push_str(&format!("{}{}", path.to_string_lossy(), fragment));
+                } else {
+                    output.push_str(href);
+                }
+                rest = &rest[end..];
+            }
+            output.push_str(rest);
+
+            output
+        }
+
+        /// 分割されたコンテンツへのリンクを, id を含むファイルへのリンクに書き換える
+        fn rewrite_split_links(xhtml: &str, doc: &PathBuf, split_anchors: &HashMap<PathBuf, HashMap<String, PathBuf>>) -> String {
+            /// `.`, `..` を取り除く
+            fn normalize(path: &Path) -> PathBuf {
+                use std::path::Component;
+
+                let mut normalized = PathBuf::new();
+                for component in path.components() {
+                    match component {
+                        Component::CurDir => {}
+                        Component::ParentDir => { normalized.pop(); }
+                        c => normalized.push(c.as_os_str()),
+                    }
+                }
+                normalized
+            }
+
+            let mut output = String::with_capacity(xhtml.len());
+            let mut rest = xhtml;
+            while let Some(begin) = rest.find(" href=\"") {
+                let begin = begin + " href=\"".len();
+                output.push_str(&rest[..begin]);
+                rest = &rest[begin..];
+                let end = match rest.find('"') {
+                    Some(end) => end,
+                    None => break,
+                };
+                let href = rest[..end].replace("&amp;", "&");
+
+                let resolved = href.find('#')
+                    .filter(|_| !href.contains("://"))
+                    .and_then(|hash| {
+                        let (path, id) = (&href[..hash], &href[hash + 1..]);
+                        if path.is_empty() {
+                            return None;
+                        }
+                        let target = normalize(&doc.parent()?.join(path));
+                        let part = split_anchors.get(&target)?.get(id)?;
+                        let path = PathBuf::path_diff(doc, part)?;
+                        Some(format!("{}#{}", path.to_str()?, id))
+                    });
+
+                match resolved {
+                    Some(href) => output.push_str(&xhtml::escape_attr(&href)),
+                    None => output.push_str(&rest[..end]),
                 }
+                rest = &rest[end..];
             }
+            output.push_str(rest);
+
+            output
         }
 
-        let srcs = if let Some(srcs) = self.data.cfg.sequence.clone().map(|s| s.ptc(&self.data.cfg)) {
+        // sequence の指定がなければ SUMMARY.md の順に並べる
+        let sequence = self.data.cfg.sequence.clone()
+            .or_else(|| self.data.cfg.summary.as_ref().map(|s| s.sequence()));
+        let srcs = if let Some(srcs) = sequence.map(|s| s.ptc(&self.data.cfg, &self.data.files.content_files)) {
             srcs
         } else {
             self.data.files.content_files.clone()
         };
 
-        let ignores = self.data.cfg.ignores.clone().ptc(&self.data.cfg);
-
         // srcs から ignore に該当するものを差し引く
-        let srcs = srcs.into_iter().filter(|p| !ignores.contains(p)).collect::<Vec<ContentSource>>();
+        let srcs = srcs.into_iter().filter(|p| !self.data.cfg.is_ignored(&p.src.path)).collect::<Vec<ContentSource>>();
 
+        // 変換後のファイル名が重複していないか
+        // `a.md`と`a.html`はどちらも`a.xhtml`になる. 番号を取り除く場合は`01_a.md`と`02_a.md`も重複する
+        let mut outputs: HashMap<PathBuf, &PathBuf> = HashMap::new();
         for src in &srcs {
+            let output = output_path(&self.data.cfg, src);
+            if let Some(other) = outputs.insert(output.clone(), &src.src.path) {
+                return Err(format_err!(
+                    "{:?} と {:?} は, 変換後のファイル名がどちらも {:?} になります", other, &src.src.path, output
+                ));
+            }
+        }
+
+        // Re:VIEW の @<chap> などで参照する, 各章の題名と変換後のパス
+        let review_chapters = self.data.files.content_files.iter()
+            .filter(|c| c.convert_type == ConvertType::ReVIEWToXHTML)
+            .filter_map(|c| {
+                let id = c.src.path.file_stem()?.to_str()?.to_string();
+                let title = review::title(&std::fs::read_to_string(&c.src.path).ok()?).unwrap_or_else(|| id.clone());
+                Some((id, review::Chapter { title, path: output_path(&self.data.cfg, c) }))
+            })
+            .collect::<HashMap<String, review::Chapter>>();
+
+        for src in &srcs {
+            let markdown = match self.data.cfg.config(src.as_ref()) {
+                Some(ContentConfigure { markdown: Some(markdown), .. }) => self.data.cfg.markdown.merged(markdown),
+                _ => self.data.cfg.markdown.clone(),
+            };
+
+            let split_level = match self.data.cfg.config(src.as_ref()) {
+                Some(ContentConfigure { split_level: Some(level), .. }) => Some(*level),
+                _ => self.data.cfg.split_level,
+            };
+
             let (properties, styles) =
                 if let Some(ContentConfigure { properties, styles, .. }) = self.data.cfg.config(src.as_ref()) {
                     let properties = properties.iter().map(|p| p.clone()).collect::<Vec<Properties>>();
 
-                    let styles = styles.iter().map(|p| {
-                        let path = self.tmp_dir.oebps.path.join(p);
-                        self.composed.style_items.iter().find(|s| s.path == path).cloned()
-                    }).flat_map(|c| c).collect::<Vec<ComposedItem>>();
+                    // repub が生成したスタイルシートは常に適用する. 基本のスタイルシートは先頭に置く
+                    let (base, generated): (Vec<&ComposedItem>, Vec<&ComposedItem>) = self.composed.style_items.iter()
+                        .filter(|s| s.src.is_none())
+                        .partition(|s| s.path == self.tmp_dir.oebps.path.join(style::BASE_CSS));
+                    let styles = base.into_iter().cloned()
+                        .chain(styles.iter().filter_map(|p| {
+                            let path = self.tmp_dir.oebps.path.join(p);
+                            self.composed.style_items.iter().find(|s| s.path == path).cloned()
+                        }))
+                        .chain(generated.into_iter().cloned())
+                        .collect::<Vec<ComposedItem>>();
 
                     (Some(properties), Some(styles))
                 } else { (None, None) };
 
-            let mut composed = convert_content_file(src, self, styles)?;
+            let mut composed = convert_content_file(src, self, styles, &markdown, split_level, &review_chapters)?;
+
+            // 変換後の xhtml から property を検出する
+            let mut detected = Vec::new();
+            for c in &composed {
+                let mut xhtml = String::new();
+                std::fs::File::open(&c.path)?.read_to_string(&mut xhtml)?;
+                detected.push(Properties::detect(&xhtml));
+            }
+
+            if let Some(prop) = properties {
+                for p in prop {
+                    if p.is_detectable() {
+                        if !detected.iter().any(|d| d.contains(&p)) {
+                            // 宣言されているが存在しない property は EPUB として不正
+                            RepubWarning(format!(
+                                "{:?} : property \"{}\" が指定されていますが, 該当する要素が見つからないため無視しました",
+                                &src.src.path, p.to_string()
+                            )).print();
+                        }
+                        continue;
+                    }
+                    // 分割した場合は, 先頭のファイルにのみ付与する
+                    if !composed[0].properties.contains(&p) {
+                        composed[0].properties.push(p);
+                    }
+                }
+            }
+            for (c, detected) in composed.iter_mut().zip(detected) {
+                for p in detected {
+                    if !c.properties.contains(&p) {
+                        c.properties.push(p);
+                    }
+                }
+            }
+            self.composed.contents.append(&mut composed);
+        }
+
+        // SUMMARY.md があれば, 目次は SUMMARY.md の構成に従う
+        if let Some(summary) = &self.data.cfg.summary {
+            let mut toc = TableOfContents::new();
+            for item in &summary.items {
+                let title = xhtml::escape_text(&item.title);
+                match &item.path {
+                    Some(path) => {
+                        let src_path = self.data.cfg.target.join(path);
+                        // 分割した場合は先頭のファイルを指す
+                        let composed = self.composed.contents.iter()
+                            .find(|c| c.src.as_ref().is_some_and(|s| s.path == src_path));
+                        if let Some(composed) = composed {
+                            toc.push(Box::new(ToCItem {
+                                items: Vec::new(),
+                                path_buf: composed.path.clone(),
+                                id: None,
+                                title,
+                                level: item.level,
+                            }));
+                        }
+                    }
+                    None => toc.push(Box::new(ToCPartItem::new(title, item.level))),
+                }
+            }
+            self.toc = toc;
+        }
 
-            if let Some(mut prop) = properties {
-                composed.properties.append(&mut prop);
+        // 分割したコンテンツへのリンクを書き換える
+        if !self.split_anchors.is_empty() {
+            for c in &self.composed.contents {
+                if c.media_type != MediaType::Application(ApplicationType::XHTML) {
+                    continue;
+                }
+                let xhtml = std::fs::read_to_string(&c.path)?;
+                let rewritten = rewrite_split_links(&xhtml, &c.path, &self.split_anchors);
+                if rewritten != xhtml {
+                    std::fs::File::create(&c.path)?.write_all(rewritten.as_bytes())?;
+                }
             }
-            self.composed.contents.push(composed);
         }
 
         Ok(self)
@@ -375,6 +823,7 @@ impl Composer {
             match ComposedItem::without_src(&to, "static", self.composed.static_items.len()) {
                 Ok(mut composed) => {
                     // 対応している拡張子ならばcopy
+                    create_parent_dir(&to)?;
                     std::fs::copy(&path, &to)?;
                     // ログ出力
                     RepubLog::packed(&format!("Cover Image ({:?})", &relative_path)).print();
@@ -392,6 +841,60 @@ impl Composer {
         Ok(self)
     }
 
+    /// Kobo のために, コンテンツの文を koboSpan で囲む
+    /// compose_contents -> *compose_kobo_spans* -> compose_nav
+    pub fn compose_kobo_spans(&mut self) -> RepubResult<&mut Self> {
+        for content in &self.composed.contents {
+            let xhtml = std::fs::read_to_string(&content.path)?;
+            std::fs::write(&content.path, kobo::kepubify(&xhtml))?;
+
+            // ログ出力
+            RepubLog::converted(&format!("koboSpan: {:?}", PathBuf::path_diff(&self.tmp_dir.path, &content.path).unwrap())).print();
+        }
+
+        Ok(self)
+    }
+
+    /// EPUB に埋め込むフォントを IDPF の方法で難読化し, META-INF/encryption.xml に記載する
+    /// plain_fonts に指定されたフォントは難読化しない
+    pub fn compose_font_obfuscation(&mut self) -> RepubResult<&mut Self> {
+        let key = font::key(&self.data.cfg.book_id);
+        let mut uris = Vec::new();
+
+        for item in &self.composed.static_items {
+            match item.media_type {
+                MediaType::Application(ApplicationType::OpenType) | MediaType::Application(ApplicationType::WOFF) => (),
+                _ => continue,
+            }
+            if item.src.as_ref().is_some_and(|src| self.data.cfg.is_plain_font(&src.path)) {
+                continue;
+            }
+
+            let mut data = std::fs::read(&item.path)?;
+            font::obfuscate(&mut data, &key);
+            std::fs::write(&item.path, &data)?;
+
+            let path = PathBuf::path_diff(&self.tmp_dir.path, &item.path).unwrap();
+            uris.push(path.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"));
+            // ログ出力
+            RepubLog::converted(&format!("obfuscated: {:?}", &path)).print();
+        }
+
+        // 以前の変換で書き出した encryption.xml が残っていれば, 難読化していないフォントを指してしまう
+        let MetaInf(meta_inf) = &self.tmp_dir.meta_inf;
+        let encryption_xml = meta_inf.join("encryption.xml");
+        if !uris.is_empty() {
+            std::fs::write(&encryption_xml, font::encryption_xml(&uris))?;
+        } else if encryption_xml.exists() {
+            std::fs::remove_file(&encryption_xml)?;
+        }
+
+        Ok(self)
+    }
+
     /// self.toc を参照して, navigation.xhtml を生成する
     /// compose_css -> compose_static -> compose_contents -> *compose_nav* -> compose_opf
     pub fn compose_nav(&mut self) -> RepubResult<&mut Self> {
@@ -465,14 +968,11 @@ impl Composer {
 
         // 並びの変更
         let spine_str = {
-            let (handmade_navs, mut contents_without_navs): (Vec<ComposedItem>, Vec<ComposedItem>)
+            // コンテンツは compose_contents で並べた順のまま
+            let (handmade_navs, contents_without_navs): (Vec<ComposedItem>, Vec<ComposedItem>)
                 = self.composed.contents.clone().into_iter()
                 .partition(|c| c.properties.contains(&Properties::Nav));
 
-            // sort
-            contents_without_navs
-                .sort_by(|a, b| a.id.cmp(&b.id));
-
             let items_str
                 = self.navigation.iter()
                 .chain(handmade_navs.iter())
@@ -513,21 +1013,63 @@ impl Composer {
         self.compose_css()?
             .compose_static()?
             .compose_contents()?
-            .compose_cover_image()?
-            .compose_nav()?
-            .compose_opf()?;
+            .compose_cover_image()?;
+
+        match self.data.cfg.format {
+            Format::Epub => {
+                self.compose_font_obfuscation()?
+                    .compose_nav()?
+                    .compose_opf()?;
 
-        if cfg!(target_os = "macos") {
-            self.zip()?;
+                if cfg!(target_os = "macos") {
+                    self.zip()?;
+                }
+            }
+            Format::Kepub => {
+                self.compose_kobo_spans()?
+                    .compose_font_obfuscation()?
+                    .compose_nav()?
+                    .compose_opf()?;
+
+                if cfg!(target_os = "macos") {
+                    self.zip()?;
+                }
+            }
+            Format::Html => site::publish(&self.publication())?,
+            Format::SingleHtml => site::publish_single(&self.publication())?,
+            Format::Fb2 => fb2::publish(&self.publication())?,
         }
 
         Ok(())
     }
 
+    /// compose したファイルを, EPUB 以外の形式で書き出すために参照する
+    fn publication(&self) -> Publication<'_> {
+        // コンテンツは spine と同じく, 手製の目次を先頭に並べる
+        let (handmade_navs, contents_without_navs): (Vec<&ComposedItem>, Vec<&ComposedItem>)
+            = self.composed.contents.iter()
+            .partition(|c| c.properties.contains(&Properties::Nav));
+
+        Publication {
+            cfg: &self.data.cfg,
+            root: &self.tmp_dir.oebps.path,
+            contents: handmade_navs.into_iter()
+                .chain(contents_without_navs)
+                .map(|c| c.path.as_path())
+                .collect(),
+            styles: self.composed.style_items.iter().map(|s| s.path.as_path()).collect(),
+            statics: self.composed.static_items.iter().map(|s| s.path.as_path()).collect(),
+            cover_image: self.composed.static_items.iter()
+                .find(|s| s.properties.contains(&Properties::CoverImage))
+                .map(|s| s.path.as_path()),
+            toc: &self.toc,
+        }
+    }
+
     pub fn zip(&mut self) -> RepubResult<()> {
         use zip::{CompressionMethod, write::{FileOptions, ZipWriter}};
 
-        let epub_path = PathBuf::from(&format!("{}.epub", &self.data.cfg.title.clone()));
+        let epub_path = self.data.cfg.format.output_path(&self.data.cfg.title);
         let epub = match std::fs::File::create(&epub_path) {
             Ok(file) => {
                 file
@@ -608,6 +1150,21 @@ impl Composer {
     }
 }
 
+/// compose したファイルの一覧
+/// パスはすべて tmp directory 内のもの
+pub struct Publication<'a> {
+    pub cfg: &'a Config,
+    /// OEBPS directory
+    pub root: &'a Path,
+    /// spine の順に並べたコンテンツ
+    pub contents: Vec<&'a Path>,
+    pub styles: Vec<&'a Path>,
+    /// 表紙を含む, 画像などのファイル
+    pub statics: Vec<&'a Path>,
+    pub cover_image: Option<&'a Path>,
+    pub toc: &'a TableOfContents,
+}
+
 struct Composed {
     contents: Vec<ComposedItem>,
     style_items: Vec<ComposedItem>,
@@ -639,7 +1196,6 @@ impl Composed {
 
 #[derive(Clone, Debug)]
 struct ComposedItem {
-    #[allow(dead_code)]
     src: Option<Source>,
     path: PathBuf,
     id: String,
@@ -910,7 +1466,6 @@ pub mod properties {
 
     /// https://imagedrive.github.io/spec/epub30-publications.xhtml#sec-item-property-values
     #[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
-    #[allow(dead_code)]
     #[serde(rename_all = "kebab-case")]
     pub enum Properties {
         /// cover-image プロパティは、出版物のカバーイメージとして説明され Publication Resource を識別する
@@ -929,6 +1484,93 @@ pub mod properties {
         Switch,
     }
 
+    impl Properties {
+        /// xhtml の内容から検出できる property か否か
+        pub fn is_detectable(&self) -> bool {
+            match self {
+                Properties::MathML
+                | Properties::RemoteResources
+                | Properties::Scripted
+                | Properties::Svg
+                | Properties::Switch => true,
+                Properties::CoverImage | Properties::Nav => false,
+            }
+        }
+
+        /// xhtml を走査して, 含まれている要素に応じた property を返す
+        /// `<svg>`, `<script>`/`<form>`, `<math>`, `<epub:switch>`, http(s) のリソースへの参照を検出する
+        pub fn detect(xhtml: &str) -> Vec<Properties> {
+            use html5ever::{
+                parse_document,
+                ParseOpts,
+                rcdom::{RcDom, NodeData, Handle},
+                tendril::TendrilSink,
+            };
+
+            fn is_remote(url: &str) -> bool {
+                let url = url.trim().to_ascii_lowercase();
+                url.starts_with("http://") || url.starts_with("https://")
+            }
+
+            fn css_is_remote(css: &str) -> bool {
+                let css = css.to_ascii_lowercase();
+                css.split("url(").skip(1).any(|s| is_remote(s.trim_start_matches(['"', '\'', ' '])))
+                    || css.split("@import").skip(1).any(|s| is_remote(s.trim_start_matches(['"', '\'', ' '])))
+            }
+
+            fn walk(node: &Handle, found: &mut Vec<Properties>) {
+                let mut push = |p: Properties| if !found.contains(&p) { found.push(p) };
+
+                if let NodeData::Element { ref name, ref attrs, .. } = node.data {
+                    match (&name.ns, name.local.as_ref()) {
+                        (&ns!(svg), "svg") => push(Properties::Svg),
+                        (&ns!(mathml), "math") => push(Properties::MathML),
+                        (&ns!(html), "script") | (&ns!(html), "form") => push(Properties::Scripted),
+                        (_, "epub:switch") => push(Properties::Switch),
+                        _ => {}
+                    }
+
+                    // <a href> はリソースの参照ではない
+                    let resource_attrs: &[&str] = match name.local.as_ref() {
+                        "a" | "area" => &[],
+                        "link" => &["href"],
+                        "object" => &["data"],
+                        "video" => &["src", "poster"],
+                        "image" | "use" | "feImage" => &["href", "src"],
+                        _ => &["src"],
+                    };
+                    for attr in attrs.borrow().iter() {
+                        let local = attr.name.local.as_ref();
+                        if (resource_attrs.contains(&local) && is_remote(&attr.value))
+                            || (local == "style" && css_is_remote(&attr.value)) {
+                            push(Properties::RemoteResources);
+                        }
+                    }
+
+                    if name.local.as_ref() == "style" {
+                        let css = node.children.borrow().iter().map(|child| match child.data {
+                            NodeData::Text { ref contents } => contents.borrow().to_string(),
+                            _ => String::new(),
+                        }).collect::<String>();
+                        if css_is_remote(&css) {
+                            push(Properties::RemoteResources);
+                        }
+                    }
+                }
+
+                for child in node.children.borrow().iter() {
+                    walk(child, found);
+                }
+            }
+
+            let dom = parse_document(RcDom::default(), ParseOpts::default()).one(xhtml);
+
+            let mut found = Vec::new();
+            walk(&dom.document, &mut found);
+            found
+        }
+    }
+
     impl ToString for Properties {
         fn to_string(&self) -> String {
             match self {
@@ -947,18 +1589,60 @@ pub mod properties {
 use std::path::Path;
 use crate::prelude::PathBuf;
 
+/// コンテンツを変換して出力するファイルの, target からの相対パス
+fn output_path(cfg: &Config, file: &ContentSource) -> PathBuf {
+    let mut relative_path = PathBuf::path_diff(&cfg.target, &file.src.path).unwrap();
+    if file.convert_type != ConvertType::NoConversion {
+        relative_path.set_extension("xhtml");
+    }
+    if cfg.strip_number_prefix {
+        relative_path = order::strip_number_prefix_path(&relative_path);
+    }
+    relative_path
+}
+
+/// ソースがサブディレクトリにある場合に備えて, 出力先のディレクトリを作成する
+fn create_parent_dir(path: &Path) -> RepubResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
 trait PathToContentFile {
-    fn ptc(&self, cfg: &Config) -> Vec<ContentSource>;
+    /// glob は content_files のうち一致するものに, パスの(自然)順に展開する. 重複するものは最初のみ残す
+    fn ptc(&self, cfg: &Config, content_files: &[ContentSource]) -> Vec<ContentSource>;
 }
 
 impl<T: AsRef<Path>> PathToContentFile for Vec<T> {
-    fn ptc(&self, cfg: &Config) -> Vec<ContentSource> {
-        self.iter().map(|p| {
-            let path = cfg.target.join(p);
-            let src = Source::try_from(&path)?;
+    fn ptc(&self, cfg: &Config, content_files: &[ContentSource]) -> Vec<ContentSource> {
+        let mut srcs: Vec<ContentSource> = Vec::new();
+
+        for p in self {
+            let p = p.as_ref();
+            let matched = if pattern::is_glob(p) {
+                let mut matched = content_files.iter()
+                    .filter(|c| PathBuf::path_diff(&cfg.target, &c.src.path).is_some_and(|r| pattern::matches(p, &r)))
+                    .cloned()
+                    .collect::<Vec<ContentSource>>();
+                matched.sort_by(|a, b| order::natural_cmp_path(&a.src.path, &b.src.path));
+                matched
+            } else {
+                let path = cfg.target.join(p);
+                Source::try_from(&path)
+                    .and_then(ContentSource::try_from)
+                    .into_iter()
+                    .collect()
+            };
+
+            for c in matched {
+                if !srcs.contains(&c) {
+                    srcs.push(c);
+                }
+            }
+        }
 
-            ContentSource::try_from(src)
-        }).flat_map(|c| c).collect::<Vec<ContentSource>>()
+        srcs
     }
 }
 
@@ -1041,6 +1725,23 @@ fn test_html5ever() {
     assert_eq!(1, 1)
 }
 
+#[test]
+fn test_detect_properties() {
+    let xhtml = r#"<?xml version='1.0' encoding='utf-8'?>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<head><title>t</title></head>
+<body>
+<p><a href="https://example.com">link</a></p>
+<svg xmlns="http://www.w3.org/2000/svg"><circle r="1" /></svg>
+<math xmlns="http://www.w3.org/1998/Math/MathML"><mi>x</mi></math>
+</body>
+</html>"#;
+    assert_eq!(Properties::detect(xhtml), vec![Properties::Svg, Properties::MathML]);
+
+    let xhtml = r#"<html><body><script src="a.js"></script><img src="https://example.com/a.png" /><epub:switch id="s"></epub:switch></body></html>"#;
+    assert_eq!(Properties::detect(xhtml), vec![Properties::Scripted, Properties::RemoteResources, Properties::Switch]);
+}
+
 /// exec with --nocapture
 #[test]
 fn test_print_failure_err() {
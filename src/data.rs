@@ -1,5 +1,6 @@
 use crate::prelude::*;
 use crate::load::*;
+use crate::aozora;
 
 pub use files::*;
 
@@ -13,7 +14,15 @@ impl From<Input> for InputData {
     fn from(input: Input) -> Self {
         let Input { cfg, src } = input;
 
-        let files = Files::from(src);
+        let mut files = Files::from(src);
+
+        // 変換対象として直接指定された`.txt`ファイルは, 記法を含まなくても青空文庫形式のテキストとして収録する
+        if cfg.target.is_file() {
+            if let Some(i) = files.static_files.iter().position(|s| s.ext.as_deref() == Some("txt")) {
+                let src = files.static_files.remove(i);
+                files.content_files.push(ContentSource { src, convert_type: ConvertType::AozoraToXHTML });
+            }
+        }
 
         Self {
             cfg,
@@ -77,13 +86,15 @@ mod files {
         fn try_from(value: Source) -> Result<Self, Self::Error> {
             let convert_type = {
                 let ext = value.ext.as_ref().ok_or(format_err!("{:?} の拡張子の取得に失敗しました", &value.path.file_name()))?;
-                match ext.as_str() {
-                    "md" => ConvertType::MarkdownToXHTML,
-                    "xhtml" => ConvertType::NoConversion,
-                    e => return Err(format_err!("{} 形式のファイルはコンテンツとして収録できません", &e)),
-                }
+                ConvertType::from_extension(ext)
+                    .ok_or_else(|| format_err!("{} 形式のファイルはコンテンツとして収録できません", ext))?
             };
 
+            // `README.txt`のような普通のテキストは, 青空文庫形式の記法を含まなければ収録しない
+            if convert_type == ConvertType::AozoraToXHTML && !aozora::is_aozora(&aozora::decode(&std::fs::read(&value.path)?)) {
+                return Err(format_err!("{:?} は青空文庫形式の記法を含まないため, コンテンツとして収録しません", &value.path));
+            }
+
             Ok(Self {
                 src: value,
                 convert_type,
@@ -91,6 +102,14 @@ mod files {
         }
     }
 
+    /// notebook の出力など, コンテンツに埋め込まれていた画像
+    #[derive(Debug, PartialEq)]
+    pub struct EmbeddedImage {
+        /// 変換後の`.xhtml`ファイルからの相対パス
+        pub path: PathBuf,
+        pub data: Vec<u8>,
+    }
+
     impl AsRef<Source> for ContentSource {
         fn as_ref(&self) -> &Source {
             &self.src
@@ -115,18 +134,34 @@ mod files {
     #[derive(Debug, Clone, PartialEq)]
     pub enum ConvertType {
         MarkdownToXHTML,
+        HTMLToXHTML,
+        /// 青空文庫形式のテキスト
+        AozoraToXHTML,
+        /// Re:VIEW 形式の原稿
+        ReVIEWToXHTML,
+        /// Jupyter notebook
+        NotebookToXHTML,
+        /// Word 文書
+        DocxToXHTML,
+        /// Org 文書
+        OrgToXHTML,
         NoConversion,
     }
 
-    impl From<&PathBuf> for ConvertType {
-        fn from(value: &PathBuf) -> Self {
-            if let Some(Some(s)) = value.extension().map(|e| e.to_str()) {
-                match s {
-                    "md" => ConvertType::MarkdownToXHTML,
-                    _ => ConvertType::NoConversion,
-                }
-            } else {
-                ConvertType::NoConversion
+    impl ConvertType {
+        /// 拡張子から変換の種類を決める. コンテンツとして収録できない拡張子であれば None
+        /// `.txt`は青空文庫形式のテキストとするが, ディレクトリの中のものは記法を含む場合のみ収録する
+        pub fn from_extension(ext: &str) -> Option<Self> {
+            match ext {
+                "md" => Some(ConvertType::MarkdownToXHTML),
+                "html" | "htm" => Some(ConvertType::HTMLToXHTML),
+                "txt" => Some(ConvertType::AozoraToXHTML),
+                "re" => Some(ConvertType::ReVIEWToXHTML),
+                "ipynb" => Some(ConvertType::NotebookToXHTML),
+                "docx" => Some(ConvertType::DocxToXHTML),
+                "org" => Some(ConvertType::OrgToXHTML),
+                "xhtml" => Some(ConvertType::NoConversion),
+                _ => None,
             }
         }
     }
@@ -0,0 +1,711 @@
+//! Word 文書 (`.docx`) の変換
+//!
+//! `word/document.xml` の段落, 表を XHTML にする. 見出しはスタイル (「見出し 1」など) かアウトラインレベルから,
+//! 箇条書きは`word/numbering.xml`の番号の書式から判断する. 脚注, 文末脚注は文書の末尾にまとめる
+
+use crate::{prelude::*, data::EmbeddedImage, aozora, xhtml};
+use std::{collections::{HashMap, HashSet}, io::{Cursor, Read}, path::Path};
+
+use roxmltree::{Document, Node};
+
+const W_NS: &str = "http://schemas.openxmlformats.org/wordprocessingml/2006/main";
+const R_NS: &str = "http://schemas.openxmlformats.org/officeDocument/2006/relationships";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+
+const DOCUMENT_XML: &str = "word/document.xml";
+const DOCUMENT_RELS: &str = "word/_rels/document.xml.rels";
+const STYLES_XML: &str = "word/styles.xml";
+const NUMBERING_XML: &str = "word/numbering.xml";
+const CORE_XML: &str = "docProps/core.xml";
+
+/// 縦書きの文書に適用するスタイル
+const VERTICAL_STYLE: &str = "<style>html { -epub-writing-mode: vertical-rl; writing-mode: vertical-rl; }</style>";
+
+/// 変換した文書
+#[derive(Debug)]
+pub struct DocxDocument {
+    /// 文書のプロパティのタイトル
+    pub title: Option<String>,
+    pub body: String,
+    /// `<head>`に置く`<style>`
+    pub head: String,
+    pub images: Vec<EmbeddedImage>,
+}
+
+/// 脚注と文末脚注
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum NoteKind {
+    Footnote,
+    Endnote,
+}
+
+impl NoteKind {
+    fn xml(self) -> &'static str {
+        match self {
+            NoteKind::Footnote => "word/footnotes.xml",
+            NoteKind::Endnote => "word/endnotes.xml",
+        }
+    }
+
+    fn element(self) -> &'static str {
+        match self {
+            NoteKind::Footnote => "footnote",
+            NoteKind::Endnote => "endnote",
+        }
+    }
+
+    /// id の接頭辞
+    fn prefix(self) -> &'static str {
+        match self {
+            NoteKind::Footnote => "fn",
+            NoteKind::Endnote => "en",
+        }
+    }
+}
+
+/// 段落スタイル
+#[derive(Debug, Default)]
+struct Style {
+    name: String,
+    based_on: Option<String>,
+    outline_level: Option<usize>,
+    numbering: Option<(String, usize)>,
+}
+
+/// WordprocessingML の要素か否か
+fn is(node: &Node, name: &str) -> bool {
+    node.is_element() && node.tag_name().name() == name && node.tag_name().namespace() == Some(W_NS)
+}
+
+fn child<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|c| is(c, name))
+}
+
+fn attr<'a>(node: &Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.attribute((W_NS, name))
+}
+
+fn val<'a>(node: &Node<'a, '_>) -> Option<&'a str> {
+    attr(node, "val")
+}
+
+/// `<w:b/>`のような on / off の指定
+fn on(node: Option<Node>) -> bool {
+    match node {
+        Some(node) => !matches!(val(&node), Some("0" | "false" | "off" | "none")),
+        None => false,
+    }
+}
+
+fn descendant<'a, 'input>(node: &Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.descendants().find(|d| d.is_element() && d.tag_name().name() == name)
+}
+
+fn read(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Option<Vec<u8>> {
+    let mut file = archive.by_name(name).ok()?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+fn read_string(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Option<String> {
+    read(archive, name).and_then(|b| String::from_utf8(b).ok())
+}
+
+/// `word/_rels/document.xml.rels`: id と (参照先, 外部のリソースか否か)
+fn relationships(xml: &str) -> HashMap<String, (String, bool)> {
+    let document = match Document::parse(xml) {
+        Ok(document) => document,
+        Err(_) => return HashMap::new(),
+    };
+    document.descendants()
+        .filter(|n| n.is_element() && n.tag_name().name() == "Relationship")
+        .filter_map(|n| Some((
+            n.attribute("Id")?.to_string(),
+            (n.attribute("Target")?.to_string(), n.attribute("TargetMode") == Some("External")),
+        )))
+        .collect()
+}
+
+fn styles(xml: &str) -> HashMap<String, Style> {
+    let document = match Document::parse(xml) {
+        Ok(document) => document,
+        Err(_) => return HashMap::new(),
+    };
+    document.root_element().children()
+        .filter(|n| is(n, "style"))
+        .filter_map(|n| {
+            let ppr = child(&n, "pPr");
+            let style = Style {
+                name: child(&n, "name").and_then(|n| val(&n)).unwrap_or_default().to_lowercase(),
+                based_on: child(&n, "basedOn").and_then(|n| val(&n)).map(|s| s.to_string()),
+                outline_level: ppr.and_then(|p| child(&p, "outlineLvl")).and_then(|o| val(&o)?.parse().ok()),
+                numbering: ppr.and_then(|p| numbering_of(&p)),
+            };
+            Some((attr(&n, "styleId")?.to_string(), style))
+        })
+        .collect()
+}
+
+/// `<w:numPr>`の番号の id とレベル
+fn numbering_of(ppr: &Node) -> Option<(String, usize)> {
+    let num = child(ppr, "numPr")?;
+    let id = child(&num, "numId").and_then(|n| val(&n))?;
+    // numId が 0 の場合は, 箇条書きではない
+    if id == "0" {
+        return None;
+    }
+    let level = child(&num, "ilvl").and_then(|n| val(&n)?.parse().ok()).unwrap_or(0);
+    Some((id.to_string(), level))
+}
+
+/// `word/numbering.xml`: 番号の id とレベルごとに, 番号付きか (記号でないか)
+fn numbering(xml: &str) -> HashMap<String, HashMap<usize, bool>> {
+    let document = match Document::parse(xml) {
+        Ok(document) => document,
+        Err(_) => return HashMap::new(),
+    };
+    let root = document.root_element();
+    let abstracts = root.children()
+        .filter(|n| is(n, "abstractNum"))
+        .filter_map(|n| {
+            let levels = n.children()
+                .filter(|l| is(l, "lvl"))
+                .filter_map(|l| {
+                    let level = attr(&l, "ilvl")?.parse().ok()?;
+                    let format = child(&l, "numFmt").and_then(|f| val(&f)).unwrap_or("bullet");
+                    Some((level, !matches!(format, "bullet" | "none")))
+                })
+                .collect::<HashMap<usize, bool>>();
+            Some((attr(&n, "abstractNumId")?.to_string(), levels))
+        })
+        .collect::<HashMap<String, HashMap<usize, bool>>>();
+
+    root.children()
+        .filter(|n| is(n, "num"))
+        .filter_map(|n| {
+            let abstract_id = child(&n, "abstractNumId").and_then(|a| val(&a))?;
+            Some((attr(&n, "numId")?.to_string(), abstracts.get(abstract_id)?.clone()))
+        })
+        .collect()
+}
+
+/// 開いている箇条書き
+#[derive(Default)]
+struct Lists {
+    tags: Vec<&'static str>,
+}
+
+impl Lists {
+    fn item(&mut self, level: usize, ordered: bool, out: &mut String) {
+        let tag = if ordered { "ol" } else { "ul" };
+        while self.tags.len() > level + 1 {
+            self.close_one(out);
+        }
+        if self.tags.len() == level + 1 {
+            if self.tags.last() == Some(&tag) {
+                out.push_str("</li>\n<li>");
+                return;
+            }
+            self.close_one(out);
+        }
+        while self.tags.len() < level + 1 {
+            out.push_str(&format!("<{}>\n<li>", tag));
+            self.tags.push(tag);
+        }
+    }
+
+    fn close_one(&mut self, out: &mut String) {
+        if let Some(tag) = self.tags.pop() {
+            out.push_str(&format!("</li>\n</{}>\n", tag));
+        }
+    }
+
+    fn close(&mut self, out: &mut String) {
+        while !self.tags.is_empty() {
+            self.close_one(out);
+        }
+    }
+}
+
+struct Converter<'a> {
+    archive: zip::ZipArchive<Cursor<&'a [u8]>>,
+    relationships: HashMap<String, (String, bool)>,
+    styles: HashMap<String, Style>,
+    numbering: HashMap<String, HashMap<usize, bool>>,
+    /// 画像を置くディレクトリ (`<stem>_files`)
+    images_dir: PathBuf,
+    images: Vec<EmbeddedImage>,
+    /// relationship の id と, 書き出した画像のパス
+    image_paths: HashMap<String, PathBuf>,
+    /// 参照された順の脚注の種類と id
+    notes: Vec<(NoteKind, String)>,
+    /// 変換中の脚注と, その番号
+    current_note: Option<(NoteKind, usize)>,
+    /// リンクされているブックマーク
+    anchors: HashSet<String>,
+    /// 段落の途中に改ページがあった
+    page_break: bool,
+}
+
+impl<'a> Converter<'a> {
+    /// 段落スタイルの見出しのレベル
+    fn heading_level(&self, ppr: Option<Node>) -> Option<usize> {
+        if let Some(level) = ppr.and_then(|p| child(&p, "outlineLvl")).and_then(|o| val(&o)?.parse::<usize>().ok()) {
+            // 9 は本文
+            return if level < 9 { Some(std::cmp::min(level + 1, 6)) } else { None };
+        }
+
+        let mut style_id = ppr.and_then(|p| child(&p, "pStyle")).and_then(|s| val(&s)).map(|s| s.to_string());
+        // basedOn をたどる. 循環している場合に備えて回数を制限する
+        for _ in 0..10 {
+            let style = self.styles.get(style_id.as_ref()?)?;
+            if style.name == "title" {
+                return Some(1);
+            }
+            if let Some(level) = style.name.strip_prefix("heading ").and_then(|l| l.parse::<usize>().ok()) {
+                return Some(std::cmp::min(level, 6));
+            }
+            if let Some(level) = style.outline_level {
+                return if level < 9 { Some(std::cmp::min(level + 1, 6)) } else { None };
+            }
+            style_id = style.based_on.clone();
+        }
+        None
+    }
+
+    /// 箇条書きの段落であれば, そのレベルと番号付きか否か
+    fn list_item(&self, ppr: Option<Node>) -> Option<(usize, bool)> {
+        let (id, level) = ppr.and_then(|p| numbering_of(&p))
+            .or_else(|| {
+                let style = ppr.and_then(|p| child(&p, "pStyle")).and_then(|s| val(&s))?;
+                self.styles.get(style)?.numbering.clone()
+            })?;
+        let ordered = self.numbering.get(&id).and_then(|levels| levels.get(&level)).copied().unwrap_or(false);
+        Some((level, ordered))
+    }
+
+    /// 段落や表を順に変換する
+    fn blocks(&mut self, parent: Node, out: &mut String) {
+        let mut lists = Lists::default();
+
+        for node in parent.children() {
+            if is(&node, "p") {
+                let ppr = child(&node, "pPr");
+                let page_break_before = on(ppr.and_then(|p| child(&p, "pageBreakBefore")));
+                let content = self.inline(node);
+                let page_break = std::mem::take(&mut self.page_break);
+
+                if let Some((level, ordered)) = self.list_item(ppr) {
+                    lists.item(level, ordered, out);
+                    out.push_str(&content);
+                    continue;
+                }
+                lists.close(out);
+
+                if page_break_before {
+                    out.push_str(&format!("<div class=\"{}\"></div>\n", aozora::PAGE_BREAK_CLASS));
+                }
+                // 空の段落は, 行間を空けるためのものなので出力しない
+                if !content.trim().is_empty() {
+                    match self.heading_level(ppr) {
+                        Some(level) => out.push_str(&format!("<h{0}>{1}</h{0}>\n", level, content)),
+                        None => {
+                            let align = match ppr.and_then(|p| child(&p, "jc")).and_then(|j| val(&j)) {
+                                Some("center") => " style=\"text-align: center;\"",
+                                Some("right" | "end") => " style=\"text-align: end;\"",
+                                _ => "",
+                            };
+                            out.push_str(&format!("<p{}>{}</p>\n", align, content));
+                        }
+                    }
+                }
+                if page_break {
+                    out.push_str(&format!("<div class=\"{}\"></div>\n", aozora::PAGE_BREAK_CLASS));
+                }
+            } else if is(&node, "tbl") {
+                lists.close(out);
+                self.table(node, out);
+            } else if is(&node, "sdt") {
+                lists.close(out);
+                if let Some(content) = child(&node, "sdtContent") {
+                    self.blocks(content, out);
+                }
+            }
+        }
+
+        lists.close(out);
+    }
+
+    fn table(&mut self, table: Node, out: &mut String) {
+        struct Cell<'a, 'input> {
+            node: Node<'a, 'input>,
+            column: usize,
+            span: usize,
+            /// 縦に結合されたセルの続き
+            merged: bool,
+            restart: bool,
+        }
+
+        let rows = table.children()
+            .filter(|r| is(r, "tr"))
+            .map(|row| {
+                let mut column = 0;
+                let cells = row.children()
+                    .filter(|c| is(c, "tc"))
+                    .map(|node| {
+                        let tcpr = child(&node, "tcPr");
+                        let span = tcpr.and_then(|p| child(&p, "gridSpan")).and_then(|s| val(&s)?.parse().ok()).unwrap_or(1);
+                        let merge = tcpr.and_then(|p| child(&p, "vMerge"));
+                        let restart = merge.map(|m| val(&m) == Some("restart")).unwrap_or(false);
+                        let cell = Cell { node, column, span, merged: merge.is_some() && !restart, restart };
+                        column += span;
+                        cell
+                    })
+                    .collect::<Vec<Cell>>();
+                let header = row.children().find(|p| is(p, "trPr"))
+                    .map(|p| on(child(&p, "tblHeader")))
+                    .unwrap_or(false);
+                (header, cells)
+            })
+            .collect::<Vec<(bool, Vec<Cell>)>>();
+
+        out.push_str("<table>\n");
+        for (r, (header, cells)) in rows.iter().enumerate() {
+            out.push_str("<tr>");
+            for cell in cells.iter().filter(|c| !c.merged) {
+                let tag = if *header { "th" } else { "td" };
+                let mut attributes = String::new();
+                if cell.span > 1 {
+                    attributes.push_str(&format!(" colspan=\"{}\"", cell.span));
+                }
+                if cell.restart {
+                    let span = 1 + rows[r + 1..].iter()
+                        .take_while(|(_, cells)| cells.iter().any(|c| c.column == cell.column && c.merged))
+                        .count();
+                    if span > 1 {
+                        attributes.push_str(&format!(" rowspan=\"{}\"", span));
+                    }
+                }
+
+                let mut content = String::new();
+                self.blocks(cell.node, &mut content);
+                // 段落がひとつだけのセルは, <p>で囲まない
+                let content = match content.trim_end().strip_prefix("<p>").and_then(|c| c.strip_suffix("</p>")) {
+                    Some(c) if !c.contains("<p>") => c.to_string(),
+                    _ => content.trim_end().to_string(),
+                };
+                out.push_str(&format!("<{0}{1}>{2}</{0}>", tag, attributes, content));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
+    }
+
+    /// 段落の中身
+    fn inline(&mut self, parent: Node) -> String {
+        let mut html = String::new();
+        for node in parent.children().filter(|n| n.is_element() && n.tag_name().namespace() == Some(W_NS)) {
+            match node.tag_name().name() {
+                "r" => html.push_str(&self.run(node)),
+                "hyperlink" => {
+                    let href = match (node.attribute((R_NS, "id")), attr(&node, "anchor")) {
+                        (Some(id), _) => self.relationships.get(id).map(|(target, _)| target.clone()),
+                        (None, Some(anchor)) => Some(format!("#{}", anchor)),
+                        _ => None,
+                    };
+                    let content = self.inline(node);
+                    match href {
+                        Some(href) => html.push_str(&format!("<a href=\"{}\">{}</a>", xhtml::escape_attr(&href), content)),
+                        None => html.push_str(&content),
+                    }
+                }
+                "bookmarkStart" => {
+                    if let Some(name) = attr(&node, "name").filter(|n| self.anchors.contains(*n)) {
+                        html.push_str(&format!("<span id=\"{}\"></span>", xhtml::escape_attr(name)));
+                    }
+                }
+                // 変更履歴の挿入, コンテンツコントロールなどは中身のみ
+                "ins" | "smartTag" | "sdt" | "sdtContent" | "customXml" | "fldSimple" => html.push_str(&self.inline(node)),
+                _ => {}
+            }
+        }
+        html
+    }
+
+    fn run(&mut self, run: Node) -> String {
+        let mut text = String::new();
+        for node in run.children().filter(|n| n.is_element()) {
+            match node.tag_name().name() {
+                "t" => text.push_str(&xhtml::escape_text(node.text().unwrap_or_default())),
+                "tab" => text.push('\t'),
+                "br" | "cr" => match attr(&node, "type") {
+                    Some("page") => self.page_break = true,
+                    _ => text.push_str("<br />"),
+                },
+                "noBreakHyphen" => text.push('\u{2011}'),
+                "ruby" => {
+                    let base = child(&node, "rubyBase").map(|b| self.inline(b)).unwrap_or_default();
+                    let rt = child(&node, "rt").map(|r| self.inline(r)).unwrap_or_default();
+                    text.push_str(&format!("<ruby>{}<rt>{}</rt></ruby>", base, rt));
+                }
+                "footnoteReference" | "endnoteReference" => {
+                    let kind = if node.tag_name().name() == "footnoteReference" { NoteKind::Footnote } else { NoteKind::Endnote };
+                    if let Some(id) = attr(&node, "id") {
+                        self.notes.push((kind, id.to_string()));
+                        let n = self.notes.iter().filter(|(k, _)| *k == kind).count();
+                        text.push_str(&format!(
+                            "<a id=\"{0}ref-{1}\" href=\"#{0}-{1}\" class=\"noteref\" epub:type=\"noteref\"><sup>{1}</sup></a>",
+                            kind.prefix(), n
+                        ));
+                    }
+                }
+                // 脚注の中の, 脚注番号
+                "footnoteRef" | "endnoteRef" => {
+                    if let Some((kind, n)) = self.current_note {
+                        text.push_str(&format!("<a href=\"#{0}ref-{1}\">{1}</a> ", kind.prefix(), n));
+                    }
+                }
+                "drawing" => {
+                    let blip = descendant(&node, "blip").and_then(|b| b.attribute((R_NS, "embed")));
+                    let alt = descendant(&node, "docPr").and_then(|d| d.attribute("descr").or_else(|| d.attribute("title")));
+                    if let Some(id) = blip {
+                        text.push_str(&self.image(id, alt.unwrap_or_default()));
+                    }
+                }
+                "pict" | "object" => {
+                    if let Some(data) = descendant(&node, "imagedata") {
+                        let alt = data.attributes().find(|a| a.name() == "title").map(|a| a.value()).unwrap_or_default();
+                        if let Some(id) = data.attribute((R_NS, "id")) {
+                            text.push_str(&self.image(id, alt));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if text.is_empty() {
+            return text;
+        }
+
+        let rpr = match child(&run, "rPr") {
+            Some(rpr) => rpr,
+            None => return text,
+        };
+        let mut html = text;
+        let wrap = |html: String, open: &str, close: &str| format!("{}{}{}", open, html, close);
+        match child(&rpr, "vertAlign").and_then(|v| val(&v)) {
+            Some("superscript") => html = wrap(html, "<sup>", "</sup>"),
+            Some("subscript") => html = wrap(html, "<sub>", "</sub>"),
+            _ => {}
+        }
+        // 縦中横
+        if child(&rpr, "eastAsianLayout").is_some_and(|e| matches!(attr(&e, "vert"), Some("1" | "true" | "on"))) {
+            html = wrap(html, "<span class=\"tcy\" style=\"-epub-text-combine: horizontal; text-combine-upright: all;\">", "</span>");
+        }
+        // 傍点
+        let emphasis = match child(&rpr, "em").and_then(|e| val(&e)) {
+            Some("comma") => Some("sesame"),
+            Some("circle") => Some("open circle"),
+            Some("dot" | "underDot") => Some("filled dot"),
+            _ => None,
+        };
+        if let Some(emphasis) = emphasis {
+            html = format!(
+                "<span class=\"emphasis-mark\" style=\"-epub-text-emphasis-style: {0}; text-emphasis-style: {0};\">{1}</span>",
+                emphasis, html
+            );
+        }
+        if on(child(&rpr, "strike")) || on(child(&rpr, "dstrike")) {
+            html = wrap(html, "<s>", "</s>");
+        }
+        if on(child(&rpr, "u")) {
+            html = wrap(html, "<u>", "</u>");
+        }
+        if on(child(&rpr, "i")) {
+            html = wrap(html, "<em>", "</em>");
+        }
+        if on(child(&rpr, "b")) {
+            html = wrap(html, "<strong>", "</strong>");
+        }
+        html
+    }
+
+    /// 画像を`<stem>_files`に書き出す. 外部の画像は収録できないので出力しない
+    fn image(&mut self, id: &str, alt: &str) -> String {
+        let path = match self.image_paths.get(id) {
+            Some(path) => path.clone(),
+            None => {
+                let target = match self.relationships.get(id) {
+                    Some((target, false)) => target.clone(),
+                    _ => return String::new(),
+                };
+                // 参照先は`word/`からの相対パス, または`/`から始まるパッケージ内のパス
+                let name = match target.strip_prefix('/') {
+                    Some(absolute) => absolute.to_string(),
+                    None => format!("word/{}", target),
+                };
+                let data = match read(&mut self.archive, &name) {
+                    Some(data) => data,
+                    None => return String::new(),
+                };
+                let file_name = Path::new(&target).file_name().map(|f| f.to_owned()).unwrap_or_default();
+                let path = self.images_dir.join(file_name);
+                self.images.push(EmbeddedImage { path: path.clone(), data });
+                self.image_paths.insert(id.to_string(), path.clone());
+                path
+            }
+        };
+
+        format!(
+            "<img src=\"{}\" alt=\"{}\" />",
+            xhtml::escape_attr(&path.to_string_lossy()), xhtml::escape_attr(alt)
+        )
+    }
+
+    /// 参照された脚注を, 参照された順に出力する
+    fn notes(&mut self, out: &mut String) {
+        let mut sources = HashMap::new();
+        for kind in &[NoteKind::Footnote, NoteKind::Endnote] {
+            if let Some(xml) = read_string(&mut self.archive, kind.xml()) {
+                sources.insert(*kind, xml);
+            }
+        }
+        let documents = sources.iter()
+            .filter_map(|(kind, xml)| Some((*kind, Document::parse(xml).ok()?)))
+            .collect::<HashMap<NoteKind, Document>>();
+
+        // 脚注の中で脚注が参照されることもあるので, 添字でたどる
+        let mut i = 0;
+        while i < self.notes.len() {
+            let (kind, id) = self.notes[i].clone();
+            let n = self.notes[..=i].iter().filter(|(k, _)| *k == kind).count();
+            i += 1;
+
+            let note = documents.get(&kind).and_then(|d| {
+                d.root_element().children().find(|c| is(c, kind.element()) && attr(c, "id") == Some(id.as_str()))
+            });
+            if let Some(note) = note {
+                self.current_note = Some((kind, n));
+                let mut content = String::new();
+                self.blocks(note, &mut content);
+                self.current_note = None;
+                out.push_str(&format!(
+                    "<aside id=\"{0}-{1}\" class=\"{2}\" epub:type=\"{2}\">\n{3}</aside>\n",
+                    kind.prefix(), n, kind.element(), content
+                ));
+            }
+        }
+    }
+}
+
+/// `.docx` を XHTML に変換する. stem は変換後のファイル名から拡張子を除いたもので, 画像は`<stem>_files`ディレクトリに置く
+pub fn convert(bytes: &[u8], stem: &str) -> RepubResult<DocxDocument> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))?;
+    let document = read_string(&mut archive, DOCUMENT_XML)
+        .ok_or_else(|| format_err!("{} が見つかりません", DOCUMENT_XML))?;
+    let relationships = read_string(&mut archive, DOCUMENT_RELS).map(|x| relationships(&x)).unwrap_or_default();
+    let styles = read_string(&mut archive, STYLES_XML).map(|x| styles(&x)).unwrap_or_default();
+    let numbering = read_string(&mut archive, NUMBERING_XML).map(|x| numbering(&x)).unwrap_or_default();
+    let title = read_string(&mut archive, CORE_XML).and_then(|xml| {
+        let core = Document::parse(&xml).ok()?;
+        let title = core.descendants().find(|n| n.tag_name().name() == "title" && n.tag_name().namespace() == Some(DC_NS))?;
+        title.text().map(|t| t.trim().to_string()).filter(|t| !t.is_empty())
+    });
+
+    let document = Document::parse(&document)?;
+    let body = document.root_element().children().find(|n| is(n, "body"))
+        .ok_or_else(|| format_err!("{} に本文がありません", DOCUMENT_XML))?;
+    let anchors = document.descendants()
+        .filter(|n| is(n, "hyperlink"))
+        .filter_map(|n| attr(&n, "anchor").map(|a| a.to_string()))
+        .collect::<HashSet<String>>();
+
+    let mut converter = Converter {
+        archive,
+        relationships,
+        styles,
+        numbering,
+        images_dir: PathBuf::from(format!("{}_files", stem)),
+        images: Vec::new(),
+        image_paths: HashMap::new(),
+        notes: Vec::new(),
+        current_note: None,
+        anchors,
+        page_break: false,
+    };
+    let mut html = String::new();
+    converter.blocks(body, &mut html);
+    converter.notes(&mut html);
+
+    // 縦書きのセクション
+    let vertical = child(&body, "sectPr")
+        .and_then(|s| child(&s, "textDirection"))
+        .is_some_and(|t| matches!(val(&t), Some("tbRl" | "tbRlV")));
+
+    Ok(DocxDocument {
+        title,
+        body: html,
+        head: if vertical { VERTICAL_STYLE.to_string() } else { String::new() },
+        images: converter.images,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn docx(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    const NS: &str = r#"xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main" xmlns:r="http://schemas.openxmlformats.org/officeDocument/2006/relationships""#;
+
+    #[test]
+    fn document() {
+        let document = format!(r#"<w:document {0}><w:body>
+<w:p><w:pPr><w:pStyle w:val="1"/></w:pPr><w:r><w:t>第一章</w:t></w:r></w:p>
+<w:p><w:r><w:rPr><w:b/></w:rPr><w:t>太字</w:t></w:r><w:r><w:rPr><w:i w:val="0"/></w:rPr><w:t xml:space="preserve"> と </w:t></w:r><w:r><w:ruby><w:rt><w:r><w:t>かんじ</w:t></w:r></w:rt><w:rubyBase><w:r><w:t>漢字</w:t></w:r></w:rubyBase></w:ruby></w:r><w:r><w:footnoteReference w:id="2"/></w:r></w:p>
+<w:p><w:pPr><w:numPr><w:ilvl w:val="0"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>一</w:t></w:r></w:p>
+<w:p><w:pPr><w:numPr><w:ilvl w:val="1"/><w:numId w:val="1"/></w:numPr></w:pPr><w:r><w:t>二</w:t></w:r></w:p>
+<w:p></w:p>
+<w:tbl><w:tr><w:trPr><w:tblHeader/></w:trPr><w:tc><w:tcPr><w:gridSpan w:val="2"/></w:tcPr><w:p><w:r><w:t>見出し</w:t></w:r></w:p></w:tc></w:tr>
+<w:tr><w:tc><w:tcPr><w:vMerge w:val="restart"/></w:tcPr><w:p><w:r><w:t>a</w:t></w:r></w:p></w:tc><w:tc><w:p><w:r><w:t>b</w:t></w:r></w:p></w:tc></w:tr>
+<w:tr><w:tc><w:tcPr><w:vMerge/></w:tcPr><w:p/></w:tc><w:tc><w:p><w:r><w:rPr><w:eastAsianLayout w:vert="1"/></w:rPr><w:t>12</w:t></w:r></w:p></w:tc></w:tr></w:tbl>
+<w:p><w:r><w:drawing><wp:docPr xmlns:wp="http://schemas.openxmlformats.org/drawingml/2006/wordprocessingDrawing" id="1" name="図" descr="写真"/><a:blip xmlns:a="http://schemas.openxmlformats.org/drawingml/2006/main" r:embed="rId5"/></w:drawing></w:r></w:p>
+<w:sectPr><w:textDirection w:val="tbRl"/></w:sectPr>
+</w:body></w:document>"#, NS);
+        let styles = format!(r#"<w:styles {0}><w:style w:type="paragraph" w:styleId="1"><w:name w:val="heading 1"/></w:style></w:styles>"#, NS);
+        let numbering = format!(r#"<w:numbering {0}><w:abstractNum w:abstractNumId="0"><w:lvl w:ilvl="0"><w:numFmt w:val="decimal"/></w:lvl><w:lvl w:ilvl="1"><w:numFmt w:val="bullet"/></w:lvl></w:abstractNum><w:num w:numId="1"><w:abstractNumId w:val="0"/></w:num></w:numbering>"#, NS);
+        let footnotes = format!(r#"<w:footnotes {0}><w:footnote w:id="2"><w:p><w:r><w:footnoteRef/></w:r><w:r><w:t>注</w:t></w:r></w:p></w:footnote></w:footnotes>"#, NS);
+        let rels = r#"<Relationships xmlns="http://schemas.openxmlformats.org/package/2006/relationships"><Relationship Id="rId5" Type="image" Target="media/image1.png"/></Relationships>"#;
+        let core = r#"<cp:coreProperties xmlns:cp="http://schemas.openxmlformats.org/package/2006/metadata/core-properties" xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>原稿</dc:title></cp:coreProperties>"#;
+
+        let bytes = docx(&[
+            (DOCUMENT_XML, &document), (STYLES_XML, &styles), (NUMBERING_XML, &numbering),
+            ("word/footnotes.xml", &footnotes), (DOCUMENT_RELS, rels), (CORE_XML, core), ("word/media/image1.png", "PNG"),
+        ]);
+        let converted = convert(&bytes, "ms").unwrap();
+
+        assert_eq!(converted.title.as_deref(), Some("原稿"));
+        assert_eq!(converted.head, VERTICAL_STYLE);
+        assert_eq!(converted.images, vec![EmbeddedImage { path: PathBuf::from("ms_files/image1.png"), data: b"PNG".to_vec() }]);
+        assert_eq!(
+            converted.body,
+            "<h1>第一章</h1>\n\
+             <p><strong>太字</strong> と <ruby>漢字<rt>かんじ</rt></ruby><a id=\"fnref-1\" href=\"#fn-1\" class=\"noteref\" epub:type=\"noteref\"><sup>1</sup></a></p>\n\
+             <ol>\n<li>一<ul>\n<li>二</li>\n</ul>\n</li>\n</ol>\n\
+             <table>\n<tr><th colspan=\"2\">見出し</th></tr>\n\
+             <tr><td rowspan=\"2\">a</td><td>b</td></tr>\n\
+             <tr><td><span class=\"tcy\" style=\"-epub-text-combine: horizontal; text-combine-upright: all;\">12</span></td></tr>\n</table>\n\
+             <p><img src=\"ms_files/image1.png\" alt=\"写真\" /></p>\n\
+             <aside id=\"fn-1\" class=\"footnote\" epub:type=\"footnote\">\n<p><a href=\"#fnref-1\">1</a> 注</p>\n</aside>\n"
+        );
+    }
+}
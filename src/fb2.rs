@@ -0,0 +1,675 @@
+//! compose したコンテンツを FictionBook 2 (`.fb2`) として書き出す
+//!
+//! `<section>`の入れ子は目次の構成に従う. 目次の各項目は, 指している見出し(またはファイルの先頭)から
+//! 次の項目の位置までの内容を持つ. 目次にない見出しは`<subtitle>`になる.
+//! 画像は base64 の`<binary>`として埋め込み, 表紙は`<coverpage>`から参照する
+
+use crate::{prelude::*, compose::{Publication, media_type::MediaType}, load::Format, toc::ToCItemTrait, site, xhtml};
+use html5ever::{
+    parse_document,
+    ParseOpts,
+    rcdom::{RcDom, Handle, NodeData},
+    tendril::TendrilSink,
+};
+use std::{collections::{HashMap, HashSet}, path::Path};
+use base64::{Engine, engine::general_purpose::STANDARD};
+
+/// 子要素を段落などとして扱う要素
+const BLOCKS: [&str; 31] = [
+    "p", "h1", "h2", "h3", "h4", "h5", "h6", "ul", "ol", "li", "dl", "dt", "dd", "blockquote", "pre", "hr",
+    "table", "div", "section", "aside", "article", "header", "footer", "nav", "main", "figure", "figcaption",
+    "details", "summary", "address", "hgroup",
+];
+
+/// `<title>.fb2`を書き出す
+pub fn publish(publication: &Publication) -> RepubResult<()> {
+    use chrono::prelude::*;
+
+    let cfg = publication.cfg;
+    let path = Format::Fb2.output_path(&cfg.title);
+
+    let mut converter = Converter {
+        chapters: publication.contents.iter()
+            .enumerate()
+            .map(|(i, path)| (path.to_path_buf(), i))
+            .collect(),
+        images: HashMap::new(),
+        binaries: Vec::new(),
+        content: PathBuf::new(),
+        chapter: 0,
+    };
+
+    let cover = publication.cover_image.and_then(|cover| converter.image(cover));
+
+    // すべてのコンテンツを, spine の順に段落などの並びにする
+    let mut blocks = Vec::new();
+    let mut starts = Vec::new();
+    let mut headings = HashMap::new();
+    for (i, content) in publication.contents.iter().enumerate() {
+        let dom = parse_document(RcDom::default(), ParseOpts::default()).one(std::fs::read_to_string(content)?);
+        converter.content = content.to_path_buf();
+        converter.chapter = i;
+
+        starts.push(blocks.len());
+        if let Some(body) = elements(&dom.document).into_iter().find(|node| xhtml::is_element(node, "body")) {
+            converter.blocks(&body, &mut blocks);
+        }
+        for (position, block) in blocks.iter().enumerate().skip(starts[i]) {
+            if let (true, Some(id)) = (block.heading, &block.id) {
+                headings.insert(id.clone(), position);
+            }
+        }
+
+        RepubLog::converted(&format!("{:?}", content.strip_prefix(publication.root).unwrap_or(content))).print();
+    }
+
+    // 目次の項目を行きがけ順に並べる
+    let mut sections = Vec::new();
+    let top = publication.toc.items().iter()
+        .flat_map(|item| flatten(item.as_ref(), &converter, &starts, &headings, &mut sections))
+        .collect::<Vec<usize>>();
+
+    // 各項目の内容は, 次の項目の位置まで. 位置の前後する項目は, 内容を持たない
+    let mut last = 0;
+    for i in 0..sections.len() {
+        let position = sections[i].position
+            .or_else(|| sections[i + 1..].iter().find_map(|s| s.position))
+            .unwrap_or(blocks.len())
+            .max(last);
+        sections[i].start = position;
+        last = position;
+    }
+    let first = sections.first().map(|s| s.start).unwrap_or(blocks.len());
+    for i in 0..sections.len() {
+        let end = sections.get(i + 1).map(|s| s.start).unwrap_or(blocks.len());
+        let start = sections[i].start;
+        // 項目の指す見出しは, <section>の<title>になる
+        let skip = sections[i].position.is_some() && blocks.get(start).is_some_and(|b| b.heading) && start < end;
+        sections[i].start = if skip { start + 1 } else { start };
+        sections[i].end = end;
+    }
+
+    let mut ids = HashSet::new();
+    let mut body = String::new();
+    // 最初の項目より前の内容は, ファイルごとに題のない<section>にする
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(blocks.len()).min(first);
+        if start < end {
+            body.push_str("<section>\n");
+            body.push_str(&render(&blocks[start..end], &mut ids));
+            body.push_str("</section>\n");
+        }
+    }
+    for &i in &top {
+        section(&sections, i, &blocks, &mut ids, &mut body);
+    }
+    if body.is_empty() {
+        body.push_str("<section><empty-line /></section>\n");
+    }
+
+    // ファイルへのリンクは, そのファイルの最初の項目へのリンクにする
+    let aliases = (0..starts.len())
+        .filter(|&chapter| !ids.contains(&chapter_id(chapter)))
+        .filter_map(|chapter| {
+            let end = starts.get(chapter + 1).copied().unwrap_or(blocks.len());
+            let section = sections.iter()
+                .find(|s| s.position.is_some_and(|p| starts[chapter] <= p && p < end))?;
+            Some((chapter_id(chapter), section.id.clone()?))
+        })
+        .collect::<HashMap<String, String>>();
+
+    let author = if cfg.creator.is_empty() {
+        String::from("<author><nickname></nickname></author>")
+    } else {
+        format!("<author><nickname>{}</nickname></author>", xhtml::escape_text(&cfg.creator))
+    };
+
+    let fb2 = format!(
+        include_str!("literals/fictionbook.fb2"),
+        author = author,
+        title = xhtml::escape_text(&cfg.title),
+        coverpage = cover
+            .map(|id| format!("<coverpage><image l:href=\"#{}\" /></coverpage>", xhtml::escape_attr(&id)))
+            .unwrap_or_default(),
+        lang = xhtml::escape_text(&cfg.language),
+        date = Utc::now().format("%Y-%m-%d"),
+        book_id = xhtml::escape_text(&cfg.book_id),
+        sections = unlink(&body, &ids, &aliases),
+        binaries = converter.binaries.iter()
+            .map(|b| format!("<binary id=\"{}\" content-type=\"{}\">{}</binary>", xhtml::escape_attr(&b.id), &b.content_type, &b.data))
+            .collect::<Vec<String>>()
+            .join("\n"),
+    );
+    std::fs::write(&path, fb2)?;
+
+    RepubLog::published(&format!("{:?}", &path)).print();
+
+    Ok(())
+}
+
+/// 段落, 見出しなど, `<section>`の直下に置く要素
+struct Block {
+    xml: String,
+    /// 要素の id
+    id: Option<String>,
+    heading: bool,
+}
+
+/// 目次の1項目に対応する`<section>`
+struct Section {
+    /// XML として escape 済み
+    title: String,
+    id: Option<String>,
+    /// 項目の指す段落の位置. 部の見出しのように, リンクを持たなければ None
+    position: Option<usize>,
+    start: usize,
+    end: usize,
+    children: Vec<usize>,
+}
+
+/// 目次の項目を sections に加え, その位置を返す. 入れ子を補うための項目は, 子の位置を返す
+fn flatten(item: &dyn ToCItemTrait, converter: &Converter, starts: &[usize], headings: &HashMap<String, usize>, sections: &mut Vec<Section>) -> Vec<usize> {
+    if item.is_dummy() {
+        return item.items().iter()
+            .flat_map(|item| flatten(item.as_ref(), converter, starts, headings, sections))
+            .collect();
+    }
+
+    let (id, position) = match item.target().and_then(|(path, id)| Some((converter.chapters.get(path)?, id))) {
+        Some((&chapter, Some(id))) => {
+            let id = anchor_id(chapter, id);
+            let position = headings.get(&id).copied().unwrap_or(starts[chapter]);
+            (Some(id), Some(position))
+        }
+        Some((&chapter, None)) => (Some(chapter_id(chapter)), Some(starts[chapter])),
+        None => (None, None),
+    };
+
+    let index = sections.len();
+    sections.push(Section {
+        title: item.title().unwrap_or_default().to_string(),
+        id,
+        position,
+        start: 0,
+        end: 0,
+        children: Vec::new(),
+    });
+    let children = item.items().iter()
+        .flat_map(|item| flatten(item.as_ref(), converter, starts, headings, sections))
+        .collect();
+    sections[index].children = children;
+
+    vec![index]
+}
+
+fn section(sections: &[Section], index: usize, blocks: &[Block], ids: &mut HashSet<String>, out: &mut String) {
+    let section = &sections[index];
+    match &section.id {
+        Some(id) => {
+            ids.insert(id.clone());
+            out.push_str(&format!("<section id=\"{}\">\n", xhtml::escape_attr(id)));
+        }
+        None => out.push_str("<section>\n"),
+    }
+    out.push_str(&format!("<title><p>{}</p></title>\n", &section.title));
+
+    // <section>は, 段落などと<section>を同時に持つことができない
+    let own = render(&blocks[section.start..section.end], ids);
+    if section.children.is_empty() {
+        out.push_str(if own.is_empty() { "<empty-line />\n" } else { &own });
+    } else {
+        if !own.is_empty() {
+            out.push_str("<section>\n");
+            out.push_str(&own);
+            out.push_str("</section>\n");
+        }
+        for &child in &section.children {
+            self::section(sections, child, blocks, ids, out);
+        }
+    }
+
+    out.push_str("</section>\n");
+}
+
+fn render(blocks: &[Block], ids: &mut HashSet<String>) -> String {
+    blocks.iter()
+        .map(|block| {
+            if let Some(id) = &block.id {
+                ids.insert(id.clone());
+            }
+            format!("{}\n", &block.xml)
+        })
+        .collect()
+}
+
+/// 書き出されなかった id へのリンクを取り除く. aliases にあれば, その id へのリンクにする
+fn unlink(xml: &str, ids: &HashSet<String>, aliases: &HashMap<String, String>) -> String {
+    const OPEN: &str = "<a l:href=\"#";
+
+    let mut unlinked = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find(OPEN) {
+        unlinked.push_str(&rest[..start]);
+        let after = &rest[start + OPEN.len()..];
+        let id = after.split('"').next().unwrap_or_default();
+        let tag_end = after.find('>').map(|i| i + 1).unwrap_or(after.len());
+        if ids.contains(&unescape(id)) {
+            unlinked.push_str(&rest[start..start + OPEN.len() + tag_end]);
+            rest = &after[tag_end..];
+        } else if let Some(alias) = aliases.get(&unescape(id)) {
+            unlinked.push_str(&format!("{}{}\">", OPEN, xhtml::escape_attr(alias)));
+            rest = &after[tag_end..];
+        } else {
+            // <a>は入れ子にならないので, 次の</a>が対応する
+            let after = &after[tag_end..];
+            match after.find("</a>") {
+                Some(end) => {
+                    unlinked.push_str(&after[..end]);
+                    rest = &after[end + "</a>".len()..];
+                }
+                None => rest = after,
+            }
+        }
+    }
+    unlinked.push_str(rest);
+    unlinked
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&#10;", "\n")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// 章の先頭の id
+fn chapter_id(chapter: usize) -> String {
+    format!("chapter{}", chapter)
+}
+
+/// 1つのファイルにまとめても衝突しないように, 章ごとに id に接頭辞を付ける
+fn anchor_id(chapter: usize, id: &str) -> String {
+    format!("{}-{}", chapter_id(chapter), id)
+}
+
+/// node の子孫の要素を文書順に並べる
+fn elements(node: &Handle) -> Vec<Handle> {
+    fn walk(node: &Handle, found: &mut Vec<Handle>) {
+        for child in node.children.borrow().iter() {
+            if let NodeData::Element { .. } = child.data {
+                found.push(child.clone());
+            }
+            walk(child, found);
+        }
+    }
+
+    let mut found = Vec::new();
+    walk(node, &mut found);
+    found
+}
+
+fn local_name(node: &Handle) -> Option<&str> {
+    match node.data {
+        NodeData::Element { ref name, .. } if name.ns == ns!(html) => Some(name.local.as_ref()),
+        _ => None,
+    }
+}
+
+fn is_block(node: &Handle) -> bool {
+    local_name(node).is_some_and(|local| BLOCKS.contains(&local))
+}
+
+/// `<binary>`として埋め込むファイル
+struct Binary {
+    id: String,
+    content_type: String,
+    /// base64
+    data: String,
+}
+
+struct Converter {
+    /// コンテンツのパス -> 何番目の章か
+    chapters: HashMap<PathBuf, usize>,
+    /// 画像のパス -> <binary>の id
+    images: HashMap<PathBuf, String>,
+    binaries: Vec<Binary>,
+    /// 変換中のコンテンツ
+    content: PathBuf,
+    chapter: usize,
+}
+
+impl Converter {
+    /// 画像を<binary>として登録し, その id を返す. FB2 で表示できない画像(SVG など)は None
+    fn image(&mut self, path: &Path) -> Option<String> {
+        if let Some(id) = self.images.get(path) {
+            return Some(id.clone());
+        }
+
+        let content_type = MediaType::try_from(&path.to_path_buf()).ok()?.to_string();
+        if !["image/png", "image/jpeg", "image/gif"].contains(&content_type.as_str()) {
+            return None;
+        }
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) => {
+                RepubWarning(format!("{:?} : {}", path, &e)).print();
+                return None;
+            }
+        };
+
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default();
+        let id = format!("image{}.{}", self.binaries.len(), extension);
+        self.binaries.push(Binary {
+            id: id.clone(),
+            content_type,
+            data: STANDARD.encode(data),
+        });
+        self.images.insert(path.to_path_buf(), id.clone());
+        Some(id)
+    }
+
+    fn image_of(&mut self, node: &Handle) -> Option<String> {
+        let path = site::resolve(&self.content, &xhtml::get_attr(node, "src")?)?;
+        self.image(&path)
+            .map(|id| format!("<image l:href=\"#{}\" />", xhtml::escape_attr(&id)))
+    }
+
+    fn id_of(&self, node: &Handle) -> Option<String> {
+        xhtml::get_attr(node, "id").map(|id| anchor_id(self.chapter, &id))
+    }
+
+    /// node の子を段落などの並びにする
+    fn blocks(&mut self, node: &Handle, out: &mut Vec<Block>) {
+        let mut run = Vec::new();
+        for child in node.children.borrow().iter() {
+            if is_block(child) {
+                self.paragraph(&run, None, out);
+                run.clear();
+                self.block(child, out);
+            } else if xhtml::is_element(child, "img") {
+                // 段落の外の画像
+                self.paragraph(&run, None, out);
+                run.clear();
+                match self.image_of(child) {
+                    Some(xml) => out.push(Block { xml, id: None, heading: false }),
+                    None => run.push(child.clone()),
+                }
+            } else {
+                run.push(child.clone());
+            }
+        }
+        self.paragraph(&run, None, out);
+    }
+
+    fn block(&mut self, node: &Handle, out: &mut Vec<Block>) {
+        let id = self.id_of(node);
+        match local_name(node).unwrap_or_default() {
+            "p" => {
+                let children = node.children.borrow().clone();
+                self.paragraph(&children, id, out);
+            }
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                let children = node.children.borrow().clone();
+                let text = self.inline(&children);
+                let xml = match &id {
+                    Some(id) => format!("<subtitle id=\"{}\">{}</subtitle>", xhtml::escape_attr(id), text),
+                    None => format!("<subtitle>{}</subtitle>", text),
+                };
+                out.push(Block { xml, id, heading: true });
+            }
+            "ul" | "ol" => self.list(node, 0, out),
+            "blockquote" => {
+                let mut inner = Vec::new();
+                self.blocks(node, &mut inner);
+                // <cite>の中には画像を置けない
+                let xml = inner.iter()
+                    .filter(|b| !b.xml.starts_with("<image"))
+                    .map(|b| b.xml.as_str())
+                    .collect::<Vec<&str>>()
+                    .join("\n");
+                if !xml.is_empty() {
+                    let xml = match &id {
+                        Some(id) => format!("<cite id=\"{}\">\n{}\n</cite>", xhtml::escape_attr(id), xml),
+                        None => format!("<cite>\n{}\n</cite>", xml),
+                    };
+                    out.push(Block { xml, id, heading: false });
+                }
+            }
+            "pre" => {
+                // pre の id は最初の行に付ける
+                let mut id = id;
+                let text = xhtml::text_content(node);
+                for line in text.trim_end_matches('\n').lines() {
+                    if line.trim().is_empty() {
+                        out.push(Block { xml: String::from("<empty-line />"), id: None, heading: false });
+                    } else {
+                        out.push(paragraph(&format!("<code>{}</code>", xhtml::escape_text(line)), id.take()));
+                    }
+                }
+            }
+            "hr" => out.push(Block { xml: String::from("<empty-line />"), id: None, heading: false }),
+            "table" => self.table(node, id, out),
+            "dt" => {
+                let children = node.children.borrow().clone();
+                let text = self.inline(&children);
+                if !text.trim().is_empty() {
+                    out.push(paragraph(&format!("<strong>{}</strong>", text.trim()), id));
+                }
+            }
+            _ => self.blocks(node, out),
+        }
+    }
+
+    /// 行内の要素の並びを段落にする. `<br />`では段落を分ける
+    fn paragraph(&mut self, nodes: &[Handle], id: Option<String>, out: &mut Vec<Block>) {
+        let mut id = id;
+        for line in nodes.split(|node| xhtml::is_element(node, "br")) {
+            // 段落の中の画像だけは, 段落の外に出す
+            if line.iter().filter(|n| !is_blank(n)).count() == 1 {
+                if let Some(img) = line.iter().find(|n| xhtml::is_element(n, "img")) {
+                    if let Some(xml) = self.image_of(img) {
+                        out.push(Block { xml, id: None, heading: false });
+                        continue;
+                    }
+                }
+            }
+            let text = self.inline(line);
+            if !text.trim().is_empty() {
+                out.push(paragraph(text.trim(), id.take()));
+            }
+        }
+    }
+
+    /// li を, 先頭に記号か番号を付けた段落にする. 入れ子のリストは全角空白で字下げする
+    fn list(&mut self, node: &Handle, depth: usize, out: &mut Vec<Block>) {
+        let ordered = xhtml::is_element(node, "ol");
+        let start = xhtml::get_attr(node, "start").and_then(|s| s.parse::<i64>().ok()).unwrap_or(1);
+
+        for (number, li) in (start..).zip(node.children.borrow().iter().filter(|c| xhtml::is_element(c, "li"))) {
+            let marker = if ordered { format!("{}. ", number) } else { String::from("• ") };
+
+            let mut run = Vec::new();
+            let mut nested = Vec::new();
+            for child in li.children.borrow().iter() {
+                if xhtml::is_element(child, "ul") || xhtml::is_element(child, "ol") {
+                    nested.push(child.clone());
+                } else if xhtml::is_element(child, "p") {
+                    run.extend(child.children.borrow().iter().cloned());
+                } else {
+                    run.push(child.clone());
+                }
+            }
+
+            let text = self.inline(&run);
+            out.push(paragraph(&format!("{}{}{}", "\u{3000}".repeat(depth), marker, text.trim()), self.id_of(li)));
+            for list in nested {
+                self.list(&list, depth + 1, out);
+            }
+        }
+    }
+
+    fn table(&mut self, node: &Handle, id: Option<String>, out: &mut Vec<Block>) {
+        let rows = elements(node).into_iter()
+            .filter(|n| xhtml::is_element(n, "tr"))
+            .map(|tr| {
+                let cells = tr.children.borrow().iter()
+                    .filter(|c| xhtml::is_element(c, "th") || xhtml::is_element(c, "td"))
+                    .map(|cell| {
+                        let local = local_name(cell).unwrap_or_default().to_string();
+                        let children = cell.children.borrow().clone();
+                        format!("<{}>{}</{}>", &local, self.inline(&children), &local)
+                    })
+                    .collect::<String>();
+                format!("<tr>{}</tr>", cells)
+            })
+            .collect::<Vec<String>>();
+        if rows.is_empty() {
+            return;
+        }
+        let xml = match &id {
+            Some(id) => format!("<table id=\"{}\">\n{}\n</table>", xhtml::escape_attr(id), rows.join("\n")),
+            None => format!("<table>\n{}\n</table>", rows.join("\n")),
+        };
+        out.push(Block { xml, id, heading: false });
+    }
+
+    /// 行内の要素を FB2 の行内の要素にする
+    fn inline(&mut self, nodes: &[Handle]) -> String {
+        let mut xml = String::new();
+        for node in nodes {
+            match node.data {
+                NodeData::Text { ref contents } => xml.push_str(&xhtml::escape_text(&contents.borrow())),
+                NodeData::Element { .. } => {
+                    let children = node.children.borrow().clone();
+                    match local_name(node).unwrap_or_default() {
+                        "em" | "i" | "cite" | "dfn" | "var" => xml.push_str(&format!("<emphasis>{}</emphasis>", self.inline(&children))),
+                        "strong" | "b" => xml.push_str(&format!("<strong>{}</strong>", self.inline(&children))),
+                        "s" | "del" | "strike" => xml.push_str(&format!("<strikethrough>{}</strikethrough>", self.inline(&children))),
+                        "sub" => xml.push_str(&format!("<sub>{}</sub>", self.inline(&children))),
+                        "sup" => xml.push_str(&format!("<sup>{}</sup>", self.inline(&children))),
+                        "code" | "kbd" | "samp" | "tt" => xml.push_str(&format!("<code>{}</code>", xhtml::escape_text(&xhtml::text_content(node)))),
+                        "a" => {
+                            let text = self.inline(&children);
+                            match xhtml::get_attr(node, "href").and_then(|href| self.href(&href)) {
+                                Some(href) => xml.push_str(&format!("<a l:href=\"{}\">{}</a>", xhtml::escape_attr(&href), text)),
+                                None => xml.push_str(&text),
+                            }
+                        }
+                        "br" => xml.push(' '),
+                        "img" => match self.image_of(node) {
+                            Some(image) => xml.push_str(&image),
+                            None => xml.push_str(&xhtml::escape_text(&xhtml::get_attr(node, "alt").unwrap_or_default())),
+                        },
+                        // ルビは親文字の後に括弧で書く
+                        "ruby" => for child in children.iter() {
+                            if xhtml::is_element(child, "rt") {
+                                xml.push_str(&format!("（{}）", xhtml::escape_text(&xhtml::text_content(child))));
+                            } else if !xhtml::is_element(child, "rp") {
+                                xml.push_str(&self.inline(std::slice::from_ref(child)));
+                            }
+                        },
+                        "script" | "style" | "svg" => {}
+                        "math" => xml.push_str(&xhtml::escape_text(&xhtml::text_content(node))),
+                        _ => xml.push_str(&self.inline(&children)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        xml
+    }
+
+    /// リンク先を, `.fb2`の中の id へのリンクにする. `http:`などの URL はそのまま
+    fn href(&self, href: &str) -> Option<String> {
+        if href.split(['/', '#', '?']).next().is_some_and(|s| s.contains(':')) {
+            return Some(href.to_string());
+        }
+        let (path, fragment) = match href.find('#') {
+            Some(hash) => (&href[..hash], Some(&href[hash + 1..])),
+            None => (href, None),
+        };
+        let chapter = if path.is_empty() {
+            self.chapter
+        } else {
+            *self.chapters.get(&site::resolve(&self.content, path)?)?
+        };
+        match fragment {
+            Some(fragment) if !fragment.is_empty() => Some(format!("#{}", anchor_id(chapter, fragment))),
+            _ => Some(format!("#{}", chapter_id(chapter))),
+        }
+    }
+}
+
+fn paragraph(text: &str, id: Option<String>) -> Block {
+    let xml = match &id {
+        Some(id) => format!("<p id=\"{}\">{}</p>", xhtml::escape_attr(id), text),
+        None => format!("<p>{}</p>", text),
+    };
+    Block { xml, id, heading: false }
+}
+
+fn is_blank(node: &Handle) -> bool {
+    match node.data {
+        NodeData::Text { ref contents } => contents.borrow().trim().is_empty(),
+        NodeData::Comment { .. } => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(html: &str) -> Vec<String> {
+        let mut converter = Converter {
+            chapters: vec![(PathBuf::from("/o/ch1.xhtml"), 0), (PathBuf::from("/o/ch2.xhtml"), 1)].into_iter().collect(),
+            images: HashMap::new(),
+            binaries: Vec::new(),
+            content: PathBuf::from("/o/ch2.xhtml"),
+            chapter: 1,
+        };
+        let dom = parse_document(RcDom::default(), ParseOpts::default()).one(html);
+        let body = elements(&dom.document).into_iter().find(|node| xhtml::is_element(node, "body")).unwrap();
+        let mut blocks = Vec::new();
+        converter.blocks(&body, &mut blocks);
+        blocks.into_iter().map(|b| b.xml).collect()
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(
+            convert("<h2 id=\"s\">見<em>出</em>し</h2><p>a<strong>b</strong><br />c<a href=\"ch1.xhtml#x\">d</a><ruby>漢<rp>(</rp><rt>かん</rt><rp>)</rp></ruby></p>\
+                     <ul><li>one<ul><li id=\"n\">two</li></ul></li></ul><pre><code>x &lt; 1\n\ny</code></pre><hr />"),
+            vec![
+                "<subtitle id=\"chapter1-s\">見<emphasis>出</emphasis>し</subtitle>",
+                "<p>a<strong>b</strong></p>",
+                "<p>c<a l:href=\"#chapter0-x\">d</a>漢（かん）</p>",
+                "<p>• one</p>",
+                "<p id=\"chapter1-n\">\u{3000}• two</p>",
+                "<p><code>x &lt; 1</code></p>",
+                "<empty-line />",
+                "<p><code>y</code></p>",
+                "<empty-line />",
+            ]
+        );
+        assert_eq!(
+            convert("<div>text <em>run</em><blockquote><p>q</p></blockquote></div><table><tr><th>h</th></tr><tr><td>1</td></tr></table>"),
+            vec![
+                "<p>text <emphasis>run</emphasis></p>",
+                "<cite>\n<p>q</p>\n</cite>",
+                "<table>\n<tr><th>h</th></tr>\n<tr><td>1</td></tr>\n</table>",
+            ]
+        );
+    }
+
+    #[test]
+    fn dangling_links() {
+        let ids = vec!["a".to_string()].into_iter().collect::<HashSet<String>>();
+        let aliases = vec![("chapter0".to_string(), "a".to_string())].into_iter().collect::<HashMap<String, String>>();
+        assert_eq!(
+            unlink("<p><a l:href=\"#a\">x</a><a l:href=\"#b\">y</a><a l:href=\"https://example.com/\">z</a><a l:href=\"#chapter0\">w</a></p>", &ids, &aliases),
+            "<p><a l:href=\"#a\">x</a>y<a l:href=\"https://example.com/\">z</a><a l:href=\"#a\">w</a></p>"
+        );
+    }
+}
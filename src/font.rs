@@ -0,0 +1,67 @@
+//! IDPF のフォントの難読化
+//!
+//! EPUB に埋め込むフォントの先頭 1040 バイトを, 本の一意な識別子の SHA-1 と XOR する.
+//! 同じ操作で元に戻るので, 難読化と, unpack での復元のどちらにも使う
+
+use crate::xhtml;
+
+/// encryption.xml の`EncryptionMethod`に書く, IDPF の難読化のアルゴリズム
+pub const ALGORITHM: &str = "http://www.idpf.org/2008/embedding";
+
+/// 難読化するフォントの先頭のバイト数
+const OBFUSCATED_LENGTH: usize = 1040;
+
+/// 一意な識別子から鍵を作る. 識別子に含まれる空白 (U+0020, U+0009, U+000D, U+000A) は取り除く
+pub fn key(identifier: &str) -> [u8; 20] {
+    let identifier = identifier.chars()
+        .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .collect::<String>();
+    sha1_smol::Sha1::from(identifier).digest().bytes()
+}
+
+/// data の先頭を key と XOR する
+pub fn obfuscate(data: &mut [u8], key: &[u8; 20]) {
+    for (byte, k) in data.iter_mut().take(OBFUSCATED_LENGTH).zip(key.iter().cycle()) {
+        *byte ^= k;
+    }
+}
+
+/// 難読化したフォントを記載した encryption.xml
+/// uris は container のルートからのフォントのパス
+pub fn encryption_xml(uris: &[String]) -> String {
+    let data = uris.iter()
+        .map(|uri| format!(
+            "    <enc:EncryptedData>\n        \
+                 <enc:EncryptionMethod Algorithm=\"{}\"/>\n        \
+                 <enc:CipherData><enc:CipherReference URI=\"{}\"/></enc:CipherData>\n    \
+             </enc:EncryptedData>",
+            ALGORITHM,
+            xhtml::escape_attr(uri),
+        ))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(include_str!("literals/encryption.xml"), data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn obfuscation() {
+        // 空白を取り除いた`urn:uuid:0123`の SHA-1
+        let key = key(" urn:uuid:\n0123\t");
+        assert_eq!(key, [102, 195, 90, 208, 22, 214, 83, 78, 69, 53, 52, 23, 170, 45, 10, 177, 73, 243, 129, 228]);
+
+        let font = (0..2000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut obfuscated = font.clone();
+        obfuscate(&mut obfuscated, &key);
+        assert_eq!(obfuscated[0], font[0] ^ key[0]);
+        assert_eq!(obfuscated[1039], font[1039] ^ key[1039 % 20]);
+        assert_eq!(obfuscated[1040..], font[1040..]);
+
+        obfuscate(&mut obfuscated, &key);
+        assert_eq!(obfuscated, font);
+    }
+}
@@ -0,0 +1,199 @@
+//! コードブロックのシンタックスハイライト
+
+use crate::{prelude::*, xhtml};
+use html5ever::{
+    parse_fragment,
+    ParseOpts,
+    QualName,
+    rcdom::{RcDom, Handle, NodeData},
+    tendril::TendrilSink,
+};
+
+use syntect::{
+    highlighting::{Theme, ThemeSet},
+    html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+
+/// 生成する`<span>`の class に付ける接頭辞
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+/// ハイライト済みの`<pre>`に付ける class
+const PRE_CLASS: &str = "hl-code";
+
+/// テーマのスタイルシートのファイル名
+pub const HIGHLIGHT_CSS: &str = "highlight.css";
+
+pub struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme: Theme,
+}
+
+impl Highlighter {
+    /// `light`, `dark` もしくは syntect 組み込みのテーマ名からハイライタを作成する
+    pub fn new(theme_name: &str) -> RepubResult<Self> {
+        let mut themes = ThemeSet::load_defaults().themes;
+
+        let name = match theme_name {
+            "light" => "InspiredGitHub",
+            "dark" => "base16-ocean.dark",
+            name => name,
+        };
+
+        let theme = themes.remove(name).ok_or_else(|| {
+            let mut names = themes.keys().cloned().collect::<Vec<String>>();
+            names.sort();
+            format_err!("テーマ {} は存在しません. light / dark / {} のいずれかを指定してください", theme_name, names.join(" / "))
+        })?;
+
+        Ok(Self {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme,
+        })
+    }
+
+    /// テーマのスタイルシート
+    pub fn css(&self) -> RepubResult<String> {
+        let mut css = css_for_theme_with_class_style(&self.theme, CLASS_STYLE)
+            .map_err(|e| format_err!("{}", e))?;
+        css.push_str(&format!("\npre.{} {{\n overflow-x: auto;\n padding: 0.5em;\n}}\n", PRE_CLASS));
+
+        Ok(css)
+    }
+
+    /// comrak が出力した html のうち, 言語の指定されたコードブロック (`<pre>`の中の`<code>`) をハイライトする
+    /// `<pre lang="…"><code>` (github_pre_lang) と `<pre><code class="language-…">` の両方に対応する
+    /// ハイライトするものがなければ, html をそのまま返す
+    pub fn highlight_html(&self, html: &str) -> String {
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        ).one(html);
+        let root = dom.document.children.borrow()[0].clone();
+
+        if self.highlight_descendants(&root) {
+            xhtml::serialize_children(&root)
+        } else {
+            html.to_string()
+        }
+    }
+
+    /// node の子孫のコードブロックをハイライトする. ハイライトしたものがあれば true
+    fn highlight_descendants(&self, node: &Handle) -> bool {
+        let mut highlighted = false;
+        for child in node.children.borrow().iter() {
+            if xhtml::is_element(child, "pre") {
+                highlighted |= self.highlight_pre(child);
+            } else {
+                highlighted |= self.highlight_descendants(child);
+            }
+        }
+        highlighted
+    }
+
+    /// 空白を除いて`<code>`だけを子に持つ`<pre>`をハイライトする
+    fn highlight_pre(&self, pre: &Handle) -> bool {
+        let code = {
+            let children = pre.children.borrow();
+            let mut elements = children.iter().filter(|c| match c.data {
+                NodeData::Text { ref contents } => !contents.borrow().trim().is_empty(),
+                _ => true,
+            });
+            match (elements.next(), elements.next()) {
+                (Some(code), None) if xhtml::is_element(code, "code") => code.clone(),
+                _ => return false,
+            }
+        };
+
+        let lang = xhtml::get_attr(pre, "lang")
+            .or_else(|| {
+                xhtml::get_attr(&code, "class")?
+                    .split_whitespace()
+                    .find_map(|c| c.strip_prefix("language-"))
+                    .map(|s| s.to_string())
+            });
+        let highlighted = match lang.as_ref().and_then(|lang| self.highlight(&xhtml::text_content(&code), lang)) {
+            Some(highlighted) => highlighted,
+            None => return false,
+        };
+
+        // ハイライトした`<span>`を, 新しい`<code>`に入れて置き換える
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("code")),
+            vec![],
+        ).one(highlighted.as_str());
+        // dom を破棄するときに子孫も空にされるので, 取り出しておく
+        let spans = std::mem::take(&mut *dom.document.children.borrow()[0].children.borrow_mut());
+        let code = xhtml::create_element("code", &[]);
+        for span in spans {
+            xhtml::append(&code, span);
+        }
+
+        xhtml::set_attr(pre, "lang", lang.as_deref().unwrap_or_default());
+        // Re:VIEW の`list`など, 元の class は残す
+        let class = match xhtml::get_attr(pre, "class") {
+            Some(class) if class.split_whitespace().any(|c| c == PRE_CLASS) => class,
+            Some(class) if !class.trim().is_empty() => format!("{} {}", class.trim(), PRE_CLASS),
+            _ => PRE_CLASS.to_string(),
+        };
+        xhtml::set_attr(pre, "class", &class);
+        pre.children.borrow_mut().clear();
+        xhtml::append(pre, code);
+
+        true
+    }
+
+    /// 対応していない言語の場合は None
+    fn highlight(&self, code: &str, lang: &str) -> Option<String> {
+        let syntax = self.syntax_set.find_syntax_by_token(lang)?;
+        let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &self.syntax_set, CLASS_STYLE);
+
+        for line in LinesWithEndings::from(code) {
+            if let Err(e) = generator.parse_html_for_line_which_includes_newline(line) {
+                RepubWarning(format!("{} のハイライトに失敗しました: {}", lang, e)).print();
+                return None;
+            }
+        }
+
+        Some(generator.finalize())
+    }
+}
+
+#[test]
+fn test_highlight_html() {
+    let highlighter = Highlighter::new("light").unwrap();
+
+    let html = "<p>a</p>\n<pre lang=\"rust\"><code>fn main() { let s = &quot;&lt;&gt;&quot;; }\n</code></pre>\n<pre><code>plain\n</code></pre>\n";
+    let highlighted = highlighter.highlight_html(html);
+
+    assert!(highlighted.starts_with("<p>a</p>\n<pre lang=\"rust\" class=\"hl-code\"><code><span class=\"hl-source hl-rust\">"));
+    assert!(highlighted.contains("&lt;&gt;"));
+    assert!(highlighted.ends_with("<pre><code>plain\n</code></pre>\n"));
+
+    assert!(Highlighter::new("no such theme").is_err());
+}
+
+#[test]
+fn test_highlight_pre_without_code() {
+    let highlighter = Highlighter::new("light").unwrap();
+
+    // `<code>`を持たない`<pre>`の後のコードブロックをハイライトしても, 間の内容は失われない
+    let html = "<pre>ascii art</pre>\n<p>middle paragraph</p>\n<pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n";
+    let highlighted = highlighter.highlight_html(html);
+
+    assert!(highlighted.starts_with("<pre>ascii art</pre>\n<p>middle paragraph</p>\n<pre lang=\"rust\" class=\"hl-code\"><code><span"));
+    assert_eq!(highlighter.highlight_html("<pre>a</pre><pre><code>b</code></pre>"), "<pre>a</pre><pre><code>b</code></pre>");
+}
+
+#[test]
+fn test_highlight_keeps_pre_class() {
+    let highlighter = Highlighter::new("light").unwrap();
+
+    let highlighted = highlighter.highlight_html("<pre class=\"list\"><code class=\"language-rust\">fn main() {}\n</code></pre>");
+    assert!(highlighted.starts_with("<pre class=\"list hl-code\" lang=\"rust\"><code><span"));
+}
@@ -0,0 +1,212 @@
+//! `{{#include path.md}}` を, markdown の変換の前に展開する
+//!
+//! - `{{#include path.md:3:10}}` のように行の範囲 (1 から始まり, 終端を含む) を指定できる.
+//!   `:3` は 3 行目のみ, `:3:` は 3 行目以降, `::10` は 10 行目まで
+//! - `{{#include path.md shift=1}}` のように, 取り込む見出しのレベルをずらすことができる
+//! - `\{{#include …}}` は展開せず, `{{#include …}}` として出力する
+
+use crate::prelude::*;
+use std::path::Path;
+
+const DIRECTIVE: &str = "{{#include ";
+const DIRECTIVE_END: &str = "}}";
+
+/// path のファイルを読み込み, include を展開する
+pub fn expand(path: &Path) -> RepubResult<String> {
+    let source = std::fs::read_to_string(path)?;
+    let mut stack = vec![canonical(path)];
+
+    expand_str(&source, path, &mut stack)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// include の引数
+#[derive(Debug, PartialEq)]
+struct Directive {
+    path: PathBuf,
+    /// 行の範囲 (1 から始まる, 終端を含む)
+    start: Option<usize>,
+    end: Option<usize>,
+    /// 見出しのレベルをずらす量
+    shift: i8,
+}
+
+impl FromStr for Directive {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split_whitespace();
+        let target = args.next().ok_or_else(|| format_err!("ファイルが指定されていません"))?;
+
+        let mut shift = 0;
+        for arg in args {
+            match arg.strip_prefix("shift=") {
+                Some(n) => shift = n.trim_start_matches('+').parse::<i8>()
+                    .map_err(|_| format_err!("shift={} は見出しのレベルとして不正です", n))?,
+                None => return Err(format_err!("{} は不明な引数です", arg)),
+            }
+        }
+
+        let mut parts = target.splitn(3, ':');
+        let path = PathBuf::from(parts.next().unwrap_or_default());
+        let line = |s: Option<&str>| -> RepubResult<Option<usize>> {
+            match s {
+                None | Some("") => Ok(None),
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) if n >= 1 => Ok(Some(n)),
+                    _ => Err(format_err!("{} は行番号として不正です", n)),
+                }
+            }
+        };
+        let (start, end) = match (parts.next(), parts.next()) {
+            // `:3` は 3 行目のみ
+            (Some(n), None) => (line(Some(n))?, line(Some(n))?),
+            (start, end) => (line(start)?, line(end)?),
+        };
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(format_err!("行の範囲 {}:{} が不正です", start, end));
+            }
+        }
+
+        Ok(Self { path, start, end, shift })
+    }
+}
+
+fn expand_str(source: &str, path: &Path, stack: &mut Vec<PathBuf>) -> RepubResult<String> {
+    let mut output = String::with_capacity(source.len());
+
+    for (number, line) in source.split_inclusive('\n').enumerate() {
+        let mut rest = line;
+        while let Some(begin) = rest.find(DIRECTIVE) {
+            // `\{{#include …}}` は展開しない
+            if rest[..begin].ends_with('\\') {
+                output.push_str(&rest[..begin - 1]);
+                output.push_str(DIRECTIVE);
+                rest = &rest[begin + DIRECTIVE.len()..];
+                continue;
+            }
+
+            let end = match rest[begin..].find(DIRECTIVE_END) {
+                Some(end) => begin + end,
+                None => break,
+            };
+            output.push_str(&rest[..begin]);
+
+            let error = |e: failure::Error| format_err!("{:?}:{} : {}", path, number + 1, e);
+            let directive = rest[begin + DIRECTIVE.len()..end].parse::<Directive>().map_err(error)?;
+            output.push_str(&include(&directive, path, stack).map_err(error)?);
+
+            rest = &rest[end + DIRECTIVE_END.len()..];
+        }
+        output.push_str(rest);
+    }
+
+    Ok(output)
+}
+
+fn include(directive: &Directive, from: &Path, stack: &mut Vec<PathBuf>) -> RepubResult<String> {
+    let path = from.parent().map(|p| p.join(&directive.path)).unwrap_or_else(|| directive.path.clone());
+    let key = canonical(&path);
+    if stack.contains(&key) {
+        return Err(format_err!("{:?} の include が循環しています", &directive.path));
+    }
+
+    let source = std::fs::read_to_string(&path)
+        .map_err(|e| format_err!("{:?} を読み込めません: {}", &directive.path, e))?;
+    let lines = source.split_inclusive('\n').collect::<Vec<&str>>();
+    let start = directive.start.unwrap_or(1) - 1;
+    let end = directive.end.unwrap_or(lines.len()).min(lines.len());
+    let mut source = lines.get(start..end).map(|l| l.concat()).unwrap_or_default();
+    // 取り込んだ内容が後続の行とつながらないように
+    if !source.is_empty() && !source.ends_with('\n') {
+        source.push('\n');
+    }
+
+    stack.push(key);
+    let expanded = expand_str(&source, &path, stack);
+    stack.pop();
+
+    Ok(shift_headings(&expanded?, directive.shift))
+}
+
+/// ATX 形式の見出し (`# …`) のレベルをずらす. レベルは 1 から 6 の範囲に収める
+fn shift_headings(markdown: &str, shift: i8) -> String {
+    if shift == 0 {
+        return markdown.to_string();
+    }
+
+    let mut output = String::with_capacity(markdown.len());
+    // フェンスで囲まれたコードブロックの中は見出しではない
+    let mut fence: Option<(char, usize)> = None;
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let marker = trimmed.chars().next()
+            .filter(|c| indent < 4 && (*c == '`' || *c == '~'))
+            .map(|c| (c, trimmed.chars().take_while(|d| *d == c).count()))
+            .filter(|(_, n)| *n >= 3);
+
+        match (fence, marker) {
+            (Some((c, n)), Some((d, m))) if c == d && m >= n && trimmed.trim_end().chars().all(|e| e == c) => fence = None,
+            (Some(_), _) => {}
+            (None, Some(m)) => fence = Some(m),
+            (None, None) => {
+                let level = trimmed.chars().take_while(|c| *c == '#').count();
+                let is_heading = indent < 4 && (1..=6).contains(&level)
+                    && trimmed[level..].chars().next().is_none_or(char::is_whitespace);
+                if is_heading {
+                    let shifted = (level as i8 + shift).clamp(1, 6) as usize;
+                    output.push_str(&line[..indent]);
+                    output.push_str(&"#".repeat(shifted));
+                    output.push_str(&trimmed[level..]);
+                    continue;
+                }
+            }
+        }
+        output.push_str(line);
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn directive() {
+        let d = "part.md:3:10 shift=+1".parse::<Directive>().unwrap();
+        assert_eq!(d, Directive { path: PathBuf::from("part.md"), start: Some(3), end: Some(10), shift: 1 });
+        let d = "part.md:3".parse::<Directive>().unwrap();
+        assert_eq!((d.start, d.end), (Some(3), Some(3)));
+        let d = "part.md::5".parse::<Directive>().unwrap();
+        assert_eq!((d.start, d.end), (None, Some(5)));
+        assert!("part.md:5:3".parse::<Directive>().is_err());
+        assert!("part.md unknown".parse::<Directive>().is_err());
+    }
+
+    #[test]
+    fn expand_files() {
+        let dir = std::env::temp_dir().join(format!("repub_include_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, s: &str| std::fs::write(dir.join(name), s).unwrap();
+
+        write("main.md", "# Main\n{{#include part.md:2: shift=1}}\n\\{{#include part.md}}\n");
+        write("part.md", "skipped\n# Part\n```\n# not heading\n```\n");
+        assert_eq!(
+            expand(&dir.join("main.md")).unwrap(),
+            "# Main\n## Part\n```\n# not heading\n```\n\n{{#include part.md}}\n"
+        );
+
+        write("a.md", "a\n{{#include b.md}}\n");
+        write("b.md", "b\n{{#include a.md}}\n");
+        let e = expand(&dir.join("a.md")).unwrap_err().to_string();
+        assert!(e.contains("b.md\":2 : \"a.md\" の include が循環しています"), "{}", e);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
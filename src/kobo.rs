@@ -0,0 +1,200 @@
+//! Kobo の`.kepub.epub`のためのコンテンツの書き換え
+//!
+//! Kobo の reader は, 文ごとに`<span class="koboSpan" id="kobo.段落.文">`で囲まれていると,
+//! ページ数の計算やハイライトを正確に行う. また, 本文全体を`book-columns`, `book-inner`の`<div>`で囲む
+
+use crate::xhtml;
+use html5ever::{
+    parse_fragment,
+    ParseOpts,
+    QualName,
+    rcdom::{RcDom, Handle, NodeData},
+    tendril::TendrilSink,
+};
+use std::rc::Rc;
+
+/// 段落として, koboSpan の id の番号を改める要素
+const PARAGRAPHS: [&str; 22] = [
+    "p", "h1", "h2", "h3", "h4", "h5", "h6", "li", "dt", "dd", "th", "td",
+    "pre", "blockquote", "figcaption", "caption", "div", "section", "aside", "header", "footer", "figure",
+];
+
+/// 中の文を分割しない要素
+const SKIPPED: [&str; 9] = [
+    "script", "style", "rt", "rp", "svg", "math", "textarea", "select", "iframe",
+];
+
+/// 文の終わりを表す文字
+const TERMINATORS: [char; 9] = ['。', '！', '？', '!', '?', '．', '‼', '⁇', '.'];
+
+/// 文の終わりの直後に続く, 閉じ括弧や引用符
+const CLOSERS: [char; 14] = ['」', '』', '）', '】', '〕', '〉', '》', '"', '\'', '’', '”', ')', ']', '〟'];
+
+/// 文の始まりの開き括弧
+const OPENERS: [char; 6] = ['「', '『', '（', '【', '〔', '〈'];
+
+/// XHTML の`<body>`の中身を, koboSpan と wrapper の`<div>`を加えたものに書き換える
+/// `<body>`が見つからなければそのまま返す
+pub fn kepubify(xhtml: &str) -> String {
+    let start = match xhtml.find("<body").and_then(|start| xhtml[start..].find('>').map(|end| start + end + 1)) {
+        Some(start) => start,
+        None => return xhtml.to_string(),
+    };
+    let end = match xhtml.rfind("</body>") {
+        Some(end) if start <= end => end,
+        _ => return xhtml.to_string(),
+    };
+
+    let dom = parse_fragment(
+        RcDom::default(),
+        ParseOpts::default(),
+        QualName::new(None, ns!(html), local_name!("body")),
+        vec![],
+    ).one(&xhtml[start..end]);
+    let root = dom.document.children.borrow()[0].clone();
+
+    let mut counter = Counter { paragraph: 0, sentence: 0 };
+    span(&root, &mut counter);
+
+    format!(
+        "{}<div id=\"book-columns\"><div id=\"book-inner\">{}</div></div>{}",
+        &xhtml[..start],
+        xhtml::serialize_children(&root),
+        &xhtml[end..],
+    )
+}
+
+/// koboSpan の id の番号. 段落の外の文は, 0番目の段落とする
+struct Counter {
+    paragraph: usize,
+    sentence: usize,
+}
+
+impl Counter {
+    fn next(&mut self) -> Handle {
+        self.sentence += 1;
+        xhtml::create_element("span", &[
+            ("class", "koboSpan"),
+            ("id", &format!("kobo.{}.{}", self.paragraph, self.sentence)),
+        ])
+    }
+}
+
+/// node の子孫の文を koboSpan で囲む
+fn span(node: &Handle, counter: &mut Counter) {
+    let children = node.children.borrow().clone();
+    let mut replaced = Vec::with_capacity(children.len());
+
+    for child in children {
+        match child.data {
+            NodeData::Text { ref contents } => {
+                let text = contents.borrow().to_string();
+                if text.trim().is_empty() {
+                    replaced.push(child.clone());
+                    continue;
+                }
+                for sentence in sentences(&text) {
+                    let span = counter.next();
+                    xhtml::append(&span, xhtml::create_text(sentence));
+                    replaced.push(span);
+                }
+            }
+            NodeData::Element { ref name, .. } => {
+                let local = name.local.as_ref();
+                if xhtml::is_element(&child, "img") || xhtml::is_element(&child, "ruby") {
+                    // 画像とルビは, 分割せずに1文として数える
+                    let span = counter.next();
+                    xhtml::append(&span, child.clone());
+                    replaced.push(span);
+                    continue;
+                }
+                if name.ns == ns!(html) && PARAGRAPHS.contains(&local) {
+                    counter.paragraph += 1;
+                    counter.sentence = 0;
+                }
+                if !(SKIPPED.contains(&local) || xhtml::get_attr(&child, "class").is_some_and(|class| class.split_whitespace().any(|c| c == "koboSpan"))) {
+                    span(&child, counter);
+                }
+                replaced.push(child.clone());
+            }
+            _ => replaced.push(child.clone()),
+        }
+    }
+
+    for child in replaced.iter() {
+        child.parent.set(Some(Rc::downgrade(node)));
+    }
+    *node.children.borrow_mut() = replaced;
+}
+
+/// 文に分割する. 文の終わりの記号に続く閉じ括弧や空白は, その文に含める
+/// `.`は, 直後に空白があるか, テキストの終わりである場合のみ文の終わりとみなす
+/// `「本当？」と聞いた。`のように, 閉じ括弧の後に文が続く場合は, 括弧の中で文を終えない
+pub fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    let mut start = 0;
+
+    while let Some((_, c)) = chars.next() {
+        if !TERMINATORS.contains(&c) {
+            continue;
+        }
+        // 続く終わりの記号と閉じ括弧
+        let mut closed = false;
+        while let Some(&(_, c)) = chars.peek() {
+            if TERMINATORS.contains(&c) || CLOSERS.contains(&c) {
+                closed |= CLOSERS.contains(&c);
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let continued = chars.peek()
+            .is_some_and(|&(_, c)| !(c.is_whitespace() || OPENERS.contains(&c)));
+        if continued && (c == '.' || closed) {
+            continue;
+        }
+        while let Some(&(_, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else {
+                break;
+            }
+        }
+        let end = chars.peek().map(|&(i, _)| i).unwrap_or(text.len());
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+
+    sentences
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sentence() {
+        assert_eq!(sentences("吾輩は猫である。名前はまだ無い。"), vec!["吾輩は猫である。", "名前はまだ無い。"]);
+        assert_eq!(sentences("「本当？」と聞いた。えっ!?そう"), vec!["「本当？」と聞いた。", "えっ!?", "そう"]);
+        assert_eq!(sentences("「はい。」「いいえ。」"), vec!["「はい。」", "「いいえ。」"]);
+        assert_eq!(sentences("It costs 1.5 dollars. Really?  Yes"), vec!["It costs 1.5 dollars. ", "Really?  ", "Yes"]);
+    }
+
+    #[test]
+    fn spans() {
+        let xhtml = "<html><head><title>t</title></head><body class=\"b\">\n<h1 id=\"h\">見出し</h1><p>一文目。<em>二文目</em>です。<ruby>漢<rt>かん</rt></ruby></p><p><img src=\"a.png\" alt=\"\" /></p>\n</body></html>";
+        assert_eq!(
+            kepubify(xhtml),
+            "<html><head><title>t</title></head><body class=\"b\"><div id=\"book-columns\"><div id=\"book-inner\">\n\
+             <h1 id=\"h\"><span class=\"koboSpan\" id=\"kobo.1.1\">見出し</span></h1>\
+             <p><span class=\"koboSpan\" id=\"kobo.2.1\">一文目。</span><em><span class=\"koboSpan\" id=\"kobo.2.2\">二文目</span></em>\
+             <span class=\"koboSpan\" id=\"kobo.2.3\">です。</span><span class=\"koboSpan\" id=\"kobo.2.4\"><ruby>漢<rt>かん</rt></ruby></span></p>\
+             <p><span class=\"koboSpan\" id=\"kobo.3.1\"><img src=\"a.png\" alt=\"\" /></span></p>\n\
+             </div></div></body></html>"
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use clap::ArgMatches;
 
-use crate::prelude::*;
+use crate::{prelude::*, mdbook::{Book, Summary}, review, org, pattern, order, tmpfile};
 pub use source::Source;
 pub use content_configures::ContentConfigure;
-pub use config::{Config, WritingMode, PageProgressionDirection};
+pub use markdown_options::MarkdownOptions;
+pub use config::{Config, Format, BaseStyle, WritingMode, PageProgressionDirection};
 
 /// 入力された情報(設定およびfile)
 #[derive(Debug)]
@@ -28,15 +29,11 @@ impl<'a> TryFrom<clap::ArgMatches<'a>> for Input {
     type Error = failure::Error;
 
     fn try_from(value: clap::ArgMatches<'a>) -> Result<Self, Self::Error> {
-        let source_path_buf = {
-            let source_path_str = value.value_of_or_err("input")?;
-            PathBuf::from_str(source_path_str)?
-        };
-
-        let src = Source::try_from_path_buf(&source_path_buf)?;
-
         let cfg = Config::try_from(&value)?;
 
+        // mdBook のプロジェクトでは, 変換対象は入力された path ではなく book.toml の src
+        let src = Source::try_from_path_buf(&cfg.target, cfg.gitignore, &cfg.output_paths())?;
+
         Ok(Self {
             src,
             cfg,
@@ -56,6 +53,10 @@ mod config {
         pub target: PathBuf,
         /// 書式
         pub writing_mode: WritingMode,
+        /// 出力形式
+        pub format: Format,
+        /// repub に同梱する基本のスタイルシート
+        pub base_style: BaseStyle,
         /// タイトル
         pub title: String,
         /// 著者, 編集者, 翻訳者など
@@ -67,6 +68,18 @@ mod config {
         /// 目次に表示するheaderの最低レベル
         /// 1を指定すればh1のみ、5以上を指定すればh1~h5の全てのheaderが目次に表示される
         pub toc_depth: u8,
+        /// コードブロックのシンタックスハイライトのテーマ
+        /// None ならばハイライトしない
+        pub highlight: Option<String>,
+        /// コンテンツを分割する見出しのレベル
+        /// 2を指定すれば, h1とh2の直前でそれぞれ別の`.xhtml`ファイルに分割する. None ならば分割しない
+        pub split_level: Option<u8>,
+        /// `01_intro.md`の`01_`のような, ファイル名の先頭の番号を変換後のファイル名から取り除くか否か
+        pub strip_number_prefix: bool,
+        /// Jupyter notebook のコードのセルの入力を隠すか否か
+        pub hide_input: bool,
+        /// Jupyter notebook のコードのセルの出力を隠すか否か
+        pub hide_output: bool,
         /// ログ表示するか否か
         pub verbose: bool,
         /// tmp_dir を消去するか否か
@@ -77,10 +90,21 @@ mod config {
         pub cover_image: Option<PathBuf>,
         /// pack 対象から外すファイル targetからの相対パス
         pub ignores: Vec<PathBuf>,
+        /// `.repubignore`に加えて, `.gitignore`に記載されたファイルも除外するか否か
+        pub gitignore: bool,
+        /// 難読化せずに埋め込むフォント targetからの相対パス (glob)
+        /// 指定しなければ, EPUB に埋め込むすべてのフォントを IDPF の方法で難読化する
+        pub plain_fonts: Vec<PathBuf>,
         /// sequence: コンテンツに対して順序の指定をする
         pub sequence: Option<Vec<PathBuf>>,
         /// content configures: コンテンツに対するpropertyおよびstyleの指定
-        content_configures: Option<Vec<ContentConfigure>>,
+        pub content_configures: Option<Vec<ContentConfigure>>,
+        /// markdown の方言と拡張機能
+        pub markdown: MarkdownOptions,
+        /// mdBook の SUMMARY.md から読み取った本の構成
+        /// sequence の指定がなければ, コンテンツはこの順に並べる
+        #[serde(skip)]
+        pub summary: Option<Summary>,
     }
 
     impl<'a> TryFrom<&clap::ArgMatches<'a>> for Config {
@@ -95,6 +119,16 @@ mod config {
                 PathBuf::from_str(source_path_str)?
             };
 
+            // mdBook, Re:VIEW のプロジェクトであれば, その原稿のディレクトリを変換対象とし, 本の設定を既定値とする
+            // Org ファイルひとつだけの本であれば, そのキーワードを既定値とする
+            let book = Book::load(&target)
+                .or_else(|| review::load(&target))
+                .or_else(|| org::load(&target));
+            let target = match &book {
+                Some(book) => book.src.clone(),
+                None => target,
+            };
+
             // 設定ファイル読み込み
             fn from_json(target: &PathBuf) -> Option<Config> {
                 if target.is_file() {
@@ -128,6 +162,8 @@ mod config {
                     title.to_string()
                 } else if let Some(cfg) = &cfg {
                     cfg.title.clone()
+                } else if let Some(title) = book.as_ref().and_then(|b| b.title.clone()) {
+                    title
                 } else {
                     print!("Title: ");
                     std::io::stdout().flush().context("Failed to read line.")?;
@@ -144,6 +180,8 @@ mod config {
                     creator.to_string()
                 } else if let Some(cfg) = &cfg {
                     cfg.creator.clone()
+                } else if let Some(book) = book.as_ref().filter(|b| !b.authors.is_empty()) {
+                    book.authors.join(", ")
                 } else {
                     print!("Creator: ");
                     std::io::stdout().flush().context("Failed to read line.")?;
@@ -160,6 +198,8 @@ mod config {
                     language.to_string()
                 } else if let Some(cfg) = &cfg {
                     cfg.language.clone()
+                } else if let Some(language) = book.as_ref().and_then(|b| b.language.clone()) {
+                    language
                 } else {
                     print!("Language: ");
                     std::io::stdout().flush().context("Failed to read line.")?;
@@ -172,7 +212,8 @@ mod config {
             };
 
             let writing_mode = {
-                if let Some(mode) = value.value_of("writing_mode") {
+                // --mode には既定値があるので, 明示された場合のみ設定ファイルより優先する
+                if let Some(mode) = value.value_of("writing_mode").filter(|_| value.occurrences_of("writing_mode") > 0) {
                     WritingMode::from_str(mode)?
                 } else if let Some(cfg) = &cfg {
                     cfg.writing_mode.clone()
@@ -181,6 +222,26 @@ mod config {
                 }
             };
 
+            let format = {
+                if let Some(format) = value.value_of("format") {
+                    Format::from_str(format)?
+                } else if let Some(cfg) = &cfg {
+                    cfg.format
+                } else {
+                    Format::default()
+                }
+            };
+
+            let base_style = {
+                if let Some(style) = value.value_of("base_style") {
+                    BaseStyle::from_str(style)?
+                } else if let Some(cfg) = &cfg {
+                    cfg.base_style
+                } else {
+                    BaseStyle::default()
+                }
+            };
+
             let book_id = {
                 if let Some(id) = value.value_of("book_id") {
                     id.to_string()
@@ -228,6 +289,55 @@ mod config {
                 } else { 2 }
             };
 
+            let highlight = {
+                if let Some(theme) = value.value_of("highlight") {
+                    Some(theme.to_string())
+                } else if let Some(cfg) = &cfg {
+                    cfg.highlight.clone()
+                } else { None }
+            };
+
+            let split_level = {
+                if let Some(level) = value.value_of("split_level") {
+                    match level.parse::<u8>() {
+                        Ok(ok) if ok >= 1 => Some(ok),
+                        _ => {
+                            RepubWarning(format!("{} は分割する見出しのレベルに設定できません 分割せずに変換します", &level)).print();
+                            None
+                        }
+                    }
+                } else if let Some(cfg) = &cfg {
+                    cfg.split_level
+                } else { None }
+            };
+
+            let strip_number_prefix = {
+                let a = value.is_present("strip_number_prefix");
+                let b =
+                    if let Some(cfg) = &cfg {
+                        cfg.strip_number_prefix
+                    } else { false };
+                (a || b) && !(a && b)
+            };
+
+            let hide_input = {
+                let a = value.is_present("hide_input");
+                let b =
+                    if let Some(cfg) = &cfg {
+                        cfg.hide_input
+                    } else { false };
+                (a || b) && !(a && b)
+            };
+
+            let hide_output = {
+                let a = value.is_present("hide_output");
+                let b =
+                    if let Some(cfg) = &cfg {
+                        cfg.hide_output
+                    } else { false };
+                (a || b) && !(a && b)
+            };
+
             let verbose = {
                 let a = value.is_present("verbose");
                 let b =
@@ -258,6 +368,15 @@ mod config {
                 (a || b) && !(a && b)
             };
 
+            let gitignore = {
+                let a = value.is_present("gitignore");
+                let b =
+                    if let Some(cfg) = &cfg {
+                        cfg.gitignore
+                    } else { false };
+                (a || b) && !(a && b)
+            };
+
             let mut ignores = {
                 // config.jsonによる指定がある場合
                 if let Some(ignores) = cfg.as_ref().map(|c| c.ignores.clone()) {
@@ -268,6 +387,9 @@ mod config {
                     if let Some(Some(cover_image)) = cover_image.clone().map(|p| p.to_str().map(|s| s.to_string())) {
                         ignores.push(PathBuf::from(&cover_image));
                     }
+                    if let Some(book) = &book {
+                        ignores.extend(book.ignores.iter().cloned());
+                    }
                     ignores
                 }
             };
@@ -277,11 +399,23 @@ mod config {
                 None => None,
             };
 
+            let summary = book.and_then(|b| b.summary);
+
             let content_configures = match cfg {
                 Some(ref cfg) => cfg.content_configures.clone(),
                 None => None,
             };
 
+            let markdown = match cfg {
+                Some(ref cfg) => cfg.markdown.clone(),
+                None => MarkdownOptions::default(),
+            };
+
+            let plain_fonts = match cfg {
+                Some(ref cfg) => cfg.plain_fonts.clone(),
+                None => Vec::new(),
+            };
+
             // logger を初期化
             env_logger::Builder::from_default_env()
                 .format(|buf, record| writeln!(buf, "{}", record.args()))
@@ -290,33 +424,142 @@ mod config {
             Ok(Self {
                 target,
                 writing_mode,
+                format,
+                base_style,
                 title,
                 creator,
                 language,
                 book_id,
                 toc_depth,
+                highlight,
+                split_level,
+                strip_number_prefix,
+                hide_input,
+                hide_output,
                 verbose,
                 save,
                 config,
                 cover_image,
                 ignores,
+                gitignore,
+                plain_fonts,
                 content_configures,
                 sequence,
+                markdown,
+                summary,
             })
         }
     }
 
     impl Config {
+        /// src に一致する content configure のうち, 最初のもの
         pub fn config(&self, src: &Source) -> Option<&ContentConfigure> {
-            match &self.content_configures {
-                None => None,
-                Some(s) => {
-                    s.iter().find(|c| {
-                        self.target.join(&c.src) == src.path
-                    })
-                }
+            let path = PathBuf::path_diff(&self.target, &src.path)?;
+
+            self.content_configures.iter()
+                .flatten()
+                .find(|c| pattern::matches(&c.src, &path))
+        }
+
+        /// path が ignores のいずれかに一致するか
+        pub fn is_ignored(&self, path: &PathBuf) -> bool {
+            match PathBuf::path_diff(&self.target, path) {
+                Some(path) => self.ignores.iter().any(|i| pattern::matches_ignore(i, &path)),
+                None => false,
+            }
+        }
+
+        /// repub がいずれかの形式で書き出すファイルと, 一時ディレクトリのパス
+        /// 変換対象のディレクトリに書き出した場合に, 次の変換でコンテンツとして読み込まないよう除外する
+        pub fn output_paths(&self) -> Vec<PathBuf> {
+            Format::ALL.iter()
+                .map(|f| f.output_path(&self.title))
+                .chain(std::iter::once(PathBuf::from(tmpfile::TMP_DIR_PATH_STR)))
+                .collect()
+        }
+
+        /// path が難読化しないフォントとして指定されているか
+        pub fn is_plain_font(&self, path: &PathBuf) -> bool {
+            match PathBuf::path_diff(&self.target, path) {
+                Some(path) => self.plain_fonts.iter().any(|f| pattern::matches(f, &path)),
+                None => false,
+            }
+        }
+    }
+
+    /// 出力形式
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Format {
+        /// EPUB3
+        #[default]
+        Epub,
+        /// 目次と前後のページへのリンクを持つ, ブラウザで読める Web サイト
+        Html,
+        /// すべてのコンテンツをまとめた1つの HTML ファイル
+        SingleHtml,
+        /// Kobo のための, 文ごとに koboSpan で囲んだ`.kepub.epub`
+        Kepub,
+        /// FictionBook 2
+        Fb2,
+    }
+
+    impl Format {
+        const ALL: [Format; 5] = [Format::Epub, Format::Html, Format::SingleHtml, Format::Kepub, Format::Fb2];
+
+        /// 書き出すファイル (`html`ではディレクトリ) のパス
+        pub fn output_path(&self, title: &str) -> PathBuf {
+            PathBuf::from(match self {
+                Format::Epub => format!("{}.epub", title),
+                Format::Html => format!("{}_html", title),
+                Format::SingleHtml => format!("{}.html", title),
+                Format::Kepub => format!("{}.kepub.epub", title),
+                Format::Fb2 => format!("{}.fb2", title),
+            })
+        }
+    }
+
+    impl FromStr for Format {
+        type Err = failure::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "epub" => Ok(Format::Epub),
+                "html" => Ok(Format::Html),
+                "single-html" => Ok(Format::SingleHtml),
+                "kepub" => Ok(Format::Kepub),
+                "fb2" => Ok(Format::Fb2),
+                _ => Err(format_err!("出力形式には epub/html/single-html/kepub/fb2 のいずれかを指定してください")),
+            }
+        }
+    }
+
+    /// repub に同梱する基本のスタイルシートのうち, どれを適用するか
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum BaseStyle {
+        /// ユーザーのスタイルシートがなければ, 書式に合わせて横書きか縦書きのものを適用する
+        #[default]
+        Auto,
+        /// 常に横書きのものを適用する
+        Horizontal,
+        /// 常に縦書きのものを適用する
+        Vertical,
+        /// 適用しない
+        None,
+    }
+
+    impl FromStr for BaseStyle {
+        type Err = failure::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "auto" => Ok(BaseStyle::Auto),
+                "horizontal" => Ok(BaseStyle::Horizontal),
+                "vertical" => Ok(BaseStyle::Vertical),
+                "none" => Ok(BaseStyle::None),
+                _ => Err(format_err!("基本のスタイルシートには auto/horizontal/vertical/none のいずれかを指定してください")),
             }
-            // todo rewrite with .flatten()
         }
     }
 
@@ -442,7 +685,6 @@ mod config {
 
 mod source {
     use super::*;
-    use std::fs::DirEntry;
 
     #[derive(Debug, Clone, PartialEq)]
     pub struct Source {
@@ -452,22 +694,41 @@ mod source {
     }
 
     impl Source {
-        pub fn try_from_path_buf(value: &PathBuf) -> RepubResult<Vec<Self>> {
+        /// value がディレクトリであれば, その中のファイルを再帰的に列挙する
+        /// 隠しファイル (`.git/`など) と, `.repubignore`に記載されたファイルは除外する
+        /// gitignore が true ならば, `.gitignore`に記載されたファイルも除外する
+        /// excludes (repub が書き出したファイルやディレクトリ) も除外する
+        pub fn try_from_path_buf(value: &PathBuf, gitignore: bool, excludes: &[PathBuf]) -> RepubResult<Vec<Self>> {
             if value.is_file() {
                 return Ok(vec![Self::try_from(value)?]);
             }
 
-            let mut entries
-                = std::fs::read_dir(value)?
-                .flat_map(|e| e)
-                .collect::<Vec<DirEntry>>();
-            entries.sort_by_key(|e| e.path());
+            // 存在するものだけを, 絶対パスにして比較する
+            let excludes = excludes.iter()
+                .filter_map(|p| p.canonicalize().ok())
+                .collect::<Vec<PathBuf>>();
+
+            let walk = ignore::WalkBuilder::new(value)
+                .standard_filters(false)
+                .hidden(true)
+                .follow_links(true)
+                .add_custom_ignore_filename(REPUBIGNORE)
+                .parents(gitignore)
+                .git_ignore(gitignore)
+                .require_git(false)
+                // `2_start.md` が`10_end.md`より前になるように, 数字は数値として比較する
+                .sort_by_file_path(order::natural_cmp_path)
+                .filter_entry(move |entry| {
+                    excludes.is_empty() || !entry.path().canonicalize().is_ok_and(|p| excludes.contains(&p))
+                })
+                .build();
 
             let mut vec = Vec::new();
-            for entry in entries {
-                let path = entry.path();
-
-                vec.append(&mut Self::try_from_path_buf(&path)?);
+            for entry in walk {
+                let entry = entry?;
+                if entry.file_type().is_some_and(|t| t.is_file()) {
+                    vec.push(Self::try_from(&entry.into_path())?);
+                }
             }
 
             Ok(vec)
@@ -543,6 +804,133 @@ mod content_configures {
         pub properties: Vec<Properties>,
         #[serde(default)]
         pub styles: Vec<PathBuf>,
+        /// このコンテンツにのみ適用する markdown の設定 (Config::markdown を上書きする)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub markdown: Option<MarkdownOptions>,
+        /// このコンテンツを分割する見出しのレベル (Config::split_level を上書きする)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub split_level: Option<u8>,
+    }
+}
+
+mod markdown_options {
+    use super::*;
+    use comrak::ComrakOptions;
+
+    /// markdown の方言
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum MarkdownPreset {
+        /// GFM の拡張 + 改行をそのまま改行として扱う (repub の従来の挙動)
+        #[default]
+        Repub,
+        /// GitHub Flavored Markdown
+        Gfm,
+        /// 拡張なしの CommonMark
+        CommonMark,
+    }
+
+    /// markdown の変換の設定
+    /// preset を基本として, 指定された項目のみを上書きする
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct MarkdownOptions {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub preset: Option<MarkdownPreset>,
+        /// 段落内の改行を`<br />`に変換する
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub hardbreaks: Option<bool>,
+        /// 引用符, ダッシュ, 三点リーダを約物に変換する
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub smart: Option<bool>,
+        /// コードブロックの言語を`<pre lang>`で示す
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub github_pre_lang: Option<bool>,
+        /// `~~取り消し線~~`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub strikethrough: Option<bool>,
+        /// `<script>`などの一部の html タグを無効化する
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tagfilter: Option<bool>,
+        /// 表
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub table: Option<bool>,
+        /// URL の自動リンク
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub autolink: Option<bool>,
+        /// `- [ ] タスクリスト`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub tasklist: Option<bool>,
+        /// `^上付き文字^`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub superscript: Option<bool>,
+        /// `[^脚注]`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub footnotes: Option<bool>,
+        /// 定義リスト
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub description_lists: Option<bool>,
+        /// markdown 中の html をそのまま出力する
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub raw_html: Option<bool>,
+    }
+
+    impl MarkdownOptions {
+        /// self を other で上書きした設定
+        pub fn merged(&self, other: &MarkdownOptions) -> MarkdownOptions {
+            MarkdownOptions {
+                preset: other.preset.or(self.preset),
+                hardbreaks: other.hardbreaks.or(self.hardbreaks),
+                smart: other.smart.or(self.smart),
+                github_pre_lang: other.github_pre_lang.or(self.github_pre_lang),
+                strikethrough: other.strikethrough.or(self.strikethrough),
+                tagfilter: other.tagfilter.or(self.tagfilter),
+                table: other.table.or(self.table),
+                autolink: other.autolink.or(self.autolink),
+                tasklist: other.tasklist.or(self.tasklist),
+                superscript: other.superscript.or(self.superscript),
+                footnotes: other.footnotes.or(self.footnotes),
+                description_lists: other.description_lists.or(self.description_lists),
+                raw_html: other.raw_html.or(self.raw_html),
+            }
+        }
+
+        pub fn to_comrak(&self) -> ComrakOptions {
+            let mut options = ComrakOptions::default();
+
+            match self.preset.unwrap_or_default() {
+                MarkdownPreset::Repub | MarkdownPreset::Gfm => {
+                    options.github_pre_lang = true;
+                    options.ext_strikethrough = true;
+                    options.ext_tagfilter = true;
+                    options.ext_table = true;
+                    options.ext_autolink = true;
+                    options.ext_tasklist = true;
+                    options.hardbreaks = self.preset.unwrap_or_default() == MarkdownPreset::Repub;
+                }
+                MarkdownPreset::CommonMark => {}
+            }
+
+            fn set(option: &mut bool, value: Option<bool>) {
+                if let Some(value) = value {
+                    *option = value;
+                }
+            }
+            set(&mut options.hardbreaks, self.hardbreaks);
+            set(&mut options.smart, self.smart);
+            set(&mut options.github_pre_lang, self.github_pre_lang);
+            set(&mut options.ext_strikethrough, self.strikethrough);
+            set(&mut options.ext_tagfilter, self.tagfilter);
+            set(&mut options.ext_table, self.table);
+            set(&mut options.ext_autolink, self.autolink);
+            set(&mut options.ext_tasklist, self.tasklist);
+            set(&mut options.ext_superscript, self.superscript);
+            set(&mut options.ext_footnotes, self.footnotes);
+            set(&mut options.ext_description_lists, self.description_lists);
+            set(&mut options.unsafe_, self.raw_html);
+
+            options
+        }
     }
 }
 
@@ -550,6 +938,24 @@ mod content_configures {
 mod test {
     use super::*;
 
+    #[test]
+    fn markdown_options() {
+        let global = serde_json::from_str::<MarkdownOptions>(r#"{"preset": "gfm", "footnotes": true}"#).unwrap();
+        let local = serde_json::from_str::<MarkdownOptions>(r#"{"hardbreaks": true}"#).unwrap();
+
+        let options = global.merged(&local).to_comrak();
+        assert!(options.hardbreaks);
+        assert!(options.ext_footnotes);
+        assert!(options.ext_table);
+
+        let options = serde_json::from_str::<MarkdownOptions>(r#"{"preset": "commonmark"}"#).unwrap().to_comrak();
+        assert!(!options.ext_table);
+        assert!(!options.hardbreaks);
+
+        // 指定がなければ従来どおり
+        assert!(MarkdownOptions::default().to_comrak().hardbreaks);
+    }
+
     #[test]
     fn app_to_input() -> RepubResult<()> {
         let app = crate::app::app();
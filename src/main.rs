@@ -5,6 +5,25 @@ mod data;
 mod tmpfile;
 mod toc;
 mod compose;
+mod math;
+mod block;
+mod include;
+mod aozora;
+mod mdbook;
+mod review;
+mod notebook;
+mod docx;
+mod org;
+mod unpack;
+mod site;
+mod kobo;
+mod fb2;
+mod style;
+mod font;
+mod pattern;
+mod order;
+mod xhtml;
+mod highlight;
 mod test;
 
 #[macro_use]
@@ -30,8 +49,23 @@ fn main() {
 
 fn run() -> RepubResult<()> {
     let app = crate::app::app();
+    let matches = app.get_matches();
 
-    let input = Input::try_from(app.get_matches())?;
+    // repub unpack <epub> <dir>
+    if let Some(matches) = matches.subcommand_matches("unpack") {
+        if matches.is_present("verbose") {
+            std::env::set_var("RUST_LOG", "info");
+        }
+        env_logger::Builder::from_default_env()
+            .format(|buf, record| writeln!(buf, "{}", record.args()))
+            .init();
+
+        let epub = PathBuf::from(matches.value_of("epub").unwrap_or_default());
+        let dir = PathBuf::from(matches.value_of("dir").unwrap_or_default());
+        return unpack::unpack(&epub, &dir, matches.is_present("xhtml"));
+    }
+
+    let input = Input::try_from(matches)?;
 
     let data = InputData::from(input);
 
@@ -0,0 +1,915 @@
+//! TeX 形式の数式 (`$…$`, `$$…$$`) を MathML に変換する
+
+/// markdown 変換の間, 数式の位置を保持するための目印
+/// comrak / html5ever のどちらにも解釈されない私用領域の文字を使う
+const PLACEHOLDER_BEGIN: char = '\u{E000}';
+const PLACEHOLDER_END: char = '\u{E001}';
+
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// markdown 中の数式
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formula {
+    /// `$` を除いた TeX のソース
+    pub tex: String,
+    /// `$$…$$` (ブロック数式) か否か
+    pub display: bool,
+}
+
+impl Formula {
+    pub fn to_mathml(&self) -> String {
+        let inner = tex::Parser::new(&self.tex, self.display).parse();
+
+        format!(
+            "<math xmlns=\"{}\" display=\"{}\" alttext=\"{}\">{}</math>",
+            MATHML_NS,
+            if self.display { "block" } else { "inline" },
+            escape(self.tex.trim(), true),
+            inner
+        )
+    }
+}
+
+/// markdown から数式を抜き出し, 目印に置き換える
+/// コードブロック, インラインコード, `\$` の中の `$` は数式として扱わない
+pub fn extract(markdown: &str) -> (String, Vec<Formula>) {
+    let mut formulas = Vec::new();
+    let mut output = String::with_capacity(markdown.len());
+    let mut text = String::new();
+
+    // フェンスで囲まれたコードブロック, インデントされたコードブロックはそのまま出力する
+    let mut fence: Option<(char, usize)> = None;
+    let mut indented = false;
+    // 直前の行が空行か否か, リストの中か否か
+    let mut after_blank = true;
+    let mut in_list = false;
+    for line in markdown.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        let marker = trimmed.chars().next()
+            .filter(|c| indent < 4 && (*c == '`' || *c == '~'))
+            .map(|c| (c, trimmed.chars().take_while(|d| *d == c).count()))
+            .filter(|(_, n)| *n >= 3);
+
+        if fence.is_none() {
+            let blank = line.trim().is_empty();
+            let width = indent_width(line);
+            // 空行の後の, 4 文字以上インデントされた行から始まる. リストの中では段落の続きになる
+            if indented && !blank && width < 4 {
+                indented = false;
+            } else if !indented && !blank && width >= 4 && after_blank && !in_list {
+                output.push_str(&replace_formulas(&text, &mut formulas));
+                text.clear();
+                indented = true;
+            }
+            if !blank && !indented {
+                in_list = is_list_item(line.trim_start()) || (in_list && !(after_blank && width == 0));
+            }
+            after_blank = blank;
+            if indented {
+                output.push_str(line);
+                continue;
+            }
+        }
+
+        match (fence, marker) {
+            (None, Some(m)) => {
+                output.push_str(&replace_formulas(&text, &mut formulas));
+                text.clear();
+                output.push_str(line);
+                fence = Some(m);
+            }
+            (Some(_), _) => {
+                output.push_str(line);
+                if let (Some((c, n)), Some((d, m))) = (fence, marker) {
+                    if c == d && m >= n && trimmed.trim_end().chars().all(|e| e == c) {
+                        fence = None;
+                    }
+                }
+            }
+            (None, None) => text.push_str(line),
+        }
+    }
+    output.push_str(&replace_formulas(&text, &mut formulas));
+
+    (output, formulas)
+}
+
+/// 行頭の空白の幅. タブは 4 文字ごとの位置まで進める
+fn indent_width(line: &str) -> usize {
+    line.chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .fold(0, |width, c| if c == '\t' { width + 4 - width % 4 } else { width + 1 })
+}
+
+/// `- `, `* `, `+ `, `1. `, `1) ` で始まるリストの項目か否か
+fn is_list_item(line: &str) -> bool {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = if digits > 0 {
+        match line[digits..].chars().next() {
+            Some('.') | Some(')') => &line[digits + 1..],
+            _ => return false,
+        }
+    } else {
+        match line.chars().next() {
+            Some('-') | Some('*') | Some('+') => &line[1..],
+            _ => return false,
+        }
+    };
+    rest.is_empty() || rest.starts_with(char::is_whitespace)
+}
+
+fn replace_formulas(text: &str, formulas: &mut Vec<Formula>) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut output = String::with_capacity(text.len());
+    let mut i = 0;
+
+    let mut push_formula = |output: &mut String, tex: String, display: bool| {
+        output.push(PLACEHOLDER_BEGIN);
+        output.push_str(&formulas.len().to_string());
+        output.push(PLACEHOLDER_END);
+        formulas.push(Formula { tex, display });
+    };
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                output.push(chars[i]);
+                if let Some(c) = chars.get(i + 1) {
+                    output.push(*c);
+                }
+                i += 2;
+            }
+            '`' => {
+                // インラインコード: 同じ長さのバッククォートで閉じられるまでそのまま
+                let len = chars[i..].iter().take_while(|c| **c == '`').count();
+                let close = (i + len..chars.len()).find(|j| {
+                    chars[*j..].iter().take_while(|c| **c == '`').count() == len
+                        && chars[*j - 1] != '`'
+                });
+                let end = close.map(|j| j + len).unwrap_or(i + len);
+                output.extend(&chars[i..end]);
+                i = end;
+            }
+            '$' if chars.get(i + 1) == Some(&'$') => {
+                let close = (i + 2..chars.len().saturating_sub(1))
+                    .find(|j| chars[*j] == '$' && chars[*j + 1] == '$' && chars[*j - 1] != '\\');
+                match close {
+                    Some(j) => {
+                        push_formula(&mut output, chars[i + 2..j].iter().collect(), true);
+                        i = j + 2;
+                    }
+                    None => {
+                        output.push_str("$$");
+                        i += 2;
+                    }
+                }
+            }
+            '$' => {
+                // 開きの直後, 閉じの直前は空白ではなく, 閉じの直後は数字ではない
+                let opens = chars.get(i + 1).map(|c| !c.is_whitespace()).unwrap_or(false);
+                let close = if opens {
+                    let mut j = i + 1;
+                    loop {
+                        match chars.get(j) {
+                            None => break None,
+                            Some('\\') => j += 2,
+                            Some('\n') if chars.get(j + 1) == Some(&'\n') => break None,
+                            Some('$') if !chars[j - 1].is_whitespace()
+                                && !chars.get(j + 1).map(|c| c.is_ascii_digit()).unwrap_or(false) => break Some(j),
+                            _ => j += 1,
+                        }
+                    }
+                } else { None };
+                match close {
+                    Some(j) => {
+                        push_formula(&mut output, chars[i + 1..j].iter().collect(), false);
+                        i = j + 1;
+                    }
+                    None => {
+                        output.push('$');
+                        i += 1;
+                    }
+                }
+            }
+            c => {
+                output.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    output
+}
+
+/// `extract` で置き換えた目印を MathML に戻す
+pub fn restore(html: &str, formulas: &[Formula]) -> String {
+    let mut output = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(begin) = rest.find(PLACEHOLDER_BEGIN) {
+        output.push_str(&rest[..begin]);
+        let after = &rest[begin + PLACEHOLDER_BEGIN.len_utf8()..];
+        let formula = after.find(PLACEHOLDER_END)
+            .and_then(|end| after[..end].parse::<usize>().ok().map(|i| (i, end)))
+            .and_then(|(i, end)| formulas.get(i).map(|f| (f, end)));
+
+        match formula {
+            Some((formula, end)) => {
+                output.push_str(&formula.to_mathml());
+                rest = &after[end + PLACEHOLDER_END.len_utf8()..];
+            }
+            None => {
+                output.push(PLACEHOLDER_BEGIN);
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+
+    output
+}
+
+fn escape(s: &str, attr: bool) -> String {
+    if attr {
+        crate::xhtml::escape_attr(s)
+    } else {
+        crate::xhtml::escape_text(s)
+    }
+}
+
+mod tex {
+    use super::escape;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Token {
+        Command(String),
+        Char(char),
+        Space,
+        BeginGroup,
+        EndGroup,
+        Sup,
+        Sub,
+        Align,
+        NewRow,
+    }
+
+    fn tokenize(tex: &str) -> Vec<Token> {
+        let chars = tex.chars().collect::<Vec<char>>();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            tokens.push(match c {
+                '\\' => match chars.get(i) {
+                    Some('\\') => {
+                        i += 1;
+                        Token::NewRow
+                    }
+                    Some(c) if c.is_ascii_alphabetic() => {
+                        let name = chars[i..].iter()
+                            .take_while(|c| c.is_ascii_alphabetic())
+                            .collect::<String>();
+                        i += name.len();
+                        Token::Command(name)
+                    }
+                    Some(c) => {
+                        i += 1;
+                        Token::Command(c.to_string())
+                    }
+                    None => Token::Char('\\'),
+                },
+                '{' => Token::BeginGroup,
+                '}' => Token::EndGroup,
+                '^' => Token::Sup,
+                '_' => Token::Sub,
+                '&' => Token::Align,
+                '%' => {
+                    // コメントは行末まで読み飛ばす
+                    while i < chars.len() && chars[i] != '\n' {
+                        i += 1;
+                    }
+                    continue;
+                }
+                c if c.is_whitespace() => Token::Space,
+                c => Token::Char(c),
+            });
+        }
+
+        tokens
+    }
+
+    /// 行 (mrow) を構成する要素
+    struct Item {
+        mathml: String,
+        /// display 時に上下へ添字を付ける演算子 (\sum, \lim など)
+        limits: bool,
+    }
+
+    impl Item {
+        fn new(mathml: String) -> Self {
+            Self { mathml, limits: false }
+        }
+    }
+
+    /// 行の読み取りを終える条件
+    #[derive(PartialEq, Clone, Copy)]
+    enum Stop {
+        Eof,
+        EndGroup,
+        Right,
+        Cell,
+        Bracket,
+    }
+
+    pub struct Parser {
+        tokens: Vec<Token>,
+        pos: usize,
+        display: bool,
+        variant: Option<&'static str>,
+    }
+
+    impl Parser {
+        pub fn new(tex: &str, display: bool) -> Self {
+            Self {
+                tokens: tokenize(tex),
+                pos: 0,
+                display,
+                variant: None,
+            }
+        }
+
+        pub fn parse(&mut self) -> String {
+            let rows = self.parse_rows(Stop::Eof);
+            if rows.len() == 1 && rows[0].len() == 1 {
+                rows[0][0].clone()
+            } else {
+                table(&rows, None)
+            }
+        }
+
+        fn peek(&self) -> Option<&Token> {
+            self.tokens.get(self.pos)
+        }
+
+        fn next(&mut self) -> Option<Token> {
+            let token = self.tokens.get(self.pos).cloned();
+            self.pos += 1;
+            token
+        }
+
+        fn skip_spaces(&mut self) {
+            while self.peek() == Some(&Token::Space) {
+                self.pos += 1;
+            }
+        }
+
+        /// `&` と `\\` で区切られた表 (環境の中身や `\\` を含む数式) を読む
+        fn parse_rows(&mut self, stop: Stop) -> Vec<Vec<String>> {
+            let mut rows = vec![];
+            let mut row = vec![];
+            loop {
+                let (cell, end) = self.parse_row(stop);
+                row.push(cell);
+                match end {
+                    Some(Token::Align) => {}
+                    Some(Token::NewRow) => rows.push(std::mem::take(&mut row)),
+                    _ => break,
+                }
+            }
+            // 末尾の `\\` による空行は捨てる
+            if !(row.len() == 1 && row[0] == "<mrow></mrow>" && !rows.is_empty()) {
+                rows.push(row);
+            }
+            rows
+        }
+
+        /// 終了条件までの要素を読み, mrow にまとめる
+        /// 戻り値の Token は読み取りを終えた区切り (`&`, `\\`, `}` など)
+        fn parse_row(&mut self, stop: Stop) -> (String, Option<Token>) {
+            let mut items: Vec<Item> = vec![];
+
+            let end = loop {
+                let token = match self.next() {
+                    None => break None,
+                    Some(token) => token,
+                };
+
+                match token {
+                    Token::Space => continue,
+                    Token::EndGroup if stop != Stop::Eof => break Some(token),
+                    Token::Char(']') if stop == Stop::Bracket => break Some(token),
+                    Token::Align | Token::NewRow if stop == Stop::Eof || stop == Stop::Cell => break Some(token),
+                    Token::Command(ref name) if name == "right" && stop == Stop::Right => break Some(token),
+                    Token::Command(ref name) if name == "end" && stop == Stop::Cell => {
+                        self.read_group_text();
+                        break Some(token);
+                    }
+                    Token::Sup | Token::Sub => {
+                        let base = items.pop().unwrap_or_else(|| Item::new("<mrow></mrow>".to_string()));
+                        let item = self.parse_scripts(base, token);
+                        items.push(item);
+                    }
+                    Token::Command(ref name) if name == "limits" || name == "nolimits" => {
+                        if let Some(last) = items.last_mut() {
+                            last.limits = name == "limits";
+                        }
+                    }
+                    token => {
+                        if let Some(item) = self.parse_atom(token) {
+                            items.push(item);
+                        }
+                    }
+                }
+            };
+
+            (mrow(items.into_iter().map(|i| i.mathml).collect()), end)
+        }
+
+        fn parse_scripts(&mut self, base: Item, first: Token) -> Item {
+            let first_arg = self.parse_argument();
+            self.skip_spaces();
+
+            let (mut sub, mut sup) = (None, None);
+            if first == Token::Sup { sup = Some(first_arg) } else { sub = Some(first_arg) }
+
+            match self.peek() {
+                Some(Token::Sub) if sub.is_none() => {
+                    self.pos += 1;
+                    sub = Some(self.parse_argument());
+                }
+                Some(Token::Sup) if sup.is_none() => {
+                    self.pos += 1;
+                    sup = Some(self.parse_argument());
+                }
+                _ => {}
+            }
+
+            let under_over = base.limits && self.display;
+            let mathml = match (sub, sup) {
+                (Some(sub), Some(sup)) if under_over => format!("<munderover>{}{}{}</munderover>", base.mathml, sub, sup),
+                (Some(sub), None) if under_over => format!("<munder>{}{}</munder>", base.mathml, sub),
+                (None, Some(sup)) if under_over => format!("<mover>{}{}</mover>", base.mathml, sup),
+                (Some(sub), Some(sup)) => format!("<msubsup>{}{}{}</msubsup>", base.mathml, sub, sup),
+                (Some(sub), None) => format!("<msub>{}{}</msub>", base.mathml, sub),
+                (None, Some(sup)) => format!("<msup>{}{}</msup>", base.mathml, sup),
+                (None, None) => base.mathml,
+            };
+
+            Item::new(mathml)
+        }
+
+        /// コマンドの引数 (`{…}` または 1 つの要素) を読む
+        fn parse_argument(&mut self) -> String {
+            self.skip_spaces();
+            match self.next() {
+                Some(Token::BeginGroup) => self.parse_row(Stop::EndGroup).0,
+                Some(token) => self.parse_atom(token).map(|i| i.mathml).unwrap_or_else(|| "<mrow></mrow>".to_string()),
+                None => "<mrow></mrow>".to_string(),
+            }
+        }
+
+        /// `\text{…}` などの引数を文字列のまま読む
+        fn read_group_text(&mut self) -> String {
+            self.skip_spaces();
+            let mut text = String::new();
+            match self.next() {
+                Some(Token::BeginGroup) => {
+                    let mut depth = 1;
+                    while let Some(token) = self.next() {
+                        match token {
+                            Token::BeginGroup => {
+                                depth += 1;
+                                text.push('{');
+                            }
+                            Token::EndGroup => {
+                                depth -= 1;
+                                if depth == 0 { break; }
+                                text.push('}');
+                            }
+                            token => text.push_str(&token_text(&token)),
+                        }
+                    }
+                }
+                Some(token) => text.push_str(&token_text(&token)),
+                None => {}
+            }
+            text
+        }
+
+        /// `\left` や `\big` に続く区切り文字を読む
+        fn read_delimiter(&mut self) -> Option<String> {
+            self.skip_spaces();
+            match self.next()? {
+                Token::Char('.') => None,
+                Token::Char(c) => Some(c.to_string()),
+                Token::Command(name) => Some(delimiter(&name).unwrap_or("").to_string()),
+                _ => None,
+            }
+        }
+
+        fn parse_with_variant(&mut self, variant: &'static str) -> String {
+            let outer = self.variant.replace(variant);
+            let arg = self.parse_argument();
+            self.variant = outer;
+            arg
+        }
+
+        fn mi(&self, s: &str) -> String {
+            match self.variant {
+                Some(variant) => format!("<mi mathvariant=\"{}\">{}</mi>", variant, escape(s, false)),
+                None => format!("<mi>{}</mi>", escape(s, false)),
+            }
+        }
+
+        fn parse_atom(&mut self, token: Token) -> Option<Item> {
+            Some(match token {
+                Token::Char(c) if c.is_ascii_digit() || c == '.' => {
+                    let mut number = c.to_string();
+                    while let Some(Token::Char(d)) = self.peek() {
+                        if d.is_ascii_digit() || (*d == '.' && number.chars().all(|c| c != '.')) {
+                            number.push(*d);
+                            self.pos += 1;
+                        } else { break; }
+                    }
+                    if number == "." {
+                        Item::new("<mo>.</mo>".to_string())
+                    } else {
+                        Item::new(format!("<mn>{}</mn>", number))
+                    }
+                }
+                Token::Char(c) if c.is_alphabetic() => Item::new(self.mi(&c.to_string())),
+                Token::Char('\'') => Item::new("<mo>\u{2032}</mo>".to_string()),
+                Token::Char('-') => Item::new("<mo>\u{2212}</mo>".to_string()),
+                Token::Char('~') => Item::new("<mspace width=\"0.333em\"></mspace>".to_string()),
+                Token::Char(c) => Item::new(format!("<mo>{}</mo>", escape(&c.to_string(), false))),
+                Token::BeginGroup => Item::new(self.parse_row(Stop::EndGroup).0),
+                Token::Command(name) => return self.parse_command(&name),
+                // 対応の取れない `}` や表の外の `&` は無視する
+                _ => return None,
+            })
+        }
+
+        fn parse_command(&mut self, name: &str) -> Option<Item> {
+            if let Some(c) = greek(name) {
+                return Some(Item::new(self.mi(&c.to_string())));
+            }
+            if let Some(c) = identifier(name) {
+                return Some(Item::new(format!("<mi>{}</mi>", c)));
+            }
+            if let Some(c) = operator(name) {
+                return Some(Item::new(format!("<mo>{}</mo>", escape(&c.to_string(), false))));
+            }
+            if let Some((c, limits)) = large_operator(name) {
+                return Some(Item { mathml: format!("<mo>{}</mo>", c), limits });
+            }
+            if let Some(limits) = function(name) {
+                return Some(Item { mathml: format!("<mi>{}</mi>", name), limits });
+            }
+            if let Some(accent) = accent(name) {
+                let base = self.parse_argument();
+                return Some(Item::new(format!("<mover accent=\"true\">{}<mo>{}</mo></mover>", base, accent)));
+            }
+            if let Some(variant) = variant(name) {
+                return Some(Item::new(self.parse_with_variant(variant)));
+            }
+            if let Some(width) = space(name) {
+                return Some(Item::new(format!("<mspace width=\"{}\"></mspace>", width)));
+            }
+
+            Some(Item::new(match name {
+                "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                    let numerator = self.parse_argument();
+                    let denominator = self.parse_argument();
+                    format!("<mfrac>{}{}</mfrac>", numerator, denominator)
+                }
+                "binom" | "dbinom" | "tbinom" => {
+                    let n = self.parse_argument();
+                    let k = self.parse_argument();
+                    format!("<mrow><mo>(</mo><mfrac linethickness=\"0\">{}{}</mfrac><mo>)</mo></mrow>", n, k)
+                }
+                "sqrt" => {
+                    self.skip_spaces();
+                    if self.peek() == Some(&Token::Char('[')) {
+                        self.pos += 1;
+                        let index = self.parse_row(Stop::Bracket).0;
+                        let radicand = self.parse_argument();
+                        format!("<mroot>{}{}</mroot>", radicand, index)
+                    } else {
+                        format!("<msqrt>{}</msqrt>", self.parse_argument())
+                    }
+                }
+                "overline" => format!("<mover accent=\"true\">{}<mo>\u{203E}</mo></mover>", self.parse_argument()),
+                "underline" => format!("<munder accentunder=\"true\">{}<mo>_</mo></munder>", self.parse_argument()),
+                "overbrace" => format!("<mover>{}<mo>\u{23DE}</mo></mover>", self.parse_argument()),
+                "underbrace" => format!("<munder>{}<mo>\u{23DF}</mo></munder>", self.parse_argument()),
+                "text" | "textrm" | "textnormal" | "mbox" | "textit" | "textbf" => {
+                    format!("<mtext>{}</mtext>", escape(&self.read_group_text(), false))
+                }
+                "operatorname" => {
+                    return Some(Item {
+                        mathml: format!("<mi mathvariant=\"normal\">{}</mi>", escape(&self.read_group_text(), false)),
+                        limits: false,
+                    });
+                }
+                "left" => {
+                    let open = self.read_delimiter();
+                    let (inner, _) = self.parse_row(Stop::Right);
+                    let close = self.read_delimiter();
+                    format!("<mrow>{}{}{}</mrow>", fence(open), inner, fence(close))
+                }
+                "big" | "Big" | "bigg" | "Bigg" | "bigl" | "bigr" | "Bigl" | "Bigr"
+                | "biggl" | "biggr" | "Biggl" | "Biggr" | "middle" => {
+                    match self.read_delimiter() {
+                        Some(delimiter) => format!("<mo>{}</mo>", escape(&delimiter, false)),
+                        None => return None,
+                    }
+                }
+                "begin" => {
+                    let env = self.read_group_text();
+                    self.parse_environment(&env)
+                }
+                "displaystyle" | "textstyle" | "scriptstyle" | "nonumber" | "notag" | "label" => {
+                    if name == "label" {
+                        self.read_group_text();
+                    }
+                    return None;
+                }
+                // 未対応のコマンドはソースのまま示す
+                name => format!("<merror><mtext>\\{}</mtext></merror>", escape(name, false)),
+            }))
+        }
+
+        fn parse_environment(&mut self, env: &str) -> String {
+            let env = env.trim_end_matches('*');
+            if env == "array" {
+                // 列の指定は読み飛ばす
+                self.read_group_text();
+            }
+
+            let rows = self.parse_rows(Stop::Cell);
+            let (open, close) = match env {
+                "pmatrix" => (Some("("), Some(")")),
+                "bmatrix" => (Some("["), Some("]")),
+                "Bmatrix" => (Some("{"), Some("}")),
+                "vmatrix" => (Some("|"), Some("|")),
+                "Vmatrix" => (Some("\u{2016}"), Some("\u{2016}")),
+                "cases" => (Some("{"), None),
+                _ => (None, None),
+            };
+            let align = match env {
+                "cases" => Some("left"),
+                "aligned" | "align" | "split" | "alignat" => Some("right left"),
+                _ => None,
+            };
+
+            let table = table(&rows, align);
+            if open.is_none() && close.is_none() {
+                table
+            } else {
+                format!(
+                    "<mrow>{}{}{}</mrow>",
+                    fence(open.map(|s| s.to_string())),
+                    table,
+                    fence(close.map(|s| s.to_string()))
+                )
+            }
+        }
+    }
+
+    fn mrow(items: Vec<String>) -> String {
+        if items.len() == 1 {
+            items.into_iter().next().unwrap()
+        } else {
+            format!("<mrow>{}</mrow>", items.join(""))
+        }
+    }
+
+    fn table(rows: &[Vec<String>], align: Option<&str>) -> String {
+        let rows = rows.iter()
+            .map(|row| {
+                let cells = row.iter().map(|cell| format!("<mtd>{}</mtd>", cell)).collect::<String>();
+                format!("<mtr>{}</mtr>", cells)
+            })
+            .collect::<String>();
+
+        match align {
+            Some(align) => format!("<mtable columnalign=\"{}\">{}</mtable>", align, rows),
+            None => format!("<mtable>{}</mtable>", rows),
+        }
+    }
+
+    fn fence(delimiter: Option<String>) -> String {
+        match delimiter {
+            Some(ref d) if !d.is_empty() => format!("<mo fence=\"true\" stretchy=\"true\">{}</mo>", escape(d, false)),
+            _ => String::new(),
+        }
+    }
+
+    fn token_text(token: &Token) -> String {
+        match token {
+            Token::Command(name) => match name.as_str() {
+                "{" | "}" | "$" | "%" | "&" | "#" | "_" | " " => name.clone(),
+                name => format!("\\{}", name),
+            },
+            Token::Char(c) => c.to_string(),
+            Token::Space => " ".to_string(),
+            Token::BeginGroup => "{".to_string(),
+            Token::EndGroup => "}".to_string(),
+            Token::Sup => "^".to_string(),
+            Token::Sub => "_".to_string(),
+            Token::Align => "&".to_string(),
+            Token::NewRow => "\\\\".to_string(),
+        }
+    }
+
+    fn greek(name: &str) -> Option<char> {
+        Some(match name {
+            "alpha" => 'α', "beta" => 'β', "gamma" => 'γ', "delta" => 'δ',
+            "epsilon" => 'ϵ', "varepsilon" => 'ε', "zeta" => 'ζ', "eta" => 'η',
+            "theta" => 'θ', "vartheta" => 'ϑ', "iota" => 'ι', "kappa" => 'κ',
+            "lambda" => 'λ', "mu" => 'μ', "nu" => 'ν', "xi" => 'ξ',
+            "pi" => 'π', "varpi" => 'ϖ', "rho" => 'ρ', "varrho" => 'ϱ',
+            "sigma" => 'σ', "varsigma" => 'ς', "tau" => 'τ', "upsilon" => 'υ',
+            "phi" => 'ϕ', "varphi" => 'φ', "chi" => 'χ', "psi" => 'ψ', "omega" => 'ω',
+            "Gamma" => 'Γ', "Delta" => 'Δ', "Theta" => 'Θ', "Lambda" => 'Λ',
+            "Xi" => 'Ξ', "Pi" => 'Π', "Sigma" => 'Σ', "Upsilon" => 'Υ',
+            "Phi" => 'Φ', "Psi" => 'Ψ', "Omega" => 'Ω',
+            _ => return None,
+        })
+    }
+
+    fn identifier(name: &str) -> Option<char> {
+        Some(match name {
+            "infty" => '∞', "partial" => '∂', "nabla" => '∇', "hbar" => 'ℏ',
+            "ell" => 'ℓ', "emptyset" | "varnothing" => '∅', "aleph" => 'ℵ',
+            "Re" => 'ℜ', "Im" => 'ℑ', "wp" => '℘', "angle" => '∠', "triangle" => '△',
+            "top" => '⊤', "bot" => '⊥',
+            _ => return None,
+        })
+    }
+
+    fn operator(name: &str) -> Option<char> {
+        Some(match name {
+            "pm" => '±', "mp" => '∓', "times" => '×', "div" => '÷', "cdot" => '⋅',
+            "ast" => '∗', "star" => '⋆', "circ" => '∘', "bullet" => '∙',
+            "oplus" => '⊕', "ominus" => '⊖', "otimes" => '⊗', "odot" => '⊙',
+            "leq" | "le" => '≤', "geq" | "ge" => '≥', "neq" | "ne" => '≠',
+            "ll" => '≪', "gg" => '≫', "approx" => '≈', "equiv" => '≡', "sim" => '∼',
+            "simeq" => '≃', "cong" => '≅', "propto" => '∝', "doteq" => '≐',
+            "in" => '∈', "notin" => '∉', "ni" => '∋', "subset" => '⊂', "supset" => '⊃',
+            "subseteq" => '⊆', "supseteq" => '⊇', "cup" => '∪', "cap" => '∩',
+            "setminus" => '∖', "wedge" | "land" => '∧', "vee" | "lor" => '∨', "neg" | "lnot" => '¬',
+            "forall" => '∀', "exists" => '∃', "nexists" => '∄',
+            "to" | "rightarrow" => '→', "leftarrow" | "gets" => '←', "leftrightarrow" => '↔',
+            "Rightarrow" | "implies" => '⇒', "Leftarrow" => '⇐', "Leftrightarrow" | "iff" => '⇔',
+            "mapsto" => '↦', "uparrow" => '↑', "downarrow" => '↓',
+            "longrightarrow" => '⟶', "longleftarrow" => '⟵', "Longrightarrow" => '⟹',
+            "ldots" | "dots" => '…', "cdots" => '⋯', "vdots" => '⋮', "ddots" => '⋱',
+            "mid" => '∣', "parallel" => '∥', "perp" => '⟂', "prime" => '′',
+            "langle" => '⟨', "rangle" => '⟩', "lfloor" => '⌊', "rfloor" => '⌋',
+            "lceil" => '⌈', "rceil" => '⌉', "vert" | "lvert" | "rvert" => '|',
+            "Vert" | "lVert" | "rVert" | "|" => '‖',
+            "{" | "lbrace" => '{', "}" | "rbrace" => '}', "$" => '$', "%" => '%',
+            "&" => '&', "#" => '#', "_" => '_',
+            "colon" => ':', "backslash" => '\\', "therefore" => '∴', "because" => '∵',
+            _ => return None,
+        })
+    }
+
+    /// 大型演算子 (文字, display 時に上下へ添字を付けるか)
+    fn large_operator(name: &str) -> Option<(char, bool)> {
+        Some(match name {
+            "sum" => ('∑', true), "prod" => ('∏', true), "coprod" => ('∐', true),
+            "bigcup" => ('⋃', true), "bigcap" => ('⋂', true), "bigoplus" => ('⨁', true),
+            "bigotimes" => ('⨂', true), "bigvee" => ('⋁', true), "bigwedge" => ('⋀', true),
+            "int" => ('∫', false), "iint" => ('∬', false), "iiint" => ('∭', false),
+            "oint" => ('∮', false),
+            _ => return None,
+        })
+    }
+
+    /// 関数名 (display 時に上下へ添字を付けるか)
+    fn function(name: &str) -> Option<bool> {
+        Some(match name {
+            "lim" | "limsup" | "liminf" | "max" | "min" | "sup" | "inf" | "det" | "gcd" | "Pr" | "argmax" | "argmin" => true,
+            "sin" | "cos" | "tan" | "cot" | "sec" | "csc" | "arcsin" | "arccos" | "arctan"
+            | "sinh" | "cosh" | "tanh" | "coth" | "log" | "ln" | "lg" | "exp" | "arg" | "deg"
+            | "dim" | "hom" | "ker" | "mod" | "bmod" => false,
+            _ => return None,
+        })
+    }
+
+    fn accent(name: &str) -> Option<char> {
+        Some(match name {
+            "hat" | "widehat" => '^',
+            "bar" => '¯',
+            "vec" | "overrightarrow" => '→',
+            "dot" => '˙',
+            "ddot" => '¨',
+            "tilde" | "widetilde" => '~',
+            "check" => 'ˇ',
+            "breve" => '˘',
+            "acute" => '´',
+            "grave" => '`',
+            _ => return None,
+        })
+    }
+
+    fn variant(name: &str) -> Option<&'static str> {
+        Some(match name {
+            "mathrm" => "normal",
+            "mathit" => "italic",
+            "mathbf" | "boldsymbol" | "bm" => "bold",
+            "mathbb" => "double-struck",
+            "mathcal" => "script",
+            "mathfrak" => "fraktur",
+            "mathsf" => "sans-serif",
+            "mathtt" => "monospace",
+            _ => return None,
+        })
+    }
+
+    fn space(name: &str) -> Option<&'static str> {
+        Some(match name {
+            "," | "thinspace" => "0.167em",
+            ":" | ">" | "medspace" => "0.222em",
+            ";" | "thickspace" => "0.278em",
+            "!" | "negthinspace" => "-0.167em",
+            " " => "0.333em",
+            "quad" => "1em",
+            "qquad" => "2em",
+            _ => return None,
+        })
+    }
+
+    fn delimiter(name: &str) -> Option<&'static str> {
+        Some(match name {
+            "{" | "lbrace" => "{",
+            "}" | "rbrace" => "}",
+            "langle" => "⟨",
+            "rangle" => "⟩",
+            "lfloor" => "⌊",
+            "rfloor" => "⌋",
+            "lceil" => "⌈",
+            "rceil" => "⌉",
+            "vert" | "lvert" | "rvert" => "|",
+            "|" | "Vert" | "lVert" | "rVert" => "‖",
+            "backslash" => "\\",
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn extract_skips_code() {
+        let md = "価格は \\$5 で, $x^2$ と `$y$`\n\n```\n$z$\n```\n\n$$\n\\frac{a}{b}\n$$\n";
+        let (replaced, formulas) = extract(md);
+
+        assert_eq!(formulas.len(), 2);
+        assert_eq!(formulas[0], Formula { tex: "x^2".to_string(), display: false });
+        assert!(formulas[1].display);
+        assert!(replaced.contains("`$y$`"));
+        assert!(replaced.contains("```\n$z$\n```"));
+        assert!(replaced.contains("\\$5"));
+
+        // インデントされたコードブロックも数式として扱わない. リストの中の段落は対象外
+        let md = "text\n\n    $a$ and $b$\n\n    $c$\nafter $d$\n\n- item\n\n    $e$\n";
+        let (replaced, formulas) = extract(md);
+        assert_eq!(formulas.iter().map(|f| f.tex.as_str()).collect::<Vec<_>>(), vec!["d", "e"]);
+        assert!(replaced.contains("    $a$ and $b$\n\n    $c$\n"));
+    }
+
+    #[test]
+    fn dollar_amounts_are_not_math() {
+        let (_, formulas) = extract("$5 and $10 each");
+        assert!(formulas.is_empty());
+    }
+
+    #[test]
+    fn to_mathml() {
+        let formula = Formula { tex: "\\frac{1}{2} + x_i^2".to_string(), display: false };
+        assert_eq!(
+            formula.to_mathml(),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\" display=\"inline\" alttext=\"\\frac{1}{2} + x_i^2\">\
+            <mrow><mfrac><mn>1</mn><mn>2</mn></mfrac><mo>+</mo><msubsup><mi>x</mi><mi>i</mi><mn>2</mn></msubsup></mrow></math>"
+        );
+
+        let formula = Formula { tex: "\\sum_{k=1}^{n} k".to_string(), display: true };
+        assert!(formula.to_mathml().contains("<munderover><mo>∑</mo>"));
+    }
+
+    #[test]
+    fn restore() {
+        let (replaced, formulas) = extract("a $b$ c");
+        let html = super::restore(&replaced, &formulas);
+        assert!(html.starts_with("a <math"));
+        assert!(html.ends_with("</math> c"));
+    }
+}
@@ -0,0 +1,255 @@
+//! mdBook のプロジェクト (`book.toml`, `SUMMARY.md`) の読み込み
+
+use crate::prelude::*;
+use std::path::Path;
+
+const BOOK_TOML: &str = "book.toml";
+const SUMMARY_MD: &str = "SUMMARY.md";
+
+/// mdBook のプロジェクト
+#[derive(Debug, Clone, Default)]
+pub struct Book {
+    /// markdown を格納するディレクトリ (`book.toml`の`book.src`)
+    pub src: PathBuf,
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub language: Option<String>,
+    pub summary: Option<Summary>,
+    /// 本の設定やビルド用のファイルなど, 収録しないファイル
+    pub ignores: Vec<PathBuf>,
+}
+
+/// `book.toml` のうち, repub が利用する項目
+#[derive(Debug, Default, Deserialize)]
+struct BookToml {
+    #[serde(default)]
+    book: BookSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct BookSection {
+    title: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    language: Option<String>,
+    src: Option<PathBuf>,
+}
+
+impl Book {
+    /// target が`book.toml`か`SUMMARY.md`を含むディレクトリであれば読み込む
+    pub fn load(target: &Path) -> Option<Self> {
+        if !target.is_dir() {
+            return None;
+        }
+
+        let book_toml = target.join(BOOK_TOML);
+        let mut book = if book_toml.is_file() {
+            let section = match std::fs::read_to_string(&book_toml)
+                .map_err(failure::Error::from)
+                .and_then(|s| toml::from_str::<BookToml>(&s).map_err(failure::Error::from)) {
+                Ok(toml) => toml.book,
+                Err(e) => {
+                    RepubWarning(format!("{:?} {}", &book_toml, &e)).print();
+                    BookSection::default()
+                }
+            };
+
+            Self {
+                src: target.join(section.src.unwrap_or_else(|| PathBuf::from("src"))),
+                title: section.title,
+                authors: section.authors,
+                language: section.language,
+                summary: None,
+                ignores: Vec::new(),
+            }
+        } else if target.join(SUMMARY_MD).is_file() {
+            Self { src: target.to_path_buf(), ..Self::default() }
+        } else {
+            return None;
+        };
+
+        let summary_md = book.src.join(SUMMARY_MD);
+        if let Ok(summary) = std::fs::read_to_string(&summary_md) {
+            book.summary = Some(Summary::parse(&summary));
+        }
+
+        Some(book)
+    }
+}
+
+/// `SUMMARY.md` の項目
+#[derive(Debug, Clone, PartialEq)]
+pub struct SummaryItem {
+    pub title: String,
+    /// `src`からの相対パス. 部の見出しは None
+    pub path: Option<PathBuf>,
+    /// 目次の階層 (1 から始まる)
+    pub level: u8,
+}
+
+/// `SUMMARY.md` から読み取った本の構成
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub items: Vec<SummaryItem>,
+}
+
+impl Summary {
+    /// 下書き (`[Title]()`) と区切り線 (`---`) は読み飛ばす
+    pub fn parse(markdown: &str) -> Self {
+        let mut items = Vec::new();
+        // 最初の見出しは SUMMARY 自体のタイトル
+        let mut seen_title = false;
+        // 部の中にいるか否か
+        let mut in_part = false;
+        // 入れ子になったリストのインデント
+        let mut indents: Vec<usize> = Vec::new();
+
+        for line in markdown.lines() {
+            let trimmed = line.trim_start();
+            let indent = line.len() - trimmed.len();
+            let trimmed = trimmed.trim_end();
+
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            if let Some(title) = trimmed.strip_prefix('#') {
+                let title = title.trim_start_matches('#').trim();
+                if seen_title || !items.is_empty() {
+                    items.push(SummaryItem { title: title.to_string(), path: None, level: 1 });
+                    in_part = true;
+                }
+                seen_title = true;
+                indents.clear();
+                continue;
+            }
+
+            let is_separator = trimmed.len() >= 3
+                && ['-', '*', '_'].iter().any(|c| trimmed.chars().all(|d| d == *c));
+            if is_separator {
+                // 区切り線の後は部の外 (後付け)
+                in_part = false;
+                indents.clear();
+                continue;
+            }
+
+            let (link, depth) = match list_item(trimmed) {
+                Some(link) => {
+                    while indents.last().is_some_and(|i| *i >= indent) {
+                        indents.pop();
+                    }
+                    let depth = indents.len();
+                    indents.push(indent);
+                    (link, depth)
+                }
+                None => {
+                    indents.clear();
+                    (trimmed, 0)
+                }
+            };
+
+            if let Some((title, dest)) = parse_link(link) {
+                // 下書き
+                if dest.is_empty() {
+                    continue;
+                }
+                let level = depth as u8 + if in_part { 2 } else { 1 };
+                items.push(SummaryItem { title, path: Some(PathBuf::from(dest)), level });
+            }
+        }
+
+        Self { items }
+    }
+
+    /// 章のファイルを`SUMMARY.md`の順に並べたもの
+    pub fn sequence(&self) -> Vec<PathBuf> {
+        let mut sequence: Vec<PathBuf> = Vec::new();
+        for path in self.items.iter().flat_map(|i| i.path.as_ref()) {
+            if !sequence.contains(path) {
+                sequence.push(path.clone());
+            }
+        }
+        sequence
+    }
+}
+
+/// `- `, `* `, `1. ` などのリストの記号を取り除く
+fn list_item(line: &str) -> Option<&str> {
+    if let Some(rest) = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")).or_else(|| line.strip_prefix("+ ")) {
+        return Some(rest.trim_start());
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        if let Some(rest) = line[digits..].strip_prefix(". ") {
+            return Some(rest.trim_start());
+        }
+    }
+    None
+}
+
+/// `[title](dest)` を title と dest に分ける
+fn parse_link(s: &str) -> Option<(String, String)> {
+    let s = s.strip_prefix('[')?.strip_suffix(')')?;
+    let middle = s.rfind("](")?;
+    let title = s[..middle].replace('\\', "");
+    let dest = s[middle + 2..].trim();
+    // 章の中の位置を指すことはできない
+    let dest = dest.split('#').next().unwrap_or_default();
+
+    Some((title, percent_decode(dest)))
+}
+
+/// `%20` などを元の文字に戻す
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            if let Some(b) = hex {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+#[test]
+fn test_summary() {
+    let summary = Summary::parse(r#"# Summary
+
+[Introduction](README.md)
+
+# User Guide
+
+- [Installation](guide/installation.md)
+    - [Reading Books](guide/reading%20books.md)
+    - [Draft]()
+- [Creating a Book](guide/creating.md)
+
+---
+
+[Contributors](misc/contributors.md)
+"#);
+
+    let item = |title: &str, path: Option<&str>, level| SummaryItem {
+        title: title.to_string(),
+        path: path.map(PathBuf::from),
+        level,
+    };
+    assert_eq!(summary.items, vec![
+        item("Introduction", Some("README.md"), 1),
+        item("User Guide", None, 1),
+        item("Installation", Some("guide/installation.md"), 2),
+        item("Reading Books", Some("guide/reading books.md"), 3),
+        item("Creating a Book", Some("guide/creating.md"), 2),
+        item("Contributors", Some("misc/contributors.md"), 1),
+    ]);
+    assert_eq!(summary.sequence().len(), 5);
+}
@@ -0,0 +1,415 @@
+//! Jupyter notebook (`.ipynb`) の変換
+//!
+//! markdown のセルと, コードのセルを fenced code block にしたものをつなげて一つの markdown とし,
+//! `.md` と同じく comrak で変換する. セルの出力は目印の段落に置き換えておき, 変換後に元に戻す
+
+use crate::{prelude::*, data::EmbeddedImage, xhtml};
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::Value;
+
+/// 出力の位置を示す目印. 私用領域の文字なので, 本文と衝突しない
+const MARKER_BEGIN: char = '\u{E004}';
+const MARKER_END: char = '\u{E005}';
+
+/// 出力の MIME type と, 画像として書き出す場合の拡張子. 先にあるものを優先する
+const IMAGE_TYPES: [(&str, &str); 4] = [
+    ("image/svg+xml", "svg"),
+    ("image/png", "png"),
+    ("image/jpeg", "jpg"),
+    ("image/gif", "gif"),
+];
+
+/// 入力や出力を隠すセルのタグ (Jupyter Book と同じもの)
+const REMOVE_CELL: [&str; 1] = ["remove-cell"];
+const REMOVE_INPUT: [&str; 2] = ["remove-input", "hide-input"];
+const REMOVE_OUTPUT: [&str; 2] = ["remove-output", "hide-output"];
+
+#[derive(Debug, Deserialize)]
+struct Notebook {
+    cells: Vec<Cell>,
+    #[serde(default)]
+    metadata: NotebookMetadata,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct NotebookMetadata {
+    language_info: Option<LanguageInfo>,
+    kernelspec: Option<KernelSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LanguageInfo {
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KernelSpec {
+    language: Option<String>,
+}
+
+/// 文字列, または行ごとに分けた文字列の配列
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum MultilineString {
+    One(String),
+    Lines(Vec<String>),
+}
+
+impl MultilineString {
+    fn joined(&self) -> String {
+        match self {
+            MultilineString::One(s) => s.clone(),
+            MultilineString::Lines(lines) => lines.concat(),
+        }
+    }
+}
+
+impl Default for MultilineString {
+    fn default() -> Self {
+        MultilineString::One(String::new())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Cell {
+    cell_type: String,
+    #[serde(default)]
+    source: MultilineString,
+    #[serde(default)]
+    outputs: Vec<Output>,
+    #[serde(default)]
+    metadata: CellMetadata,
+    /// markdown のセルに添付された画像 (`attachment:image.png` で参照する)
+    #[serde(default)]
+    attachments: BTreeMap<String, HashMap<String, Value>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CellMetadata {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    jupyter: JupyterMetadata,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct JupyterMetadata {
+    #[serde(default)]
+    source_hidden: bool,
+    #[serde(default)]
+    outputs_hidden: bool,
+}
+
+impl CellMetadata {
+    fn has_tag(&self, tags: &[&str]) -> bool {
+        self.tags.iter().any(|t| tags.contains(&t.as_str()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Output {
+    output_type: String,
+    /// stream の名前 (`stdout`, `stderr`)
+    name: Option<String>,
+    text: Option<MultilineString>,
+    /// MIME type ごとの出力
+    #[serde(default)]
+    data: HashMap<String, Value>,
+    #[serde(default)]
+    traceback: Vec<String>,
+}
+
+/// 入力と出力のどちらを表示するか
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub hide_input: bool,
+    pub hide_output: bool,
+}
+
+/// 変換の途中の notebook
+#[derive(Debug)]
+pub struct Converted {
+    /// セルをつなげた markdown. 出力は目印の段落になっている
+    pub markdown: String,
+    /// 出力の XHTML
+    pub outputs: Vec<String>,
+    /// セルの出力やセルの添付ファイルから取り出した画像
+    pub images: Vec<EmbeddedImage>,
+}
+
+/// `.ipynb` の内容を markdown と出力に分ける
+/// stem は変換後のファイル名から拡張子を除いたもので, 画像は`<stem>_files`ディレクトリに置く
+pub fn convert(json: &str, stem: &str, options: Options) -> RepubResult<Converted> {
+    let notebook = serde_json::from_str::<Notebook>(json)?;
+    let language = notebook.metadata.language_info.as_ref().and_then(|l| l.name.clone())
+        .or_else(|| notebook.metadata.kernelspec.as_ref().and_then(|k| k.language.clone()))
+        .unwrap_or_default();
+    let images_dir = PathBuf::from(format!("{}_files", stem));
+
+    let mut converted = Converted { markdown: String::new(), outputs: Vec::new(), images: Vec::new() };
+    for (n, cell) in notebook.cells.iter().enumerate() {
+        if cell.metadata.has_tag(&REMOVE_CELL) {
+            continue;
+        }
+
+        match cell.cell_type.as_str() {
+            "markdown" => {
+                let mut source = cell.source.joined();
+                // 添付された画像を書き出し, 参照を書き換える
+                for (name, data) in &cell.attachments {
+                    // 添付ファイルの名前は, ディレクトリを除いたものだけを使う
+                    let file_name = match Path::new(name).file_name() {
+                        Some(file_name) => file_name.to_string_lossy(),
+                        None => continue,
+                    };
+                    let image = IMAGE_TYPES.iter()
+                        .find_map(|(mime, _)| data.get(*mime).and_then(|d| decode(mime, d)));
+                    if let Some(data) = image {
+                        let path = images_dir.join(format!("attachment_{}_{}", n, file_name));
+                        source = source.replace(&format!("attachment:{}", name), &path.to_string_lossy());
+                        converted.images.push(EmbeddedImage { path, data });
+                    }
+                }
+                converted.markdown.push_str(&source);
+                converted.markdown.push_str("\n\n");
+            }
+            "code" => {
+                let hide_input = options.hide_input || cell.metadata.jupyter.source_hidden
+                    || cell.metadata.has_tag(&REMOVE_INPUT);
+                let hide_output = options.hide_output || cell.metadata.jupyter.outputs_hidden
+                    || cell.metadata.has_tag(&REMOVE_OUTPUT);
+
+                let source = cell.source.joined();
+                if !hide_input && !source.trim().is_empty() {
+                    converted.markdown.push_str(&fenced_code(&source, &language));
+                }
+                if hide_output {
+                    continue;
+                }
+                for (m, output) in cell.outputs.iter().enumerate() {
+                    let html = match render_output(output, &images_dir, &format!("output_{}_{}", n, m), &mut converted.images) {
+                        Some(html) => html,
+                        None => continue,
+                    };
+                    converted.markdown.push_str(&format!("{}{}{}\n\n", MARKER_BEGIN, converted.outputs.len(), MARKER_END));
+                    converted.outputs.push(html);
+                }
+            }
+            // raw のセルは, そのまま出力に含める
+            "raw" => {
+                converted.markdown.push_str(&format!("{}{}{}\n\n", MARKER_BEGIN, converted.outputs.len(), MARKER_END));
+                converted.outputs.push(cell.source.joined());
+            }
+            _ => {}
+        }
+    }
+
+    Ok(converted)
+}
+
+/// source を囲むのに十分な長さの fence で, fenced code block にする
+fn fenced_code(source: &str, language: &str) -> String {
+    let longest = source.split(|c| c != '`').map(|s| s.len()).max().unwrap_or(0);
+    let fence = "`".repeat(std::cmp::max(3, longest + 1));
+    format!("{}{}\n{}\n{}\n\n", fence, language, source.trim_end_matches('\n'), fence)
+}
+
+/// base64 で記録された画像. SVG は文字列のまま記録されている
+fn decode(mime: &str, data: &Value) -> Option<Vec<u8>> {
+    let data = match data {
+        Value::String(s) => s.clone(),
+        Value::Array(lines) => lines.iter().filter_map(Value::as_str).collect(),
+        _ => return None,
+    };
+    if mime == "image/svg+xml" {
+        Some(data.into_bytes())
+    } else {
+        STANDARD.decode(data.split_whitespace().collect::<String>()).ok()
+    }
+}
+
+fn text(data: &Value) -> Option<String> {
+    match data {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(lines) => Some(lines.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    }
+}
+
+/// 出力を XHTML にする. 画像は images に加える
+fn render_output(output: &Output, images_dir: &Path, name: &str, images: &mut Vec<EmbeddedImage>) -> Option<String> {
+    match output.output_type.as_str() {
+        "stream" => {
+            let text = output.text.as_ref()?.joined();
+            let class = match output.name.as_deref() {
+                Some("stderr") => "stderr",
+                _ => "stdout",
+            };
+            Some(format!("<pre class=\"output {}\">{}</pre>", class, xhtml::escape_text(text.trim_end_matches('\n'))))
+        }
+        "execute_result" | "display_data" => {
+            let image = IMAGE_TYPES.iter()
+                .find_map(|(mime, ext)| Some((decode(mime, output.data.get(*mime)?)?, ext)));
+            if let Some((data, ext)) = image {
+                let path = images_dir.join(format!("{}.{}", name, ext));
+                let alt = output.data.get("text/plain").and_then(text).unwrap_or_default();
+                let html = format!(
+                    "<div class=\"output\"><img src=\"{}\" alt=\"{}\" /></div>",
+                    xhtml::escape_attr(&path.to_string_lossy()), xhtml::escape_attr(alt.trim())
+                );
+                images.push(EmbeddedImage { path, data });
+                return Some(html);
+            }
+            if let Some(html) = output.data.get("text/html").and_then(text) {
+                return Some(format!("<div class=\"output\">{}</div>", strip_elements(&html, &["script", "style"])));
+            }
+            let plain = output.data.get("text/plain").and_then(text)?;
+            Some(format!("<pre class=\"output\">{}</pre>", xhtml::escape_text(plain.trim_end_matches('\n'))))
+        }
+        "error" => {
+            let traceback = output.traceback.iter().map(|l| strip_ansi(l)).collect::<Vec<String>>().join("\n");
+            Some(format!("<pre class=\"output error\">{}</pre>", xhtml::escape_text(&traceback)))
+        }
+        _ => None,
+    }
+}
+
+/// `<script>` など, 電子書籍では動かない要素を取り除く
+fn strip_elements(html: &str, names: &[&str]) -> String {
+    let mut html = html.to_string();
+    for name in names {
+        let (open, close) = (format!("<{}", name), format!("</{}>", name));
+        while let Some(begin) = html.find(&open) {
+            let end = match html[begin..].find(&close) {
+                Some(end) => begin + end + close.len(),
+                None => html.len(),
+            };
+            html.replace_range(begin..end, "");
+        }
+    }
+    html
+}
+
+/// 端末の色などのエスケープシーケンスを取り除く
+fn strip_ansi(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1B}' {
+            // `ESC [ … 英字`
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        stripped.push(c);
+    }
+    stripped
+}
+
+/// 目印の段落を出力に置き換える
+pub fn restore(html: &str, outputs: &[String]) -> String {
+    let mut restored = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(begin) = rest.find(MARKER_BEGIN) {
+        let after = &rest[begin + MARKER_BEGIN.len_utf8()..];
+        let output = after.find(MARKER_END)
+            .and_then(|end| Some((after[..end].parse::<usize>().ok()?, end)))
+            .and_then(|(index, end)| Some((outputs.get(index)?, end)));
+        match output {
+            Some((output, end)) => {
+                // 目印だけの段落は, 段落ごと置き換える
+                let before = &rest[..begin];
+                let after = &after[end + MARKER_END.len_utf8()..];
+                let (before, after) = match (before.strip_suffix("<p>"), after.strip_prefix("</p>")) {
+                    (Some(before), Some(after)) => (before, after),
+                    _ => (before, after),
+                };
+                restored.push_str(before);
+                restored.push_str(output);
+                rest = after;
+            }
+            None => {
+                restored.push_str(&rest[..begin + MARKER_BEGIN.len_utf8()]);
+                rest = after;
+            }
+        }
+    }
+    restored.push_str(rest);
+
+    restored
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+ "cells": [
+  {"cell_type": "markdown", "metadata": {}, "source": ["# Title\n", "\n", "![plot](attachment:a.png)"],
+   "attachments": {"a.png": {"image/png": "iVBORw0KGgo="}}},
+  {"cell_type": "code", "metadata": {}, "execution_count": 1, "source": "print('a < b')",
+   "outputs": [{"output_type": "stream", "name": "stdout", "text": ["a < b\n"]}]},
+  {"cell_type": "code", "metadata": {"tags": ["remove-input"]}, "execution_count": 2, "source": "plot()",
+   "outputs": [{"output_type": "display_data", "metadata": {}, "data": {"image/png": "iVBORw0KGgo=\n", "text/plain": ["<Figure>"]}},
+               {"output_type": "error", "ename": "E", "evalue": "", "traceback": ["\u001b[0;31mError\u001b[0m"]}]},
+  {"cell_type": "code", "metadata": {}, "source": "df", "outputs": [
+    {"output_type": "execute_result", "metadata": {}, "execution_count": 3,
+     "data": {"text/html": "<style scoped>td {}</style><table><tr><td>1</td></tr></table>", "text/plain": "1"}}]}
+ ],
+ "metadata": {"language_info": {"name": "python"}},
+ "nbformat": 4, "nbformat_minor": 5
+}"##;
+
+    #[test]
+    fn notebook() {
+        let converted = convert(NOTEBOOK, "nb", Options::default()).unwrap();
+        assert_eq!(
+            converted.markdown,
+            "# Title\n\n![plot](nb_files/attachment_0_a.png)\n\n```python\nprint('a < b')\n```\n\n\u{E004}0\u{E005}\n\n\
+             \u{E004}1\u{E005}\n\n\u{E004}2\u{E005}\n\n```python\ndf\n```\n\n\u{E004}3\u{E005}\n\n"
+        );
+        assert_eq!(converted.outputs, vec![
+            "<pre class=\"output stdout\">a &lt; b</pre>",
+            "<div class=\"output\"><img src=\"nb_files/output_2_0.png\" alt=\"&lt;Figure&gt;\" /></div>",
+            "<pre class=\"output error\">Error</pre>",
+            "<div class=\"output\"><table><tr><td>1</td></tr></table></div>",
+        ]);
+        assert_eq!(converted.images.iter().map(|i| i.path.clone()).collect::<Vec<PathBuf>>(), vec![
+            PathBuf::from("nb_files/attachment_0_a.png"),
+            PathBuf::from("nb_files/output_2_0.png"),
+        ]);
+        assert_eq!(&converted.images[1].data[..4], b"\x89PNG");
+
+        let hidden = convert(NOTEBOOK, "nb", Options { hide_input: true, hide_output: true }).unwrap();
+        assert_eq!(hidden.markdown, "# Title\n\n![plot](nb_files/attachment_0_a.png)\n\n");
+
+        assert_eq!(
+            restore("<p>text</p>\n<p>\u{E004}0\u{E005}</p>\n", &["<pre>out</pre>".to_string()]),
+            "<p>text</p>\n<pre>out</pre>\n"
+        );
+    }
+
+    #[test]
+    fn attachment_traversal() {
+        let json = r#"{
+ "cells": [
+  {"cell_type": "markdown", "metadata": {}, "source": "![a](attachment:../../x.png) ![b](attachment:..)",
+   "attachments": {"../../x.png": {"image/png": "iVBORw0KGgo="}, "..": {"image/png": "iVBORw0KGgo="}}}
+ ],
+ "metadata": {}, "nbformat": 4, "nbformat_minor": 5
+}"#;
+        let converted = convert(json, "nb", Options::default()).unwrap();
+        assert_eq!(converted.markdown, "![a](nb_files/attachment_0_x.png) ![b](attachment:..)\n\n");
+        assert_eq!(converted.images.iter().map(|i| i.path.clone()).collect::<Vec<PathBuf>>(), vec![
+            PathBuf::from("nb_files/attachment_0_x.png"),
+        ]);
+    }
+}
@@ -0,0 +1,130 @@
+//! コンテンツの並び順と, ファイル名の先頭の番号
+//!
+//! ファイル名に含まれる数字は数値として比較する (`2_start.md` < `10_end.md`). 全角数字も同様に扱う
+
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+/// 数字であれば, その値を返す. 全角数字も含む
+fn digit(c: char) -> Option<u32> {
+    match c {
+        '0'..='9' => Some(c as u32 - '0' as u32),
+        '０'..='９' => Some(c as u32 - '０' as u32),
+        _ => None,
+    }
+}
+
+/// 文字列を, 数字の連続とそれ以外の文字に分けながら比較する
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+
+    loop {
+        let (x, y) = match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => (*x, *y),
+        };
+
+        if digit(x).is_some() && digit(y).is_some() {
+            // 先頭の 0 を除いた桁数, 各桁の値の順に比べる
+            let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                let mut digits = Vec::new();
+                while let Some(d) = chars.peek().and_then(|c| digit(*c)) {
+                    digits.push(d);
+                    chars.next();
+                }
+                let zeros = digits.iter().take_while(|d| **d == 0).count();
+                digits.split_off(zeros)
+            };
+            let (m, n) = (take_number(&mut a_chars), take_number(&mut b_chars));
+            match m.len().cmp(&n.len()).then_with(|| m.cmp(&n)) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+
+        // 全角数字も半角数字と同じく, 数字以外の文字より前にする
+        let ordering = digit(y).is_some().cmp(&digit(x).is_some()).then_with(|| x.cmp(&y));
+        match ordering {
+            Ordering::Equal => {
+                a_chars.next();
+                b_chars.next();
+            }
+            ordering => return ordering,
+        }
+    }
+
+    // `01` と `1` のように数値として等しい場合は, 元の文字列の順にする
+    a.cmp(b)
+}
+
+/// パスをディレクトリごとに自然順で比較する
+pub fn natural_cmp_path(a: &Path, b: &Path) -> Ordering {
+    let mut a_components = a.components();
+    let mut b_components = b.components();
+
+    loop {
+        match (a_components.next(), b_components.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => {
+                let ordering = natural_cmp(&x.as_os_str().to_string_lossy(), &y.as_os_str().to_string_lossy());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+/// `01_intro.md` の`01_`のような, 並び順のための番号を取り除く
+/// 番号の後には`_`, `-`, `.`, 空白のいずれかが必要. 番号を取り除いて何も残らない場合はそのまま返す
+pub fn strip_number_prefix(file_name: &str) -> &str {
+    let number = file_name.char_indices()
+        .find(|(_, c)| digit(*c).is_none())
+        .map(|(i, _)| i)
+        .unwrap_or(file_name.len());
+    if number == 0 {
+        return file_name;
+    }
+
+    let rest = &file_name[number..];
+    let separator = match rest.chars().next() {
+        Some(c @ ('_' | '-' | '.' | ' ' | '　')) => c,
+        _ => return file_name,
+    };
+    let stripped = &rest[separator.len_utf8()..];
+    // `10.md` を`md`としないように, 拡張子は残っている必要がある
+    let keeps_extension = Path::new(stripped).extension() == Path::new(file_name).extension();
+    if stripped.is_empty() || stripped.starts_with('.') || !keeps_extension {
+        file_name
+    } else {
+        stripped
+    }
+}
+
+/// path のファイル名から番号を取り除く
+pub fn strip_number_prefix_path(path: &Path) -> PathBuf {
+    match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => path.with_file_name(strip_number_prefix(name)),
+        None => path.to_path_buf(),
+    }
+}
+
+#[test]
+fn test_order() {
+    let mut names = vec!["10_end.md", "2_start.md", "１１_全角.md", "02_zero.md", "a.md", "1_first.md"];
+    names.sort_by(|a, b| natural_cmp(a, b));
+    assert_eq!(names, vec!["1_first.md", "2_start.md", "02_zero.md", "10_end.md", "１１_全角.md", "a.md"]);
+
+    assert_eq!(natural_cmp_path(Path::new("ch2/10.md"), Path::new("ch10/1.md")), Ordering::Less);
+
+    assert_eq!(strip_number_prefix("01_intro.md"), "intro.md");
+    assert_eq!(strip_number_prefix("０３－章.md"), "０３－章.md");
+    assert_eq!(strip_number_prefix("２ 章.md"), "章.md");
+    assert_eq!(strip_number_prefix("10.md"), "10.md");
+    assert_eq!(strip_number_prefix("1_.md"), "1_.md");
+    assert_eq!(strip_number_prefix("intro.md"), "intro.md");
+}
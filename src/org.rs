@@ -0,0 +1,834 @@
+//! Org (`.org`) の変換
+//!
+//! 見出し, 強調, 箇条書き, 表, リンク, ソースブロック, 脚注と, `#+TITLE`などのキーワードを解釈する.
+//! `#+TITLE`は本文に出力せず, コンテンツのタイトルとする. 見出し`*`は`<h1>`になる
+
+use crate::{prelude::*, mdbook::Book, load::Source, data::ContentSource, math::Formula, xhtml};
+use std::{collections::HashMap, path::Path};
+
+/// `#+TITLE`, `#+AUTHOR`, `#+LANGUAGE`
+#[derive(Debug, Default, PartialEq)]
+pub struct Keywords {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub language: Option<String>,
+}
+
+/// 変換した Org 文書
+#[derive(Debug)]
+pub struct OrgDocument {
+    pub keywords: Keywords,
+    pub body: String,
+}
+
+/// 書き出さない見出しのタグ
+const NOEXPORT: &str = "noexport";
+/// 見出しの TODO キーワード
+const TODO_KEYWORDS: [&str; 2] = ["TODO", "DONE"];
+/// 画像として表示するリンクの拡張子
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+/// `#+KEY: value`
+fn keyword(line: &str) -> Option<(String, &str)> {
+    let rest = line.trim_start().strip_prefix("#+")?;
+    let colon = rest.find(':')?;
+    let key = &rest[..colon];
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((key.to_uppercase(), rest[colon + 1..].trim()))
+}
+
+/// `#+BEGIN_SRC rust` の`src`と`rust`
+fn block_begin(line: &str) -> Option<(String, &str)> {
+    let rest = line.trim_start();
+    if !rest.get(..8)?.eq_ignore_ascii_case("#+begin_") {
+        return None;
+    }
+    let rest = &rest[8..];
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    Some((rest[..end].to_lowercase(), rest[end..].trim()))
+}
+
+fn is_block_end(line: &str, name: &str) -> bool {
+    let line = line.trim();
+    line.len() == 6 + name.len() && line[..6].eq_ignore_ascii_case("#+end_") && line[6..].eq_ignore_ascii_case(name)
+}
+
+/// 文書のキーワードを読み取る
+pub fn keywords(source: &str) -> Keywords {
+    let mut keywords = Keywords::default();
+    for (key, value) in source.lines().filter_map(keyword) {
+        let value = Some(value.to_string()).filter(|v| !v.is_empty());
+        match key.as_str() {
+            "TITLE" => keywords.title = value,
+            "AUTHOR" => keywords.author = value,
+            "LANGUAGE" => keywords.language = value,
+            _ => {}
+        }
+    }
+    keywords
+}
+
+/// 変換対象が Org ファイルひとつだけであれば, そのキーワードを本の設定の既定値とする
+pub fn load(target: &Path) -> Option<Book> {
+    let sources = Source::try_from_path_buf(&target.to_path_buf(), false, &[]).ok()?
+        .into_iter()
+        .filter_map(|s| ContentSource::try_from(s).ok())
+        .collect::<Vec<ContentSource>>();
+    let source = match sources.as_slice() {
+        [source] if source.src.ext.as_deref() == Some("org") => source,
+        _ => return None,
+    };
+    let keywords = keywords(&std::fs::read_to_string(&source.src.path).ok()?);
+
+    Some(Book {
+        src: target.to_path_buf(),
+        title: keywords.title,
+        authors: keywords.author.into_iter().collect(),
+        language: keywords.language,
+        ..Book::default()
+    })
+}
+
+/// `** TODO [#A] 見出し :tag:` の見出し
+#[derive(Debug, PartialEq)]
+struct Heading<'a> {
+    level: usize,
+    title: &'a str,
+    tags: Vec<&'a str>,
+}
+
+impl<'a> Heading<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let level = line.chars().take_while(|c| *c == '*').count();
+        if level == 0 {
+            return None;
+        }
+        let rest = line[level..].strip_prefix(' ')?.trim();
+
+        // 末尾のタグ
+        let (rest, tags) = match rest.rfind(char::is_whitespace) {
+            Some(space) if rest[space..].trim().len() > 1 && rest[space..].trim().starts_with(':') && rest.ends_with(':') => {
+                let tags = rest[space..].trim().split(':').filter(|t| !t.is_empty()).collect();
+                (rest[..space].trim_end(), tags)
+            }
+            _ if rest.len() > 1 && rest.starts_with(':') && rest.ends_with(':') && !rest.contains(' ') => {
+                ("", rest.split(':').filter(|t| !t.is_empty()).collect())
+            }
+            _ => (rest, Vec::new()),
+        };
+        // TODO キーワードと優先度
+        let mut title = rest;
+        if let Some(keyword) = TODO_KEYWORDS.iter().find(|k| title.strip_prefix(**k).is_some_and(|t| t.is_empty() || t.starts_with(' '))) {
+            title = title[keyword.len()..].trim_start();
+        }
+        if title.starts_with("[#") && title.get(3..4) == Some("]") {
+            title = title[4..].trim_start();
+        }
+
+        Some(Self { level, title, tags })
+    }
+
+    /// `COMMENT`で始まる見出しと`:noexport:`の見出しは, その下の内容ごと書き出さない
+    fn is_exported(&self) -> bool {
+        !(self.tags.contains(&NOEXPORT) || self.title == "COMMENT" || self.title.starts_with("COMMENT "))
+    }
+}
+
+/// 箇条書きの項目: インデント, 番号付きか否か, 項目の内容の始まる位置
+fn list_item(line: &str) -> Option<(usize, bool, usize)> {
+    let indent = line.len() - line.trim_start().len();
+    let rest = &line[indent..];
+    // 行頭の`*`は見出し
+    let bullet = match rest.chars().next()? {
+        '-' | '+' => 1,
+        '*' if indent > 0 => 1,
+        c if c.is_ascii_digit() || c.is_ascii_alphabetic() => {
+            let digits = rest.find(|c: char| !c.is_ascii_alphanumeric())?;
+            let all_digits = rest[..digits].chars().all(|c| c.is_ascii_digit());
+            let single_letter = digits == 1 && rest.chars().next()?.is_ascii_alphabetic();
+            if !(all_digits || single_letter) || !matches!(rest[digits..].chars().next(), Some('.' | ')')) {
+                return None;
+            }
+            digits + 1
+        }
+        _ => return None,
+    };
+    let after = &rest[bullet..];
+    if !(after.is_empty() || after.starts_with(' ')) {
+        return None;
+    }
+    let ordered = !matches!(rest.chars().next(), Some('-' | '+' | '*'));
+    let content = indent + bullet + (after.len() - after.trim_start().len());
+    Some((indent, ordered, content))
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// 数字や英字どうしの間のみ空白を入れて, 行をつなげる
+fn needs_space(before: Option<char>, after: Option<char>) -> bool {
+    before.is_some_and(|c| c.is_ascii() && !c.is_whitespace()) && after.is_some_and(|c| c.is_ascii() && !c.is_whitespace())
+}
+
+/// 強調の直前に置ける文字
+fn is_pre(c: Option<char>) -> bool {
+    match c {
+        None => true,
+        Some(c) => c.is_whitespace() || "-('\"{".contains(c) || !c.is_ascii(),
+    }
+}
+
+/// 強調の直後に置ける文字
+fn is_post(c: Option<char>) -> bool {
+    match c {
+        None => true,
+        Some(c) => c.is_whitespace() || "-.,;:!?')}\"\\[".contains(c) || !c.is_ascii(),
+    }
+}
+
+struct Converter {
+    /// 脚注の名前と, その定義
+    definitions: HashMap<String, String>,
+    /// 参照された順の脚注の名前
+    footnotes: Vec<String>,
+    /// 次の要素に付ける`#+CAPTION`と`#+NAME`
+    caption: Option<String>,
+    name: Option<String>,
+}
+
+impl Converter {
+    fn footnote_reference(&mut self, name: &str) -> String {
+        let n = match self.footnotes.iter().position(|f| f == name) {
+            Some(i) => i + 1,
+            None => {
+                self.footnotes.push(name.to_string());
+                self.footnotes.len()
+            }
+        };
+        format!("<a id=\"fnref-{0}\" href=\"#fn-{0}\" class=\"noteref\" epub:type=\"noteref\"><sup>{0}</sup></a>", n)
+    }
+
+    fn link(&mut self, target: &str, description: Option<&str>) -> String {
+        let is_image = |t: &str| {
+            let path = t.strip_prefix("file:").unwrap_or(t);
+            !t.contains("://") && Path::new(path).extension().and_then(|e| e.to_str())
+                .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+        };
+        if description.is_none() && is_image(target) {
+            let src = target.strip_prefix("file:").unwrap_or(target);
+            let alt = self.caption.clone().unwrap_or_default();
+            return format!("<img src=\"{}\" alt=\"{}\" />", xhtml::escape_attr(src), xhtml::escape_attr(&alt));
+        }
+
+        let href = if let Some(id) = target.strip_prefix('#') {
+            format!("#{}", id)
+        } else if let Some(heading) = target.strip_prefix('*') {
+            // 見出しへのリンクは, 見出しの文字列のみ残す
+            return match description {
+                Some(d) => self.inline(d),
+                None => xhtml::escape_text(heading),
+            };
+        } else if let Some(file) = target.strip_prefix("file:") {
+            file.to_string()
+        } else {
+            target.to_string()
+        };
+        let text = match description {
+            Some(d) if is_image(d) => self.link(d, None),
+            Some(d) => self.inline(d),
+            None => xhtml::escape_text(target),
+        };
+        format!("<a href=\"{}\">{}</a>", xhtml::escape_attr(&href), text)
+    }
+
+    /// インラインの要素を XHTML にする
+    fn inline(&mut self, s: &str) -> String {
+        let mut html = String::new();
+        let mut prev: Option<char> = None;
+        let mut i = 0;
+
+        while i < s.len() {
+            let rest = &s[i..];
+            let c = rest.chars().next().unwrap_or_default();
+
+            // [[リンク][説明]]
+            if let Some(link) = rest.strip_prefix("[[") {
+                if let Some(end) = link.find("]]") {
+                    let inner = &link[..end];
+                    let (target, description) = match inner.find("][") {
+                        Some(d) => (&inner[..d], Some(&inner[d + 2..])),
+                        None => (inner, None),
+                    };
+                    html.push_str(&self.link(target, description));
+                    i += 2 + end + 2;
+                    prev = Some(']');
+                    continue;
+                }
+            }
+            // [fn:名前], [fn::定義], [fn:名前:定義]
+            if let Some(footnote) = rest.strip_prefix("[fn:") {
+                let mut depth = 1;
+                let end = footnote.char_indices().find(|(_, c)| {
+                    match c {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    depth == 0
+                }).map(|(e, _)| e);
+                if let Some(end) = end {
+                    let inner = &footnote[..end];
+                    let (name, definition) = match inner.find(':') {
+                        Some(colon) => (&inner[..colon], Some(inner[colon + 1..].trim())),
+                        None => (inner, None),
+                    };
+                    let name = if name.is_empty() { format!("anonymous-{}", self.definitions.len()) } else { name.to_string() };
+                    if let Some(definition) = definition {
+                        self.definitions.insert(name.clone(), definition.to_string());
+                    }
+                    html.push_str(&self.footnote_reference(&name));
+                    i += 4 + end + 1;
+                    prev = Some(']');
+                    continue;
+                }
+            }
+            // <<ターゲット>>
+            if let Some(target) = rest.strip_prefix("<<").filter(|t| !t.starts_with('<')) {
+                if let Some(end) = target.find(">>").filter(|e| *e > 0) {
+                    html.push_str(&format!("<span id=\"{}\"></span>", xhtml::escape_attr(target[..end].trim())));
+                    i += 2 + end + 2;
+                    continue;
+                }
+            }
+            // \( 数式 \)
+            if let Some(tex) = rest.strip_prefix("\\(") {
+                if let Some(end) = tex.find("\\)") {
+                    html.push_str(&Formula { tex: tex[..end].to_string(), display: false }.to_mathml());
+                    i += 2 + end + 2;
+                    prev = Some(')');
+                    continue;
+                }
+            }
+            // $数式$
+            if c == '$' && !rest[1..].starts_with(|c: char| c.is_whitespace() || c == '$') {
+                let end = rest[1..].char_indices()
+                    .find(|(e, c)| *c == '$' && !rest[1..][..*e].ends_with(char::is_whitespace) && is_post(rest[1 + e + 1..].chars().next()))
+                    .map(|(e, _)| e);
+                if let Some(end) = end.filter(|e| *e > 0) {
+                    html.push_str(&Formula { tex: rest[1..1 + end].to_string(), display: false }.to_mathml());
+                    i += 1 + end + 1;
+                    prev = Some('$');
+                    continue;
+                }
+            }
+            // 強調
+            if "*/_=~+".contains(c) && is_pre(prev) && rest[1..].starts_with(|n: char| !n.is_whitespace()) {
+                let end = rest[1..].char_indices()
+                    .find(|(e, m)| *m == c && *e > 0
+                        && !rest[1..][..*e].ends_with(char::is_whitespace)
+                        && is_post(rest[1 + e + 1..].chars().next()))
+                    .map(|(e, _)| e);
+                if let Some(end) = end {
+                    let content = &rest[1..1 + end];
+                    let element = match c {
+                        '*' => format!("<strong>{}</strong>", self.inline(content)),
+                        '/' => format!("<em>{}</em>", self.inline(content)),
+                        '_' => format!("<u>{}</u>", self.inline(content)),
+                        '+' => format!("<del>{}</del>", self.inline(content)),
+                        _ => format!("<code>{}</code>", xhtml::escape_text(content)),
+                    };
+                    html.push_str(&element);
+                    i += 1 + end + 1;
+                    prev = Some(c);
+                    continue;
+                }
+            }
+            // 行末の \\ は改行
+            if let Some(after) = rest.strip_prefix("\\\\") {
+                if after.is_empty() || after.starts_with('\n') {
+                    html.push_str("<br />");
+                    i += 2 + if after.is_empty() { 0 } else { 1 };
+                    prev = Some('\n');
+                    continue;
+                }
+            }
+            if c == '\n' {
+                if needs_space(prev, rest[1..].chars().next()) {
+                    html.push(' ');
+                }
+                prev = Some(c);
+                i += 1;
+                continue;
+            }
+
+            html.push_str(&xhtml::escape_text(&c.to_string()));
+            prev = Some(c);
+            i += c.len_utf8();
+        }
+
+        html
+    }
+
+    fn paragraph(&mut self, lines: &[&str], out: &mut String) {
+        if lines.is_empty() {
+            return;
+        }
+        let text = lines.iter().map(|l| l.trim()).collect::<Vec<&str>>().join("\n");
+        let content = self.inline(&text);
+
+        // 画像だけの段落は, #+CAPTION があれば figure にする
+        let caption = self.caption.take();
+        let name = self.name.take().map(|n| format!(" id=\"{}\"", xhtml::escape_attr(&n))).unwrap_or_default();
+        match caption {
+            Some(caption) if content.starts_with("<img ") && content.ends_with("/>") && content.matches('<').count() == 1 => {
+                out.push_str(&format!("<figure{}>\n{}\n<figcaption>{}</figcaption>\n</figure>\n", name, content, self.inline(&caption)));
+            }
+            _ => out.push_str(&format!("<p{}>{}</p>\n", name, content)),
+        }
+    }
+
+    fn table(&mut self, lines: &[&str], out: &mut String) {
+        let is_rule = |l: &str| l.trim().starts_with("|-");
+        let header_end = lines.iter().position(|l| is_rule(l)).filter(|p| *p > 0 && *p < lines.len() - 1);
+
+        let name = self.name.take().map(|n| format!(" id=\"{}\"", xhtml::escape_attr(&n))).unwrap_or_default();
+        out.push_str(&format!("<table{}>\n", name));
+        if let Some(caption) = self.caption.take() {
+            out.push_str(&format!("<caption>{}</caption>\n", self.inline(&caption)));
+        }
+        for (n, line) in lines.iter().enumerate() {
+            if is_rule(line) {
+                continue;
+            }
+            let tag = if header_end.is_some_and(|h| n < h) { "th" } else { "td" };
+            let row = line.trim().trim_start_matches('|');
+            let row = row.strip_suffix('|').unwrap_or(row);
+            let cells = row.split('|')
+                .map(|c| format!("<{0}>{1}</{0}>", tag, self.inline(c.trim())))
+                .collect::<String>();
+            out.push_str(&format!("<tr>{}</tr>\n", cells));
+        }
+        out.push_str("</table>\n");
+    }
+
+    /// 箇条書きを変換して, 読んだ行数を返す
+    fn list(&mut self, lines: &[&str], out: &mut String) -> usize {
+        let (indent, ordered, _) = match list_item(lines[0]) {
+            Some(item) => item,
+            None => return 0,
+        };
+        let mut items: Vec<Vec<String>> = Vec::new();
+        let mut n = 0;
+        while n < lines.len() {
+            let line = lines[n];
+            if line.trim().is_empty() {
+                // 空行の後に項目の続きがなければ終わる
+                let next = lines[n + 1..].iter().find(|l| !l.trim().is_empty());
+                match next {
+                    Some(next) if indent_of(next) > indent || list_item(next).is_some_and(|(i, o, _)| i == indent && o == ordered) => {
+                        if let Some(item) = items.last_mut() {
+                            item.push(String::new());
+                        }
+                        n += 1;
+                        continue;
+                    }
+                    _ => break,
+                }
+            }
+            match list_item(line) {
+                Some((i, o, content)) if i == indent && o == ordered => items.push(vec![line[content..].to_string()]),
+                _ if indent_of(line) > indent => match items.last_mut() {
+                    Some(item) => item.push(line.to_string()),
+                    None => break,
+                },
+                _ => break,
+            }
+            n += 1;
+        }
+
+        // 説明リスト `- 用語 :: 説明`
+        let description = !ordered && items.first().is_some_and(|i| i[0].contains(" :: "));
+        let tag = if description { "dl" } else if ordered { "ol" } else { "ul" };
+        out.push_str(&format!("<{}>\n", tag));
+        for item in items {
+            let mut first = item[0].clone();
+            // チェックボックス
+            for (mark, check) in &[("[ ] ", "☐ "), ("[X] ", "☑ "), ("[x] ", "☑ "), ("[-] ", "☐ ")] {
+                if let Some(rest) = first.strip_prefix(mark) {
+                    first = format!("{}{}", check, rest);
+                }
+            }
+            let term = if description {
+                match first.find(" :: ") {
+                    Some(d) => {
+                        let term = first[..d].to_string();
+                        first = first[d + 4..].to_string();
+                        Some(term)
+                    }
+                    None => None,
+                }
+            } else { None };
+
+            // 続きの行はインデントを揃えて, ブロックとして変換する
+            let rest = &item[1..];
+            let dedent = rest.iter().filter(|l| !l.trim().is_empty()).map(|l| indent_of(l)).min().unwrap_or(0);
+            let mut body = vec![first];
+            body.extend(rest.iter().map(|l| l.get(dedent..).unwrap_or("").to_string()));
+            let body = body.iter().map(|l| l.as_str()).collect::<Vec<&str>>();
+            let mut content = String::new();
+            self.blocks(&body, &mut content);
+            // 段落がひとつだけであれば, <p>で囲まない
+            let content = content.trim_end();
+            let content = match content.strip_prefix("<p>").and_then(|c| c.find("</p>").map(|e| (c, e))) {
+                Some((c, e)) if content.matches("<p").count() == 1 => format!("{}{}", &c[..e], &c[e + 4..]),
+                _ => content.to_string(),
+            };
+
+            match term {
+                Some(term) => out.push_str(&format!("<dt>{}</dt>\n<dd>{}</dd>\n", self.inline(&term), content)),
+                None if description => out.push_str(&format!("<dd>{}</dd>\n", content)),
+                None => out.push_str(&format!("<li>{}</li>\n", content)),
+            }
+        }
+        out.push_str(&format!("</{}>\n", tag));
+
+        n
+    }
+
+    fn blocks(&mut self, lines: &[&str], out: &mut String) {
+        let mut paragraph: Vec<&str> = Vec::new();
+        let mut i = 0;
+
+        while i < lines.len() {
+            let line = lines[i];
+            let trimmed = line.trim();
+
+            if trimmed.is_empty() {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += 1;
+                continue;
+            }
+
+            // 見出し
+            if let Some(heading) = Heading::parse(line) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += 1;
+                if !heading.is_exported() {
+                    // 同じか上の階層の見出しまで読み飛ばす
+                    while i < lines.len() && Heading::parse(lines[i]).is_none_or(|h| h.level > heading.level) {
+                        i += 1;
+                    }
+                    continue;
+                }
+                // :PROPERTIES: の CUSTOM_ID
+                let mut id = None;
+                if lines.get(i).is_some_and(|l| l.trim().eq_ignore_ascii_case(":PROPERTIES:")) {
+                    while i < lines.len() && !lines[i].trim().eq_ignore_ascii_case(":END:") {
+                        if let Some(value) = lines[i].trim().strip_prefix(":CUSTOM_ID:") {
+                            id = Some(value.trim().to_string());
+                        }
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                let id = id.map(|id| format!(" id=\"{}\"", xhtml::escape_attr(&id))).unwrap_or_default();
+                out.push_str(&format!("<h{0}{1}>{2}</h{0}>\n", std::cmp::min(heading.level, 6), id, self.inline(heading.title)));
+                continue;
+            }
+
+            // ブロック
+            if let Some((name, parameters)) = block_begin(line) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                let begin = i + 1;
+                let mut end = begin;
+                while end < lines.len() && !is_block_end(lines[end], &name) {
+                    end += 1;
+                }
+                let body = &lines[begin..end];
+                i = end + 1;
+                self.block(&name, parameters, body, out);
+                continue;
+            }
+
+            // キーワード
+            if let Some((key, value)) = keyword(line) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                match key.as_str() {
+                    "CAPTION" => self.caption = Some(value.to_string()),
+                    "NAME" => self.name = Some(value.to_string()),
+                    "HTML" => {
+                        out.push_str(value);
+                        out.push('\n');
+                    }
+                    _ => {}
+                }
+                i += 1;
+                continue;
+            }
+
+            // コメント
+            if trimmed == "#" || trimmed.starts_with("# ") {
+                i += 1;
+                continue;
+            }
+
+            // ドロワー
+            if trimmed.len() > 2 && trimmed.starts_with(':') && trimmed.ends_with(':') && !trimmed.contains(' ')
+                && lines[i + 1..].iter().any(|l| l.trim().eq_ignore_ascii_case(":END:")) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                while i < lines.len() && !lines[i].trim().eq_ignore_ascii_case(":END:") {
+                    i += 1;
+                }
+                i += 1;
+                continue;
+            }
+
+            // 表
+            if trimmed.starts_with('|') {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                let begin = i;
+                while i < lines.len() && lines[i].trim().starts_with('|') {
+                    i += 1;
+                }
+                self.table(&lines[begin..i], out);
+                continue;
+            }
+
+            // 水平線
+            if trimmed.len() >= 5 && trimmed.chars().all(|c| c == '-') {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                out.push_str("<hr />\n");
+                i += 1;
+                continue;
+            }
+
+            // 固定幅の行 `: `
+            if trimmed == ":" || trimmed.starts_with(": ") {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                let mut fixed = Vec::new();
+                while i < lines.len() && (lines[i].trim() == ":" || lines[i].trim().starts_with(": ")) {
+                    fixed.push(lines[i].trim().get(2..).unwrap_or(""));
+                    i += 1;
+                }
+                out.push_str(&format!("<pre class=\"example\">{}</pre>\n", xhtml::escape_text(&fixed.join("\n"))));
+                continue;
+            }
+
+            // \[ 数式 \]
+            if trimmed.starts_with("\\[") {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                let begin = i;
+                while i < lines.len() && !lines[i].trim_end().ends_with("\\]") {
+                    i += 1;
+                }
+                let tex = lines[begin..=std::cmp::min(i, lines.len() - 1)].join("\n");
+                let tex = tex.trim().trim_start_matches("\\[").trim_end_matches("\\]");
+                out.push_str(&Formula { tex: tex.to_string(), display: true }.to_mathml());
+                out.push('\n');
+                i += 1;
+                continue;
+            }
+
+            // 箇条書き
+            if list_item(line).is_some() {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += self.list(&lines[i..], out);
+                continue;
+            }
+
+            paragraph.push(line);
+            i += 1;
+        }
+
+        self.paragraph(&paragraph, out);
+    }
+
+    fn block(&mut self, name: &str, parameters: &str, body: &[&str], out: &mut String) {
+        // `,*` のようにエスケープされた行頭
+        let unescape = |l: &&str| -> String {
+            let trimmed = l.trim_start();
+            if trimmed.starts_with(",*") || trimmed.starts_with(",#+") {
+                l.replacen(',', "", 1)
+            } else {
+                l.to_string()
+            }
+        };
+        let verbatim = || xhtml::escape_text(&body.iter().map(unescape).collect::<Vec<String>>().join("\n"));
+        let name_attribute = self.name.take().map(|n| format!(" id=\"{}\"", xhtml::escape_attr(&n))).unwrap_or_default();
+
+        match name {
+            "src" => {
+                let language = parameters.split_whitespace().next().unwrap_or_default();
+                let class = if language.is_empty() { String::new() } else { format!(" class=\"language-{}\"", xhtml::escape_attr(language)) };
+                let code = format!("<pre><code{}>{}</code></pre>\n", class, verbatim());
+                match self.caption.take() {
+                    Some(caption) => out.push_str(&format!(
+                        "<figure{}>\n<figcaption>{}</figcaption>\n{}</figure>\n", name_attribute, self.inline(&caption), code
+                    )),
+                    None if name_attribute.is_empty() => out.push_str(&code),
+                    None => out.push_str(&format!("<div{}>\n{}</div>\n", name_attribute, code)),
+                }
+            }
+            "example" => out.push_str(&format!("<pre class=\"example\"{}>{}</pre>\n", name_attribute, verbatim())),
+            "quote" => {
+                out.push_str(&format!("<blockquote{}>\n", name_attribute));
+                self.blocks(body, out);
+                out.push_str("</blockquote>\n");
+            }
+            "center" => {
+                out.push_str(&format!("<div class=\"center\" style=\"text-align: center;\"{}>\n", name_attribute));
+                self.blocks(body, out);
+                out.push_str("</div>\n");
+            }
+            "verse" => {
+                let lines = body.iter().map(|l| self.inline(l.trim_end())).collect::<Vec<String>>().join("<br />\n");
+                out.push_str(&format!("<p class=\"verse\"{}>{}</p>\n", name_attribute, lines));
+            }
+            "export" => {
+                if parameters.split_whitespace().next().is_some_and(|b| b.eq_ignore_ascii_case("html")) {
+                    out.push_str(&body.join("\n"));
+                    out.push('\n');
+                }
+            }
+            "comment" => {}
+            // 特殊ブロックは, その名前の class を持つ div にする
+            _ => {
+                out.push_str(&format!("<div class=\"{}\"{}>\n", xhtml::escape_attr(name), name_attribute));
+                self.blocks(body, out);
+                out.push_str("</div>\n");
+            }
+        }
+        self.caption = None;
+    }
+
+    fn footnotes(&mut self, out: &mut String) {
+        // 定義の中で参照された脚注も出力する
+        let mut n = 0;
+        while n < self.footnotes.len() {
+            let name = self.footnotes[n].clone();
+            n += 1;
+            let definition = match self.definitions.get(&name) {
+                Some(definition) => definition.clone(),
+                None => continue,
+            };
+            let mut content = String::new();
+            let lines = definition.lines().collect::<Vec<&str>>();
+            self.blocks(&lines, &mut content);
+            // 最初の段落の先頭に, 参照元へのリンクを置く
+            let back = format!("<a href=\"#fnref-{0}\">{0}</a> ", n);
+            let content = match content.strip_prefix("<p>") {
+                Some(rest) => format!("<p>{}{}", back, rest),
+                None => format!("<p>{}</p>\n{}", back.trim_end(), content),
+            };
+            out.push_str(&format!("<aside id=\"fn-{}\" class=\"footnote\" epub:type=\"footnote\">\n{}</aside>\n", n, content));
+        }
+    }
+}
+
+/// 行頭の`[fn:名前]`で始まる脚注の定義を取り出す
+fn extract_definitions(source: &str) -> (Vec<&str>, HashMap<String, String>) {
+    let mut lines = Vec::new();
+    let mut definitions = HashMap::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    let mut in_block: Option<String> = None;
+
+    for line in source.lines() {
+        if let Some(name) = &in_block {
+            if is_block_end(line, name) {
+                in_block = None;
+            }
+            lines.push(line);
+            continue;
+        }
+        let definition = line.strip_prefix("[fn:").and_then(|rest| {
+            let end = rest.find(']')?;
+            let name = &rest[..end];
+            if name.is_empty() || name.contains(':') {
+                return None;
+            }
+            Some((name.to_string(), rest[end + 1..].trim()))
+        });
+        if let Some((name, text)) = definition {
+            if let Some((name, text)) = current.take() {
+                definitions.insert(name, text.join("\n"));
+            }
+            current = Some((name, vec![text]));
+            continue;
+        }
+        // 定義は, 空行2つか見出しで終わる
+        if let Some((_, text)) = &mut current {
+            let ends = Heading::parse(line).is_some()
+                || (line.trim().is_empty() && text.last().is_some_and(|l| l.trim().is_empty()));
+            if !ends {
+                text.push(line);
+                continue;
+            }
+            if let Some((name, text)) = current.take() {
+                definitions.insert(name, text.join("\n"));
+            }
+        }
+        if let Some((name, _)) = block_begin(line) {
+            in_block = Some(name);
+        }
+        lines.push(line);
+    }
+    if let Some((name, text)) = current {
+        definitions.insert(name, text.join("\n"));
+    }
+
+    (lines, definitions)
+}
+
+/// Org 文書を XHTML に変換する
+pub fn convert(source: &str) -> OrgDocument {
+    let keywords = keywords(source);
+    let (lines, definitions) = extract_definitions(source);
+
+    let mut converter = Converter { definitions, footnotes: Vec::new(), caption: None, name: None };
+    let mut body = String::new();
+    converter.blocks(&lines, &mut body);
+    converter.footnotes(&mut body);
+
+    OrgDocument { keywords, body }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inline() {
+        let mut converter = Converter { definitions: HashMap::new(), footnotes: Vec::new(), caption: None, name: None };
+        assert_eq!(
+            converter.inline("*太字* と /斜体/, =a<b= と ~code~ と +del+ a*b*c"),
+            "<strong>太字</strong> と <em>斜体</em>, <code>a&lt;b</code> と <code>code</code> と <del>del</del> a*b*c"
+        );
+        assert_eq!(
+            converter.inline("[[https://example.com][例]] [[./img.png]] [[file:other.org][次]]"),
+            "<a href=\"https://example.com\">例</a> <img src=\"./img.png\" alt=\"\" /> <a href=\"other.org\">次</a>"
+        );
+        assert_eq!(converter.inline("日本語の\n行と\nEnglish\nwords"), "日本語の行とEnglish words");
+    }
+
+    #[test]
+    fn document() {
+        let source = "#+TITLE: 題名\n#+AUTHOR: 著者\n\n* TODO 第一章 :tag:\n:PROPERTIES:\n:CUSTOM_ID: ch1\n:END:\n本文[fn:1]です。\n\n\
+                      - 項目\n  - 入れ子\n- [X] 済み\n\n1. 一\n2. 二\n\n#+CAPTION: 表\n| a | b |\n|---+---|\n| 1 | 2 |\n\n\
+                      #+BEGIN_SRC rust\nfn main() {}\n#+END_SRC\n\n* 非公開 :noexport:\n秘密\n\n[fn:1] 脚注\n";
+        let document = convert(source);
+        assert_eq!(document.keywords, Keywords { title: Some("題名".to_string()), author: Some("著者".to_string()), language: None });
+        assert_eq!(
+            document.body,
+            "<h1 id=\"ch1\">第一章</h1>\n\
+             <p>本文<a id=\"fnref-1\" href=\"#fn-1\" class=\"noteref\" epub:type=\"noteref\"><sup>1</sup></a>です。</p>\n\
+             <ul>\n<li>項目\n<ul>\n<li>入れ子</li>\n</ul></li>\n<li>☑ 済み</li>\n</ul>\n\
+             <ol>\n<li>一</li>\n<li>二</li>\n</ol>\n\
+             <table>\n<caption>表</caption>\n<tr><th>a</th><th>b</th></tr>\n<tr><td>1</td><td>2</td></tr>\n</table>\n\
+             <pre><code class=\"language-rust\">fn main() {}</code></pre>\n\
+             <aside id=\"fn-1\" class=\"footnote\" epub:type=\"footnote\">\n<p><a href=\"#fnref-1\">1</a> 脚注</p>\n</aside>\n"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! 設定ファイルで指定するパスのパターン
+//!
+//! `ignores`は gitignore と同様に解釈し, `sequence`と`content_configures.src`は glob として解釈する
+
+use std::path::Path;
+
+use globset::{GlobBuilder, GlobMatcher};
+
+/// glob として解釈される文字を含むか否か
+pub fn is_glob(pattern: &Path) -> bool {
+    pattern.to_str().is_some_and(|s| s.contains(['*', '?', '[', '{']))
+}
+
+fn matcher(pattern: &str) -> Option<GlobMatcher> {
+    GlobBuilder::new(pattern)
+        // `*` はディレクトリの区切りをまたがない
+        .literal_separator(true)
+        .build()
+        .ok()
+        .map(|g| g.compile_matcher())
+}
+
+/// target からの相対パス path が glob の pattern に一致するか
+pub fn matches(pattern: &Path, path: &Path) -> bool {
+    if pattern == path {
+        return true;
+    }
+
+    match pattern.to_str().and_then(matcher) {
+        Some(matcher) => matcher.is_match(path),
+        None => false,
+    }
+}
+
+/// target からの相対パス path が gitignore 形式の pattern に一致するか
+/// - `/` を含まない pattern は, どの階層のファイル名にも一致する
+/// - `/` で始まる pattern は target 直下からのパスとして扱う
+/// - ディレクトリに一致する pattern は, その中のすべてのファイルに一致する
+pub fn matches_ignore(pattern: &Path, path: &Path) -> bool {
+    let pattern = match pattern.to_str() {
+        Some(pattern) => pattern,
+        None => return pattern == path,
+    };
+    let anchored = pattern.starts_with('/');
+    let pattern = pattern.trim_start_matches('/').trim_end_matches('/');
+    if pattern.is_empty() {
+        return false;
+    }
+
+    let pattern = if !anchored && !pattern.contains('/') {
+        format!("**/{}", pattern)
+    } else {
+        pattern.to_string()
+    };
+    let matcher = match matcher(&pattern) {
+        Some(matcher) => matcher,
+        None => return Path::new(&pattern) == path,
+    };
+
+    path.ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| matcher.is_match(p))
+}
+
+#[test]
+fn test_patterns() {
+    use std::path::PathBuf;
+
+    let path = |s: &str| PathBuf::from(s);
+
+    assert!(matches(&path("appendix/*.md"), &path("appendix/a.md")));
+    assert!(!matches(&path("appendix/*.md"), &path("appendix/sub/a.md")));
+    assert!(matches(&path("appendix/**/*.md"), &path("appendix/sub/a.md")));
+    assert!(matches(&path("[draft].md"), &path("[draft].md")));
+
+    assert!(matches_ignore(&path(".DS_Store"), &path("images/.DS_Store")));
+    assert!(matches_ignore(&path("drafts/**"), &path("drafts/a/b.md")));
+    assert!(matches_ignore(&path("drafts/"), &path("drafts/a.md")));
+    assert!(matches_ignore(&path("*.bak"), &path("a/b.bak")));
+    assert!(!matches_ignore(&path("/notes.md"), &path("sub/notes.md")));
+    assert!(matches_ignore(&path("/notes.md"), &path("notes.md")));
+    assert!(!matches_ignore(&path("drafts"), &path("final/a.md")));
+}
@@ -8,6 +8,8 @@ pub use message::{
 use crate::prelude::*;
 
 pub const CONFIG_JSON: &str = "repub_config.json";
+/// 変換対象から除外するファイルを gitignore 形式で記載する
+pub const REPUBIGNORE: &str = ".repubignore";
 
 pub mod message {
     use super::*;
@@ -0,0 +1,1025 @@
+//! Re:VIEW 形式 (`.re`, `catalog.yml`, `config.yml`) の読み込みと変換
+//!
+//! - `catalog.yml` の`PREDEF`, `CHAPS`, `APPENDIX`, `POSTDEF` の順にコンテンツを並べ, 部を目次の階層とする
+//! - `config.yml` の`booktitle`, `aut`, `language` はタイトル, クリエイター, 言語の既定値になる
+//! - 図表やリストの番号は章ごとに振り, 章番号は付けない
+
+use crate::{prelude::*, mdbook::{Book, Summary, SummaryItem}, block, math::Formula, aozora, xhtml};
+use std::{collections::HashMap, path::Path};
+
+use serde_yaml::Value;
+
+const CATALOG_YML: &str = "catalog.yml";
+const CONFIG_YML: &str = "config.yml";
+/// `catalog.yml` の項目. この順にコンテンツを並べる
+const CATALOG_SECTIONS: [&str; 4] = ["PREDEF", "CHAPS", "APPENDIX", "POSTDEF"];
+
+/// admonition として出力するブロック命令と, その種類
+const ADMONITIONS: [(&str, &str); 8] = [
+    ("note", "note"),
+    ("memo", "note"),
+    ("notice", "note"),
+    ("tip", "tip"),
+    ("info", "info"),
+    ("important", "important"),
+    ("warning", "warning"),
+    ("caution", "caution"),
+];
+
+/// 表の見出し行と本体を区切る行
+const TABLE_SEPARATOR: &str = "------------";
+
+/// Re:VIEW の設定やビルドのためのファイル. 原稿と同じディレクトリにあっても収録しない
+const BUILD_FILES: [&str; 7] = ["*.yml", "Rakefile", "Gemfile", "Gemfile.lock", "layouts", "sty", "*.css.scss"];
+
+/// `//image` の画像を探す拡張子
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "svg", "webp"];
+
+/// target が`catalog.yml`を含むディレクトリであれば, Re:VIEW のプロジェクトとして読み込む
+pub fn load(target: &Path) -> Option<Book> {
+    if !target.is_dir() {
+        return None;
+    }
+
+    let config = match std::fs::read_to_string(target.join(CONFIG_YML)) {
+        Ok(yaml) => match serde_yaml::from_str::<Value>(&yaml) {
+            Ok(config) => config,
+            Err(e) => {
+                RepubWarning(format!("{:?} {}", target.join(CONFIG_YML), &e)).print();
+                Value::Null
+            }
+        },
+        Err(_) => Value::Null,
+    };
+    let catalog = target.join(config.get("catalogfile").and_then(Value::as_str).unwrap_or(CATALOG_YML));
+    let catalog = std::fs::read_to_string(&catalog).ok()?;
+
+    let src = target.join(config.get("contentdir").and_then(Value::as_str).unwrap_or("."));
+    let summary = match parse_catalog(&catalog, &src) {
+        Ok(summary) => summary,
+        Err(e) => {
+            RepubWarning(format!("{:?} {}", target.join(CATALOG_YML), &e)).print();
+            return None;
+        }
+    };
+
+    // `booktitle` は言語ごとに指定されることもある
+    let title = match config.get("booktitle") {
+        Some(Value::String(title)) => Some(title.clone()),
+        Some(Value::Mapping(titles)) => titles.iter().find_map(|(_, t)| t.as_str().map(|t| t.to_string())),
+        _ => None,
+    };
+    let name = |v: &Value| match v {
+        Value::String(name) => Some(name.clone()),
+        Value::Mapping(_) => v.get("name").and_then(Value::as_str).map(|n| n.to_string()),
+        _ => None,
+    };
+    let authors = match config.get("aut") {
+        Some(Value::Sequence(authors)) => authors.iter().flat_map(name).collect(),
+        Some(author) => name(author).into_iter().collect(),
+        None => Vec::new(),
+    };
+
+    Some(Book {
+        src,
+        title,
+        authors,
+        language: config.get("language").and_then(Value::as_str).map(|l| l.to_string()),
+        summary: Some(summary),
+        ignores: BUILD_FILES.iter().map(PathBuf::from).collect(),
+    })
+}
+
+/// `catalog.yml` を本の構成にする. 章の題名は各ファイルの最初の見出しから読み取る
+fn parse_catalog(yaml: &str, src: &Path) -> RepubResult<Summary> {
+    let catalog = serde_yaml::from_str::<Value>(yaml)?;
+    let chapter = |file: &str, level: u8| {
+        let title = std::fs::read_to_string(src.join(file)).ok()
+            .and_then(|source| title(&source))
+            .unwrap_or_else(|| file.trim_end_matches(".re").to_string());
+        SummaryItem { title, path: Some(PathBuf::from(file)), level }
+    };
+
+    let mut items = Vec::new();
+    for section in &CATALOG_SECTIONS {
+        let entries = match catalog.get(section) {
+            Some(Value::Sequence(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries {
+            match entry {
+                Value::String(file) => items.push(chapter(file, 1)),
+                // 部: `- 部の題名: [章, …]` または `- part.re: [章, …]`
+                Value::Mapping(part) => for (part, chapters) in part {
+                    let part = part.as_str().ok_or_else(|| format_err!("部の名前が不正です"))?;
+                    if part.ends_with(".re") {
+                        items.push(chapter(part, 1));
+                    } else {
+                        items.push(SummaryItem { title: part.to_string(), path: None, level: 1 });
+                    }
+                    for file in chapters.as_sequence().into_iter().flatten().flat_map(Value::as_str) {
+                        items.push(chapter(file, 2));
+                    }
+                },
+                _ => return Err(format_err!("{} の項目が不正です", section)),
+            }
+        }
+    }
+
+    Ok(Summary { items })
+}
+
+/// 最初の見出し (`= 題名`) の文字列
+pub fn title(source: &str) -> Option<String> {
+    source.lines()
+        .filter_map(Heading::parse)
+        .find(|h| !h.options.iter().any(|o| o.starts_with('/')))
+        .map(|h| plain_text(&h.title))
+}
+
+/// インライン命令を取り除いた文字列
+fn plain_text(s: &str) -> String {
+    let mut text = String::new();
+    for token in tokenize(s) {
+        match token {
+            Token::Text(t) => text.push_str(&t),
+            Token::Command(_, content) => text.push_str(&plain_text(&content)),
+        }
+    }
+    text
+}
+
+/// 変換するファイルについての情報
+pub struct Context<'a> {
+    /// `.re` ファイルのあるディレクトリ. 画像はその`images`ディレクトリから探す
+    pub dir: &'a Path,
+    /// ファイル名から拡張子を除いたもの (章の id)
+    pub id: &'a str,
+    /// 変換後のファイルの, OEBPS からのパス
+    pub path: &'a Path,
+    pub language: &'a str,
+    /// 章の id と, その章. `@<chap>`などの参照に使う
+    pub chapters: &'a HashMap<String, Chapter>,
+}
+
+/// `@<chap>`などで参照される章
+#[derive(Debug, Clone)]
+pub struct Chapter {
+    pub title: String,
+    /// 変換後のファイルの, OEBPS からのパス
+    pub path: PathBuf,
+}
+
+/// `= 題名`, `=={id} 題名`, `===[column] 題名`
+#[derive(Debug, PartialEq)]
+struct Heading {
+    level: usize,
+    options: Vec<String>,
+    id: Option<String>,
+    title: String,
+}
+
+impl Heading {
+    fn parse(line: &str) -> Option<Self> {
+        let level = line.chars().take_while(|c| *c == '=').count();
+        if level == 0 || level > 6 {
+            return None;
+        }
+        let mut rest = &line[level..];
+        let mut options = Vec::new();
+        while let Some(option) = rest.strip_prefix('[') {
+            let end = option.find(']')?;
+            options.push(option[..end].to_string());
+            rest = &option[end + 1..];
+        }
+        let id = match rest.strip_prefix('{') {
+            Some(id) => {
+                let end = id.find('}')?;
+                rest = &id[end + 1..];
+                Some(id[..end].to_string())
+            }
+            None => None,
+        };
+        // `=` の後には空白が必要
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+
+        Some(Self { level, options, id, title: rest.trim().to_string() })
+    }
+}
+
+/// `//command[arg][arg]{`
+#[derive(Debug, PartialEq)]
+struct BlockCommand {
+    name: String,
+    args: Vec<String>,
+    /// `{` で始まり `//}` で終わる本体を持つか
+    has_body: bool,
+}
+
+impl BlockCommand {
+    fn parse(line: &str) -> Option<Self> {
+        let rest = line.strip_prefix("//")?;
+        let name_end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+        if name_end == 0 {
+            return None;
+        }
+        let name = rest[..name_end].to_string();
+
+        let mut args = Vec::new();
+        let mut chars = rest[name_end..].chars().peekable();
+        while chars.peek() == Some(&'[') {
+            chars.next();
+            let mut arg = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') => match chars.next() {
+                        Some(c @ (']' | '\\')) => arg.push(c),
+                        Some(c) => { arg.push('\\'); arg.push(c); }
+                        None => return None,
+                    },
+                    Some(']') => break,
+                    Some(c) => arg.push(c),
+                    None => return None,
+                }
+            }
+            args.push(arg);
+        }
+        let rest = chars.collect::<String>();
+        let has_body = match rest.trim_end() {
+            "{" => true,
+            "" => false,
+            _ => return None,
+        };
+
+        Some(Self { name, args, has_body })
+    }
+
+    fn arg(&self, i: usize) -> Option<&str> {
+        self.args.get(i).map(|a| a.as_str()).filter(|a| !a.is_empty())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Text(String),
+    /// 命令の名前と中身
+    Command(String, String),
+}
+
+/// `@<name>{content}`, `@<name>$content$`, `@<name>|content|` を切り出す
+fn tokenize(s: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = s;
+
+    while let Some(begin) = rest.find("@<") {
+        text.push_str(&rest[..begin]);
+        let after = &rest[begin + 2..];
+        let command = after.find('>')
+            .filter(|end| after[..*end].chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && *end > 0)
+            .and_then(|end| {
+                let name = &after[..end];
+                let body = &after[end + 1..];
+                let open = body.chars().next()?;
+                let close = match open {
+                    '{' => '}',
+                    '$' | '|' => open,
+                    _ => return None,
+                };
+
+                let mut content = String::new();
+                let mut chars = body[open.len_utf8()..].char_indices();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        // `{…}` の中のみ `\}` でエスケープできる. `\,` は引数の区切りとして後で扱う
+                        '\\' if open == '{' => match chars.next() {
+                            Some((_, d @ ('}' | '\\'))) => content.push(d),
+                            Some((_, d)) => { content.push('\\'); content.push(d); }
+                            None => return None,
+                        },
+                        c if c == close => {
+                            let consumed = 2 + end + 1 + open.len_utf8() + i + close.len_utf8();
+                            return Some((name.to_string(), content, consumed));
+                        }
+                        c => content.push(c),
+                    }
+                }
+                None
+            });
+
+        match command {
+            Some((name, content, consumed)) => {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+                tokens.push(Token::Command(name, content));
+                rest = &rest[begin + consumed..];
+            }
+            None => {
+                text.push_str("@<");
+                rest = &rest[begin + 2..];
+            }
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+
+    tokens
+}
+
+/// `a, b` を 2 つに分ける. `\,` は区切りとしない
+fn split_pair(s: &str) -> (String, Option<String>) {
+    let mut first = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(',') => first.push(','),
+                Some(d) => { first.push('\\'); first.push(d); }
+                None => first.push('\\'),
+            },
+            ',' => return (first.trim().to_string(), Some(chars.as_str().trim().to_string())),
+            c => first.push(c),
+        }
+    }
+    (first.trim().to_string(), None)
+}
+
+/// 変換の途中の状態
+struct Converter<'a> {
+    context: &'a Context<'a>,
+    divs: Vec<block::Div>,
+    /// 図表などの種類と id ごとの番号
+    numbers: HashMap<(&'static str, String), usize>,
+    /// 脚注の id と中身
+    footnotes: Vec<(String, String)>,
+    /// 次の段落を字下げしない (`//noindent`)
+    noindent: bool,
+}
+
+/// 番号を振るブロック命令と, その番号の種類
+fn numbered_kind(command: &str) -> Option<&'static str> {
+    match command {
+        "list" | "listnum" => Some("list"),
+        "image" => Some("image"),
+        "table" => Some("table"),
+        "texequation" => Some("equation"),
+        "footnote" => Some("footnote"),
+        _ => None,
+    }
+}
+
+impl<'a> Converter<'a> {
+    fn new(source: &str, context: &'a Context<'a>) -> Self {
+        let mut numbers = HashMap::new();
+        for command in source.lines().filter_map(BlockCommand::parse) {
+            if let (Some(kind), Some(id)) = (numbered_kind(&command.name), command.arg(0)) {
+                let n = numbers.keys().filter(|(k, _)| *k == kind).count() + 1;
+                numbers.entry((kind, id.to_string())).or_insert(n);
+            }
+        }
+
+        Self { context, divs: Vec::new(), numbers, footnotes: Vec::new(), noindent: false }
+    }
+
+    fn is_ja(&self) -> bool {
+        self.context.language.starts_with("ja")
+    }
+
+    /// 図表などの見出しの前に付ける名前
+    fn label(&self, kind: &str) -> &'static str {
+        match (kind, self.is_ja()) {
+            ("list", true) => "リスト",
+            ("list", false) => "Listing ",
+            ("image", true) => "図",
+            ("image", false) => "Figure ",
+            ("table", true) => "表",
+            ("table", false) => "Table ",
+            (_, true) => "式",
+            (_, false) => "Equation ",
+        }
+    }
+
+    /// 番号付きの見出し. 番号のないものは caption のみ
+    fn caption(&self, kind: &'static str, id: Option<&str>, caption: Option<&str>) -> String {
+        let number = id.and_then(|id| self.numbers.get(&(kind, id.to_string())));
+        let caption = caption.map(|c| self.inline(c)).unwrap_or_default();
+        match number {
+            Some(n) if caption.is_empty() => format!("{}{}", self.label(kind), n),
+            Some(n) => format!("{}{}{}{}", self.label(kind), n, if self.is_ja() { "　" } else { ": " }, caption),
+            None => caption,
+        }
+    }
+
+    fn reference(&self, kind: &'static str, id: &str) -> String {
+        // 他の章の図表 (`chap|id`) は番号がわからないので, id を示す
+        match self.numbers.get(&(kind, id.to_string())) {
+            Some(n) => format!(
+                "<a href=\"#{}-{}\">{}{}</a>",
+                kind, xhtml::escape_attr(id), self.label(kind), n
+            ),
+            None => format!("{}{}", self.label(kind), xhtml::escape_text(id)),
+        }
+    }
+
+    fn chapter_link(&self, id: &str, with_title: bool) -> String {
+        let chapter = self.context.chapters.get(id);
+        let title = chapter.map(|c| c.title.clone()).unwrap_or_else(|| id.to_string());
+        let text = if with_title && self.is_ja() { format!("「{}」", title) } else { title };
+        // リンク先は, 参照する章の変換後のファイルの, この章からの相対パス
+        let href = chapter.and_then(|c| PathBuf::path_diff(&self.context.path, &c.path.as_path()))
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_else(|| format!("{}.xhtml", id));
+        format!("<a href=\"{}\">{}</a>", xhtml::escape_attr(&href), xhtml::escape_text(&text))
+    }
+
+    /// インライン命令を html にする
+    fn inline(&self, s: &str) -> String {
+        let mut html = String::new();
+        for token in tokenize(s) {
+            match token {
+                Token::Text(text) => html.push_str(&xhtml::escape_text(&text)),
+                Token::Command(name, content) => html.push_str(&self.inline_command(&name, &content)),
+            }
+        }
+        html
+    }
+
+    fn inline_command(&self, name: &str, content: &str) -> String {
+        let escaped = xhtml::escape_text(content);
+        let element = |tag: &str, inner: String| format!("<{0}>{1}</{0}>", tag, inner);
+        let span = |class: &str, inner: String| format!("<span class=\"{}\">{}</span>", class, inner);
+
+        match name {
+            "b" | "strong" | "i" | "em" | "u" | "sup" | "sub" | "del" | "ins" | "big" | "small"
+            | "abbr" | "acronym" | "cite" | "dfn" | "var" => element(name, self.inline(content)),
+            "kbd" | "samp" => element(name, escaped),
+            "code" | "tt" => element("code", escaped),
+            "tti" => element("code", element("i", escaped)),
+            "ttb" => element("code", element("b", escaped)),
+            "ami" | "bou" | "balloon" | "tcy" => span(name, self.inline(content)),
+            "kw" => {
+                let (word, alt) = split_pair(content);
+                let alt = match alt {
+                    Some(alt) if self.is_ja() => format!("（{}）", alt),
+                    Some(alt) => format!(" ({})", alt),
+                    None => String::new(),
+                };
+                format!("<b class=\"kw\">{}{}</b>", self.inline(&word), xhtml::escape_text(&alt))
+            }
+            "ruby" => {
+                let (base, rt) = split_pair(content);
+                format!("<ruby>{}<rt>{}</rt></ruby>", xhtml::escape_text(&base), xhtml::escape_text(&rt.unwrap_or_default()))
+            }
+            "href" => {
+                let (url, label) = split_pair(content);
+                let label = label.unwrap_or_else(|| url.clone());
+                format!("<a href=\"{}\" class=\"link\">{}</a>", xhtml::escape_attr(&url), xhtml::escape_text(&label))
+            }
+            "br" => "<br />".to_string(),
+            "m" => Formula { tex: content.to_string(), display: false }.to_mathml(),
+            "uchar" => u32::from_str_radix(content.trim(), 16).ok()
+                .and_then(char::from_u32)
+                .map(|c| xhtml::escape_text(&c.to_string()))
+                .unwrap_or_default(),
+            "fn" => match self.numbers.get(&("footnote", content.to_string())) {
+                Some(n) => format!(
+                    "<a id=\"fnb-{0}\" href=\"#fn-{0}\" class=\"noteref\" epub:type=\"noteref\">*{1}</a>",
+                    xhtml::escape_attr(content), n
+                ),
+                None => String::new(),
+            },
+            "list" => self.reference("list", content),
+            "img" => self.reference("image", content),
+            "table" => self.reference("table", content),
+            "eq" => self.reference("equation", content),
+            "chap" | "chapref" => self.chapter_link(content, false),
+            "title" => self.chapter_link(content, true),
+            "hd" | "sec" | "secref" => {
+                // `章|見出し`
+                let heading = content.rsplit('|').next().unwrap_or(content);
+                if self.is_ja() { format!("「{}」", self.inline(heading)) } else { self.inline(heading) }
+            }
+            "icon" => format!("<img src=\"{}\" alt=\"{}\" class=\"icon\" />", xhtml::escape_attr(&self.image(content)), escaped),
+            "raw" | "embed" => raw(content).unwrap_or_default(),
+            "bib" => format!("[{}]", escaped),
+            // 索引, コメントなどは出力しない
+            "hidx" | "comment" | "pageref" | "w" | "wb" => String::new(),
+            "idx" | "column" | "labelref" | "ref" => escaped,
+            _ => self.inline(content),
+        }
+    }
+
+    /// `images/<章>/<id>.png`, `images/<章>-<id>.png`, `images/<id>.png` の順に探す
+    /// 見つからなければ`images/<id>.png`とする
+    fn image(&self, id: &str) -> String {
+        let candidates = IMAGE_EXTENSIONS.iter().flat_map(|ext| vec![
+            format!("images/{}/{}.{}", self.context.id, id, ext),
+            format!("images/{}-{}.{}", self.context.id, id, ext),
+            format!("images/{}.{}", id, ext),
+        ]).collect::<Vec<String>>();
+
+        candidates.iter()
+            .find(|c| self.context.dir.join(c).is_file())
+            .cloned()
+            .unwrap_or_else(|| format!("images/{}.png", id))
+    }
+
+    fn paragraph(&mut self, lines: &[&str], out: &mut String) {
+        if lines.is_empty() {
+            return;
+        }
+        // 行は, 英数字どうしの間のみ空白でつなぐ
+        let mut text = String::new();
+        for line in lines {
+            let line = line.trim();
+            let needs_space = text.chars().last().is_some_and(|c| c.is_ascii_alphanumeric() || c.is_ascii_punctuation())
+                && line.chars().next().is_some_and(|c| c.is_ascii_alphanumeric());
+            if needs_space {
+                text.push(' ');
+            }
+            text.push_str(line);
+        }
+        let class = if std::mem::take(&mut self.noindent) { " class=\"noindent\"" } else { "" };
+        out.push_str(&format!("<p{}>{}</p>\n", class, self.inline(&text)));
+    }
+
+    fn convert(&mut self, lines: &[&str], out: &mut String) {
+        let mut paragraph: Vec<&str> = Vec::new();
+        // 開いているコラムの見出しのレベル
+        let mut columns: Vec<usize> = Vec::new();
+        let mut i = 0;
+
+        while i < lines.len() {
+            let line = lines[i];
+
+            // コメント
+            if line.starts_with("#@") {
+                i += 1;
+                continue;
+            }
+            if line.trim().is_empty() {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += 1;
+                continue;
+            }
+
+            if let Some(heading) = Heading::parse(line) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += 1;
+
+                // 同じか上のレベルの見出しでコラムは終わる
+                while columns.last().is_some_and(|l| *l >= heading.level) {
+                    columns.pop();
+                    out.push_str(&block::close_marker());
+                }
+                if heading.options.iter().any(|o| o.starts_with('/')) {
+                    continue;
+                }
+                if heading.options.iter().any(|o| o == "column") {
+                    let attributes = block::BlockAttributes {
+                        id: heading.id.clone(),
+                        classes: Vec::new(),
+                        attrs: vec![("epub:type".to_string(), "sidebar".to_string())],
+                    };
+                    out.push_str(&block::open_marker(self.divs.len(), &self.inline(&heading.title)));
+                    self.divs.push(block::Div { kind: Some("column".to_string()), attributes });
+                    columns.push(heading.level);
+                    continue;
+                }
+                if heading.options.iter().any(|o| o == "nodisp") {
+                    continue;
+                }
+                let id = heading.id.as_ref().map(|id| format!(" id=\"{}\"", xhtml::escape_attr(id))).unwrap_or_default();
+                out.push_str(&format!("<h{0}{1}>{2}</h{0}>\n", heading.level, id, self.inline(&heading.title)));
+                continue;
+            }
+
+            if let Some(command) = BlockCommand::parse(line) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += 1;
+                let body = if command.has_body {
+                    // 入れ子になったブロックの `//}` を読み飛ばす
+                    let mut depth = 1;
+                    let begin = i;
+                    while i < lines.len() {
+                        match BlockCommand::parse(lines[i]) {
+                            Some(c) if c.has_body => depth += 1,
+                            _ if lines[i].trim_end() == "//}" => depth -= 1,
+                            _ => {}
+                        }
+                        if depth == 0 {
+                            break;
+                        }
+                        i += 1;
+                    }
+                    let body = &lines[begin..i.min(lines.len())];
+                    i += 1;
+                    body
+                } else {
+                    &[][..]
+                };
+                self.block(&command, body, out);
+                continue;
+            }
+
+            if let Some(list) = self.list(&lines[i..], out) {
+                self.paragraph(&std::mem::take(&mut paragraph), out);
+                i += list;
+                continue;
+            }
+
+            paragraph.push(line);
+            i += 1;
+        }
+
+        self.paragraph(&paragraph, out);
+        for _ in columns {
+            out.push_str(&block::close_marker());
+        }
+    }
+
+    /// 箇条書きであれば html にして, 読んだ行数を返す
+    fn list(&mut self, lines: &[&str], out: &mut String) -> Option<usize> {
+        fn unordered(line: &str) -> Option<(usize, &str)> {
+            let rest = line.strip_prefix(char::is_whitespace)?.trim_start();
+            let depth = rest.chars().take_while(|c| *c == '*').count();
+            let item = rest[depth..].strip_prefix(char::is_whitespace)?;
+            if depth == 0 { None } else { Some((depth, item.trim())) }
+        }
+        fn ordered(line: &str) -> Option<(usize, &str)> {
+            let rest = line.strip_prefix(char::is_whitespace)?.trim_start();
+            let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+            let item = rest[digits..].strip_prefix('.')?.strip_prefix(char::is_whitespace)?;
+            if digits == 0 { None } else { Some((rest[..digits].parse().ok()?, item.trim())) }
+        }
+        fn term(line: &str) -> Option<&str> {
+            line.trim_start().strip_prefix(':')?.strip_prefix(char::is_whitespace).map(|t| t.trim())
+        }
+        /// 前の項目の続きの行
+        fn continuation(line: &str) -> bool {
+            line.starts_with(char::is_whitespace) && !line.trim().is_empty()
+        }
+
+        let first = lines[0];
+        if unordered(first).is_some() {
+            let mut depth = 0;
+            let mut n = 0;
+            while n < lines.len() {
+                let (d, item) = match unordered(lines[n]) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let mut text = item.to_string();
+                n += 1;
+                while n < lines.len() && continuation(lines[n]) && unordered(lines[n]).is_none() {
+                    text.push_str(lines[n].trim());
+                    n += 1;
+                }
+
+                if d > depth {
+                    for _ in depth..d {
+                        out.push_str("<ul>\n<li>");
+                    }
+                } else {
+                    for _ in d..depth {
+                        out.push_str("</li>\n</ul>\n");
+                    }
+                    out.push_str("</li>\n<li>");
+                }
+                depth = d;
+                out.push_str(&self.inline(&text));
+            }
+            for _ in 0..depth {
+                out.push_str("</li>\n</ul>\n");
+            }
+            return Some(n);
+        }
+
+        if let Some((start, _)) = ordered(first) {
+            let start = if start == 1 { String::new() } else { format!(" start=\"{}\"", start) };
+            out.push_str(&format!("<ol{}>\n", start));
+            let mut n = 0;
+            while n < lines.len() {
+                let item = match ordered(lines[n]) {
+                    Some((_, item)) => item,
+                    None => break,
+                };
+                let mut text = item.to_string();
+                n += 1;
+                while n < lines.len() && continuation(lines[n]) && ordered(lines[n]).is_none() {
+                    text.push_str(lines[n].trim());
+                    n += 1;
+                }
+                out.push_str(&format!("<li>{}</li>\n", self.inline(&text)));
+            }
+            out.push_str("</ol>\n");
+            return Some(n);
+        }
+
+        if term(first).is_some() {
+            out.push_str("<dl>\n");
+            let mut n = 0;
+            while n < lines.len() {
+                let t = match term(lines[n]) {
+                    Some(t) => t,
+                    None => break,
+                };
+                out.push_str(&format!("<dt>{}</dt>\n", self.inline(t)));
+                n += 1;
+                let mut definition = Vec::new();
+                while n < lines.len() && continuation(lines[n]) && term(lines[n]).is_none() {
+                    definition.push(lines[n]);
+                    n += 1;
+                }
+                let mut dd = String::new();
+                self.paragraph(&definition, &mut dd);
+                let dd = dd.trim_end().strip_prefix("<p>").and_then(|d| d.strip_suffix("</p>")).unwrap_or(&dd).to_string();
+                out.push_str(&format!("<dd>{}</dd>\n", dd));
+            }
+            out.push_str("</dl>\n");
+            return Some(n);
+        }
+
+        None
+    }
+
+    fn block(&mut self, command: &BlockCommand, body: &[&str], out: &mut String) {
+        let code = |lines: &[&str]| xhtml::escape_text(&lines.join("\n"));
+        let name = command.name.as_str();
+
+        match name {
+            "list" | "listnum" | "emlist" | "emlistnum" | "source" | "cmd" => {
+                let (kind_id, caption, lang) = match name {
+                    "list" | "listnum" => (command.arg(0), command.arg(1), command.arg(2)),
+                    "cmd" => (None, command.arg(0), None),
+                    _ => (None, command.arg(0), command.arg(1)),
+                };
+                let id = kind_id.map(|id| format!(" id=\"list-{}\"", xhtml::escape_attr(id))).unwrap_or_default();
+                let caption = self.caption("list", kind_id, caption);
+                out.push_str(&format!("<div class=\"caption-code\"{}>\n", id));
+                if !caption.is_empty() {
+                    out.push_str(&format!("<p class=\"caption\">{}</p>\n", caption));
+                }
+                // 言語の指定があればハイライトの対象にするので, インライン命令は解釈しない
+                let class = if name == "cmd" { "cmd" } else { "list" };
+                match lang {
+                    Some(lang) => out.push_str(&format!(
+                        "<pre class=\"{}\"><code class=\"language-{}\">{}</code></pre>\n",
+                        class, xhtml::escape_attr(lang), code(body)
+                    )),
+                    None => out.push_str(&format!(
+                        "<pre class=\"{}\"><code>{}</code></pre>\n",
+                        class, body.iter().map(|l| self.inline(l)).collect::<Vec<String>>().join("\n")
+                    )),
+                }
+                out.push_str("</div>\n");
+            }
+            "image" | "indepimage" | "numberlessimage" => {
+                let id = match command.arg(0) {
+                    Some(id) => id,
+                    None => return,
+                };
+                let caption = if name == "image" {
+                    self.caption("image", Some(id), command.arg(1))
+                } else {
+                    command.arg(1).map(|c| self.inline(c)).unwrap_or_default()
+                };
+                // `scale=0.5`
+                let style = command.arg(2)
+                    .and_then(|m| m.split(',').find_map(|m| m.trim().strip_prefix("scale=")?.parse::<f32>().ok()))
+                    .map(|scale| format!(" style=\"width: {}%;\"", (scale * 100.0).round()))
+                    .unwrap_or_default();
+                out.push_str(&format!(
+                    "<figure id=\"image-{}\">\n<img src=\"{}\" alt=\"{}\"{} />\n",
+                    xhtml::escape_attr(id), xhtml::escape_attr(&self.image(id)),
+                    xhtml::escape_attr(&plain_text(command.arg(1).unwrap_or(id))), style
+                ));
+                if !caption.is_empty() {
+                    out.push_str(&format!("<figcaption>{}</figcaption>\n", caption));
+                }
+                out.push_str("</figure>\n");
+            }
+            "table" | "emtable" => {
+                let (id, caption) = if name == "table" { (command.arg(0), command.arg(1)) } else { (None, command.arg(0)) };
+                let caption = self.caption("table", id, caption);
+                let id = id.map(|id| format!(" id=\"table-{}\"", xhtml::escape_attr(id))).unwrap_or_default();
+                out.push_str(&format!("<table{}>\n", id));
+                if !caption.is_empty() {
+                    out.push_str(&format!("<caption>{}</caption>\n", caption));
+                }
+
+                let separator = body.iter().position(|l| l.starts_with(TABLE_SEPARATOR));
+                let cell = |c: &str| {
+                    // `.` のみのセルは空, 先頭の `.` は取り除く
+                    let c = c.strip_prefix('.').unwrap_or(c);
+                    self.inline(c)
+                };
+                for (n, row) in body.iter().enumerate() {
+                    if Some(n) == separator || row.trim().is_empty() {
+                        continue;
+                    }
+                    let tag = if separator.is_some_and(|s| n < s) { "th" } else { "td" };
+                    let cells = row.split('\t').filter(|c| !c.is_empty())
+                        .map(|c| format!("<{0}>{1}</{0}>", tag, cell(c)))
+                        .collect::<String>();
+                    out.push_str(&format!("<tr>{}</tr>\n", cells));
+                }
+                out.push_str("</table>\n");
+            }
+            "footnote" => {
+                if let (Some(id), Some(text)) = (command.arg(0), command.args.get(1)) {
+                    let text = self.inline(text);
+                    self.footnotes.push((id.to_string(), text));
+                }
+            }
+            "texequation" => {
+                let (id, caption) = match body.is_empty() {
+                    true => (None, None),
+                    false => (command.arg(0), command.arg(1)),
+                };
+                let caption = self.caption("equation", id, caption);
+                let id = id.map(|id| format!(" id=\"equation-{}\"", xhtml::escape_attr(id))).unwrap_or_default();
+                out.push_str(&format!("<div class=\"equation\"{}>\n", id));
+                if !caption.is_empty() {
+                    out.push_str(&format!("<p class=\"caption\">{}</p>\n", caption));
+                }
+                out.push_str(&Formula { tex: body.join("\n"), display: true }.to_mathml());
+                out.push_str("\n</div>\n");
+            }
+            "quote" => {
+                out.push_str("<blockquote>\n");
+                self.convert(body, out);
+                out.push_str("</blockquote>\n");
+            }
+            "lead" | "read" => {
+                out.push_str("<div class=\"lead\">\n");
+                self.convert(body, out);
+                out.push_str("</div>\n");
+            }
+            "flushright" | "centering" => {
+                let align = if name == "flushright" { "end" } else { "center" };
+                let lines = body.iter().map(|l| self.inline(l)).collect::<Vec<String>>().join("<br />");
+                out.push_str(&format!("<p class=\"{}\" style=\"text-align: {};\">{}</p>\n", name, align, lines));
+            }
+            "box" => {
+                out.push_str("<div class=\"syntax\">\n");
+                if let Some(caption) = command.arg(0) {
+                    out.push_str(&format!("<p class=\"caption\">{}</p>\n", self.inline(caption)));
+                }
+                out.push_str(&format!("<pre class=\"syntax\"><code>{}</code></pre>\n</div>\n", code(body)));
+            }
+            "embed" => {
+                // 対象のビルダーに html が含まれる場合のみ出力する
+                let builders = command.arg(0).unwrap_or("|html|");
+                if builders.split(['|', ',']).any(|b| b.trim() == "html") {
+                    out.push_str(&body.join("\n"));
+                    out.push('\n');
+                }
+            }
+            "raw" => {
+                if let Some(html) = command.arg(0).and_then(raw) {
+                    out.push_str(&html);
+                    out.push('\n');
+                }
+            }
+            "noindent" => self.noindent = true,
+            "blankline" => out.push_str("<p><br /></p>\n"),
+            "hr" => out.push_str("<hr />\n"),
+            "pagebreak" => out.push_str(&format!("<div class=\"{}\"></div>\n", aozora::PAGE_BREAK_CLASS)),
+            // admonition
+            _ if ADMONITIONS.iter().any(|(n, _)| *n == name) => {
+                let kind = ADMONITIONS.iter().find(|(n, _)| *n == name).map(|(_, k)| *k).unwrap_or("note");
+                let title = command.arg(0).map(|c| self.inline(c)).unwrap_or_default();
+                out.push_str(&block::open_marker(self.divs.len(), &title));
+                self.divs.push(block::Div { kind: Some(kind.to_string()), attributes: block::BlockAttributes::default() });
+                self.convert(body, out);
+                out.push_str(&block::close_marker());
+            }
+            // 見た目に関わらない命令
+            "comment" | "tsize" | "firstlinenum" | "olnum" | "label" | "printendnotes" | "beginchild" | "endchild"
+            | "graph" | "bibpaper" | "linebreak" => {}
+            // 未対応のブロックは, 中身を段落として出力する
+            _ => {
+                out.push_str(&format!("<div class=\"{}\">\n", xhtml::escape_attr(name)));
+                self.convert(body, out);
+                out.push_str("</div>\n");
+            }
+        }
+    }
+
+    fn footnotes(&self) -> String {
+        let mut html = String::new();
+        for (id, text) in &self.footnotes {
+            let n = self.numbers.get(&("footnote", id.clone())).copied().unwrap_or_default();
+            html.push_str(&format!(
+                "<aside id=\"fn-{0}\" class=\"footnote\" epub:type=\"footnote\"><p class=\"footnote\"><a href=\"#fnb-{0}\">[*{1}]</a> {2}</p></aside>\n",
+                xhtml::escape_attr(id), n, text
+            ));
+        }
+        html
+    }
+}
+
+/// `|html|<b>x</b>` のうち, html のビルダー向けの内容
+fn raw(s: &str) -> Option<String> {
+    match s.strip_prefix('|') {
+        Some(rest) => {
+            let end = rest.find('|')?;
+            if rest[..end].split(',').any(|b| b.trim() == "html") {
+                Some(rest[end + 1..].replace("\\n", "\n"))
+            } else {
+                None
+            }
+        }
+        None => Some(s.replace("\\n", "\n")),
+    }
+}
+
+/// `.re` ファイルを html に変換する. admonition とコラムは fenced div と同じく目印の段落として出力する
+pub fn convert(source: &str, context: &Context) -> (String, Vec<block::Div>) {
+    let mut converter = Converter::new(source, context);
+    let lines = source.lines().map(|l| l.trim_end_matches('\r')).collect::<Vec<&str>>();
+
+    let mut html = String::new();
+    converter.convert(&lines, &mut html);
+    html.push_str(&converter.footnotes());
+
+    (html, converter.divs)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert_str(source: &str) -> String {
+        let context = Context { dir: Path::new("."), id: "ch01", path: Path::new("ch01.xhtml"), language: "ja", chapters: &HashMap::new() };
+        convert(source, &context).0
+    }
+
+    #[test]
+    fn inline() {
+        assert_eq!(
+            convert_str("@<b>{太字}と@<code>{a < b}と@<ruby>{漢字, かんじ}と@<href>{https://example.com, 例}\n"),
+            "<p><b>太字</b>と<code>a &lt; b</code>と<ruby>漢字<rt>かんじ</rt></ruby>と<a href=\"https://example.com\" class=\"link\">例</a></p>\n"
+        );
+        assert_eq!(convert_str("@<code>$a}b$ @<kw>{API\\, SDK, 補足}\n"), "<p><code>a}b</code> <b class=\"kw\">API, SDK（補足）</b></p>\n");
+        assert_eq!(convert_str("メール@<foo"), "<p>メール@&lt;foo</p>\n");
+    }
+
+    #[test]
+    fn blocks() {
+        assert_eq!(
+            convert_str("={intro} はじめに\n\n本文は@<list>{hello}を参照@<fn>{note}。\n\n//list[hello][挨拶][rust]{\nfn main() {}\n//}\n\n//footnote[note][脚注です]\n"),
+            "<h1 id=\"intro\">はじめに</h1>\n\
+             <p>本文は<a href=\"#list-hello\">リスト1</a>を参照<a id=\"fnb-note\" href=\"#fn-note\" class=\"noteref\" epub:type=\"noteref\">*1</a>。</p>\n\
+             <div class=\"caption-code\" id=\"list-hello\">\n<p class=\"caption\">リスト1　挨拶</p>\n\
+             <pre class=\"list\"><code class=\"language-rust\">fn main() {}</code></pre>\n</div>\n\
+             <aside id=\"fn-note\" class=\"footnote\" epub:type=\"footnote\"><p class=\"footnote\"><a href=\"#fnb-note\">[*1]</a> 脚注です</p></aside>\n"
+        );
+        assert_eq!(
+            convert_str(" * 一\n ** 二\n * 三\n\n 1. A\n 2. B\n\n : 用語\n\t説明\n"),
+            "<ul>\n<li>一<ul>\n<li>二</li>\n</ul>\n</li>\n<li>三</li>\n</ul>\n\
+             <ol>\n<li>A</li>\n<li>B</li>\n</ol>\n\
+             <dl>\n<dt>用語</dt>\n<dd>説明</dd>\n</dl>\n"
+        );
+        assert_eq!(
+            convert_str("//table[t][表]{\n名前\t値\n------------\na\t.\n//}\n"),
+            "<table id=\"table-t\">\n<caption>表1　表</caption>\n<tr><th>名前</th><th>値</th></tr>\n<tr><td>a</td><td></td></tr>\n</table>\n"
+        );
+
+        let (html, divs) = convert("//note[注意書き]{\n中身\n//}\n", &Context { dir: Path::new("."), id: "ch01", path: Path::new("ch01.xhtml"), language: "ja", chapters: &HashMap::new() });
+        assert_eq!(html, format!("{}<p>中身</p>\n{}", block::open_marker(0, "注意書き"), block::close_marker()));
+        assert_eq!(divs[0].kind.as_deref(), Some("note"));
+    }
+
+    #[test]
+    fn chapter_links() {
+        let mut chapters = HashMap::new();
+        chapters.insert("ch02".to_string(), Chapter { title: "次の章".to_string(), path: PathBuf::from("part2/ch02.xhtml") });
+        let context = Context { dir: Path::new("."), id: "ch01", path: Path::new("part1/ch01.xhtml"), language: "ja", chapters: &chapters };
+        assert_eq!(
+            convert("@<chap>{ch02}と@<title>{ch02}と@<chap>{ch03}\n", &context).0,
+            "<p><a href=\"../part2/ch02.xhtml\">次の章</a>と<a href=\"../part2/ch02.xhtml\">「次の章」</a>と<a href=\"ch03.xhtml\">ch03</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn catalog() {
+        let summary = parse_catalog("PREDEF:\n  - pre.re\nCHAPS:\n  - ch01.re\n  - 第I部:\n    - ch02.re\nPOSTDEF:\n  - post.re\n", Path::new("/nonexistent")).unwrap();
+        let item = |title: &str, path: Option<&str>, level| SummaryItem { title: title.to_string(), path: path.map(PathBuf::from), level };
+        assert_eq!(summary.items, vec![
+            item("pre", Some("pre.re"), 1),
+            item("ch01", Some("ch01.re"), 1),
+            item("第I部", None, 1),
+            item("ch02", Some("ch02.re"), 2),
+            item("post", Some("post.re"), 1),
+        ]);
+
+        assert_eq!(title("#@# comment\n={ch} 章の@<b>{題名}\n== 節\n"), Some("章の題名".to_string()));
+    }
+}
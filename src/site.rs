@@ -0,0 +1,490 @@
+//! compose したコンテンツを, ブラウザで読める Web サイトとして書き出す
+//!
+//! コンテンツの`.xhtml`ファイルは, サイドバーの目次と前後のページへのリンクを加えて`.html`ファイルにする.
+//! スタイルシートや画像などは, OEBPS directory 内と同じ相対パスにコピーする
+//!
+//! `--format single-html`では, すべてのコンテンツを spine の順に1つの`.html`ファイルにまとめる.
+//! スタイルシートは`<style>`に, 画像は data URL に埋め込み, 他のファイルを参照しない
+
+use crate::{prelude::*, compose::{Publication, media_type::MediaType}, load::Format, xhtml, unpack};
+use std::{collections::{HashMap, HashSet}, path::{Component, Path}};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use html5ever::{
+    parse_document,
+    parse_fragment,
+    ParseOpts,
+    QualName,
+    rcdom::{RcDom, Handle},
+    tendril::TendrilSink,
+};
+
+/// サイト全体のレイアウトのスタイルシート
+const SITE_CSS: &str = "repub_site.css";
+const INDEX_HTML: &str = "index.html";
+
+/// 書き出す1ページ
+struct Page {
+    /// OEBPS directory 内の`.xhtml`ファイル
+    src: PathBuf,
+    /// サイトの directory からの相対パス
+    path: PathBuf,
+    title: String,
+}
+
+/// `<title>_html` directory に Web サイトを書き出す
+pub fn publish(publication: &Publication) -> RepubResult<()> {
+    let cfg = publication.cfg;
+    let dir = Format::Html.output_path(&cfg.title);
+
+    // スタイルシートと画像などは, そのままコピーする
+    for path in publication.styles.iter().chain(publication.statics.iter()) {
+        if let Some(rel_path) = relative_path(publication.root, path) {
+            let to = dir.join(&rel_path);
+            create_dir(&to)?;
+            std::fs::copy(path, &to)?;
+            RepubLog::packed(&format!("{:?}", &rel_path)).print();
+        }
+    }
+
+    std::fs::write(dir.join(SITE_CSS), include_str!("literals/site/site.css"))?;
+
+    let pages = publication.contents.iter()
+        .filter_map(|path| {
+            let rel_path = relative_path(publication.root, path)?;
+            let title = title_of(&std::fs::read_to_string(path).ok()?)
+                .unwrap_or_else(|| rel_path.file_stem().unwrap_or_default().to_string_lossy().to_string());
+            Some(Page {
+                src: path.to_path_buf(),
+                path: rel_path.with_extension("html"),
+                title,
+            })
+        })
+        .collect::<Vec<Page>>();
+
+    for (i, page) in pages.iter().enumerate() {
+        let prev = if i > 0 { pages.get(i - 1) } else { None };
+        let html = render_page(publication, page, prev, pages.get(i + 1))?;
+        let to = dir.join(&page.path);
+        create_dir(&to)?;
+        std::fs::write(&to, html)?;
+        RepubLog::converted(&format!("{:?}", &page.path)).print();
+    }
+
+    // index.html という名前のコンテンツがあれば, それを表紙のページとする
+    if pages.iter().all(|page| page.path != Path::new(INDEX_HTML)) {
+        let html = render_index(publication, pages.first())?;
+        std::fs::write(dir.join(INDEX_HTML), html)?;
+        RepubLog::packed(&INDEX_HTML).print();
+    }
+
+    RepubLog::published(&format!("{:?}", &dir)).print();
+
+    Ok(())
+}
+
+/// `<title>.html` に, すべてのコンテンツをまとめた1つの HTML ファイルを書き出す
+pub fn publish_single(publication: &Publication) -> RepubResult<()> {
+    let cfg = publication.cfg;
+    let path = Format::SingleHtml.output_path(&cfg.title);
+
+    // コンテンツのパス -> 何番目の章か
+    let chapters = publication.contents.iter()
+        .enumerate()
+        .map(|(i, path)| (path.to_path_buf(), i))
+        .collect::<HashMap<PathBuf, usize>>();
+
+    let mut styles = vec![include_str!("literals/site/single.css").to_string()];
+    let mut linked = HashSet::new();
+    let mut sections = Vec::new();
+
+    for (i, content) in publication.contents.iter().enumerate() {
+        let dom = parse_document(RcDom::default(), ParseOpts::default()).one(std::fs::read_to_string(content)?);
+
+        for node in elements(&dom.document) {
+            if xhtml::is_element(&node, "head") {
+                for child in node.children.borrow().iter() {
+                    if xhtml::is_element(child, "style") {
+                        let style = inline_urls(&xhtml::text_content(child), content);
+                        if !styles.contains(&style) {
+                            styles.push(style);
+                        }
+                    } else if xhtml::is_element(child, "link") && xhtml::get_attr(child, "rel").is_some_and(|rel| rel.contains("stylesheet")) {
+                        // 同じスタイルシートは一度だけ埋め込む
+                        let css = xhtml::get_attr(child, "href").and_then(|href| resolve(content, &href));
+                        if let Some(css) = css.filter(|css| linked.insert(css.clone())) {
+                            match std::fs::read_to_string(&css) {
+                                Ok(style) => styles.push(inline_urls(&style, &css)),
+                                Err(e) => RepubWarning(format!("{:?} : {}", &css, &e)).print(),
+                            }
+                        }
+                    }
+                }
+            } else if xhtml::is_element(&node, "body") {
+                inline(&node, content, Some(i), &chapters);
+                sections.push(format!(
+                    "<section class=\"repub-chapter\" id=\"{}\">\n{}\n</section>",
+                    chapter_id(i),
+                    xhtml::serialize_children(&node),
+                ));
+            }
+        }
+        RepubLog::converted(&format!("{:?}", relative_path(publication.root, content).unwrap_or_default())).print();
+    }
+
+    // 目次は本のはじめに置く
+    let index = publication.root.join(INDEX_HTML);
+    let toc = {
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        ).one(publication.toc.to_xhtml(cfg.toc_depth, &index));
+        let root = dom.document.children.borrow()[0].clone();
+        inline(&root, &index, None, &chapters);
+        xhtml::serialize_children(&root)
+    };
+
+    let cover = publication.cover_image
+        .and_then(data_url)
+        .map(|url| format!(
+            "<p><img class=\"repub-cover\" src=\"{}\" alt=\"{}\" /></p>",
+            url,
+            xhtml::escape_attr(&cfg.title),
+        ))
+        .unwrap_or_default();
+
+    let html = format!(
+        include_str!("literals/site/single.html"),
+        lang = xhtml::escape_attr(&cfg.language),
+        book_title = xhtml::escape_text(&cfg.title),
+        // `</style>`で閉じられてしまわないように
+        style = styles.join("\n").replace("</", "<\\/"),
+        creator = xhtml::escape_text(&cfg.creator),
+        cover = cover,
+        toc = toc,
+        chapters = sections.join("\n"),
+    );
+    std::fs::write(&path, html)?;
+
+    RepubLog::published(&format!("{:?}", &path)).print();
+
+    Ok(())
+}
+
+/// 章を囲む`<section>`の id
+fn chapter_id(chapter: usize) -> String {
+    format!("chapter{}", chapter)
+}
+
+/// 1つのファイルにまとめても衝突しないように, 章ごとに id に接頭辞を付ける
+fn anchor_id(chapter: usize, id: &str) -> String {
+    format!("{}-{}", chapter_id(chapter), id)
+}
+
+/// content の node 以下について, id を章ごとのものに, コンテンツへのリンクを文書内のリンクに書き換え,
+/// 画像などを data URL として埋め込む
+/// chapter は content が何番目の章か. 目次のようにどの章でもなければ None
+fn inline(node: &Handle, content: &Path, chapter: Option<usize>, chapters: &HashMap<PathBuf, usize>) {
+    if let (Some(id), Some(chapter)) = (xhtml::get_attr(node, "id"), chapter) {
+        xhtml::set_attr(node, "id", &anchor_id(chapter, &id));
+    }
+
+    if let Some(href) = xhtml::get_attr(node, "href") {
+        let (path, fragment) = match href.find('#') {
+            Some(hash) => (&href[..hash], Some(&href[hash + 1..])),
+            None => (href.as_str(), None),
+        };
+        let target = if path.is_empty() {
+            chapter
+        } else {
+            resolve(content, path).and_then(|path| chapters.get(&path).copied())
+        };
+        match (target, fragment) {
+            (Some(target), Some(fragment)) if !fragment.is_empty() => xhtml::set_attr(node, "href", &format!("#{}", anchor_id(target, fragment))),
+            (Some(target), _) => xhtml::set_attr(node, "href", &format!("#{}", chapter_id(target))),
+            // svg の`<image>`など
+            (None, _) if !xhtml::is_element(node, "a") && !xhtml::is_element(node, "link") => {
+                if let Some(url) = resolve(content, path).and_then(|path| data_url(&path)) {
+                    xhtml::set_attr(node, "href", &url);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for name in ["src", "poster"].iter() {
+        let url = xhtml::get_attr(node, name)
+            .and_then(|src| resolve(content, &src))
+            .and_then(|path| data_url(&path));
+        if let Some(url) = url {
+            xhtml::set_attr(node, name, &url);
+        }
+    }
+
+    for child in node.children.borrow().iter() {
+        inline(child, content, chapter, chapters);
+    }
+}
+
+/// css の`url()`で参照しているファイルを data URL として埋め込む
+fn inline_urls(css: &str, from: &Path) -> String {
+    let mut inlined = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("url(") {
+        let (before, after) = rest.split_at(start + "url(".len());
+        inlined.push_str(before);
+        let end = match after.find(')') {
+            Some(end) => end,
+            None => {
+                rest = after;
+                break;
+            }
+        };
+        let url = after[..end].trim().trim_matches(|c| c == '"' || c == '\'');
+        match resolve(from, url).and_then(|path| data_url(&path)) {
+            Some(data) => inlined.push_str(&format!("\"{}\"", data)),
+            None => inlined.push_str(&after[..end]),
+        }
+        rest = &after[end..];
+    }
+    inlined.push_str(rest);
+    inlined
+}
+
+/// from のファイルからの相対 URL が指すファイル. data URL や`http:`などの URL は None
+pub fn resolve(from: &Path, url: &str) -> Option<PathBuf> {
+    let path = url.split(['#', '?']).next().unwrap_or_default();
+    if path.is_empty() || url.split(['/', '#', '?']).next().is_some_and(|s| s.contains(':')) {
+        return None;
+    }
+
+    let mut resolved = PathBuf::new();
+    for component in from.parent()?.join(unpack::percent_decode(path)).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    Some(resolved)
+}
+
+/// ファイルを埋め込んだ data URL. EPUB3 に収録できない種類のファイルは None
+fn data_url(path: &Path) -> Option<String> {
+    let media_type = MediaType::try_from(&path.to_path_buf()).ok()?;
+    let bytes = std::fs::read(path).ok()?;
+    Some(format!("data:{};base64,{}", media_type.to_string(), STANDARD.encode(bytes)))
+}
+
+fn render_page(publication: &Publication, page: &Page, prev: Option<&Page>, next: Option<&Page>) -> RepubResult<String> {
+    let cfg = publication.cfg;
+    let src = std::fs::read_to_string(&page.src)?;
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(src);
+
+    // スタイルシートへの<link>と<style>は, そのまま<head>に置く
+    let mut head = String::new();
+    let mut body = String::new();
+    for node in elements(&dom.document) {
+        if xhtml::is_element(&node, "head") {
+            for child in node.children.borrow().iter() {
+                if xhtml::is_element(child, "link") || xhtml::is_element(child, "style") {
+                    head.push_str(&xhtml::serialize_children(&wrap(child.clone())));
+                    head.push('\n');
+                }
+            }
+        } else if xhtml::is_element(&node, "body") {
+            rewrite_links(&node);
+            body = xhtml::serialize_children(&node);
+        }
+    }
+
+    let toc = rewrite_links_in(&publication.toc.to_xhtml(cfg.toc_depth, &publication.root.join(&page.path)));
+
+    Ok(format!(
+        include_str!("literals/site/page.html"),
+        lang = xhtml::escape_attr(&cfg.language),
+        title = xhtml::escape_text(&format!("{} - {}", &page.title, &cfg.title)),
+        site_css = href(&page.path, Path::new(SITE_CSS)),
+        head = head,
+        index = href(&page.path, Path::new(INDEX_HTML)),
+        book_title = xhtml::escape_text(&cfg.title),
+        toc = toc,
+        body = body,
+        prev = prev.map(|prev| pager_link(page, prev, "repub-prev", "← 前へ")).unwrap_or_default(),
+        next = next.map(|next| pager_link(page, next, "repub-next", "次へ →")).unwrap_or_default(),
+    ))
+}
+
+fn render_index(publication: &Publication, first: Option<&Page>) -> RepubResult<String> {
+    let cfg = publication.cfg;
+    let index = Path::new(INDEX_HTML);
+
+    let cover = publication.cover_image
+        .and_then(|path| relative_path(publication.root, path))
+        .map(|path| format!(
+            "<p><img class=\"repub-cover\" src=\"{}\" alt=\"{}\" /></p>",
+            href(index, &path),
+            xhtml::escape_attr(&cfg.title),
+        ))
+        .unwrap_or_default();
+
+    let toc = rewrite_links_in(&publication.toc.to_xhtml(cfg.toc_depth, &publication.root.join(index)));
+
+    Ok(format!(
+        include_str!("literals/site/index.html"),
+        lang = xhtml::escape_attr(&cfg.language),
+        book_title = xhtml::escape_text(&cfg.title),
+        site_css = SITE_CSS,
+        creator = xhtml::escape_text(&cfg.creator),
+        cover = cover,
+        toc = toc,
+        first = first.map(|first| pager_link(&Page {
+            src: PathBuf::new(),
+            path: index.to_path_buf(),
+            title: String::new(),
+        }, first, "repub-next", "読む →")).unwrap_or_default(),
+    ))
+}
+
+fn pager_link(from: &Page, to: &Page, class: &str, label: &str) -> String {
+    format!(
+        "<a class=\"{}\" href=\"{}\">{} {}</a>",
+        class,
+        href(&from.path, &to.path),
+        label,
+        xhtml::escape_text(&to.title),
+    )
+}
+
+/// ページの題名. 最初の見出しがなければ`<title>`を使う
+fn title_of(src: &str) -> Option<String> {
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(src);
+    let nodes = elements(&dom.document);
+    let heading = nodes.iter()
+        .find(|node| ["h1", "h2", "h3", "h4", "h5", "h6"].iter().any(|h| xhtml::is_element(node, h)));
+    let title = nodes.iter().find(|node| xhtml::is_element(node, "title"));
+
+    heading.into_iter().chain(title)
+        .map(|node| xhtml::text_content(node).trim().to_string())
+        .find(|text| !text.is_empty())
+}
+
+/// node の子孫の要素を文書順に並べる
+fn elements(node: &Handle) -> Vec<Handle> {
+    fn walk(node: &Handle, found: &mut Vec<Handle>) {
+        for child in node.children.borrow().iter() {
+            if let html5ever::rcdom::NodeData::Element { .. } = child.data {
+                found.push(child.clone());
+            }
+            walk(child, found);
+        }
+    }
+
+    let mut found = Vec::new();
+    walk(node, &mut found);
+    found
+}
+
+/// serialize_children で node 自身を書き出すために, 親を作る
+fn wrap(node: Handle) -> Handle {
+    let parent = xhtml::create_element("div", &[]);
+    parent.children.borrow_mut().push(node);
+    parent
+}
+
+/// `.xhtml`ファイルへのリンクを`.html`ファイルへのリンクに書き換える
+fn rewrite_links(node: &Handle) {
+    if let Some(href) = xhtml::get_attr(node, "href") {
+        let (path, fragment) = match href.find('#') {
+            Some(hash) => href.split_at(hash),
+            None => (href.as_str(), ""),
+        };
+        if let Some(stem) = path.strip_suffix(".xhtml").filter(|_| !path.contains(':')) {
+            xhtml::set_attr(node, "href", &format!("{}.html{}", stem, fragment));
+        }
+    }
+    for child in node.children.borrow().iter() {
+        rewrite_links(child);
+    }
+}
+
+fn rewrite_links_in(html: &str) -> String {
+    let dom = parse_fragment(
+        RcDom::default(),
+        ParseOpts::default(),
+        QualName::new(None, ns!(html), local_name!("body")),
+        vec![],
+    ).one(html);
+    let root = dom.document.children.borrow()[0].clone();
+    rewrite_links(&root);
+    xhtml::serialize_children(&root)
+}
+
+/// from のページから to へのリンク. どちらもサイトの directory からの相対パス
+fn href(from: &Path, to: &Path) -> String {
+    let base = PathBuf::from("/").join(from);
+    let to = PathBuf::from("/").join(to);
+    PathBuf::path_diff(&base, &to)
+        .map(|path| xhtml::escape_attr(&path.to_string_lossy()))
+        .unwrap_or_default()
+}
+
+fn relative_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    path.strip_prefix(root).ok().map(|path| path.to_path_buf())
+}
+
+fn create_dir(path: &Path) -> RepubResult<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn links() {
+        assert_eq!(
+            rewrite_links_in("<p><a href=\"ch1.xhtml#s\">a</a><a href=\"https://example.com/a.xhtml\">b</a><a href=\"#x\">c</a></p>"),
+            "<p><a href=\"ch1.html#s\">a</a><a href=\"https://example.com/a.xhtml\">b</a><a href=\"#x\">c</a></p>"
+        );
+        assert_eq!(href(Path::new("part/ch1.html"), Path::new("repub_site.css")), "../repub_site.css");
+        assert_eq!(href(Path::new("ch1.html"), Path::new("part/ch2.html")), "part/ch2.html");
+    }
+
+    #[test]
+    fn single() {
+        let chapters = vec![
+            (PathBuf::from("/o/ch1.xhtml"), 0),
+            (PathBuf::from("/o/part/ch2.xhtml"), 1),
+        ].into_iter().collect::<HashMap<PathBuf, usize>>();
+
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        ).one("<h2 id=\"s\">a</h2><a href=\"../ch1.xhtml#top\">b</a><a href=\"ch2.xhtml\">c</a><a href=\"#s\">d</a><a href=\"https://example.com/\">e</a>");
+        let root = dom.document.children.borrow()[0].clone();
+        inline(&root, Path::new("/o/part/ch2.xhtml"), Some(1), &chapters);
+        assert_eq!(
+            xhtml::serialize_children(&root),
+            "<h2 id=\"chapter1-s\">a</h2><a href=\"#chapter0-top\">b</a><a href=\"#chapter1\">c</a><a href=\"#chapter1-s\">d</a><a href=\"https://example.com/\">e</a>"
+        );
+
+        assert_eq!(resolve(Path::new("/o/part/ch2.xhtml"), "../img/a%20b.png?x"), Some(PathBuf::from("/o/img/a b.png")));
+        assert_eq!(resolve(Path::new("/o/ch1.xhtml"), "data:image/png;base64,AA"), None);
+        assert_eq!(inline_urls("a { background: url( 'none.png' ); }", Path::new("/o/style.css")), "a { background: url( 'none.png' ); }");
+    }
+
+    #[test]
+    fn title() {
+        assert_eq!(title_of("<html><head><title>file</title></head><body><p>a</p><h2> 見出し </h2></body></html>"), Some("見出し".to_string()));
+        assert_eq!(title_of("<html><head><title>file</title></head><body><p>a</p></body></html>"), Some("file".to_string()));
+    }
+}
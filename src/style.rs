@@ -0,0 +1,44 @@
+//! repub に同梱する基本のスタイルシート
+//!
+//! 横書きと縦書きの2種類があり, どちらも日本語の組版を前提とする.
+//! ユーザーのスタイルシートより先に読み込むので, ユーザーのスタイルシートで上書きできる
+
+use crate::load::{BaseStyle, WritingMode};
+
+pub const BASE_CSS: &str = "repub_base.css";
+const HORIZONTAL_STYLE: &str = include_str!("literals/base/horizontal.css");
+const VERTICAL_STYLE: &str = include_str!("literals/base/vertical.css");
+
+/// 適用する基本のスタイルシート. 適用しなければ None
+/// has_user_css はユーザーのスタイルシートがあるか否か
+pub fn base_style(base: BaseStyle, mode: &WritingMode, has_user_css: bool) -> Option<String> {
+    let vertical = match (base, mode) {
+        (BaseStyle::None, _) => return None,
+        (BaseStyle::Auto, _) if has_user_css => return None,
+        (BaseStyle::Auto, WritingMode::HorizontalTb) | (BaseStyle::Horizontal, _) => false,
+        (BaseStyle::Auto, _) | (BaseStyle::Vertical, _) => true,
+    };
+
+    if !vertical {
+        Some(HORIZONTAL_STYLE.to_string())
+    } else if let WritingMode::VerticalLr = mode {
+        Some(VERTICAL_STYLE.replace("vertical-rl", "vertical-lr"))
+    } else {
+        Some(VERTICAL_STYLE.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn selection() {
+        assert_eq!(base_style(BaseStyle::Auto, &WritingMode::HorizontalTb, false), Some(HORIZONTAL_STYLE.to_string()));
+        assert_eq!(base_style(BaseStyle::Auto, &WritingMode::VerticalRl, false), Some(VERTICAL_STYLE.to_string()));
+        assert_eq!(base_style(BaseStyle::Auto, &WritingMode::VerticalRl, true), None);
+        assert_eq!(base_style(BaseStyle::Vertical, &WritingMode::HorizontalTb, true), Some(VERTICAL_STYLE.to_string()));
+        assert!(base_style(BaseStyle::Vertical, &WritingMode::VerticalLr, true).unwrap().contains("writing-mode: vertical-lr;"));
+        assert_eq!(base_style(BaseStyle::None, &WritingMode::VerticalRl, false), None);
+    }
+}
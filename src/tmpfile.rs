@@ -4,7 +4,7 @@ pub use meta_inf::MetaInf;
 pub use oebps::OEBPS;
 pub use mimetype::Mimetype;
 
-const TMP_DIR_PATH_STR: &str = "repub_tmp";
+pub const TMP_DIR_PATH_STR: &str = "repub_tmp";
 
 pub struct TmpDir {
     /// 一時ディレクトリのpath
@@ -50,6 +50,10 @@ impl TableOfContents {
         }
     }
 
+    pub fn items(&self) -> &Vec<Box<dyn ToCItemTrait>> {
+        &self.items
+    }
+
     pub fn size(&self) -> usize {
         let mut size = 0;
         for item in &self.items {
@@ -89,6 +93,21 @@ pub trait ToCItemTrait {
 
     fn to_a(&self, navigation_path: &PathBuf) -> Option<A>;
 
+    /// リンクを持たない項目の見出し
+    fn to_span(&self) -> Option<Span> {
+        None
+    }
+
+    /// 項目の見出し. XHTML として escape 済み
+    fn title(&self) -> Option<&str> {
+        None
+    }
+
+    /// 項目の指すコンテンツのパスと, その中の id
+    fn target(&self) -> Option<(&PathBuf, Option<&str>)> {
+        None
+    }
+
     fn to_xhtml_elem(&self, min_level: u8, navigation_path: &PathBuf) -> Box<dyn Elem> {
         let mut li = LI {
             elems: Vec::new(),
@@ -97,6 +116,8 @@ pub trait ToCItemTrait {
         let a = self.to_a(navigation_path);
         if let Some(a) = a {
             li.push(Box::new(a));
+        } else if let Some(span) = self.to_span() {
+            li.push(Box::new(span));
         }
 
         let li_vec
@@ -156,12 +177,69 @@ impl ToCItemTrait for ToCItem {
                             .map(|s| s.to_string())
                             .unwrap_or_default()
                     }).unwrap_or_default();
-                let id = self.id.clone().unwrap_or_default();
-
-                format!("{}#{}", &path, &id)
+                match &self.id {
+                    Some(id) => format!("{}#{}", &path, id),
+                    None => path,
+                }
             },
         })
     }
+
+    fn title(&self) -> Option<&str> {
+        Some(&self.title)
+    }
+
+    fn target(&self) -> Option<(&PathBuf, Option<&str>)> {
+        Some((&self.path_buf, self.id.as_deref()))
+    }
+}
+
+/// 部の見出しのように, リンクを持たない項目
+pub struct ToCPartItem {
+    items: Vec<Box<dyn ToCItemTrait>>,
+    title: String,
+    level: u8,
+}
+
+impl ToCPartItem {
+    pub fn new(title: String, level: u8) -> Self {
+        Self {
+            items: Vec::new(),
+            title,
+            level,
+        }
+    }
+}
+
+impl ToCItemTrait for ToCPartItem {
+    fn items(&self) -> &Vec<Box<dyn ToCItemTrait>> {
+        self.items.as_ref()
+    }
+
+    fn items_mut(&mut self) -> &mut Vec<Box<dyn ToCItemTrait>> {
+        self.items.as_mut()
+    }
+
+    fn level(&self) -> u8 {
+        self.level
+    }
+
+    fn is_dummy(&self) -> bool {
+        false
+    }
+
+    #[allow(unused_variables)]
+    fn to_a(&self, navigation_path: &PathBuf) -> Option<A> {
+        None
+    }
+
+    fn to_span(&self) -> Option<Span> {
+        Some(Span { text: self.title.clone() })
+    }
+
+    fn title(&self) -> Option<&str> {
+        Some(&self.title)
+    }
 }
 
 pub struct ToCDummyItem {
@@ -283,6 +361,23 @@ mod xhtml_elem {
         }
     }
 
+    pub struct Span {
+        pub text: String,
+    }
+
+    impl Elem for Span {
+        fn elems(&self) -> Option<&Vec<Box<dyn Elem>>> {
+            None
+        }
+
+        fn to_html(&self) -> String {
+            format!("<span>{}</span>", &self.text)
+        }
+
+        /// 子要素を持たないので, 何もしない
+        fn push(&mut self, _elem: Box<dyn Elem>) {}
+    }
+
     #[test]
     fn test() {
         use super::*;
@@ -0,0 +1,1052 @@
+//! 既存の EPUB を, repub で編集, 再変換できるディレクトリに展開する
+//!
+//! package document (OPF) を読み, コンテンツは markdown に (markdown で表せない要素を含むものは HTML のまま),
+//! 画像, フォント, スタイルシートはそのまま書き出す (IDPF の方法で難読化されたフォントは元に戻す).
+//! メタデータ, spine の順序, 表紙, manifest の property は`repub_config.json`に書き出す
+
+use crate::{prelude::*, load::{Config, ContentConfigure, WritingMode}, compose::Properties, xhtml, font};
+use html5ever::rcdom::{Handle, NodeData};
+use std::{collections::{HashMap, HashSet}, path::{Component, Path}};
+
+const CONTAINER_XML: &str = "META-INF/container.xml";
+const ENCRYPTION_XML: &str = "META-INF/encryption.xml";
+const OPF_NS: &str = "http://www.idpf.org/2007/opf";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+
+/// コンテンツとして扱う media type
+const CONTENT_TYPES: [&str; 2] = ["application/xhtml+xml", "text/html"];
+/// repub が生成し直すので展開しない NCX の media type
+const NCX_TYPE: &str = "application/x-dtbncx+xml";
+
+/// manifest の item
+#[derive(Debug, Default, PartialEq)]
+struct Item {
+    id: String,
+    /// OPF のあるディレクトリからの相対 URL
+    href: String,
+    media_type: String,
+    properties: Vec<String>,
+}
+
+/// package document から読み取った本の情報
+#[derive(Debug, Default)]
+struct Package {
+    title: Option<String>,
+    creators: Vec<String>,
+    language: Option<String>,
+    /// unique-identifier で指定された dc:identifier
+    identifier: Option<String>,
+    items: Vec<Item>,
+    /// spine の itemref の idref
+    spine: Vec<String>,
+    /// spine の page-progression-direction
+    page_progression_direction: Option<String>,
+    /// EPUB 2 の`<meta name="cover">`で指定された, 表紙の画像の item の id
+    cover: Option<String>,
+}
+
+impl Package {
+    fn parse(opf: &str) -> RepubResult<Self> {
+        let document = roxmltree::Document::parse(opf)?;
+        let root = document.root_element();
+        let unique_identifier = root.attribute("unique-identifier");
+
+        fn text(node: &roxmltree::Node) -> Option<String> {
+            let text = node.descendants().filter(|n| n.is_text()).filter_map(|n| n.text()).collect::<String>();
+            Some(text.trim().to_string()).filter(|t| !t.is_empty())
+        }
+
+        let mut package = Self::default();
+        for node in root.descendants().filter(|n| n.is_element()) {
+            let name = node.tag_name();
+            match (name.namespace(), name.name()) {
+                (Some(DC_NS), "title") if package.title.is_none() => package.title = text(&node),
+                (Some(DC_NS), "creator") => package.creators.extend(text(&node)),
+                (Some(DC_NS), "language") if package.language.is_none() => package.language = text(&node),
+                (Some(DC_NS), "identifier")
+                    if package.identifier.is_none() || (unique_identifier.is_some() && node.attribute("id") == unique_identifier) => {
+                    package.identifier = text(&node);
+                }
+                (Some(OPF_NS), "meta") if node.attribute("name") == Some("cover") => {
+                    package.cover = node.attribute("content").map(|c| c.to_string());
+                }
+                (Some(OPF_NS), "item") => package.items.push(Item {
+                    id: node.attribute("id").unwrap_or_default().to_string(),
+                    href: node.attribute("href").unwrap_or_default().to_string(),
+                    media_type: node.attribute("media-type").unwrap_or_default().to_string(),
+                    properties: node.attribute("properties").unwrap_or_default()
+                        .split_whitespace()
+                        .map(|p| p.to_string())
+                        .collect(),
+                }),
+                (Some(OPF_NS), "spine") => {
+                    package.page_progression_direction = node.attribute("page-progression-direction").map(|d| d.to_string());
+                }
+                (Some(OPF_NS), "itemref") => package.spine.extend(node.attribute("idref").map(|i| i.to_string())),
+                _ => {}
+            }
+        }
+
+        Ok(package)
+    }
+
+    fn item(&self, id: &str) -> Option<&Item> {
+        self.items.iter().find(|i| i.id == id)
+    }
+
+    /// 表紙の画像の item
+    fn cover_image(&self) -> Option<&Item> {
+        self.items.iter().find(|i| i.properties.iter().any(|p| p == "cover-image"))
+            .or_else(|| self.cover.as_ref().and_then(|id| self.item(id)))
+    }
+}
+
+/// container.xml が指定する package document のパス
+fn rootfile(container: &str) -> RepubResult<PathBuf> {
+    let document = roxmltree::Document::parse(container)?;
+    let full_path = document.descendants()
+        .find(|n| n.tag_name().name() == "rootfile")
+        .and_then(|n| n.attribute("full-path"))
+        .ok_or_else(|| format_err!("{} に package document の指定がありません", CONTAINER_XML))?;
+
+    enclosed(&percent_decode(full_path))
+        .ok_or_else(|| format_err!("{} は EPUB の外を指しています", full_path))
+}
+
+/// アーカイブ内の相対パスを正規化する
+/// 絶対パスや, `..`によってアーカイブの外を指すパスは展開先の外に書き出されてしまうので None
+fn enclosed(path: &str) -> Option<PathBuf> {
+    // EPUB のパスの区切りは`/`のみ. `\`は Windows で区切りとして解釈されてしまう
+    if path.contains('\\') {
+        return None;
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(c) => normalized.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(normalized).filter(|p| !p.as_os_str().is_empty())
+}
+
+/// encryption.xml に記載された, 暗号化されているファイルのパスと, その暗号化のアルゴリズム
+fn encrypted(xml: &str) -> RepubResult<HashMap<PathBuf, Option<String>>> {
+    let document = roxmltree::Document::parse(xml)?;
+    Ok(document.descendants()
+        .filter(|n| n.tag_name().name() == "EncryptedData")
+        .flat_map(|data| {
+            let algorithm = data.children()
+                .find(|n| n.tag_name().name() == "EncryptionMethod")
+                .and_then(|n| n.attribute("Algorithm"))
+                .map(str::to_string);
+            data.descendants()
+                .filter(|n| n.tag_name().name() == "CipherReference")
+                .filter_map(|n| enclosed(&percent_decode(n.attribute("URI")?)))
+                .map(move |path| (path, algorithm.clone()))
+        })
+        .collect())
+}
+
+/// zip の中でのファイル名. 区切りは常に`/`
+fn zip_name(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// URL の`%xx`を元に戻す
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = s.get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// `http:`, `mailto:`などのスキームを持つ URL か否か
+fn is_remote(href: &str) -> bool {
+    href.split(['/', '#', '?']).next().is_some_and(|s| s.contains(':'))
+}
+
+/// from のコンテンツからの href が指すファイルと, その中の id
+fn resolve(from: &Path, href: &str) -> Option<(PathBuf, Option<String>)> {
+    if is_remote(href) {
+        return None;
+    }
+    let (path, fragment) = match href.find('#') {
+        Some(hash) => (&href[..hash], Some(href[hash + 1..].to_string())),
+        None => (href, None),
+    };
+    let path = if path.is_empty() {
+        from.to_path_buf()
+    } else {
+        let dir = from.parent().map(zip_name).unwrap_or_default();
+        let path = percent_decode(path);
+        enclosed(&if dir.is_empty() { path } else { format!("{}/{}", dir, path) })?
+    };
+    Some((path, fragment))
+}
+
+fn elements(node: &Handle, found: &mut Vec<Handle>) {
+    if let NodeData::Element { .. } = node.data {
+        found.push(node.clone());
+    }
+    for child in node.children.borrow().iter() {
+        elements(child, found);
+    }
+}
+
+fn find(node: &Handle, local: &str) -> Option<Handle> {
+    node.children.borrow().iter().find(|c| xhtml::is_element(c, local)).cloned()
+}
+
+/// 展開するコンテンツ
+struct Content<'a> {
+    item: &'a Item,
+    /// OPF のあるディレクトリ (展開先) からの相対パス
+    path: PathBuf,
+    data: Vec<u8>,
+    dom: html5ever::rcdom::RcDom,
+}
+
+impl Content<'_> {
+    fn head(&self) -> Option<Handle> {
+        find(&find(&self.dom.document, "html")?, "head")
+    }
+
+    fn body(&self) -> Option<Handle> {
+        find(&find(&self.dom.document, "html")?, "body")
+    }
+
+    /// `<link rel="stylesheet">`で適用されているスタイルシート
+    fn styles(&self) -> Vec<PathBuf> {
+        let mut links = Vec::new();
+        if let Some(head) = self.head() {
+            elements(&head, &mut links);
+        }
+        links.iter()
+            .filter(|l| xhtml::is_element(l, "link"))
+            .filter(|l| xhtml::get_attr(l, "rel").is_some_and(|r| r.split_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet"))))
+            .filter_map(|l| resolve(&self.path, &xhtml::get_attr(l, "href")?))
+            .map(|(path, _)| path)
+            .collect()
+    }
+}
+
+/// EPUB を dir に展開する. xhtml が true ならば, コンテンツを markdown に変換しない
+pub fn unpack(epub: &Path, dir: &Path, xhtml: bool) -> RepubResult<()> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(epub)?)?;
+
+    fn read(archive: &mut zip::ZipArchive<std::fs::File>, name: &str) -> Option<Vec<u8>> {
+        let mut file = archive.by_name(name).ok()?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).ok()?;
+        Some(bytes)
+    }
+
+    fn write(path: &Path, data: &[u8]) -> RepubResult<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::File::create(path)?.write_all(data)?;
+        Ok(())
+    }
+
+    let container = read(&mut archive, CONTAINER_XML)
+        .ok_or_else(|| format_err!("{:?} に {} がありません", epub, CONTAINER_XML))?;
+    let opf_path = rootfile(&String::from_utf8_lossy(&container))?;
+    let opf = read(&mut archive, &zip_name(&opf_path))
+        .ok_or_else(|| format_err!("{:?} に {:?} がありません", epub, opf_path))?;
+    let package = Package::parse(&String::from_utf8_lossy(&opf))?;
+    let opf_dir = opf_path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+    // 既存のファイルを上書きしないよう, 展開先は空のディレクトリに限る
+    if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+        return Err(format_err!("{:?} は空のディレクトリではありません", dir));
+    }
+    std::fs::create_dir_all(dir)?;
+
+    // 難読化, 暗号化されているファイル
+    let encrypted = read(&mut archive, ENCRYPTION_XML)
+        .map(|xml| encrypted(&String::from_utf8_lossy(&xml)))
+        .transpose()?
+        .unwrap_or_default();
+
+    // ナビゲーション文書と NCX は repub が生成するので展開しない
+    let mut contents = Vec::new();
+    let mut nav = None;
+    for item in &package.items {
+        if is_remote(&item.href) {
+            continue;
+        }
+        let path = match enclosed(&percent_decode(&item.href)) {
+            Some(path) => path,
+            None => {
+                RepubWarning(format!("{} は展開先の外を指しているため, 展開しません", &item.href)).print();
+                continue;
+            }
+        };
+        let data = match read(&mut archive, &zip_name(&opf_dir.join(&path))) {
+            Some(data) => data,
+            None => {
+                RepubWarning(format!("{:?} が EPUB に含まれていません", &path)).print();
+                continue;
+            }
+        };
+
+        if item.properties.iter().any(|p| p == "nav") {
+            RepubLog::ignored(&format!("{:?}", &path)).print();
+            nav = Some(data);
+        } else if item.media_type == NCX_TYPE {
+            RepubLog::ignored(&format!("{:?}", &path)).print();
+        } else if CONTENT_TYPES.contains(&item.media_type.as_str()) {
+            let dom = {
+                use html5ever::{parse_document, ParseOpts, rcdom::RcDom, tendril::TendrilSink};
+                parse_document(RcDom::default(), ParseOpts::default()).one(String::from_utf8_lossy(&data).as_ref())
+            };
+            contents.push(Content { item, path, data, dom });
+        } else {
+            let mut data = data;
+            match encrypted.get(&opf_dir.join(&path)) {
+                // IDPF の方法で難読化されたフォントは元に戻す
+                Some(Some(algorithm)) if algorithm == font::ALGORITHM => {
+                    font::obfuscate(&mut data, &font::key(package.identifier.as_deref().unwrap_or_default()));
+                }
+                Some(_) => {
+                    RepubWarning(format!("{:?} は暗号化されているため, そのまま書き出しました", &path)).print();
+                }
+                None => (),
+            }
+            write(&dir.join(&path), &data)?;
+            RepubLog::packed(&format!("{:?}", &path)).print();
+        }
+    }
+
+    // コンテンツの id のうち, 他のコンテンツ (自身を含む) からリンクされているもの
+    // markdown に変換して失われると, リンクが切れてしまう
+    let mut referenced: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    for content in &contents {
+        let mut found = Vec::new();
+        elements(&content.dom.document, &mut found);
+        for href in found.iter().filter_map(|e| xhtml::get_attr(e, "href")) {
+            if let Some((path, Some(id))) = resolve(&content.path, &href) {
+                referenced.entry(path).or_default().insert(id);
+            }
+        }
+    }
+
+    // markdown に変換できるかを調べてから, 変換後のファイル名へのリンクに書き換えて変換する
+    let no_ids = HashSet::new();
+    let convertible = contents.iter()
+        .map(|c| {
+            let referenced = referenced.get(&c.path).unwrap_or(&no_ids);
+            let renamed = HashMap::new();
+            !xhtml && Markdown { path: &c.path, referenced, renamed: &renamed }.document(c).is_some()
+        })
+        .collect::<Vec<bool>>();
+    let renamed = contents.iter().zip(&convertible)
+        .map(|(c, md)| (c.path.clone(), c.path.with_extension(if *md { "md" } else { "html" })))
+        .collect::<HashMap<PathBuf, PathBuf>>();
+
+    let stylesheets = package.items.iter()
+        .filter(|i| i.media_type == "text/css")
+        .filter_map(|i| enclosed(&percent_decode(&i.href)))
+        .collect::<HashSet<PathBuf>>();
+
+    let mut content_configures = Vec::new();
+    for (content, md) in contents.iter().zip(&convertible) {
+        let path = &renamed[&content.path];
+        if *md {
+            let referenced = referenced.get(&content.path).unwrap_or(&no_ids);
+            let markdown = Markdown { path: &content.path, referenced, renamed: &renamed }.document(content).unwrap_or_default();
+            write(&dir.join(path), markdown.as_bytes())?;
+            RepubLog::converted(&format!("{:?}", path)).print();
+        } else {
+            // repub は HTML の <head> の <style> と <body> の中身を取り込む
+            write(&dir.join(path), &content.data)?;
+            RepubLog::packed(&format!("{:?}", path)).print();
+        }
+
+        // 一部のスタイルシートのみを適用しているコンテンツ, property が宣言されているコンテンツ
+        let styles = content.styles();
+        let properties = content.item.properties.iter()
+            .filter_map(|p| serde_json::from_value::<Properties>(serde_json::Value::String(p.clone())).ok())
+            .filter(|p| p.is_detectable())
+            .collect::<Vec<Properties>>();
+        if !properties.is_empty() || styles.iter().collect::<HashSet<_>>() != stylesheets.iter().collect() {
+            content_configures.push(ContentConfigure {
+                src: path.clone(),
+                properties,
+                styles,
+                markdown: None,
+                split_level: None,
+            });
+        }
+    }
+
+    // spine の順に並べ, spine にないコンテンツは最後に置く
+    let mut sequence = package.spine.iter()
+        .filter_map(|idref| package.item(idref))
+        .filter_map(|item| contents.iter().find(|c| std::ptr::eq(c.item, item)))
+        .map(|c| renamed[&c.path].clone())
+        .collect::<Vec<PathBuf>>();
+    for content in &contents {
+        let path = &renamed[&content.path];
+        if !sequence.contains(path) {
+            sequence.push(path.clone());
+        }
+    }
+
+    let cover_image = package.cover_image().and_then(|i| enclosed(&percent_decode(&i.href)));
+
+    let book_id = package.identifier.clone().unwrap_or_else(|| {
+        use rand::{Rng, distributions::Alphanumeric};
+        rand::thread_rng().sample_iter(&Alphanumeric).take(30).collect::<String>()
+    });
+
+    let mut ignores = vec![PathBuf::from(CONFIG_JSON), PathBuf::from(".DS_Store")];
+    ignores.extend(cover_image.clone());
+
+    let cfg = Config {
+        target: dir.to_path_buf(),
+        writing_mode: match package.page_progression_direction.as_deref() {
+            Some("rtl") => WritingMode::VerticalRl,
+            _ => WritingMode::HorizontalTb,
+        },
+        title: package.title.clone().unwrap_or_default(),
+        creator: package.creators.join(", "),
+        language: package.language.clone().unwrap_or_default(),
+        book_id,
+        toc_depth: nav.map(|nav| toc_depth(&String::from_utf8_lossy(&nav))).unwrap_or(2),
+        cover_image,
+        ignores,
+        sequence: Some(sequence),
+        content_configures: Some(content_configures).filter(|c| !c.is_empty()),
+        ..Config::default()
+    };
+
+    let path = dir.join(CONFIG_JSON);
+    write(&path, serde_json::to_string_pretty(&cfg)?.as_bytes())?;
+    RepubLog::config(&format!("Saved to {:?}", &path)).print();
+
+    Ok(())
+}
+
+/// ナビゲーション文書の目次の深さ
+fn toc_depth(nav: &str) -> u8 {
+    use html5ever::{parse_document, ParseOpts, rcdom::RcDom, tendril::TendrilSink};
+
+    fn depth(node: &Handle) -> u8 {
+        let deepest = node.children.borrow().iter().map(depth).max().unwrap_or(0);
+        if xhtml::is_element(node, "ol") { deepest + 1 } else { deepest }
+    }
+
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(nav);
+    let mut found = Vec::new();
+    elements(&dom.document, &mut found);
+    found.iter()
+        .find(|e| xhtml::is_element(e, "nav") && xhtml::get_attr(e, "epub:type").is_some_and(|t| t.split_whitespace().any(|t| t == "toc")))
+        .map(depth)
+        .filter(|d| *d > 0)
+        .unwrap_or(2)
+}
+
+/// XHTML のコンテンツを markdown に変換する
+/// markdown (と repub のブロック属性, fenced div) で表せない要素, 属性を含む場合は None
+struct Markdown<'a> {
+    /// 変換するコンテンツのパス
+    path: &'a Path,
+    /// このコンテンツの id のうち, リンクされているもの
+    referenced: &'a HashSet<String>,
+    /// コンテンツの変換前のパス -> 変換後のパス
+    renamed: &'a HashMap<PathBuf, PathBuf>,
+}
+
+/// 見出し
+const HEADINGS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+impl Markdown<'_> {
+    fn document(&self, content: &Content) -> Option<String> {
+        // <head> の <style> や <script> は markdown では表せない
+        let head = content.head()?;
+        let plain_head = head.children.borrow().iter().all(|c| match c.data {
+            NodeData::Element { .. } => ["title", "meta", "link"].iter().any(|l| xhtml::is_element(c, l)),
+            _ => true,
+        });
+        if !plain_head {
+            return None;
+        }
+
+        let blocks = self.blocks(&content.body()?, true)?;
+        Some(format!("{}\n", blocks.join("\n\n")))
+    }
+
+    /// element の html の名前空間での名前
+    fn local(node: &Handle) -> Option<&str> {
+        match node.data {
+            NodeData::Element { ref name, .. } if name.ns == ns!(html) => Some(name.local.as_ref()),
+            _ => None,
+        }
+    }
+
+    fn is_block(node: &Handle) -> bool {
+        match Self::local(node) {
+            Some(local) => !["a", "abbr", "b", "bdi", "bdo", "br", "cite", "code", "del", "dfn", "em", "i", "img", "ins",
+                "kbd", "mark", "q", "rp", "rt", "ruby", "s", "samp", "small", "span", "strong", "sub", "sup", "time",
+                "u", "var", "wbr"].contains(&local),
+            // svg, math なども inline として扱う (変換できない)
+            None => false,
+        }
+    }
+
+    /// 属性がないか, あってもリンクされていない id のみであるか
+    fn plain(&self, node: &Handle, allowed: &[&str]) -> Option<()> {
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            for attr in attrs.borrow().iter() {
+                let name = attr.name.local.as_ref();
+                if !(allowed.contains(&name) || (name == "id" && !self.referenced.contains(attr.value.as_ref()))) {
+                    return None;
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// 属性をブロック属性 ` {#id .class key="value"}` として書き出す
+    fn attributes(node: &Handle) -> Option<String> {
+        let mut tokens = Vec::new();
+        if let NodeData::Element { ref attrs, .. } = node.data {
+            for attr in attrs.borrow().iter() {
+                let (name, value) = (attr.name.local.as_ref(), attr.value.as_ref());
+                if value.contains(['{', '}', '"', '\n']) {
+                    return None;
+                }
+                match name {
+                    "id" if !value.is_empty() && !value.contains(char::is_whitespace) => tokens.push(format!("#{}", value)),
+                    "class" => tokens.extend(value.split_whitespace().map(|c| format!(".{}", c))),
+                    _ if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == ':') => {
+                        tokens.push(format!("{}=\"{}\"", name, value))
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        Some(if tokens.is_empty() { String::new() } else { format!(" {{{}}}", tokens.join(" ")) })
+    }
+
+    /// 子要素をブロックの列に変換する. top は fenced div を置ける階層 (body の直下, fenced div の中) か否か
+    fn blocks(&self, node: &Handle, top: bool) -> Option<Vec<String>> {
+        let mut blocks = Vec::new();
+        let mut inline = Vec::new();
+        // 直前のブロックが箇条書きであれば, その要素名. 続けて同じ種類の箇条書きを書くとひとつになってしまう
+        let mut last_list: Option<&str> = None;
+
+        for child in node.children.borrow().iter() {
+            match child.data {
+                NodeData::Comment { .. } | NodeData::ProcessingInstruction { .. } => continue,
+                _ if !Self::is_block(child) => {
+                    inline.push(child.clone());
+                    continue;
+                }
+                _ => {}
+            }
+
+            if let Some(paragraph) = self.paragraph(&inline)? {
+                blocks.push(paragraph);
+                last_list = None;
+            }
+            inline.clear();
+
+            let local = Self::local(child)?;
+            if (local == "ul" || local == "ol") && last_list == Some(local) {
+                return None;
+            }
+            last_list = Some(local).filter(|l| *l == "ul" || *l == "ol");
+            blocks.extend(self.block(child, top)?);
+        }
+        blocks.extend(self.paragraph(&inline)?);
+
+        Some(blocks)
+    }
+
+    /// inline の要素の列を段落にする. 空白のみであれば Some(None)
+    fn paragraph(&self, nodes: &[Handle]) -> Option<Option<String>> {
+        let blank = nodes.iter().all(|n| match n.data {
+            NodeData::Text { ref contents } => contents.borrow().trim().is_empty(),
+            _ => false,
+        });
+        if blank {
+            return Some(None);
+        }
+
+        let text = self.inline(nodes, false)?;
+        let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+        // 空の段落, 末尾の`{…}`がブロック属性と解釈されてしまう段落
+        if text.is_empty() || text.ends_with('\\') || text.ends_with('}') {
+            return None;
+        }
+
+        Some(Some(escape_line_starts(text)))
+    }
+
+    fn block(&self, node: &Handle, top: bool) -> Option<Vec<String>> {
+        let local = Self::local(node)?;
+        let block = match local {
+            "p" => {
+                let text = self.paragraph(&node.children.borrow())??;
+                format!("{}{}", text, Self::attributes(node)?)
+            }
+            _ if HEADINGS.contains(&local) => {
+                let text = self.inline(&node.children.borrow(), false)?;
+                let text = text.trim_matches(|c: char| c.is_ascii_whitespace());
+                if text.is_empty() || text.contains('\n') || text.ends_with(['}', '#']) {
+                    return None;
+                }
+                format!("{} {}{}", "#".repeat(local[1..].parse().ok()?), text, Self::attributes(node)?)
+            }
+            "div" | "section" if self.plain(node, &[]).is_some() => return self.blocks(node, top),
+            // 属性を持つ <div> は fenced div で表す
+            "div" if top => {
+                let attributes = Self::attributes(node)?;
+                // epub:type によっては <aside> になってしまう
+                if xhtml::get_attr(node, "epub:type").is_some() {
+                    return None;
+                }
+                let blocks = self.blocks(node, true)?;
+                format!(":::{}\n\n{}\n\n:::", attributes, blocks.join("\n\n"))
+            }
+            "blockquote" => {
+                self.plain(node, &[])?;
+                let blocks = self.blocks(node, false)?;
+                blocks.join("\n\n").lines()
+                    .map(|l| if l.is_empty() { ">".to_string() } else { format!("> {}", l) })
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            }
+            "ul" | "ol" => self.list(node, local == "ol")?,
+            "pre" => self.code_block(node)?,
+            "hr" => {
+                self.plain(node, &[])?;
+                "---".to_string()
+            }
+            "table" => self.table(node)?,
+            _ => return None,
+        };
+        Some(vec![block])
+    }
+
+    fn list(&self, node: &Handle, ordered: bool) -> Option<String> {
+        let allowed: &[&str] = if ordered { &["start"] } else { &[] };
+        self.plain(node, allowed)?;
+        let start = match xhtml::get_attr(node, "start") {
+            Some(start) => start.trim().parse::<usize>().ok()?,
+            None => 1,
+        };
+
+        let mut items = Vec::new();
+        for child in node.children.borrow().iter() {
+            match child.data {
+                NodeData::Text { ref contents } if contents.borrow().trim().is_empty() => continue,
+                NodeData::Comment { .. } => continue,
+                _ if xhtml::is_element(child, "li") => {
+                    self.plain(child, &[])?;
+                    items.push(child.clone());
+                }
+                _ => return None,
+            }
+        }
+
+        // <p> を含む項目があれば, 項目の間を空ける
+        let loose = items.iter().any(|li| li.children.borrow().iter().any(|c| xhtml::is_element(c, "p")));
+        let separator = if loose { "\n\n" } else { "\n" };
+
+        let mut markdown = Vec::new();
+        for (i, li) in items.iter().enumerate() {
+            let marker = if ordered { format!("{}. ", start + i) } else { "- ".to_string() };
+            let body = self.blocks(li, false)?.join(separator);
+            let indent = " ".repeat(marker.len());
+            let body = body.lines().enumerate()
+                .map(|(n, l)| match (n, l.is_empty()) {
+                    (0, _) => format!("{}{}", marker, l),
+                    (_, true) => String::new(),
+                    (_, false) => format!("{}{}", indent, l),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            markdown.push(if body.is_empty() { marker.trim_end().to_string() } else { body });
+        }
+
+        Some(markdown.join(separator))
+    }
+
+    fn code_block(&self, node: &Handle) -> Option<String> {
+        self.plain(node, &[])?;
+        let children = node.children.borrow();
+        let (language, text) = match children.as_slice() {
+            [code] if xhtml::is_element(code, "code") => {
+                let language = match xhtml::get_attr(code, "class") {
+                    Some(class) => Some(class.strip_prefix("language-").filter(|l| !l.contains(char::is_whitespace))?.to_string()),
+                    None => None,
+                };
+                self.plain(code, &["class"])?;
+                if !code.children.borrow().iter().all(|c| matches!(c.data, NodeData::Text { .. })) {
+                    return None;
+                }
+                (language, xhtml::text_content(code))
+            }
+            nodes if nodes.iter().all(|c| matches!(c.data, NodeData::Text { .. })) => (None, xhtml::text_content(node)),
+            _ => return None,
+        };
+
+        let longest = text.split(|c| c != '`').map(|s| s.len()).max().unwrap_or(0);
+        let fence = "`".repeat(std::cmp::max(3, longest + 1));
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        Some(format!("{}{}\n{}\n{}", fence, language.unwrap_or_default(), text, fence))
+    }
+
+    fn table(&self, node: &Handle) -> Option<String> {
+        self.plain(node, &[])?;
+
+        let mut rows = Vec::new();
+        fn collect_rows(node: &Handle, rows: &mut Vec<Handle>) -> Option<()> {
+            for child in node.children.borrow().iter() {
+                match child.data {
+                    NodeData::Text { ref contents } if contents.borrow().trim().is_empty() => {}
+                    NodeData::Comment { .. } => {}
+                    _ if xhtml::is_element(child, "tr") => rows.push(child.clone()),
+                    _ if ["thead", "tbody", "tfoot"].iter().any(|l| xhtml::is_element(child, l)) => collect_rows(child, rows)?,
+                    _ => return None,
+                }
+            }
+            Some(())
+        }
+        collect_rows(node, &mut rows)?;
+
+        let mut lines = Vec::new();
+        let mut columns = None;
+        for (i, row) in rows.iter().enumerate() {
+            self.plain(row, &[])?;
+            let mut cells = Vec::new();
+            for cell in row.children.borrow().iter() {
+                match cell.data {
+                    NodeData::Text { ref contents } if contents.borrow().trim().is_empty() => continue,
+                    _ => {}
+                }
+                // 最初の行のみ見出しのセル
+                let local = if i == 0 { "th" } else { "td" };
+                if !xhtml::is_element(cell, local) {
+                    return None;
+                }
+                self.plain(cell, &[])?;
+                let text = self.inline(&cell.children.borrow(), false)?;
+                if text.contains('\n') {
+                    return None;
+                }
+                cells.push(text.trim().to_string());
+            }
+            if *columns.get_or_insert(cells.len()) != cells.len() || cells.is_empty() {
+                return None;
+            }
+            lines.push(format!("| {} |", cells.join(" | ")));
+            if i == 0 {
+                lines.push(format!("|{}", " --- |".repeat(cells.len())));
+            }
+        }
+        if lines.is_empty() {
+            return None;
+        }
+
+        Some(lines.join("\n"))
+    }
+
+    /// in_link はリンクの中か否か. リンクの中では URL が自動リンクにならない
+    fn inline(&self, nodes: &[Handle], in_link: bool) -> Option<String> {
+        // (markdown, 強調などの区切り文字で囲んだものか)
+        let mut pieces: Vec<(String, bool)> = Vec::new();
+
+        for node in nodes {
+            match node.data {
+                NodeData::Text { ref contents } => {
+                    let text = contents.borrow();
+                    // 自動リンク, {{#include}} として解釈されてしまう
+                    if (!in_link && (text.contains("://") || text.contains("www.") || text.contains('@'))) || text.contains("{{#") {
+                        return None;
+                    }
+                    let collapsed = text.split(|c: char| c.is_ascii_whitespace()).collect::<Vec<&str>>().join(" ");
+                    let collapsed = collapsed.split(' ').filter(|s| !s.is_empty()).collect::<Vec<&str>>();
+                    let mut text = collapsed.join(" ");
+                    if contents.borrow().starts_with(|c: char| c.is_ascii_whitespace()) {
+                        text.insert(0, ' ');
+                    }
+                    if contents.borrow().ends_with(|c: char| c.is_ascii_whitespace()) && !collapsed.is_empty() {
+                        text.push(' ');
+                    }
+                    pieces.push((escape(&text), false));
+                }
+                NodeData::Comment { .. } => {}
+                NodeData::Element { .. } => {
+                    let local = Self::local(node)?;
+                    let delimiter = match local {
+                        "em" | "i" => Some("*"),
+                        "strong" | "b" => Some("**"),
+                        "del" | "s" => Some("~~"),
+                        _ => None,
+                    };
+                    let piece = match (local, delimiter) {
+                        (_, Some(delimiter)) => {
+                            self.plain(node, &[])?;
+                            let text = self.inline(&node.children.borrow(), in_link)?;
+                            if text.is_empty() || text.starts_with(char::is_whitespace) || text.ends_with(char::is_whitespace) {
+                                return None;
+                            }
+                            pieces.push((format!("{}{}{}", delimiter, text, delimiter), true));
+                            continue;
+                        }
+                        ("code", _) => {
+                            self.plain(node, &[])?;
+                            if !node.children.borrow().iter().all(|c| matches!(c.data, NodeData::Text { .. })) {
+                                return None;
+                            }
+                            let text = xhtml::text_content(node).replace('\n', " ");
+                            if text.trim().is_empty() {
+                                return None;
+                            }
+                            let longest = text.split(|c| c != '`').map(|s| s.len()).max().unwrap_or(0);
+                            let ticks = "`".repeat(longest + 1);
+                            let pad = if text.starts_with('`') || text.ends_with('`') { " " } else { "" };
+                            format!("{}{}{}{}{}", ticks, pad, text, pad, ticks)
+                        }
+                        ("a", _) => {
+                            let text = self.inline(&node.children.borrow(), true)?;
+                            match xhtml::get_attr(node, "href") {
+                                Some(href) => {
+                                    self.plain(node, &["href"])?;
+                                    if text.trim().is_empty() {
+                                        return None;
+                                    }
+                                    format!("[{}]({})", text, self.href(&href)?)
+                                }
+                                // リンクでない <a> は中身のみ
+                                None => {
+                                    self.plain(node, &[])?;
+                                    text
+                                }
+                            }
+                        }
+                        ("img", _) => {
+                            self.plain(node, &["src", "alt"])?;
+                            let src = xhtml::get_attr(node, "src")?;
+                            let alt = xhtml::get_attr(node, "alt").unwrap_or_default();
+                            format!("![{}]({})", escape(&alt), self.href(&src)?)
+                        }
+                        ("br", _) => {
+                            self.plain(node, &[])?;
+                            "\\\n".to_string()
+                        }
+                        ("span", _) => {
+                            self.plain(node, &[])?;
+                            self.inline(&node.children.borrow(), in_link)?
+                        }
+                        _ => return None,
+                    };
+                    pieces.push((piece, false));
+                }
+                _ => return None,
+            }
+        }
+
+        // 強調の区切り文字は, 前後の文字によっては区切りとして解釈されない
+        for (i, (piece, delimited)) in pieces.iter().enumerate() {
+            if !*delimited {
+                continue;
+            }
+            let before = pieces[..i].iter().rev().find_map(|(p, _)| p.chars().last());
+            let after = pieces[i + 1..].iter().find_map(|(p, _)| p.chars().next());
+            let first = piece.trim_start_matches(['*', '~']).chars().next();
+            let last = piece.trim_end_matches(['*', '~']).chars().last();
+            if !flanking(first, before) || !flanking(last, after) {
+                return None;
+            }
+        }
+
+        Some(pieces.into_iter().map(|(p, _)| p).collect())
+    }
+
+    /// リンク先が変換したコンテンツであれば, 変換後のファイル名に書き換える
+    fn href(&self, href: &str) -> Option<String> {
+        if href.contains([' ', '(', ')', '<', '>']) {
+            return None;
+        }
+        let renamed = resolve(self.path, href)
+            .filter(|_| !href.starts_with('#'))
+            .and_then(|(path, _)| self.renamed.get(&path));
+        match renamed {
+            Some(renamed) => {
+                let (path, fragment) = match href.find('#') {
+                    Some(hash) => href.split_at(hash),
+                    None => (href, ""),
+                };
+                let path = Path::new(path).with_extension(renamed.extension()?);
+                Some(format!("{}{}", path.to_str()?, fragment))
+            }
+            None => Some(href.to_string()),
+        }
+    }
+}
+
+/// 強調の区切り文字の内側の文字 inner と外側の文字 outer が, 区切りとして解釈される組み合わせか
+/// 内側が約物ならば, 外側は空白か ASCII の約物でなければならない
+fn flanking(inner: Option<char>, outer: Option<char>) -> bool {
+    match inner {
+        Some(c) if c.is_whitespace() => false,
+        Some(c) if !c.is_alphanumeric() => outer.is_none_or(|o| o.is_whitespace() || o.is_ascii_punctuation()),
+        _ => true,
+    }
+}
+
+/// markdown の記号として解釈される文字を escape する
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '!' | '$' | '~' | '|' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // 文字参照
+            '&' if chars.peek().is_some_and(|n| n.is_ascii_alphabetic() || *n == '#') => escaped.push_str("\\&"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// 行頭の空白を取り除き, 行頭で見出し, 箇条書き, 引用などとして解釈される文字を escape する
+fn escape_line_starts(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let line = line.trim_start_matches(' ');
+            let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+            if line.starts_with(['#', '>', '-', '+', '=', ':']) {
+                format!("\\{}", line)
+            } else if digits > 0 && line[digits..].starts_with(['.', ')']) {
+                format!("{}\\{}", &line[..digits], &line[digits..])
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        assert_eq!(enclosed("OEBPS/./text/../image.png"), Some(PathBuf::from("OEBPS/image.png")));
+        assert_eq!(enclosed("../../etc/passwd"), None);
+        assert_eq!(enclosed("text/../../x"), None);
+        assert_eq!(enclosed("/etc/passwd"), None);
+        assert_eq!(enclosed("..\\x"), None);
+        assert_eq!(percent_decode("my%20book%E3%81%82.xhtml"), "my bookあ.xhtml");
+        assert_eq!(resolve(Path::new("text/a.xhtml"), "b.xhtml#x"), Some((PathBuf::from("text/b.xhtml"), Some("x".to_string()))));
+        assert_eq!(resolve(Path::new("a.xhtml"), "#x"), Some((PathBuf::from("a.xhtml"), Some("x".to_string()))));
+        assert_eq!(resolve(Path::new("a.xhtml"), "https://example.com/"), None);
+    }
+
+    #[test]
+    fn encryption() {
+        let xml = font::encryption_xml(&["OEBPS/fonts/a b.otf".to_string()])
+            .replace("</encryption>", "<enc:EncryptedData><enc:EncryptionMethod Algorithm=\"http://www.w3.org/2001/04/xmlenc#aes128-cbc\"/>\
+                <enc:CipherData><enc:CipherReference URI=\"OEBPS/image%201.png\"/></enc:CipherData></enc:EncryptedData></encryption>");
+        let encrypted = encrypted(&xml).unwrap();
+        assert_eq!(encrypted.get(Path::new("OEBPS/fonts/a b.otf")), Some(&Some(font::ALGORITHM.to_string())));
+        assert_eq!(encrypted.get(Path::new("OEBPS/image 1.png")), Some(&Some("http://www.w3.org/2001/04/xmlenc#aes128-cbc".to_string())));
+    }
+
+    #[test]
+    fn package() {
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uid" version="3.0">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+<dc:identifier>isbn</dc:identifier><dc:identifier id="uid">urn:uuid:1</dc:identifier>
+<dc:title>本</dc:title><dc:creator>A</dc:creator><dc:creator>B</dc:creator><dc:language>ja</dc:language>
+<meta name="cover" content="img"/>
+</metadata>
+<manifest>
+<item id="c1" href="text/c%201.xhtml" media-type="application/xhtml+xml" properties="svg"/>
+<item id="img" href="image/cover.jpg" media-type="image/jpeg"/>
+</manifest>
+<spine page-progression-direction="rtl"><itemref idref="c1"/></spine>
+</package>"#;
+        let package = Package::parse(opf).unwrap();
+        assert_eq!(package.title.as_deref(), Some("本"));
+        assert_eq!(package.creators, vec!["A", "B"]);
+        assert_eq!(package.identifier.as_deref(), Some("urn:uuid:1"));
+        assert_eq!(package.page_progression_direction.as_deref(), Some("rtl"));
+        assert_eq!(package.spine, vec!["c1"]);
+        assert_eq!(package.items[0].properties, vec!["svg"]);
+        assert_eq!(package.cover_image().map(|i| i.href.as_str()), Some("image/cover.jpg"));
+        assert_eq!(rootfile(r#"<container><rootfiles><rootfile full-path="OEBPS/package.opf"/></rootfiles></container>"#).unwrap(), PathBuf::from("OEBPS/package.opf"));
+        assert!(rootfile(r#"<container><rootfile full-path="../package.opf"/></container>"#).is_err());
+    }
+
+    #[test]
+    fn markdown() {
+        use html5ever::{parse_document, ParseOpts, rcdom::RcDom, tendril::TendrilSink};
+
+        fn convert(body: &str, referenced: &[&str]) -> Option<String> {
+            let html = format!("<html><head><title>t</title></head><body>{}</body></html>", body);
+            let item = Item::default();
+            let content = Content {
+                item: &item,
+                path: PathBuf::from("text/a.xhtml"),
+                data: Vec::new(),
+                dom: parse_document(RcDom::default(), ParseOpts::default()).one(html),
+            };
+            let referenced = referenced.iter().map(|s| s.to_string()).collect();
+            let renamed = vec![(PathBuf::from("text/b.xhtml"), PathBuf::from("text/b.md"))].into_iter().collect();
+            Markdown { path: &content.path, referenced: &referenced, renamed: &renamed }.document(&content)
+        }
+
+        assert_eq!(
+            convert("<h1 id=\"c1\">第一章</h1>\n<p class=\"lead\">これは<strong>強調</strong>と<a href=\"b.xhtml#x\">リンク</a>です。<br/>\n1. 次の行</p>\n\
+                     <ul><li>一</li><li>二<ol start=\"3\"><li>三</li></ol></li></ul>\n<pre><code class=\"language-rust\">fn main() {}\n</code></pre>\n\
+                     <div class=\"box\"><p>*と$</p></div><table><tr><th>a</th><th>b</th></tr><tr><td>1</td><td>2</td></tr></table>", &[]).unwrap(),
+            "# 第一章 {#c1}\n\nこれは**強調**と[リンク](b.md#x)です。\\\n1\\. 次の行 {.lead}\n\n- 一\n- 二\n  3. 三\n\n```rust\nfn main() {}\n```\n\n\
+             ::: {.box}\n\n\\*と\\$\n\n:::\n\n| a | b |\n| --- | --- |\n| 1 | 2 |\n"
+        );
+        // 空の段落, リンクされている <span> の id, 約物の直後の区切り文字, <ruby> は markdown で表せない
+        assert_eq!(convert("<p><br/></p>", &[]), None);
+        assert_eq!(convert("<p><span id=\"n\">注</span></p>", &["n"]), None);
+        assert_eq!(convert("<p><span id=\"n\">注</span></p>", &[]), Some("注\n".to_string()));
+        assert_eq!(convert("<p>これは<em>「強調」</em>です</p>", &[]), None);
+        assert_eq!(convert("<p><ruby>漢字<rt>かんじ</rt></ruby></p>", &[]), None);
+    }
+}
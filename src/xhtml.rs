@@ -0,0 +1,368 @@
+//! html5ever の DOM を XHTML (XML) として書き出す, また DOM を操作する
+//!
+//! html5ever の serializer は HTML の構文で出力するため, 空要素が閉じられない, `&nbsp;` などの
+//! XML では未定義の実体参照が残る, SVG / MathML の名前空間が宣言されない, といった問題がある
+
+use html5ever::{
+    QualName,
+    LocalName,
+    Attribute,
+    rcdom::{Node, NodeData, Handle},
+    tendril::StrTendril,
+};
+use std::{cell::RefCell, rc::Rc};
+
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+
+/// 子要素を持たない HTML の要素
+const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img",
+    "input", "link", "meta", "param", "source", "track", "wbr",
+];
+
+/// node の子要素を XHTML として書き出す
+pub fn serialize_children(node: &Handle) -> String {
+    let mut xhtml = String::new();
+    for child in node.children.borrow().iter() {
+        write_node(child, &mut xhtml, &Context::default());
+    }
+    xhtml
+}
+
+/// テキストとして書き出すための escape
+pub fn escape_text(text: &str) -> String {
+    escape(text, false)
+}
+
+/// 属性値として書き出すための escape
+pub fn escape_attr(value: &str) -> String {
+    escape(value, true)
+}
+
+fn escape(s: &str, attr: bool) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars().filter(|c| is_xml_char(*c)) {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if attr => escaped.push_str("&quot;"),
+            '\n' if attr => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// XML 1.0 の文書に含めることのできる文字か否か
+fn is_xml_char(c: char) -> bool {
+    match c {
+        '\t' | '\n' | '\r' => true,
+        '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}' => false,
+        _ => true,
+    }
+}
+
+/// XML の属性名として使える名前か否か
+/// HTML の parser は `"` や `=` を含む属性名も受理してしまう
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == ':' || c == '-' || c == '.')
+}
+
+/// HTML の要素を作成する
+pub fn create_element(local: &str, attrs: &[(&str, &str)]) -> Handle {
+    Node::new(NodeData::Element {
+        name: QualName::new(None, ns!(html), LocalName::from(local)),
+        attrs: RefCell::new(attrs.iter().map(|(name, value)| create_attribute(name, value)).collect()),
+        template_contents: None,
+        mathml_annotation_xml_integration_point: false,
+    })
+}
+
+/// テキストノードを作成する
+pub fn create_text(text: &str) -> Handle {
+    Node::new(NodeData::Text { contents: RefCell::new(StrTendril::from(text)) })
+}
+
+pub fn create_attribute(name: &str, value: &str) -> Attribute {
+    Attribute {
+        name: QualName::new(None, ns!(), LocalName::from(name)),
+        value: StrTendril::from(value),
+    }
+}
+
+/// child を parent の末尾に追加する
+/// rcdom の append は公開されていないので, 親への参照もここで張り替える
+pub fn append(parent: &Handle, child: Handle) {
+    child.parent.set(Some(Rc::downgrade(parent)));
+    parent.children.borrow_mut().push(child);
+}
+
+/// HTML の要素 local であるか否か
+pub fn is_element(node: &Handle, local: &str) -> bool {
+    match node.data {
+        NodeData::Element { ref name, .. } => name.ns == ns!(html) && name.local.as_ref() == local,
+        _ => false,
+    }
+}
+
+/// 子孫のテキストを連結したもの
+pub fn text_content(node: &Handle) -> String {
+    fn collect(node: &Handle, text: &mut String) {
+        if let NodeData::Text { ref contents } = node.data {
+            text.push_str(&contents.borrow());
+        }
+        for child in node.children.borrow().iter() {
+            collect(child, text);
+        }
+    }
+
+    let mut text = String::new();
+    collect(node, &mut text);
+    text
+}
+
+/// 属性の値
+pub fn get_attr(node: &Handle, name: &str) -> Option<String> {
+    match node.data {
+        NodeData::Element { ref attrs, .. } => attrs.borrow().iter()
+            .find(|a| a.name.local.as_ref() == name)
+            .map(|a| a.value.to_string()),
+        _ => None,
+    }
+}
+
+/// 属性の値を設定する. 既にあれば置き換える
+pub fn set_attr(node: &Handle, name: &str, value: &str) {
+    if let NodeData::Element { ref attrs, .. } = node.data {
+        let mut attrs = attrs.borrow_mut();
+        match attrs.iter_mut().find(|a| a.name.local.as_ref() == name) {
+            Some(attr) => attr.value = StrTendril::from(value),
+            None => attrs.push(create_attribute(name, value)),
+        }
+    }
+}
+
+/// `.html`ファイルから取り出した, コンテンツとして必要な部分
+#[derive(Debug, Default, PartialEq)]
+pub struct HtmlDocument {
+    /// `<title>`の内容
+    pub title: Option<String>,
+    /// `<head>`内の`<style>`要素 (XHTML として書き出したもの)
+    pub styles: Vec<String>,
+    /// `<body>`の子要素 (HTML として再び parse できる)
+    pub body: String,
+}
+
+/// HTML の文書を parse する
+/// `<link>`によるスタイルシートは, repub がコンテンツに適用するものと重複するので取り出さない
+pub fn parse_document(html: &str) -> HtmlDocument {
+    use html5ever::{parse_document, ParseOpts, rcdom::RcDom, tendril::TendrilSink};
+
+    fn find(node: &Handle, local: &str) -> Option<Handle> {
+        node.children.borrow().iter().find(|c| is_element(c, local)).cloned()
+    }
+
+    let dom = parse_document(RcDom::default(), ParseOpts::default()).one(html);
+    let mut document = HtmlDocument::default();
+    // html, head, body は parser が補う
+    let html = match find(&dom.document, "html") {
+        Some(html) => html,
+        None => return document,
+    };
+
+    if let Some(head) = find(&html, "head") {
+        for child in head.children.borrow().iter() {
+            if is_element(child, "title") {
+                let title = text_content(child).trim().to_string();
+                if !title.is_empty() {
+                    document.title = Some(title);
+                }
+            } else if is_element(child, "style") {
+                let mut style = String::new();
+                write_node(child, &mut style, &Context::default());
+                document.styles.push(style);
+            }
+        }
+    }
+    if let Some(body) = find(&html, "body") {
+        document.body = serialize_children(&body);
+    }
+
+    document
+}
+
+#[derive(Clone)]
+struct Context {
+    /// 親要素の既定の名前空間
+    ns: &'static str,
+    /// xlink の名前空間が宣言済みか
+    xlink: bool,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self { ns: XHTML_NS, xlink: false }
+    }
+}
+
+fn namespace_of(name: &QualName) -> &'static str {
+    match name.ns {
+        ns!(svg) => SVG_NS,
+        ns!(mathml) => MATHML_NS,
+        _ => XHTML_NS,
+    }
+}
+
+fn write_node(node: &Handle, out: &mut String, ctx: &Context) {
+    match node.data {
+        NodeData::Text { ref contents } => out.push_str(&escape_text(&contents.borrow())),
+        NodeData::Comment { ref contents } => {
+            // `--` はコメント内に置けない
+            let mut comment = contents.to_string();
+            while comment.contains("--") {
+                comment = comment.replace("--", "- -");
+            }
+            out.push_str(&format!("<!--{}-->", comment.trim_end_matches('-')));
+        }
+        NodeData::Element { ref name, ref attrs, .. } => {
+            let ns = namespace_of(name);
+            let local = name.local.as_ref();
+            let mut child_ctx = Context { ns, xlink: ctx.xlink };
+
+            out.push('<');
+            out.push_str(local);
+
+            // 親と名前空間が異なる場合は宣言する
+            if ns != ctx.ns {
+                out.push_str(&format!(" xmlns=\"{}\"", ns));
+            }
+
+            let attrs = attrs.borrow();
+            if !ctx.xlink && attrs.iter().any(|a| a.name.ns == ns!(xlink)) {
+                out.push_str(&format!(" xmlns:xlink=\"{}\"", XLINK_NS));
+                child_ctx.xlink = true;
+            }
+
+            for attr in attrs.iter() {
+                let local = attr.name.local.as_ref();
+                let qualified = match attr.name.ns {
+                    ns!(xml) => format!("xml:{}", local),
+                    ns!(xlink) => format!("xlink:{}", local),
+                    // 名前空間の宣言は上で行う
+                    ns!(xmlns) => continue,
+                    _ if local == "xmlns" => continue,
+                    _ if local == "xmlns:xlink" => continue,
+                    _ => local.to_string(),
+                };
+                if !is_xml_name(&qualified) {
+                    continue;
+                }
+                out.push_str(&format!(" {}=\"{}\"", qualified, escape_attr(&attr.value)));
+            }
+
+            let children = node.children.borrow();
+            if children.is_empty() && (ns != XHTML_NS || VOID_ELEMENTS.contains(&local)) {
+                out.push_str(" />");
+                return;
+            }
+            out.push('>');
+
+            // template 要素の中身は contents に格納される
+            let template_contents = if let NodeData::Element { template_contents: Some(ref contents), .. } = node.data {
+                Some(contents.clone())
+            } else { None };
+
+            for child in children.iter() {
+                write_node(child, out, &child_ctx);
+            }
+            if let Some(contents) = template_contents {
+                for child in contents.children.borrow().iter() {
+                    write_node(child, out, &child_ctx);
+                }
+            }
+
+            out.push_str(&format!("</{}>", local));
+        }
+        NodeData::Document => {
+            for child in node.children.borrow().iter() {
+                write_node(child, out, ctx);
+            }
+        }
+        // DOCTYPE や処理命令は template の側で書く
+        NodeData::Doctype { .. } | NodeData::ProcessingInstruction { .. } => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use html5ever::{
+        parse_fragment,
+        ParseOpts,
+        rcdom::RcDom,
+        tendril::TendrilSink,
+    };
+
+    fn roundtrip(html: &str) -> String {
+        let dom = parse_fragment(
+            RcDom::default(),
+            ParseOpts::default(),
+            QualName::new(None, ns!(html), local_name!("body")),
+            vec![],
+        ).one(html);
+        let root = dom.document.children.borrow()[0].clone();
+        serialize_children(&root)
+    }
+
+    #[test]
+    fn void_elements() {
+        assert_eq!(
+            roundtrip("<p>a<br>b<img src=\"x.png\" alt=\"\"><wbr><input type=\"checkbox\" checked></p><hr>"),
+            "<p>a<br />b<img src=\"x.png\" alt=\"\" /><wbr /><input type=\"checkbox\" checked=\"\" /></p><hr />"
+        );
+    }
+
+    #[test]
+    fn text_is_escaped() {
+        // `<img` を含むテキストを壊さない
+        assert_eq!(
+            roundtrip("<p>&lt;img src&gt; &amp; a&nbsp;b</p>"),
+            "<p>&lt;img src&gt; &amp; a\u{A0}b</p>"
+        );
+        assert_eq!(roundtrip("<a href=\"?a=1&amp;b=&quot;\">x</a>"), "<a href=\"?a=1&amp;b=&quot;\">x</a>");
+    }
+
+    #[test]
+    fn namespaces() {
+        assert_eq!(
+            roundtrip("<svg viewBox=\"0 0 1 1\"><use xlink:href=\"#a\"></use></svg>"),
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 1 1\"><use xmlns:xlink=\"http://www.w3.org/1999/xlink\" xlink:href=\"#a\" /></svg>"
+        );
+        assert_eq!(
+            roundtrip("<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mi>x</mi></math>"),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mi>x</mi></math>"
+        );
+    }
+
+    #[test]
+    fn document() {
+        let document = parse_document(
+            "<!DOCTYPE html><html><head><title> Title </title><link rel=\"stylesheet\" href=\"a.css\">\
+             <style>p > a { color: red; }</style></head><body><h1>H</h1><p>a<br>b</body></html>"
+        );
+        assert_eq!(document, HtmlDocument {
+            title: Some("Title".to_string()),
+            styles: vec!["<style>p &gt; a { color: red; }</style>".to_string()],
+            body: "<h1>H</h1><p>a<br />b</p>".to_string(),
+        });
+    }
+}